@@ -0,0 +1,115 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use serde_json::json;
+use std::sync::Once;
+
+use fortuna::host_functions::take_emitted;
+use fortuna::{init_v8, JSEnv};
+
+static INIT: Once = Once::new();
+
+// Doc sizes in bytes for the benchmarks that take a doc
+const DOC_SIZES: &[usize] = &[100, 1_000, 10_000, 100_000];
+
+const MAP_FUNS: &str = r#"[
+    "(function (doc) { emit(doc._id, doc.value); })",
+    "(function (doc) { if (doc.padding) { emit([doc.value, doc.padding.length], null); } })"
+]"#;
+
+// A cut down version of the map harness from the bench client
+const HARNESS_JS: &str = r#"
+let mapFuns = [];
+
+function init(mapFunsJSON) {
+    mapFuns = JSON.parse(mapFunsJSON).map((source) => eval(source));
+    return true;
+}
+
+function mapDoc(docJSON) {
+    const doc = JSON.parse(docJSON);
+    return mapFuns.map((mapFun) => {
+        emitGroup();
+        mapFun(doc);
+        return null;
+    });
+}
+
+function echo(docJSON) {
+    return JSON.parse(docJSON);
+}
+"#;
+
+fn js_env() -> JSEnv {
+    INIT.call_once(init_v8);
+    JSEnv::new()
+}
+
+fn doc(size: usize) -> String {
+    let mut doc = json!({ "_id": "doc", "value": 1, "padding": "" });
+    let padding = "x".repeat(size.saturating_sub(doc.to_string().len()));
+    doc["padding"] = json!(padding);
+    doc.to_string()
+}
+
+fn create_isolate(c: &mut Criterion) {
+    let js_env = js_env();
+    c.bench_function("create_isolate", |b| b.iter(|| js_env.create_isolate()));
+}
+
+fn eval_map_function(c: &mut Criterion) {
+    let js_env = js_env();
+    let mut isolate = js_env.create_isolate();
+    c.bench_function("eval_map_function", |b| {
+        b.iter(|| {
+            isolate
+                .eval("(function (doc) { emit(doc._id, doc.value); });", &[])
+                .unwrap()
+        })
+    });
+}
+
+fn map_doc(c: &mut Criterion) {
+    let js_env = js_env();
+    let mut isolate = js_env.create_isolate();
+    isolate.eval(HARNESS_JS, &[]).unwrap();
+    isolate.call("init", &[MAP_FUNS.to_string()]).unwrap();
+
+    let mut group = c.benchmark_group("map_doc");
+    for size in DOC_SIZES {
+        let doc = doc(*size);
+        group.throughput(Throughput::Bytes(*size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &doc, |b, doc| {
+            b.iter(|| {
+                isolate.call("mapDoc", &[doc.clone()]).unwrap();
+                // Rows pile up on the thread until they're taken
+                take_emitted().unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+// Parsing a doc in JS and stringifying it back again for the reply
+fn stringify(c: &mut Criterion) {
+    let js_env = js_env();
+    let mut isolate = js_env.create_isolate();
+    isolate.eval(HARNESS_JS, &[]).unwrap();
+
+    let mut group = c.benchmark_group("stringify");
+    for size in DOC_SIZES {
+        let doc = doc(*size);
+        group.throughput(Throughput::Bytes(*size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &doc, |b, doc| {
+            b.iter(|| isolate.call("echo", &[doc.clone()]).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    create_isolate,
+    eval_map_function,
+    map_doc,
+    stringify
+);
+criterion_main!(benches);
@@ -1,33 +1,153 @@
 use std::env;
 use std::fs;
 use std::fs::read_dir;
-use std::path::Path;
+#[cfg(feature = "prebuilt-snapshot")]
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "prebuilt-snapshot")]
+use rusty_v8_build as v8;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     create_js_src_file()?;
     tonic_build::compile_protos("proto/ateles.proto")?;
+    tonic_build::compile_protos("proto/health.proto")?;
     Ok(())
 }
 
-// Load all the files from js/ and create a string with them to be added to the
-// snapshot isolate
+// List the files in js/ so they can be added to the snapshot isolate. Each
+// file is included separately, in name order, so that errors can name the
+// file and the admin API can report what was loaded. ES modules live in
+// js/modules/ and are listed separately since they can't be run as scripts.
 fn create_js_src_file() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=js");
+    println!("cargo:rerun-if-changed=proto");
     let out_dir = env::var_os("OUT_DIR").unwrap();
     let dest_path = Path::new(&out_dir).join("js_startup_code.rs");
-    let js_codes = read_dir("./js")
-        .unwrap()
-        .filter(|entry| entry.as_ref().unwrap().path().is_file())
-        .map(|file_entry| {
-            let name = file_entry.unwrap().path();
-            println!("reading from file {:?}", name);
-            fs::read_to_string(&name).unwrap()
-        })
-        .collect::<Vec<String>>()
-        .join("");
 
-    let code = format!("pub const JS_CODE: &str = r#\"{}\"#;", js_codes);
+    let js_files = js_entries(Path::new("./js"))?;
+    let js_modules = js_entries(Path::new("./js/modules"))?;
+    let snapshot = match prebuilt_snapshot(Path::new(&out_dir)) {
+        Some(path) => format!("Some(include_bytes!({:?}))", path),
+        None => "None".to_string(),
+    };
+    let code = format!(
+        "pub const JS_FILES: &[(&str, &str)] = &[\n{}];\n\
+         pub const JS_MODULES: &[(&str, &str)] = &[\n{}];\n\
+         pub const PREBUILT_SNAPSHOT: Option<&[u8]> = {};\n",
+        js_files, js_modules, snapshot
+    );
 
     fs::write(dest_path, code).unwrap();
 
     Ok(())
 }
+
+// Every file directly in dir, in name order
+fn js_paths(dir: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut paths = read_dir(dir)?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+    paths.sort();
+    Ok(paths)
+}
+
+// A (name, include_str!(path)) entry for every file directly in dir
+fn js_entries(dir: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    let entries = js_paths(dir)?
+        .iter()
+        .map(|path| {
+            println!("reading from file {:?}", path);
+            let name = path.file_name().unwrap().to_string_lossy();
+            let full_path = fs::canonicalize(path).unwrap();
+            format!("    ({:?}, include_str!({:?})),\n", name, full_path)
+        })
+        .collect::<String>();
+    Ok(entries)
+}
+
+// With the prebuilt-snapshot feature the snapshot is created here, the way
+// JSEnv::try_new would at startup, and embedded in the binary. A snapshot
+// only works with the V8 it was made by so this is skipped when cross
+// compiling, as it is if the bundle doesn't load. JSEnv then builds it at
+// startup and reports why.
+#[cfg(feature = "prebuilt-snapshot")]
+fn prebuilt_snapshot(out_dir: &Path) -> Option<PathBuf> {
+    if env::var_os("HOST") != env::var_os("TARGET") {
+        println!("cargo:warning=not prebuilding the snapshot when cross compiling");
+        return None;
+    }
+    let blob = match create_snapshot() {
+        Ok(blob) => blob,
+        Err(err) => {
+            println!("cargo:warning=not prebuilding the snapshot: {}", err);
+            return None;
+        }
+    };
+    let path = out_dir.join("snapshot.bin");
+    fs::write(&path, blob).unwrap();
+    Some(fs::canonicalize(path).unwrap())
+}
+
+#[cfg(not(feature = "prebuilt-snapshot"))]
+fn prebuilt_snapshot(_out_dir: &Path) -> Option<PathBuf> {
+    None
+}
+
+// The files in js/ followed by the hardening script, see
+// JSEnv::load_bundle
+#[cfg(feature = "prebuilt-snapshot")]
+fn create_snapshot() -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut paths = js_paths(Path::new("./js"))?;
+    paths.push(PathBuf::from("./js/sandbox/harden.js"));
+    let sources = paths
+        .iter()
+        .map(|path| Ok((path.display().to_string(), fs::read_to_string(path)?)))
+        .collect::<Result<Vec<_>, io::Error>>()?;
+
+    let platform = v8::new_default_platform().unwrap();
+    v8::V8::initialize_platform(platform);
+    v8::V8::initialize();
+
+    let mut snapshot_creator = v8::SnapshotCreator::new(None);
+    {
+        let mut isolate = unsafe { snapshot_creator.get_owned_isolate() };
+        let loaded = load_bundle(&mut isolate, &mut snapshot_creator, &sources);
+        std::mem::forget(isolate);
+        loaded?;
+    }
+
+    let blob = snapshot_creator
+        .create_blob(v8::FunctionCodeHandling::Clear)
+        .ok_or("failed to create the snapshot")?;
+    Ok(blob.to_vec())
+}
+
+#[cfg(feature = "prebuilt-snapshot")]
+fn load_bundle(
+    isolate: &mut v8::OwnedIsolate,
+    snapshot_creator: &mut v8::SnapshotCreator,
+    sources: &[(String, String)],
+) -> Result<(), String> {
+    let mut hs = v8::HandleScope::new(isolate);
+    let scope = hs.enter();
+    let context = v8::Context::new(scope);
+    let mut cs = v8::ContextScope::new(scope, context);
+    let scope = cs.enter();
+
+    for (name, code) in sources {
+        let source = v8::String::new(scope, code).unwrap();
+        let ran = v8::Script::compile(scope, context, source, None)
+            .and_then(|mut script| script.run(scope, context));
+        if ran.is_none() {
+            return Err(format!("{} failed to load", name));
+        }
+    }
+    snapshot_creator.set_default_context(context);
+    Ok(())
+}
@@ -0,0 +1,51 @@
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+//
+// Run in a context created with a clock other than the real one. Evaluates to
+// a function that takes the global object and a native now() giving the
+// clock's time in milliseconds, and puts a Date in place that asks it
+// whenever the real one would ask the system.
+
+(function (global, now) {
+    "use strict";
+
+    const RealDate = global.Date;
+
+    function Date(...args) {
+        // Date() called as a function is a string of the current time
+        if (new.target === undefined) {
+            return new RealDate(now()).toString();
+        }
+        if (args.length === 0) {
+            return Reflect.construct(RealDate, [now()], new.target);
+        }
+        return Reflect.construct(RealDate, args, new.target);
+    }
+    Object.defineProperty(Date, "prototype", {
+        value: RealDate.prototype,
+        writable: false,
+        configurable: false,
+        enumerable: false,
+    });
+    Object.defineProperty(Date, "length", { value: RealDate.length });
+    Date.now = () => now();
+    Date.parse = RealDate.parse;
+    Date.UTC = RealDate.UTC;
+    Object.freeze(Date);
+
+    Object.defineProperty(global, "Date", {
+        value: Date,
+        writable: false,
+        configurable: false,
+        enumerable: false,
+    });
+});
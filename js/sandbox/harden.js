@@ -0,0 +1,88 @@
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+//
+// Run once at the end of snapshot creation, after the runtime JS in js/ has
+// been loaded. It is not part of the js/ bundle because it has to run last.
+
+(function (global) {
+    "use strict";
+
+    // Globals user code has no business with. They allow shared memory
+    // between isolates. WebAssembly is kept for contexts granted the wasm
+    // capability and removed from the others when they're created.
+    const stripped = ["SharedArrayBuffer", "Atomics"];
+    stripped.forEach((name) => {
+        delete global[name];
+    });
+
+    // Freeze the intrinsics so design docs can't monkey patch what other
+    // functions (and the harness) rely on.
+    const intrinsics = [
+        Object, Object.prototype,
+        Function, Function.prototype,
+        Array, Array.prototype,
+        String, String.prototype,
+        Number, Number.prototype,
+        Boolean, Boolean.prototype,
+        Symbol, Symbol.prototype,
+        Date, Date.prototype,
+        RegExp, RegExp.prototype,
+        Error, Error.prototype,
+        TypeError, TypeError.prototype,
+        SyntaxError, SyntaxError.prototype,
+        RangeError, RangeError.prototype,
+        ReferenceError, ReferenceError.prototype,
+        Map, Map.prototype,
+        Set, Set.prototype,
+        WeakMap, WeakMap.prototype,
+        WeakSet, WeakSet.prototype,
+        Promise, Promise.prototype,
+        JSON, Math, Reflect,
+    ];
+    if (typeof WebAssembly !== "undefined") {
+        intrinsics.push(
+            WebAssembly,
+            WebAssembly.Module, WebAssembly.Module.prototype,
+            WebAssembly.Instance, WebAssembly.Instance.prototype,
+        );
+    }
+    intrinsics.forEach((obj) => Object.freeze(obj));
+
+    // Makes the named globals read-only so later scripts can't replace them.
+    // Names that don't exist are ignored.
+    function lockGlobals(names) {
+        names.forEach((name) => {
+            if (!(name in global)) {
+                return;
+            }
+            Object.defineProperty(global, name, {
+                value: global[name],
+                writable: false,
+                configurable: false,
+                enumerable: false,
+            });
+        });
+        return true;
+    }
+
+    lockGlobals(["esprima", "escodegen", "rewriteFun", "rewriteFuns", "rewriteFunInt"]);
+    lockGlobals(["sum", "isArray", "toJSON", "makeRequire"]);
+
+    // Exposed so a harness loaded later (e.g. map.js with emit) can lock
+    // itself down as well: CALL lockGlobals '["emit", "mapDoc"]'
+    Object.defineProperty(global, "lockGlobals", {
+        value: (namesJSON) => lockGlobals(JSON.parse(namesJSON)),
+        writable: false,
+        configurable: false,
+        enumerable: false,
+    });
+})(this);
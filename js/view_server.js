@@ -0,0 +1,83 @@
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+//
+// The globals CouchDB's JavaScript view server gives design doc functions,
+// so existing design docs run unmodified. Loaded into the snapshot and into
+// every design doc's context. emit() and log() come from Rust, see
+// host_functions.rs.
+
+function sum(values) {
+    var total = 0;
+    for (var i in values) {
+        total += values[i];
+    }
+    return total;
+}
+
+function isArray(obj) {
+    return Array.isArray(obj);
+}
+
+function toJSON(obj) {
+    return JSON.stringify(obj);
+}
+
+// A CommonJS require() over a design doc's views.lib, e.g.
+// require("views/lib/util"). modules maps each module id to its source
+// wrapped in a function(module, exports, require), which register_ddoc
+// compiles. Paths starting with ./ or ../ are relative to the requiring
+// module. Each module runs once per design doc.
+function makeRequire(modules) {
+    "use strict";
+
+    const cache = {};
+    const has = (obj, key) => Object.prototype.hasOwnProperty.call(obj, key);
+
+    function resolve(path, parent) {
+        const parts = path.split("/");
+        const relative = parts[0] === "." || parts[0] === "..";
+        const resolved = relative ? parent.slice(0, -1) : [];
+        parts.forEach((part) => {
+            if (part === "..") {
+                if (resolved.length === 0) {
+                    throw new Error("invalid require path " + path);
+                }
+                resolved.pop();
+            } else if (part !== "." && part !== "") {
+                resolved.push(part);
+            }
+        });
+        return resolved;
+    }
+
+    function load(path, parent) {
+        const resolved = resolve(path, parent);
+        const id = resolved.join("/");
+        if (has(cache, id)) {
+            return cache[id].exports;
+        }
+
+        if (!has(modules, id)) {
+            throw new Error("require: " + id + " not found");
+        }
+
+        const module = {id: id, exports: {}};
+        cache[id] = module;
+        const fun = modules[id];
+        fun.call(module.exports, module, module.exports, (path) => load(String(path), resolved));
+        return module.exports;
+    }
+
+    return (path) => load(String(path), []);
+}
+
+var require = makeRequire({});
@@ -0,0 +1,149 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::line_writer::LineWriter;
+use crate::metrics;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessLogFormat {
+    // The Common Log Format followed by op=, queue_us=, exec_us= and
+    // request_id= for requests that ran a command
+    Common,
+    // A JSON object per line
+    Json,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "common" => Ok(AccessLogFormat::Common),
+            "json" => Ok(AccessLogFormat::Json),
+            _ => Err(format!(
+                "unknown access log format {}, expected common or json",
+                format
+            )),
+        }
+    }
+}
+
+// What /Ateles/Execute adds to its response for the access log
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExecDetails {
+    pub op: String,
+    pub request_id: String,
+    // Body bytes received, before decompression
+    pub bytes: u64,
+    pub queue_wait_us: u64,
+    pub execution_us: u64,
+}
+
+// One request as the access log sees it
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AccessEntry {
+    // When the request arrived
+    #[serde(with = "rfc3339")]
+    pub ts: DateTime<Utc>,
+    pub peer: String,
+    pub method: String,
+    pub path: String,
+    pub version: String,
+    // Request body bytes, as sent
+    pub bytes: u64,
+    pub status: u16,
+    pub took_us: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub op: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_us: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exec_us: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+mod rfc3339 {
+    use chrono::{DateTime, SecondsFormat, Utc};
+    use serde::Serializer;
+
+    pub fn serialize<S: Serializer>(ts: &DateTime<Utc>, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&ts.to_rfc3339_opts(SecondsFormat::Millis, true))
+    }
+}
+
+impl AccessEntry {
+    pub fn exec(&mut self, details: ExecDetails) {
+        self.op = Some(details.op);
+        self.bytes = details.bytes;
+        self.queue_us = Some(details.queue_wait_us);
+        self.exec_us = Some(details.execution_us);
+        if !details.request_id.is_empty() {
+            self.request_id = Some(details.request_id);
+        }
+    }
+
+    pub fn format(&self, format: AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Json => serde_json::to_string(self).unwrap(),
+            AccessLogFormat::Common => {
+                let mut line = format!(
+                    "{} - - [{}] \"{} {} {}\" {} {}",
+                    self.peer,
+                    self.ts.format("%d/%b/%Y:%H:%M:%S %z"),
+                    self.method,
+                    self.path,
+                    self.version,
+                    self.status,
+                    self.bytes
+                );
+                if let Some(op) = &self.op {
+                    line.push_str(&format!(
+                        " op={} queue_us={} exec_us={}",
+                        op,
+                        self.queue_us.unwrap_or(0),
+                        self.exec_us.unwrap_or(0)
+                    ));
+                }
+                if let Some(request_id) = &self.request_id {
+                    line.push_str(&format!(" request_id={}", request_id));
+                }
+                line
+            }
+        }
+    }
+}
+
+// Writes an access log line for every request, to a file or stdout and
+// apart from the application log
+pub struct AccessLog {
+    format: AccessLogFormat,
+    writer: LineWriter,
+}
+
+impl AccessLog {
+    // dest is a file to append to, or - for stdout
+    pub fn open(dest: &Path, format: AccessLogFormat) -> io::Result<AccessLog> {
+        let out: Box<dyn Write + Send> = if dest == Path::new("-") {
+            Box::new(io::stdout())
+        } else {
+            Box::new(OpenOptions::new().create(true).append(true).open(dest)?)
+        };
+        let writer = LineWriter::start(
+            "fortuna-access-log".to_string(),
+            "the access log".to_string(),
+            &metrics::ACCESS_LOG_DROPPED_TOTAL,
+            BufWriter::new(out),
+        )?;
+        Ok(AccessLog { format, writer })
+    }
+
+    // Never waits on the disk
+    pub fn log(&self, entry: &AccessEntry) {
+        self.writer.send(entry.format(self.format));
+    }
+}
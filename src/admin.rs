@@ -0,0 +1,30 @@
+use std::io;
+use std::path::Path;
+
+use crate::auth;
+use crate::listen::Bind;
+
+pub use crate::auth::authorized;
+
+// Where the admin and debug endpoints are served when they're kept off the
+// listeners Execute is on, and the bearer token every request to it needs
+#[derive(Clone, Debug, PartialEq)]
+pub struct AdminConfig {
+    pub bind: Bind,
+    pub token: String,
+}
+
+impl AdminConfig {
+    // The token is the first line of token_file, which should only be
+    // readable by whoever runs fortuna
+    pub fn new(bind: Bind, token_file: &Path) -> io::Result<AdminConfig> {
+        let token = auth::read_token(token_file, "admin")?;
+        Ok(AdminConfig { bind, token })
+    }
+}
+
+// Whether path is one of the admin and debug endpoints. /live, /ready and
+// /metrics stay wherever Execute is so probes and scrapers need no token.
+pub fn is_admin_path(path: &str) -> bool {
+    path == "/stats" || path.starts_with("/admin/")
+}
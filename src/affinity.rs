@@ -0,0 +1,55 @@
+use std::io;
+use std::mem;
+
+// Parses a list of cores like "0-3,8,10-11"
+pub fn parse_cores(list: &str) -> Result<Vec<usize>, String> {
+    let mut cores = Vec::new();
+    for part in list
+        .split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+    {
+        let invalid = || format!("invalid core list {}", list);
+        match part.find('-') {
+            Some(idx) => {
+                let first: usize = part[..idx].parse().map_err(|_| invalid())?;
+                let last: usize = part[idx + 1..].parse().map_err(|_| invalid())?;
+                if first > last {
+                    return Err(invalid());
+                }
+                cores.extend(first..=last);
+            }
+            None => cores.push(part.parse().map_err(|_| invalid())?),
+        }
+    }
+    Ok(cores)
+}
+
+// The cores the calling thread may run on
+pub fn current_cores() -> io::Result<Vec<usize>> {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        if libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((0..libc::CPU_SETSIZE as usize)
+            .filter(|core| libc::CPU_ISSET(*core, &set))
+            .collect())
+    }
+}
+
+// Restricts the calling thread, and any threads it starts afterwards, to
+// cores
+pub fn pin_current_thread(cores: &[usize]) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        for core in cores {
+            libc::CPU_SET(*core, &mut set);
+        }
+        if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
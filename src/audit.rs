@@ -0,0 +1,285 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
+
+use crate::http_service::request_hash;
+use crate::line_writer::{LineWriter, Sink};
+use crate::metrics;
+use crate::proto::ateles::js_response::ErrorType;
+use crate::proto::ateles::{JsRequest, JsResponse};
+use crate::quarantine::script_hash;
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+// The auth facility at info severity
+const SYSLOG_PRIORITY: u8 = 4 * 8 + 6;
+
+// Operations that run code the request brought along. Mapping and filtering
+// only run what an audited REGISTER_DDOC compiled.
+pub const AUDITED_OPS: &[&str] = &[
+    "eval",
+    "call",
+    "call_json",
+    "rewrite",
+    "register_ddoc",
+    "wasm_call",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AuditDest {
+    File(PathBuf),
+    Syslog,
+}
+
+// syslog for the local syslog daemon, anything else is a file
+impl FromStr for AuditDest {
+    type Err = String;
+
+    fn from_str(dest: &str) -> Result<Self, Self::Err> {
+        match dest {
+            "" => Err("the audit log needs a file or syslog".to_string()),
+            "syslog" => Ok(AuditDest::Syslog),
+            path => Ok(AuditDest::File(PathBuf::from(path))),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AuditConfig {
+    pub dest: AuditDest,
+    // A file is rotated once it's over this many bytes, 0 to never
+    pub max_bytes: u64,
+    // Rotated files kept, file.1 being the most recent
+    pub keep: usize,
+    // Record the script itself rather than only its hash
+    pub source: bool,
+    // Also record a hash of the script with its arguments, the same one
+    // quarantine goes by
+    pub hash_args: bool,
+}
+
+impl AuditConfig {
+    pub fn new(dest: AuditDest) -> AuditConfig {
+        AuditConfig {
+            dest,
+            max_bytes: 100 * 1024 * 1024,
+            keep: 5,
+            source: false,
+            hash_args: false,
+        }
+    }
+}
+
+// One audited request, written as a JSON object per line
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AuditEntry {
+    #[serde(serialize_with = "rfc3339")]
+    pub ts: DateTime<Utc>,
+    pub peer: String,
+    pub op: String,
+    pub script_hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    pub took_us: u64,
+    // ok, or the error type, e.g. runtime_error
+    pub outcome: String,
+    #[serde(skip)]
+    started: Option<Instant>,
+}
+
+fn rfc3339<S: serde::Serializer>(ts: &DateTime<Utc>, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&ts.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+}
+
+// Records which code was run, by whom and how it went, for security review.
+// Kept apart from the access and application logs.
+pub struct AuditLog {
+    source: bool,
+    hash_args: bool,
+    writer: LineWriter,
+}
+
+impl AuditLog {
+    // Fails if the file or syslog can't be opened, so a server that's meant
+    // to be audited doesn't start without it
+    pub fn open(config: &AuditConfig) -> io::Result<AuditLog> {
+        let writer = match &config.dest {
+            AuditDest::File(path) => start_writer(RotatingFile::open(path, config)?)?,
+            AuditDest::Syslog => start_writer(Syslog::connect()?)?,
+        };
+        Ok(AuditLog {
+            source: config.source,
+            hash_args: config.hash_args,
+            writer,
+        })
+    }
+
+    // Starts an entry for js_request if op is audited. Has to be called
+    // before the request is run as that takes it.
+    pub fn begin(&self, peer: &str, op: &str, js_request: &JsRequest) -> Option<AuditEntry> {
+        if !AUDITED_OPS.contains(&op) {
+            return None;
+        }
+        Some(AuditEntry {
+            ts: Utc::now(),
+            peer: peer.to_string(),
+            op: op.to_string(),
+            script_hash: script_hash(&js_request.script, None),
+            request_hash: if self.hash_args {
+                Some(request_hash(js_request))
+            } else {
+                None
+            },
+            source: if self.source {
+                Some(js_request.script.clone())
+            } else {
+                None
+            },
+            took_us: 0,
+            outcome: String::new(),
+            started: Some(Instant::now()),
+        })
+    }
+
+    // Writes entry with how js_resp went. Never waits on the disk.
+    pub fn finish(&self, mut entry: AuditEntry, js_resp: &JsResponse) {
+        if let Some(started) = entry.started {
+            entry.took_us = started.elapsed().as_micros() as u64;
+        }
+        entry.outcome = outcome(js_resp);
+        self.writer.send(serde_json::to_string(&entry).unwrap());
+    }
+}
+
+// ok, or the snake case name of the error type
+pub fn outcome(js_resp: &JsResponse) -> String {
+    if js_resp.status == 0 {
+        return "ok".to_string();
+    }
+    let name = match ErrorType::from_i32(js_resp.error_type) {
+        Some(error_type) => format!("{:?}", error_type),
+        None => return format!("error_{}", js_resp.error_type),
+    };
+    let mut snake = String::with_capacity(name.len() + 4);
+    for (i, c) in name.chars().enumerate() {
+        if c.is_ascii_uppercase() && i > 0 {
+            snake.push('_');
+        }
+        snake.push(c.to_ascii_lowercase());
+    }
+    snake
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+// Shifts file.1 to file.2 and so on, dropping the oldest, then moves path
+// to file.1
+pub fn rotate(path: &Path, keep: usize) -> io::Result<()> {
+    if keep == 0 {
+        return fs::remove_file(path);
+    }
+    let numbered = |n: usize| PathBuf::from(format!("{}.{}", path.display(), n));
+    for n in (1..keep).rev() {
+        match fs::rename(numbered(n), numbered(n + 1)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => (),
+        }
+    }
+    fs::rename(path, numbered(1))
+}
+
+fn start_writer<S: Sink>(sink: S) -> io::Result<LineWriter> {
+    LineWriter::start(
+        "fortuna-audit-log".to_string(),
+        "the audit log".to_string(),
+        &metrics::AUDIT_LOG_DROPPED_TOTAL,
+        sink,
+    )
+}
+
+// The audit log file, rotated once it's over max_bytes
+struct RotatingFile {
+    out: BufWriter<File>,
+    path: PathBuf,
+    size: u64,
+    max_bytes: u64,
+    keep: usize,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, config: &AuditConfig) -> io::Result<RotatingFile> {
+        let file = open_append(path)?;
+        Ok(RotatingFile {
+            size: file.metadata().map(|meta| meta.len()).unwrap_or(0),
+            out: BufWriter::new(file),
+            path: path.to_path_buf(),
+            max_bytes: config.max_bytes,
+            keep: config.keep,
+        })
+    }
+}
+
+impl Sink for RotatingFile {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.max_bytes > 0 && self.size > 0 && self.size + len > self.max_bytes {
+            let rotated = Write::flush(&mut self.out)
+                .and_then(|_| rotate(&self.path, self.keep))
+                .and_then(|_| open_append(&self.path));
+            match rotated {
+                Ok(file) => {
+                    self.out = BufWriter::new(file);
+                    self.size = 0;
+                }
+                Err(err) => {
+                    let path = self.path.display();
+                    log::warn!("can't rotate the audit log {}: {}", path, err);
+                }
+            }
+        }
+        writeln!(self.out, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Write::flush(&mut self.out)
+    }
+}
+
+struct Syslog {
+    socket: UnixDatagram,
+    pid: u32,
+}
+
+impl Syslog {
+    fn connect() -> io::Result<Syslog> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(SYSLOG_SOCKET)?;
+        Ok(Syslog {
+            socket,
+            pid: std::process::id(),
+        })
+    }
+}
+
+impl Sink for Syslog {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let message = format!("<{}>fortuna[{}]: {}", SYSLOG_PRIORITY, self.pid, line);
+        // Syslog may be restarting, the entry is counted as dropped rather
+        // than giving up on the rest
+        if let Err(err) = self.socket.send(message.as_bytes()) {
+            log::warn!("can't send to syslog: {}", err);
+            metrics::AUDIT_LOG_DROPPED_TOTAL.inc();
+        }
+        Ok(())
+    }
+}
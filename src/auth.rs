@@ -0,0 +1,52 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Shortest token accepted, so a typo'd or truncated file isn't taken for one
+const MIN_TOKEN_LEN: usize = 16;
+
+// The token in the first line of path, which should only be readable by
+// whoever runs fortuna. what names it in the error.
+pub fn read_token(path: &Path, what: &str) -> io::Result<String> {
+    let contents = fs::read_to_string(path)?;
+    let token = contents.lines().next().unwrap_or("").trim().to_string();
+    if token.len() < MIN_TOKEN_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} token in {} is shorter than {} characters",
+                what,
+                path.display(),
+                MIN_TOKEN_LEN
+            ),
+        ));
+    }
+    Ok(token)
+}
+
+// Whether an Authorization header, or the access_token query parameter
+// browsers have to use for links and websockets, has token. Compared in
+// constant time so the token can't be guessed a byte at a time.
+pub fn authorized(authorization: Option<&str>, access_token: Option<&str>, token: &str) -> bool {
+    let given = authorization
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or(access_token)
+        .map(str::trim);
+    match given {
+        Some(given) => constant_time_eq(given.as_bytes(), token.as_bytes()),
+        None => false,
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Whether path runs or can run JS, so needs the Execute token if the server
+// has one. GetServerInfo is included so probing the server needs it too.
+pub fn is_execute_path(path: &str) -> bool {
+    path.starts_with("/Ateles/") || path == "/ws"
+}
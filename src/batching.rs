@@ -0,0 +1,32 @@
+use std::time::Duration;
+
+// How long a batch should take to run when the worker is otherwise idle
+const TARGET_BATCH_TIME: Duration = Duration::from_millis(100);
+
+const MIN_BATCH_SIZE: u32 = 1;
+const MAX_BATCH_SIZE: u32 = 10_000;
+
+// Works out how many docs the caller should send in its next batch given how
+// long this one of batch_size docs took. Commands queued behind it on the
+// same worker and a throttled host both shrink the batch, so the worker
+// stays responsive when it's busy. The suggestion never more than doubles
+// from one batch to the next so a single fast batch can't overshoot.
+pub fn suggest(batch_size: u32, execution: Duration, queue_depth: usize, throttled: bool) -> u32 {
+    if batch_size == 0 {
+        return 0;
+    }
+
+    let per_doc = execution.as_secs_f64() / batch_size as f64;
+    let mut ideal = if per_doc > 0.0 {
+        TARGET_BATCH_TIME.as_secs_f64() / per_doc
+    } else {
+        f64::from(MAX_BATCH_SIZE)
+    };
+    ideal /= (queue_depth + 1) as f64;
+    if throttled {
+        ideal /= 2.0;
+    }
+
+    let ceiling = batch_size.saturating_mul(2).min(MAX_BATCH_SIZE);
+    (ideal.round() as u32).max(MIN_BATCH_SIZE).min(ceiling)
+}
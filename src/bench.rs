@@ -0,0 +1,499 @@
+use futures::{future, stream, StreamExt};
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::json;
+use structopt::StructOpt;
+
+use fortuna::ateles::JsRequest;
+use fortuna::JsRequestBuilder;
+use prost::Message;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+// How long the server may take over each request
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/*
+   Each session:
+   * optionally rewrites the map funs
+   * adds map.js
+   * inits the map funs
+   * maps --docs docs one at a time
+
+   With --profile the workload is read from a JSON file instead. See
+   Profile below for what it can describe.
+*/
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "fortuna-bench", about = "Load test a fortuna server")]
+struct Opt {
+    /// Execute endpoint of the server
+    #[structopt(long, default_value = "http://localhost:8444/Ateles/Execute")]
+    url: String,
+
+    /// Number of sessions to run
+    #[structopt(long, default_value = "1000")]
+    sessions: usize,
+
+    /// Number of sessions running at the same time
+    #[structopt(long, default_value = "60")]
+    concurrency: usize,
+
+    /// Number of docs mapped per session
+    #[structopt(long, default_value = "100")]
+    docs: usize,
+
+    /// JSON file with an array of map function sources
+    #[structopt(long, parse(from_os_str))]
+    map_funs: Option<PathBuf>,
+
+    /// JSON file with the doc to map
+    #[structopt(long, parse(from_os_str))]
+    doc: Option<PathBuf>,
+
+    /// Also rewrite the map functions at the start of each session
+    #[structopt(long)]
+    rewrite: bool,
+
+    /// JSON workload profile to run instead of the options above
+    #[structopt(long, parse(from_os_str))]
+    profile: Option<PathBuf>,
+
+    /// Write the results as JSON to this file
+    #[structopt(long, parse(from_os_str))]
+    output: Option<PathBuf>,
+}
+
+// A repeatable scenario, e.g.
+//
+// {
+//     "name": "mixed",
+//     "docs_per_session": 50,
+//     "doc_sizes": [64, 4096],
+//     "mix": {"map_doc": 8, "init": 1, "rewrite": 1},
+//     "stages": [
+//         {"concurrency": 10, "duration_secs": 10},
+//         {"concurrency": 60, "duration_secs": 30}
+//     ]
+// }
+//
+// Each stage keeps concurrency sessions running until duration_secs is up.
+// After setting up map.js a session runs docs_per_session ops picked from
+// mix by weight, cycling through doc_sizes for the mapped docs.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Profile {
+    #[serde(default)]
+    name: String,
+    url: Option<String>,
+    #[serde(default = "default_docs_per_session")]
+    docs_per_session: usize,
+    #[serde(default)]
+    doc_sizes: Vec<usize>,
+    #[serde(default)]
+    mix: BTreeMap<Op, u32>,
+    stages: Vec<Stage>,
+}
+
+fn default_docs_per_session() -> usize {
+    100
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Stage {
+    concurrency: usize,
+    duration_secs: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+enum Op {
+    MapDoc,
+    Init,
+    Rewrite,
+}
+
+impl Op {
+    fn name(self) -> &'static str {
+        match self {
+            Op::MapDoc => "map_doc",
+            Op::Init => "init",
+            Op::Rewrite => "rewrite",
+        }
+    }
+}
+
+impl Profile {
+    fn load(path: &PathBuf) -> Result<Profile, Box<dyn std::error::Error>> {
+        let profile: Profile = serde_json::from_str(&fs::read_to_string(path)?)?;
+        if profile.stages.is_empty() {
+            return Err("profile has no stages".into());
+        }
+        if profile.stages.iter().any(|stage| stage.concurrency == 0) {
+            return Err("stage concurrency must be at least 1".into());
+        }
+        Ok(profile)
+    }
+
+    // The ops a session runs in order, each repeated by its weight. Cycling
+    // through a fixed schedule keeps runs of the same profile comparable.
+    fn schedule(&self) -> Vec<Op> {
+        let schedule: Vec<Op> = self
+            .mix
+            .iter()
+            .flat_map(|(op, weight)| std::iter::repeat(*op).take(*weight as usize))
+            .collect();
+        if schedule.is_empty() {
+            return vec![Op::MapDoc];
+        }
+        schedule
+    }
+}
+
+struct Workload {
+    url: String,
+    map_funs: String,
+    docs: Vec<String>,
+}
+
+// Sends one request and returns how long it took
+async fn execute(client: &Client, url: &str, js_req: JsRequest) -> Duration {
+    let start = Instant::now();
+
+    let mut resp = Vec::<u8>::new();
+    js_req.encode(&mut resp).unwrap();
+
+    let _body = client
+        .post(url)
+        .body(resp)
+        .send()
+        .await
+        .unwrap()
+        .bytes()
+        .await
+        .unwrap();
+
+    start.elapsed()
+}
+
+async fn rewrite_map_funs(client: &Client, workload: &Workload) -> Duration {
+    let js_req = JsRequestBuilder::rewrite("rewriteFuns")
+        .arg(&workload.map_funs)
+        .timeout(TIMEOUT)
+        .build()
+        .unwrap();
+    execute(client, workload.url.as_str(), js_req).await
+}
+
+async fn add_map_js(client: &Client, workload: &Workload) -> Duration {
+    let js_req = JsRequestBuilder::eval(MAP_JS)
+        .args(&["file=map.js", "line=1"])
+        .timeout(TIMEOUT)
+        .build()
+        .unwrap();
+    execute(client, workload.url.as_str(), js_req).await
+}
+
+async fn init_map(client: &Client, workload: &Workload) -> Duration {
+    let js_req = JsRequestBuilder::call("init")
+        .args(&["{}", workload.map_funs.as_str()])
+        .timeout(TIMEOUT)
+        .build()
+        .unwrap();
+    execute(client, workload.url.as_str(), js_req).await
+}
+
+async fn map_doc(client: &Client, workload: &Workload, doc: &str) -> Duration {
+    let js_req = JsRequestBuilder::call("mapDoc")
+        .arg(doc)
+        .timeout(TIMEOUT)
+        .build()
+        .unwrap();
+    execute(client, workload.url.as_str(), js_req).await
+}
+
+async fn run_session(workload: &Workload, rewrite: bool, docs: usize) -> Vec<Duration> {
+    let client = Client::new();
+    let mut latencies = Vec::with_capacity(docs + 3);
+
+    if rewrite {
+        latencies.push(rewrite_map_funs(&client, workload).await);
+    }
+    latencies.push(add_map_js(&client, workload).await);
+    latencies.push(init_map(&client, workload).await);
+    for _ in 0..docs {
+        latencies.push(map_doc(&client, workload, &workload.docs[0]).await);
+    }
+
+    latencies
+}
+
+async fn run_profile_session(
+    workload: &Workload,
+    profile: &Profile,
+    schedule: &[Op],
+) -> Vec<(Op, Duration)> {
+    let client = Client::new();
+    let mut latencies = Vec::with_capacity(profile.docs_per_session + 2);
+
+    // Loading map.js is setup and isn't part of the results
+    add_map_js(&client, workload).await;
+    latencies.push((Op::Init, init_map(&client, workload).await));
+    for step in 0..profile.docs_per_session {
+        let op = schedule[step % schedule.len()];
+        let latency = match op {
+            Op::MapDoc => {
+                let doc = &workload.docs[step % workload.docs.len()];
+                map_doc(&client, workload, doc).await
+            }
+            Op::Init => init_map(&client, workload).await,
+            Op::Rewrite => rewrite_map_funs(&client, workload).await,
+        };
+        latencies.push((op, latency));
+    }
+
+    latencies
+}
+
+async fn run_stage(
+    workload: &Workload,
+    profile: &Profile,
+    schedule: &[Op],
+    stage: &Stage,
+) -> (Vec<(Op, Duration)>, Duration) {
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(stage.duration_secs);
+
+    let sessions = (0..stage.concurrency).map(|_| async move {
+        let mut latencies = Vec::new();
+        while Instant::now() < deadline {
+            latencies.extend(run_profile_session(workload, profile, schedule).await);
+        }
+        latencies
+    });
+    let latencies = future::join_all(sessions)
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+
+    (latencies, start.elapsed())
+}
+
+// A doc of roughly size bytes once serialized
+fn sized_doc(id: usize, size: usize) -> String {
+    let mut doc = json!({ "_id": format!("doc-{}", id), "value": 1, "padding": "" });
+    let padding = "x".repeat(size.saturating_sub(doc.to_string().len()));
+    doc["padding"] = json!(padding);
+    doc.to_string()
+}
+
+fn percentile(sorted: &[Duration], pct: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::default();
+    }
+    let idx = ((sorted.len() - 1) as f64 * pct / 100.0).round() as usize;
+    sorted[idx]
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+// Machine readable version of report
+fn summary(latencies: &mut Vec<Duration>, elapsed: Duration) -> serde_json::Value {
+    latencies.sort();
+    let count = latencies.len();
+    json!({
+        "requests": count,
+        "elapsed_secs": elapsed.as_secs_f64(),
+        "throughput": count as f64 / elapsed.as_secs_f64(),
+        "latency_ms": {
+            "p50": millis(percentile(latencies, 50.0)),
+            "p90": millis(percentile(latencies, 90.0)),
+            "p99": millis(percentile(latencies, 99.0)),
+            "p99.9": millis(percentile(latencies, 99.9)),
+            "max": millis(latencies.last().cloned().unwrap_or_default()),
+        },
+    })
+}
+
+fn report(mut latencies: Vec<Duration>, elapsed: Duration) {
+    latencies.sort();
+    let count = latencies.len();
+
+    println!("requests:   {}", count);
+    println!("took:       {:?}", elapsed);
+    println!(
+        "throughput: {:.1} req/s",
+        count as f64 / elapsed.as_secs_f64()
+    );
+    for pct in &[50.0, 90.0, 99.0, 99.9] {
+        println!("p{:<9} {:?}", pct, percentile(&latencies, *pct));
+    }
+    println!(
+        "max:        {:?}",
+        latencies.last().cloned().unwrap_or_default()
+    );
+}
+
+async fn run_profile(
+    mut workload: Workload,
+    path: &PathBuf,
+) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+    let profile = Profile::load(path)?;
+    if let Some(url) = &profile.url {
+        workload.url = url.clone();
+    }
+    if !profile.doc_sizes.is_empty() {
+        workload.docs = profile
+            .doc_sizes
+            .iter()
+            .enumerate()
+            .map(|(id, size)| sized_doc(id, *size))
+            .collect();
+    }
+    let schedule = profile.schedule();
+
+    let mut stages = Vec::new();
+    for (i, stage) in profile.stages.iter().enumerate() {
+        println!(
+            "Running stage {} ({} sessions for {}s)...",
+            i + 1,
+            stage.concurrency,
+            stage.duration_secs
+        );
+        let (latencies, elapsed) = run_stage(&workload, &profile, &schedule, stage).await;
+
+        let mut by_op: BTreeMap<Op, Vec<Duration>> = BTreeMap::new();
+        for (op, latency) in latencies.iter() {
+            by_op.entry(*op).or_default().push(*latency);
+        }
+        let ops: serde_json::Map<String, serde_json::Value> = by_op
+            .into_iter()
+            .map(|(op, mut latencies)| (op.name().to_string(), summary(&mut latencies, elapsed)))
+            .collect();
+
+        let mut all: Vec<Duration> = latencies.into_iter().map(|(_, latency)| latency).collect();
+        let mut result = summary(&mut all, elapsed);
+        result["concurrency"] = json!(stage.concurrency);
+        result["duration_secs"] = json!(stage.duration_secs);
+        result["ops"] = json!(ops);
+        stages.push(result);
+
+        report(all, elapsed);
+    }
+
+    Ok(json!({
+        "profile": profile.name,
+        "url": workload.url,
+        "stages": stages,
+    }))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let opt = Opt::from_args();
+
+    let map_funs = match &opt.map_funs {
+        Some(path) => fs::read_to_string(path)?,
+        None => MAP_FUNS.to_string(),
+    };
+    let doc = match &opt.doc {
+        Some(path) => fs::read_to_string(path)?,
+        None => DOC.to_string(),
+    };
+    let workload = Workload {
+        url: opt.url.clone(),
+        map_funs,
+        docs: vec![doc],
+    };
+
+    let results = match &opt.profile {
+        Some(path) => run_profile(workload, path).await?,
+        None => {
+            let start = Instant::now();
+            let sessions = stream::iter(
+                (0..opt.sessions).map(|_| run_session(&workload, opt.rewrite, opt.docs)),
+            )
+            .buffer_unordered(opt.concurrency)
+            .collect::<Vec<Vec<Duration>>>();
+            println!("Running...");
+            let mut latencies: Vec<Duration> = sessions.await.into_iter().flatten().collect();
+            let elapsed = start.elapsed();
+
+            let result = summary(&mut latencies, elapsed);
+            report(latencies, elapsed);
+            result
+        }
+    };
+
+    if let Some(path) = &opt.output {
+        fs::write(path, serde_json::to_string_pretty(&results)?)?;
+    }
+    Ok(())
+}
+
+const DOC: &str = "{\"_id\":\"foo\",\"value\":1}";
+
+const MAP_FUNS: &str = "
+    [
+        \"(function (doc) { emit(doc._id, doc.value);});\",
+         \"(function (doc) {\
+           let val = 0;\
+           for(let i = 0; i < 1000; i++) {\
+                val = i;\
+            }\
+            emit(doc._id, val);\
+         });\"
+
+    ]";
+
+const MAP_JS: &str = r#"
+  let lib = {};
+let map_funs = [];
+
+function init(libJSON, mapFunsJSON) {
+    try {
+        lib = JSON.parse(libJSON);
+    } catch (ex) {
+        const ret = {"error": "invalid_library", "reason": ex.toString()};
+        return JSON.stringify(ret);
+    }
+
+    try {
+        mapFuns = Array.from(JSON.parse(mapFunsJSON), (source) => {
+            return eval(source)
+        })
+    } catch (ex) {
+        const ret = {"error": "invalid_map_functions", "reason": ex.toString()};
+        return JSON.stringify(ret);
+    }
+
+    return true;
+}
+
+// emit() is provided by the server and the rows come back in the
+// response, one group per map function
+function mapEach(mapFun, doc) {
+    emitGroup();
+    try {
+        mapFun(doc);
+        return null;
+    } catch (ex) {
+        return ex.toString();
+    }
+};
+
+function mapDoc(docJSON) {
+    const doc = JSON.parse(docJSON);
+    const mapResults = Array.from(mapFuns, (mapFun) => {
+        return mapEach(mapFun, doc);
+    });
+
+    return mapResults;
+}
+"#;
@@ -0,0 +1,280 @@
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+
+// CouchDB's builtin reduce functions. They don't run any JS so they're
+// handled here without going near an isolate.
+
+// Reduce sources naming a builtin start with an underscore, e.g. "_sum"
+pub fn is_builtin(source: &str) -> bool {
+    source.trim_start().starts_with('_')
+}
+
+// Reduces rows of [key, value]
+pub fn reduce(name: &str, rows: &[Value]) -> Result<Value, String> {
+    match name.trim() {
+        "_sum" => sum(rows.iter().map(row_value)),
+        "_count" => Ok(json!(rows.len())),
+        "_stats" => stats(rows.iter().map(row_value)),
+        "_approx_count_distinct" => {
+            let mut hll = HyperLogLog::new();
+            for row in rows {
+                hll.add(row.get(0).unwrap_or(&Value::Null));
+            }
+            Ok(hll.to_json())
+        }
+        name => Err(unknown(name)),
+    }
+}
+
+// Reduces the results of earlier reduces
+pub fn rereduce(name: &str, values: &[Value]) -> Result<Value, String> {
+    match name.trim() {
+        "_sum" | "_count" => sum(values.iter()),
+        "_stats" => stats(values.iter()),
+        "_approx_count_distinct" => {
+            let mut hll = HyperLogLog::new();
+            for value in values {
+                hll.merge(&HyperLogLog::from_json(value)?);
+            }
+            Ok(hll.to_json())
+        }
+        name => Err(unknown(name)),
+    }
+}
+
+fn unknown(name: &str) -> String {
+    format!("unknown builtin reduce function {}", name)
+}
+
+fn row_value(row: &Value) -> &Value {
+    row.get(1).unwrap_or(&Value::Null)
+}
+
+// Numbers are added up. Arrays are added element by element and objects
+// member by member, so the values can be lists or maps of numbers.
+fn sum<'a>(values: impl Iterator<Item = &'a Value>) -> Result<Value, String> {
+    let mut total = None;
+    for value in values {
+        let sum = match total {
+            Some(total) => add(total, value)?,
+            None => add(zero_like(value)?, value)?,
+        };
+        total = Some(sum);
+    }
+    Ok(total.unwrap_or_else(|| json!(0)))
+}
+
+fn zero_like(value: &Value) -> Result<Value, String> {
+    match value {
+        Value::Number(_) => Ok(json!(0)),
+        Value::Array(_) => Ok(Value::Array(Vec::new())),
+        Value::Object(_) => Ok(Value::Object(Map::new())),
+        value => Err(format!(
+            "the _sum function requires that map values be numbers, arrays or objects, not {}",
+            value
+        )),
+    }
+}
+
+fn add(total: Value, value: &Value) -> Result<Value, String> {
+    match (total, value) {
+        (Value::Number(a), Value::Number(b)) => Ok(number(
+            a.as_f64().unwrap_or(0.0) + b.as_f64().unwrap_or(0.0),
+        )),
+        (Value::Array(mut a), Value::Array(b)) => {
+            for (i, b) in b.iter().enumerate() {
+                if i < a.len() {
+                    a[i] = add(a[i].take(), b)?;
+                } else {
+                    a.push(add(zero_like(b)?, b)?);
+                }
+            }
+            Ok(Value::Array(a))
+        }
+        (Value::Object(mut a), Value::Object(b)) => {
+            for (key, b) in b {
+                let total = match a.remove(key) {
+                    Some(total) => total,
+                    None => zero_like(b)?,
+                };
+                a.insert(key.clone(), add(total, b)?);
+            }
+            Ok(Value::Object(a))
+        }
+        (total, value) => Err(format!(
+            "the _sum function can't add {} to {}",
+            value, total
+        )),
+    }
+}
+
+// Whole numbers stay integers in the JSON
+fn number(n: f64) -> Value {
+    if n.fract() == 0.0 && n.abs() < 9_007_199_254_740_992.0 {
+        json!(n as i64)
+    } else {
+        json!(n)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Stats {
+    sum: f64,
+    count: f64,
+    min: f64,
+    max: f64,
+    sumsqr: f64,
+}
+
+impl Stats {
+    fn of(n: f64) -> Stats {
+        Stats {
+            sum: n,
+            count: 1.0,
+            min: n,
+            max: n,
+            sumsqr: n * n,
+        }
+    }
+
+    // Either a number or the output of an earlier _stats
+    fn from_json(value: &Value) -> Result<Stats, String> {
+        if let Some(n) = value.as_f64() {
+            return Ok(Stats::of(n));
+        }
+        let field = |name: &str| value.get(name).and_then(Value::as_f64);
+        match (
+            field("sum"),
+            field("count"),
+            field("min"),
+            field("max"),
+            field("sumsqr"),
+        ) {
+            (Some(sum), Some(count), Some(min), Some(max), Some(sumsqr)) => Ok(Stats {
+                sum,
+                count,
+                min,
+                max,
+                sumsqr,
+            }),
+            _ => Err(format!(
+                "the _stats function requires that map values be numbers or _stats objects, not {}",
+                value
+            )),
+        }
+    }
+
+    fn merge(self, other: Stats) -> Stats {
+        Stats {
+            sum: self.sum + other.sum,
+            count: self.count + other.count,
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+            sumsqr: self.sumsqr + other.sumsqr,
+        }
+    }
+
+    fn to_json(self) -> Value {
+        json!({
+            "sum": number(self.sum),
+            "count": number(self.count),
+            "min": number(self.min),
+            "max": number(self.max),
+            "sumsqr": number(self.sumsqr),
+        })
+    }
+}
+
+fn stats<'a>(values: impl Iterator<Item = &'a Value>) -> Result<Value, String> {
+    let mut total: Option<Stats> = None;
+    for value in values {
+        let stats = Stats::from_json(value)?;
+        total = Some(match total {
+            Some(total) => total.merge(stats),
+            None => stats,
+        });
+    }
+    match total {
+        Some(total) => Ok(total.to_json()),
+        None => Ok(json!({"sum": 0, "count": 0, "min": 0, "max": 0, "sumsqr": 0})),
+    }
+}
+
+// 2^PRECISION registers, which gives about a 2% standard error
+const PRECISION: u32 = 11;
+const REGISTERS: usize = 1 << PRECISION;
+
+// The reduce value of _approx_count_distinct is the sketch itself, as hex,
+// so that rereduce can merge them, along with the estimate it gives.
+struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    fn new() -> HyperLogLog {
+        HyperLogLog {
+            registers: vec![0; REGISTERS],
+        }
+    }
+
+    fn add(&mut self, key: &Value) {
+        let digest = Sha256::digest(key.to_string().as_bytes());
+        let mut hash = [0u8; 8];
+        hash.copy_from_slice(&digest[..8]);
+        let hash = u64::from_be_bytes(hash);
+
+        let idx = (hash >> (64 - PRECISION)) as usize;
+        let rest = hash << PRECISION;
+        let rank = (rest.leading_zeros() + 1).min(64 - PRECISION + 1) as u8;
+        if rank > self.registers[idx] {
+            self.registers[idx] = rank;
+        }
+    }
+
+    fn merge(&mut self, other: &HyperLogLog) {
+        for (mine, theirs) in self.registers.iter_mut().zip(other.registers.iter()) {
+            *mine = (*mine).max(*theirs);
+        }
+    }
+
+    fn estimate(&self) -> u64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum();
+        let estimate = alpha * m * m / sum;
+
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let registers: String = self
+            .registers
+            .iter()
+            .map(|r| format!("{:02x}", r))
+            .collect();
+        json!({
+            "count": self.estimate(),
+            "registers": registers,
+        })
+    }
+
+    fn from_json(value: &Value) -> Result<HyperLogLog, String> {
+        let invalid = || format!("invalid _approx_count_distinct value {}", value);
+        let hex = value
+            .get("registers")
+            .and_then(Value::as_str)
+            .ok_or_else(invalid)?;
+        if hex.len() != REGISTERS * 2 {
+            return Err(invalid());
+        }
+        let registers = (0..REGISTERS)
+            .map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid()))
+            .collect::<Result<_, _>>()?;
+        Ok(HyperLogLog { registers })
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+// Whether registered design docs are being kept track of, only once a
+// checkpoint is started
+static TRACKING: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref TRACKED: Mutex<DesignDocs> = Mutex::new(DesignDocs::new(0));
+}
+
+// A design doc as REGISTER_DDOC was given it and how often it's been used
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SavedDesignDoc {
+    pub signature: String,
+    // The JSON of its functions
+    pub functions: String,
+    pub uses: u64,
+}
+
+// The design docs registered since the server started, by signature. Only
+// their functions are kept, nothing from the heap of the workers that ran
+// them.
+pub struct DesignDocs {
+    max: usize,
+    docs: HashMap<String, SavedDesignDoc>,
+}
+
+impl DesignDocs {
+    // The max most used are what a checkpoint keeps
+    pub fn new(max: usize) -> DesignDocs {
+        DesignDocs {
+            max,
+            docs: HashMap::new(),
+        }
+    }
+
+    pub fn registered(&mut self, signature: &str, functions: &str) {
+        let doc = self
+            .docs
+            .entry(signature.to_string())
+            .or_insert_with(|| SavedDesignDoc {
+                signature: signature.to_string(),
+                functions: String::new(),
+                uses: 0,
+            });
+        doc.functions = functions.to_string();
+        doc.uses += 1;
+        // Keeps those that won't make the cut from piling up, while giving
+        // new ones a chance to catch up
+        if self.docs.len() > self.max.max(1) * 2 {
+            let hottest = self.hottest();
+            self.docs.clear();
+            for doc in hottest {
+                self.docs.insert(doc.signature.clone(), doc);
+            }
+        }
+    }
+
+    // Counts a MAP_DOC, MAP_DOCS or FILTER with signature
+    pub fn used(&mut self, signature: &str) {
+        if let Some(doc) = self.docs.get_mut(signature) {
+            doc.uses += 1;
+        }
+    }
+
+    // At most max, the most used first
+    pub fn hottest(&self) -> Vec<SavedDesignDoc> {
+        let mut docs: Vec<SavedDesignDoc> = self.docs.values().cloned().collect();
+        docs.sort_by(|a, b| {
+            b.uses
+                .cmp(&a.uses)
+                .then_with(|| a.signature.cmp(&b.signature))
+        });
+        docs.truncate(self.max);
+        docs
+    }
+
+    // Design docs from an earlier checkpoint, kept with their uses so they
+    // stay in the next one unless others overtake them
+    pub fn restore(&mut self, docs: &[SavedDesignDoc]) {
+        for doc in docs {
+            self.docs
+                .entry(doc.signature.clone())
+                .or_insert_with(|| doc.clone());
+        }
+    }
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct File {
+    design_docs: Vec<SavedDesignDoc>,
+}
+
+// Writes docs to path, through a temporary file so a crash partway through
+// doesn't leave half a checkpoint behind
+pub fn save(path: &Path, docs: &[SavedDesignDoc]) -> io::Result<()> {
+    let file = File {
+        design_docs: docs.to_vec(),
+    };
+    let json =
+        serde_json::to_vec(&file).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, json)?;
+    fs::rename(&tmp, path)
+}
+
+// The design docs saved at path, none if there's no checkpoint yet
+pub fn load(path: &Path) -> io::Result<Vec<SavedDesignDoc>> {
+    let json = match fs::read(path) {
+        Ok(json) => json,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let file: File = serde_json::from_slice(&json).map_err(|err| {
+        let reason = format!("invalid checkpoint {}: {}", path.display(), err);
+        io::Error::new(io::ErrorKind::InvalidData, reason)
+    })?;
+    Ok(file.design_docs)
+}
+
+// Where the server's design docs are checkpointed and how
+#[derive(Clone, Debug)]
+pub struct CheckpointConfig {
+    pub path: PathBuf,
+    // The most used design docs kept
+    pub max_design_docs: usize,
+    pub interval: Duration,
+}
+
+// Keeps track of the design docs registered from now on and saves the
+// hottest of them every interval. Returns those saved by an earlier run, to
+// be registered again before traffic comes in.
+pub fn start(config: &CheckpointConfig) -> io::Result<Vec<SavedDesignDoc>> {
+    let saved = load(&config.path)?;
+    {
+        let mut tracked = TRACKED.lock().unwrap();
+        *tracked = DesignDocs::new(config.max_design_docs);
+        tracked.restore(&saved);
+    }
+    TRACKING.store(true, Ordering::SeqCst);
+
+    let config = config.clone();
+    thread::Builder::new()
+        .name("checkpoint".to_string())
+        .spawn(move || loop {
+            thread::sleep(config.interval);
+            let hottest = TRACKED.lock().unwrap().hottest();
+            if let Err(err) = save(&config.path, &hottest) {
+                log::warn!("can't checkpoint to {}: {}", config.path.display(), err);
+            }
+        })?;
+    Ok(saved)
+}
+
+pub fn is_tracking() -> bool {
+    TRACKING.load(Ordering::Relaxed)
+}
+
+pub fn record_registered(signature: &str, functions: &str) {
+    if TRACKING.load(Ordering::Relaxed) {
+        TRACKED.lock().unwrap().registered(signature, functions);
+    }
+}
+
+pub fn record_use(signature: &str) {
+    if TRACKING.load(Ordering::Relaxed) {
+        TRACKED.lock().unwrap().used(signature);
+    }
+}
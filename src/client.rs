@@ -1,200 +1,301 @@
-use futures::{stream, StreamExt};
-use reqwest::Client;
-
-use ateles::JsRequest;
 use prost::Message;
-use std::fs;
-use std::time::Instant;
+use std::time::Duration;
 
-pub mod ateles {
-    tonic::include_proto!("ateles"); // The string specified here must match the proto package name
-}
+use crate::framing::{encode_frame, FrameDecoder};
+use crate::proto::ateles::js_response::ErrorType;
+use crate::proto::ateles::{JsRequest, JsResponse, MapDocsRequest, ServerInfo};
+use crate::request::JsRequestBuilder;
 
-/*
-   steps:
-   * rewrite map funs
-   * add map.js
-   * map docs
-*/
-
-async fn rewrite_map_funs(client: &Client) {
-    let js_req = JsRequest {
-        action: 0,
-        script: "rewriteFun".to_string(),
-        args: vec!["\"function(doc) {emit(doc._id, null);}\"".to_string()],
-        timeout: 5000,
-    };
-
-    let mut resp = Vec::<u8>::new();
-    js_req.encode(&mut resp).unwrap();
-
-    let _body = client
-        .post("http://localhost:8444/Ateles/Execute")
-        .body(resp)
-        .send()
-        .await
-        .unwrap()
-        .text()
-        .await
-        .unwrap();
+// How an AtelesClient connects and how hard it tries
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    // Give up on a request that hasn't been answered by then
+    pub timeout: Option<Duration>,
+    // Idle connections kept open to the server, and for how long
+    pub max_idle_connections: usize,
+    pub idle_timeout: Duration,
+    // Speak HTTP/2 from the start, as the server doesn't offer an upgrade
+    pub http2: bool,
+    // Times a request is tried again after failing to reach the server or
+    // being turned away with QUEUE_FULL or RATE_LIMITED, none of which ran
+    // it. The wait doubles after each try, starting at backoff.
+    pub retries: u32,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    // Sent as a bearer token, for servers run with --execute-token-file
+    pub token: Option<String>,
 }
 
-async fn add_map_js(client: &Client) {
-    let js_req = JsRequest {
-        action: 1,
-        script: MAP_JS.to_string(),
-        args: vec!["file=map.js".to_string(), "line=1".to_string()],
-        timeout: 5000,
-    };
-
-    let mut resp = Vec::<u8>::new();
-    js_req.encode(&mut resp).unwrap();
-
-    let _body = client
-        .post("http://localhost:8444/Ateles/Execute")
-        .body(resp)
-        .send()
-        .await
-        .unwrap()
-        .text()
-        .await
-        .unwrap();
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            timeout: None,
+            max_idle_connections: 32,
+            idle_timeout: Duration::from_secs(90),
+            http2: false,
+            retries: 0,
+            backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            token: None,
+        }
+    }
 }
 
-async fn init_map(client: &Client) {
-    let js_req = JsRequest {
-        action: 2,
-        script: "init".to_string(),
-        args: vec!["{}".to_string(), MAP_FUNS.to_string()],
-        timeout: 5000,
-    };
-
-    let mut resp = Vec::<u8>::new();
-    js_req.encode(&mut resp).unwrap();
-
-    let _body = client
-        .post("http://localhost:8444/Ateles/Execute")
-        .body(resp)
-        .send()
-        .await
-        .unwrap()
-        .text()
-        .await
-        .unwrap();
+// Talks to a fortuna server over HTTP, keeping connections open between
+// requests. Requests may go out on different connections, and so run on
+// different workers, so state that later requests rely on should be set up
+// in a session or sent together with execute_pipelined.
+#[derive(Clone)]
+pub struct AtelesClient {
+    http: reqwest::Client,
+    url: String,
+    pipelined_url: String,
+    map_docs_url: String,
+    map_docs_stream_url: String,
+    info_url: String,
+    config: ClientConfig,
 }
 
-async fn map_doc(client: &Client, doc: &str) {
-    let js_req = JsRequest {
-        action: 2,
-        script: "mapDoc".to_string(),
-        args: vec![doc.to_string()],
-        timeout: 5000,
-    };
-
-    let mut resp = Vec::<u8>::new();
-    js_req.encode(&mut resp).unwrap();
-
-    let _body = client
-        .post("http://localhost:8444/Ateles/Execute")
-        .body(resp)
-        .send()
-        .await
-        .unwrap()
-        .text()
+// The name it had before it could be configured
+pub type Client = AtelesClient;
+
+impl AtelesClient {
+    // base is the server's address, e.g. http://127.0.0.1:8444
+    pub fn new(base: &str) -> AtelesClient {
+        AtelesClient::with_config(base, ClientConfig::default())
+            .expect("the default client config is valid")
+    }
+
+    pub fn with_config(base: &str, config: ClientConfig) -> Result<AtelesClient, String> {
+        let mut builder = reqwest::Client::builder()
+            .pool_max_idle_per_host(config.max_idle_connections)
+            .pool_idle_timeout(config.idle_timeout);
+        if let Some(timeout) = config.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if config.http2 {
+            builder = builder.http2_prior_knowledge();
+        }
+        let http = builder
+            .build()
+            .map_err(|err| format!("can't create client: {}", err))?;
+        let base = base.trim_end_matches('/');
+        Ok(AtelesClient {
+            http,
+            url: format!("{}/Ateles/Execute", base),
+            pipelined_url: format!("{}/Ateles/ExecutePipelined", base),
+            map_docs_url: format!("{}/Ateles/MapDocs", base),
+            map_docs_stream_url: format!("{}/Ateles/MapDocsStream", base),
+            info_url: format!("{}/Ateles/GetServerInfo", base),
+            config,
+        })
+    }
+
+    // Errors are for requests that never got a JsResponse back. Anything the
+    // server answered, including rejected requests, is a JsResponse.
+    pub async fn execute(&self, request: &JsRequest) -> Result<JsResponse, String> {
+        let mut body = Vec::new();
+        request
+            .encode(&mut body)
+            .map_err(|err| format!("can't encode request: {}", err))?;
+        self.execute_raw(body).await
+    }
+
+    // Sends body as is, e.g. to see what the server makes of a bad one
+    pub async fn execute_raw(&self, body: Vec<u8>) -> Result<JsResponse, String> {
+        let body = &body;
+        self.retrying(move || async move {
+            let bytes = self.post(&self.url, body.clone()).await?;
+            JsResponse::decode(bytes)
+                .map_err(|err| Attempt::Fail(format!("can't decode response: {}", err)))
+        })
         .await
-        .unwrap();
-}
+    }
+
+    // Sends requests in one body to run one after the other on the same
+    // worker and returns their responses in the same order. The stream is
+    // only retried as a whole if it never reached the server.
+    pub async fn execute_pipelined(
+        &self,
+        requests: &[JsRequest],
+    ) -> Result<Vec<JsResponse>, String> {
+        let body: Vec<u8> = requests.iter().flat_map(encode_frame).collect();
+        let body = &body;
+        let bytes = self
+            .retrying(move || self.post(&self.pipelined_url, body.clone()))
+            .await?;
+        decode_frames(&bytes, requests.len())
+    }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let start = Instant::now();
-    let reqs = 0..1000;
-    // let doc = fs::read_to_string("test.json")?;
-
-    let fetches = stream::iter(reqs.map(|_| {
-        async {
-            let docs = 0..100;
-            let client = Client::new();
-            add_map_js(&client).await;
-            init_map(&client).await;
-            let doc_fetches = stream::iter(docs.map(|_| async {
-                map_doc(&client, DOC).await;
-            }))
-            .buffer_unordered(1)
-            .collect::<Vec<()>>();
-            doc_fetches.await;
+    // Maps a batch of docs with a design doc already registered on the
+    // worker it goes to, e.g. in request's session, returning a response
+    // per doc. Retried like execute_pipelined.
+    pub async fn execute_map_docs(
+        &self,
+        request: &MapDocsRequest,
+    ) -> Result<Vec<JsResponse>, String> {
+        let mut body = Vec::new();
+        request
+            .encode(&mut body)
+            .map_err(|err| format!("can't encode request: {}", err))?;
+        let body = &body;
+        let bytes = self
+            .retrying(move || self.post(&self.map_docs_url, body.clone()))
+            .await?;
+        decode_frames(&bytes, request.docs.len())
+    }
+
+    // Like execute_map_docs but sends request's docs in frames of at most
+    // per_frame docs, which the server decodes and maps one at a time, so
+    // it never holds the whole batch
+    pub async fn execute_map_docs_stream(
+        &self,
+        request: &MapDocsRequest,
+        per_frame: usize,
+    ) -> Result<Vec<JsResponse>, String> {
+        let per_frame = per_frame.max(1);
+        let mut body = Vec::new();
+        let mut doc_ids = request.doc_ids.chunks(per_frame);
+        for (i, docs) in request.docs.chunks(per_frame).enumerate() {
+            // Only the first frame's options count
+            let mut frame = if i == 0 {
+                request.clone()
+            } else {
+                MapDocsRequest::default()
+            };
+            frame.docs = docs.to_vec();
+            frame.doc_ids = doc_ids.next().map(<[String]>::to_vec).unwrap_or_default();
+            body.extend(encode_frame(&frame));
         }
-    }))
-    .buffer_unordered(60)
-    .collect::<Vec<()>>();
-    println!("Running...");
-    fetches.await;
-
-    println!("requests took {:?}", start.elapsed());
-    Ok(())
-}
+        let body = &body;
+        let bytes = self
+            .retrying(move || self.post(&self.map_docs_stream_url, body.clone()))
+            .await?;
+        decode_frames(&bytes, request.docs.len())
+    }
 
-const DOC: &str = "{\"_id\":\"foo\",\"value\":1}";
+    // The server's version, protocol version, actions, features and limits,
+    // to check for what a caller needs up front
+    pub async fn server_info(&self) -> Result<ServerInfo, String> {
+        let bytes = self
+            .retrying(move || self.post(&self.info_url, Vec::new()))
+            .await?;
+        ServerInfo::decode(bytes).map_err(|err| format!("can't decode server info: {}", err))
+    }
 
-const MAP_FUNS: &str = "
-    [
-        \"(function (doc) { emit(doc._id, doc.value);});\",
-         \"(function (doc) {\
-           let val = 0;\
-           for(let i = 0; i < 1000; i++) {\
-                val = i;\
-            }\
-            emit(doc._id, val);\
-         });\"
+    pub async fn eval(&self, script: &str) -> Result<JsResponse, String> {
+        self.execute(&JsRequestBuilder::eval(script).build()?).await
+    }
 
-    ]";
+    pub async fn call(&self, function: &str, args: &[&str]) -> Result<JsResponse, String> {
+        self.execute(&JsRequestBuilder::call(function).args(args).build()?)
+            .await
+    }
 
-const MAP_JS: &str = r#"
-  let lib = {};
-let map_funs = [];
+    // Registers a design doc's functions and maps each of docs with them on
+    // one worker, returning a response per doc. The rows each map function
+    // emitted are in its emitted groups.
+    pub async fn map_docs(
+        &self,
+        signature: &str,
+        functions: &str,
+        docs: &[&str],
+    ) -> Result<Vec<JsResponse>, String> {
+        let mut requests = vec![JsRequestBuilder::register_ddoc(signature, functions).build()?];
+        for doc in docs {
+            requests.push(JsRequestBuilder::map_doc(signature, doc).build()?);
+        }
+        let mut responses = self.execute_pipelined(&requests).await?.into_iter();
+        match responses.next() {
+            Some(registered) if registered.status == 0 => Ok(responses.collect()),
+            Some(registered) => Err(format!(
+                "can't register {}: {}",
+                signature, registered.result
+            )),
+            None => Err("no response to registering the design doc".to_string()),
+        }
+    }
 
-function init(libJSON, mapFunsJSON) {
-    try {
-        lib = JSON.parse(libJSON);
-    } catch (ex) {
-        const ret = {"error": "invalid_library", "reason": ex.toString()};
-        return JSON.stringify(ret);
+    async fn post(&self, url: &str, body: Vec<u8>) -> Result<bytes::Bytes, Attempt> {
+        let mut req = self.http.post(url).body(body);
+        if let Some(token) = &self.config.token {
+            req = req.bearer_auth(token);
+        }
+        let resp = req
+            .send()
+            .await
+            .map_err(|err| Attempt::Retry(format!("request failed: {}", err)))?;
+        let status = resp.status();
+        resp.bytes()
+            .await
+            .map_err(|err| Attempt::Fail(format!("can't read {} response: {}", status, err)))
     }
 
-    try {
-        mapFuns = Array.from(JSON.parse(mapFunsJSON), (source) => {
-            return eval(source)
-        })
-    } catch (ex) {
-        const ret = {"error": "invalid_map_functions", "reason": ex.toString()};
-        return JSON.stringify(ret);
+    // Runs attempt until it gets an answer worth returning or runs out of
+    // retries
+    async fn retrying<F, Fut, T>(&self, attempt: F) -> Result<T, String>
+    where
+        F: Fn() -> Fut,
+        Fut: std::future::Future<Output = Result<T, Attempt>>,
+        T: Retryable,
+    {
+        let mut backoff = self.config.backoff;
+        let mut tries = 0;
+        loop {
+            let last_try = tries >= self.config.retries;
+            match attempt().await {
+                Ok(answer) if last_try || !answer.turned_away() => return Ok(answer),
+                Err(Attempt::Retry(err)) if last_try => return Err(err),
+                Err(Attempt::Fail(err)) => return Err(err),
+                _ => (),
+            }
+            tokio::time::delay_for(backoff).await;
+            backoff = (backoff * 2).min(self.config.max_backoff);
+            tries += 1;
+        }
     }
+}
 
-    return true;
+fn decode_frames(bytes: &[u8], expected: usize) -> Result<Vec<JsResponse>, String> {
+    let mut decoder = FrameDecoder::new();
+    decoder.extend(bytes);
+    let mut responses = Vec::with_capacity(expected);
+    while let Some(response) = decoder
+        .next_frame::<JsResponse>()
+        .map_err(|err| format!("can't decode response: {}", err))?
+    {
+        responses.push(response);
+    }
+    if !decoder.is_empty() {
+        return Err("truncated response frame".to_string());
+    }
+    Ok(responses)
 }
 
-let doc_results = [];
+// Why a request didn't get an answer
+enum Attempt {
+    // It never reached the server, so it may be sent again
+    Retry(String),
+    Fail(String),
+}
 
-function emit(key, value) {
-    doc_results.push([key, value]);
+trait Retryable {
+    // The server didn't run the request and asked to try again later
+    fn turned_away(&self) -> bool;
 }
 
-function mapEach(mapFun, doc) {
-    try {
-        doc_results = [];
-        mapFun(doc);
-        return doc_results;
-    } catch (ex) {
-        return ex.toString();
+impl Retryable for JsResponse {
+    fn turned_away(&self) -> bool {
+        self.status != 0
+            && (self.error_type == ErrorType::QueueFull as i32
+                || self.error_type == ErrorType::RateLimited as i32
+                || self.error_type == ErrorType::Overloaded as i32)
     }
-};
-
-function mapDoc(docJSON) {
-    const doc = JSON.parse(docJSON);
-    const mapResults = Array.from(mapFuns, (mapFun) => {
-        return mapEach(mapFun, doc);
-    });
+}
 
-    return mapResults;
+// Pipelined streams are only retried when they never reached the server
+impl Retryable for bytes::Bytes {
+    fn turned_away(&self) -> bool {
+        false
+    }
 }
-"#;
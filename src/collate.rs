@@ -0,0 +1,138 @@
+use serde_json::Value;
+use std::cmp::Ordering;
+
+// CouchDB view collation for JSON values:
+//
+//   null < false < true < numbers < strings < arrays < objects
+//
+// Arrays compare element by element and objects compare their key/value
+// pairs in order. Strings are compared case-insensitively first with
+// lowercase sorting before uppercase on ties, which approximates the ICU
+// ordering CouchDB uses for the common cases.
+pub fn collate(a: &Value, b: &Value) -> Ordering {
+    let by_type = type_rank(a).cmp(&type_rank(b));
+    if by_type != Ordering::Equal {
+        return by_type;
+    }
+
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => {
+            let a = a.as_f64().unwrap_or(0.0);
+            let b = b.as_f64().unwrap_or(0.0);
+            a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+        }
+        (Value::String(a), Value::String(b)) => collate_strings(a, b),
+        (Value::Array(a), Value::Array(b)) => {
+            for (a, b) in a.iter().zip(b.iter()) {
+                let ord = collate(a, b);
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            }
+            a.len().cmp(&b.len())
+        }
+        (Value::Object(a), Value::Object(b)) => {
+            for ((a_key, a_val), (b_key, b_val)) in a.iter().zip(b.iter()) {
+                let ord = collate_strings(a_key, b_key);
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+                let ord = collate(a_val, b_val);
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            }
+            a.len().cmp(&b.len())
+        }
+        _ => Ordering::Equal,
+    }
+}
+
+fn type_rank(value: &Value) -> u8 {
+    match value {
+        Value::Null => 0,
+        Value::Bool(false) => 1,
+        Value::Bool(true) => 2,
+        Value::Number(_) => 3,
+        Value::String(_) => 4,
+        Value::Array(_) => 5,
+        Value::Object(_) => 6,
+    }
+}
+
+fn collate_strings(a: &str, b: &str) -> Ordering {
+    a.to_lowercase()
+        .cmp(&b.to_lowercase())
+        .then_with(|| b.cmp(a))
+}
+
+// Encodes a JSON value so that comparing the bytes gives the same order as
+// collate, e.g. to use as a key in an ordered key value store.
+pub fn encode_key(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_into(value, &mut out);
+    out
+}
+
+// Arrays and objects are ended by a 0x00 which sorts before every tag
+const END: u8 = 0x00;
+// Starts each of an object's keys. A key's encoding can start with 0x00,
+// e.g. for "", so without this a shorter object could sort after a longer
+// one.
+const KEY: u8 = 0x01;
+
+fn encode_into(value: &Value, out: &mut Vec<u8>) {
+    out.push(type_rank(value) + 1);
+    match value {
+        Value::Null | Value::Bool(_) => {}
+        Value::Number(n) => {
+            let n = n.as_f64().unwrap_or(0.0);
+            // -0.0 collates equal to 0.0
+            let n = if n == 0.0 { 0.0 } else { n };
+            let bits = n.to_bits();
+            let bits = if n.is_sign_negative() {
+                !bits
+            } else {
+                bits ^ (1 << 63)
+            };
+            out.extend_from_slice(&bits.to_be_bytes());
+        }
+        Value::String(s) => encode_string(s, out),
+        Value::Array(items) => {
+            for item in items {
+                encode_into(item, out);
+            }
+            out.push(END);
+        }
+        Value::Object(members) => {
+            for (key, member) in members {
+                out.push(KEY);
+                encode_string(key, out);
+                encode_into(member, out);
+            }
+            out.push(END);
+        }
+    }
+}
+
+// The lowercased string first and then the original bytes inverted, so that
+// ties sort in reverse byte order like collate_strings. Each part is escaped
+// so that its terminator sorts before (or for the inverted part after) any
+// byte that could follow.
+fn encode_string(s: &str, out: &mut Vec<u8>) {
+    for b in s.to_lowercase().bytes() {
+        match b {
+            0x00 => out.extend_from_slice(&[0x00, 0x01]),
+            b => out.push(b),
+        }
+    }
+    out.extend_from_slice(&[0x00, 0x00]);
+
+    for b in s.bytes() {
+        match !b {
+            0xff => out.extend_from_slice(&[0xff, 0xfe]),
+            b => out.push(b),
+        }
+    }
+    out.extend_from_slice(&[0xff, 0xff]);
+}
@@ -0,0 +1,104 @@
+use std::io::{Read, Write};
+
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+// Picks the encoding to use for a response from an Accept-Encoding header,
+// preferring gzip. None means the response is sent as is.
+pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let mut gzip = None;
+    let mut deflate = None;
+    let mut any = None;
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = parts.find_map(|param| {
+            let param = param.trim();
+            param
+                .strip_prefix("q=")
+                .or_else(|| param.strip_prefix("Q="))
+                .and_then(|q| q.trim().parse::<f32>().ok())
+        });
+        // q=0 means the coding must not be used
+        let accepted = q.unwrap_or(1.0) > 0.0;
+        match coding.as_str() {
+            "gzip" | "x-gzip" => gzip = Some(accepted),
+            "deflate" => deflate = Some(accepted),
+            "*" => any = Some(accepted),
+            _ => (),
+        }
+    }
+
+    // Codings that aren't listed are only acceptable through *
+    let any = any.unwrap_or(false);
+    if gzip.unwrap_or(any) {
+        Some(Encoding::Gzip)
+    } else if deflate.unwrap_or(any) {
+        Some(Encoding::Deflate)
+    } else {
+        None
+    }
+}
+
+pub fn compress(encoding: Encoding, data: &[u8]) -> Vec<u8> {
+    // Writing to a Vec can't fail
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        }
+        Encoding::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        }
+    }
+}
+
+// Whether a body with this Content-Encoding can be used as it is
+pub fn is_identity(content_encoding: &str) -> bool {
+    let coding = content_encoding.trim();
+    coding.is_empty() || coding.eq_ignore_ascii_case("identity")
+}
+
+// Undoes the Content-Encoding of a request body. Bodies without one are
+// returned unchanged. Bodies that decompress to more than limit bytes are
+// rejected so a small compressed body can't be used to exhaust memory.
+pub fn decompress(content_encoding: &str, data: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+    if is_identity(content_encoding) {
+        return Ok(data.to_vec());
+    }
+    let coding = content_encoding.trim().to_ascii_lowercase();
+    let mut decoded = Vec::new();
+    let read = match coding.as_str() {
+        "gzip" | "x-gzip" => GzDecoder::new(data)
+            .take(limit as u64 + 1)
+            .read_to_end(&mut decoded),
+        "deflate" => ZlibDecoder::new(data)
+            .take(limit as u64 + 1)
+            .read_to_end(&mut decoded),
+        coding => return Err(format!("unsupported content encoding {}", coding)),
+    };
+    read.map_err(|err| format!("invalid {} body: {}", coding, err))?;
+    if decoded.len() > limit {
+        return Err(format!("decompressed body is over {} bytes", limit));
+    }
+    Ok(decoded)
+}
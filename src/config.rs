@@ -0,0 +1,334 @@
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::host_functions::Capabilities;
+use crate::listen::Bind;
+use crate::quarantine;
+use crate::slow_requests;
+use crate::v8_config::{self, SecurityMode};
+
+// How often the config file is looked at for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+// Most commands waiting for a worker before more are turned away with
+// QUEUE_FULL, 0 for no limit
+static MAX_QUEUE_DEPTH: AtomicUsize = AtomicUsize::new(0);
+// Timeout in milliseconds of requests that don't give one, 0 for none
+static DEFAULT_TIMEOUT_MS: AtomicU64 = AtomicU64::new(0);
+// Largest result, emitted rows included, a request can get back, 0 for no
+// limit
+static MAX_RESULT_BYTES: AtomicUsize = AtomicUsize::new(0);
+// BACKGROUND requests are shed once a worker's oldest queued command has
+// waited this many milliseconds, 0 to never shed them
+static SHED_QUEUE_AGE_MS: AtomicU64 = AtomicU64::new(0);
+// REWRITE and MAP commands lost to a crashed worker are sent once more
+static RETRY_CRASHED: AtomicBool = AtomicBool::new(false);
+// Design doc contexts an isolate keeps before evicting its least recently
+// used one, 0 for no limit
+static MAX_CONTEXTS_PER_ISOLATE: AtomicUsize = AtomicUsize::new(0);
+// Design doc contexts kept over every isolate, 0 for no limit
+static MAX_CONTEXTS: AtomicUsize = AtomicUsize::new(0);
+
+// Read from the TOML file given with --config, e.g. fortuna.toml of
+//
+//   listen = "127.0.0.1:8444"
+//   pool_max_size = 16
+//   log_level = "info"
+//   default_timeout_ms = 5000
+//   max_queue_depth = 100
+//
+// Every setting is optional. Those in the file take the place of the command
+// line's. Settings marked reloadable are applied again whenever the file
+// changes or the process gets SIGHUP, the others need a restart.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: Option<String>,
+    pub bind: Vec<String>,
+    pub pool_max_size: Option<usize>,
+    pub pool_min_size: Option<usize>,
+    pub pool_idle_ttl_secs: Option<u64>,
+    pub session_ttl_secs: Option<u64>,
+    pub max_body_size: Option<usize>,
+    pub access_log: Option<PathBuf>,
+    pub access_log_format: Option<String>,
+    pub v8_flags: Option<String>,
+    pub security_mode: Option<String>,
+    // Host functions workers get, e.g. "digest,collate,emit"
+    pub capabilities: Option<String>,
+    // What a single command can emit, see EmitLimits
+    pub max_emit_rows: Option<usize>,
+    pub max_emit_row_bytes: Option<usize>,
+    pub max_emit_bytes: Option<usize>,
+    // Reloadable
+    pub log_level: Option<String>,
+    pub slow_request_ms: Option<u64>,
+    pub quarantine_after: Option<u64>,
+    pub max_queue_depth: Option<usize>,
+    pub default_timeout_ms: Option<u64>,
+    pub max_result_bytes: Option<usize>,
+    pub shed_queue_age_ms: Option<u64>,
+    pub retry_crashed: Option<bool>,
+    pub max_contexts_per_isolate: Option<usize>,
+    pub max_contexts: Option<usize>,
+}
+
+impl Config {
+    pub fn parse(toml: &str) -> Result<Config, String> {
+        let config: Config =
+            toml::from_str(toml).map_err(|err| format!("invalid config: {}", err))?;
+        if let Some(level) = &config.log_level {
+            log::LevelFilter::from_str(level)
+                .map_err(|_| format!("invalid config: unknown log_level {}", level))?;
+        }
+        if let Some(flags) = &config.v8_flags {
+            v8_config::parse_flags(flags).map_err(|err| format!("invalid config: {}", err))?;
+        }
+        if let Some(mode) = &config.security_mode {
+            SecurityMode::from_str(mode).map_err(|err| format!("invalid config: {}", err))?;
+        }
+        if let Some(caps) = &config.capabilities {
+            Capabilities::parse(caps).map_err(|err| format!("invalid config: {}", err))?;
+        }
+        for bind in config.listen.iter().chain(config.bind.iter()) {
+            Bind::from_str(bind).map_err(|err| format!("invalid config: {}", err))?;
+        }
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> io::Result<Config> {
+        let toml = fs::read_to_string(path)?;
+        Config::parse(&toml).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    // Where to listen, if the file says
+    pub fn binds(&self) -> Vec<Bind> {
+        self.listen
+            .iter()
+            .chain(self.bind.iter())
+            .filter_map(|bind| bind.parse().ok())
+            .collect()
+    }
+
+    // Puts the reloadable settings into effect. Those left out keep their
+    // current values.
+    pub fn apply(&self) {
+        if let Some(level) = self.log_level.as_ref().and_then(|l| l.parse().ok()) {
+            log::set_max_level(level);
+        }
+        if let Some(ms) = self.slow_request_ms {
+            slow_requests::set_threshold(Duration::from_millis(ms));
+        }
+        if let Some(crashes) = self.quarantine_after {
+            quarantine::set_threshold(crashes);
+        }
+        if let Some(depth) = self.max_queue_depth {
+            MAX_QUEUE_DEPTH.store(depth, Ordering::SeqCst);
+        }
+        if let Some(ms) = self.default_timeout_ms {
+            DEFAULT_TIMEOUT_MS.store(ms, Ordering::SeqCst);
+        }
+        if let Some(bytes) = self.max_result_bytes {
+            MAX_RESULT_BYTES.store(bytes, Ordering::SeqCst);
+        }
+        if let Some(ms) = self.shed_queue_age_ms {
+            SHED_QUEUE_AGE_MS.store(ms, Ordering::SeqCst);
+        }
+        if let Some(retry) = self.retry_crashed {
+            RETRY_CRASHED.store(retry, Ordering::SeqCst);
+        }
+        if let Some(max) = self.max_contexts_per_isolate {
+            MAX_CONTEXTS_PER_ISOLATE.store(max, Ordering::SeqCst);
+        }
+        if let Some(max) = self.max_contexts {
+            MAX_CONTEXTS.store(max, Ordering::SeqCst);
+        }
+    }
+
+    // The settings that differ from old: the reloadable ones, then those
+    // that only take effect after a restart
+    pub fn changes(&self, old: &Config) -> (Vec<&'static str>, Vec<&'static str>) {
+        let reloadable = [
+            ("log_level", self.log_level != old.log_level),
+            (
+                "slow_request_ms",
+                self.slow_request_ms != old.slow_request_ms,
+            ),
+            (
+                "quarantine_after",
+                self.quarantine_after != old.quarantine_after,
+            ),
+            (
+                "max_queue_depth",
+                self.max_queue_depth != old.max_queue_depth,
+            ),
+            (
+                "default_timeout_ms",
+                self.default_timeout_ms != old.default_timeout_ms,
+            ),
+            (
+                "max_result_bytes",
+                self.max_result_bytes != old.max_result_bytes,
+            ),
+            (
+                "shed_queue_age_ms",
+                self.shed_queue_age_ms != old.shed_queue_age_ms,
+            ),
+            ("retry_crashed", self.retry_crashed != old.retry_crashed),
+            (
+                "max_contexts_per_isolate",
+                self.max_contexts_per_isolate != old.max_contexts_per_isolate,
+            ),
+            ("max_contexts", self.max_contexts != old.max_contexts),
+        ];
+        let restart = [
+            ("listen", self.listen != old.listen),
+            ("bind", self.bind != old.bind),
+            ("pool_max_size", self.pool_max_size != old.pool_max_size),
+            ("pool_min_size", self.pool_min_size != old.pool_min_size),
+            (
+                "pool_idle_ttl_secs",
+                self.pool_idle_ttl_secs != old.pool_idle_ttl_secs,
+            ),
+            (
+                "session_ttl_secs",
+                self.session_ttl_secs != old.session_ttl_secs,
+            ),
+            ("max_body_size", self.max_body_size != old.max_body_size),
+            ("access_log", self.access_log != old.access_log),
+            (
+                "access_log_format",
+                self.access_log_format != old.access_log_format,
+            ),
+            ("v8_flags", self.v8_flags != old.v8_flags),
+            ("security_mode", self.security_mode != old.security_mode),
+            ("capabilities", self.capabilities != old.capabilities),
+            ("max_emit_rows", self.max_emit_rows != old.max_emit_rows),
+            (
+                "max_emit_row_bytes",
+                self.max_emit_row_bytes != old.max_emit_row_bytes,
+            ),
+            ("max_emit_bytes", self.max_emit_bytes != old.max_emit_bytes),
+        ];
+        let changed = |settings: &[(&'static str, bool)]| {
+            settings
+                .iter()
+                .filter(|(_, changed)| *changed)
+                .map(|(name, _)| *name)
+                .collect()
+        };
+        (changed(&reloadable), changed(&restart))
+    }
+}
+
+pub fn max_queue_depth() -> usize {
+    MAX_QUEUE_DEPTH.load(Ordering::SeqCst)
+}
+
+// Like default_timeout_ms in the config file, for embedders. None for no
+// timeout.
+pub fn set_default_timeout(timeout: Option<Duration>) {
+    let ms = timeout.map_or(0, |timeout| timeout.as_millis().max(1) as u64);
+    DEFAULT_TIMEOUT_MS.store(ms, Ordering::SeqCst);
+}
+
+pub fn default_timeout() -> Option<Duration> {
+    match DEFAULT_TIMEOUT_MS.load(Ordering::SeqCst) {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
+
+pub fn max_result_bytes() -> usize {
+    MAX_RESULT_BYTES.load(Ordering::SeqCst)
+}
+
+pub fn shed_queue_age() -> Option<Duration> {
+    match SHED_QUEUE_AGE_MS.load(Ordering::SeqCst) {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
+
+pub fn retry_crashed() -> bool {
+    RETRY_CRASHED.load(Ordering::SeqCst)
+}
+
+pub fn max_contexts_per_isolate() -> usize {
+    MAX_CONTEXTS_PER_ISOLATE.load(Ordering::SeqCst)
+}
+
+pub fn max_contexts() -> usize {
+    MAX_CONTEXTS.load(Ordering::SeqCst)
+}
+
+// Reads path again and applies what changed. A file that can't be read or
+// parsed leaves everything as it was.
+pub fn reload(path: &Path, current: &mut Config) {
+    let config = match Config::from_file(path) {
+        Ok(config) => config,
+        Err(err) => {
+            log::warn!("not reloading {}: {}", path.display(), err);
+            return;
+        }
+    };
+    let (reloaded, restart) = config.changes(current);
+    if reloaded.is_empty() && restart.is_empty() {
+        return;
+    }
+    config.apply();
+    if !reloaded.is_empty() {
+        log::info!("reloaded {}: {}", path.display(), reloaded.join(", "));
+    }
+    if !restart.is_empty() {
+        log::warn!(
+            "{} changed in {} but only take effect after a restart",
+            restart.join(", "),
+            path.display()
+        );
+    }
+    *current = config;
+}
+
+// Reloads path whenever it changes or the process gets SIGHUP, for as long
+// as the runtime is running
+pub async fn watch(path: PathBuf, mut current: Config) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => Some(hangups),
+        Err(err) => {
+            log::warn!("can't reload {} on SIGHUP: {}", path.display(), err);
+            None
+        }
+    };
+    let mut last_modified = modified(&path);
+    let mut interval = tokio::time::interval(WATCH_INTERVAL);
+
+    loop {
+        let hangup = async {
+            match hangups.as_mut() {
+                Some(hangups) => hangups.recv().await,
+                None => futures::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = hangup => reload(&path, &mut current),
+            _ = interval.tick() => {
+                let now = modified(&path);
+                if now != last_modified {
+                    last_modified = now;
+                    reload(&path, &mut current);
+                }
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).ok()?.modified().ok()
+}
@@ -0,0 +1,73 @@
+use chrono::DateTime;
+use hyper::HeaderMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// A relative timeout as gRPC sends it, e.g. "250m" or "5S"
+pub const GRPC_TIMEOUT: &str = "grpc-timeout";
+
+// An absolute deadline, either RFC 3339 or milliseconds since the epoch
+pub const REQUEST_DEADLINE: &str = "x-request-deadline";
+
+// When the caller stops waiting for an answer, if its headers say. Given
+// both, the earlier one wins.
+pub fn from_headers(headers: &HeaderMap) -> Result<Option<Instant>, String> {
+    let header = |name: &str| -> Result<Option<&str>, String> {
+        match headers.get(name) {
+            Some(value) => value
+                .to_str()
+                .map(Some)
+                .map_err(|_| format!("invalid {} header", name)),
+            None => Ok(None),
+        }
+    };
+    let now = Instant::now();
+    let mut deadline = None;
+    if let Some(timeout) = header(GRPC_TIMEOUT)? {
+        deadline = Some(now + parse_grpc_timeout(timeout)?);
+    }
+    if let Some(at) = header(REQUEST_DEADLINE)? {
+        let remaining = parse_deadline(at)?
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        let at = now + remaining;
+        deadline = Some(deadline.map_or(at, |deadline: Instant| deadline.min(at)));
+    }
+    Ok(deadline)
+}
+
+// At most 8 digits and a unit, one of H, M, S, m, u or n
+pub fn parse_grpc_timeout(timeout: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid {} {:?}", GRPC_TIMEOUT, timeout);
+    if timeout.len() < 2 || timeout.len() > 9 || !timeout.is_ascii() {
+        return Err(invalid());
+    }
+    let (number, unit) = timeout.split_at(timeout.len() - 1);
+    if !number.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    match unit {
+        "H" => Ok(Duration::from_secs(number * 3600)),
+        "M" => Ok(Duration::from_secs(number * 60)),
+        "S" => Ok(Duration::from_secs(number)),
+        "m" => Ok(Duration::from_millis(number)),
+        "u" => Ok(Duration::from_micros(number)),
+        "n" => Ok(Duration::from_nanos(number)),
+        _ => Err(invalid()),
+    }
+}
+
+// e.g. "2020-05-01T12:00:00.250Z" or "1588334400250"
+pub fn parse_deadline(deadline: &str) -> Result<SystemTime, String> {
+    let invalid = || format!("invalid {} {:?}", REQUEST_DEADLINE, deadline);
+    if !deadline.is_empty() && deadline.bytes().all(|b| b.is_ascii_digit()) {
+        let ms: u64 = deadline.parse().map_err(|_| invalid())?;
+        return Ok(UNIX_EPOCH + Duration::from_millis(ms));
+    }
+    let at = DateTime::parse_from_rfc3339(deadline).map_err(|_| invalid())?;
+    let ms = at.timestamp_millis();
+    if ms < 0 {
+        return Ok(UNIX_EPOCH);
+    }
+    Ok(UNIX_EPOCH + Duration::from_millis(ms as u64))
+}
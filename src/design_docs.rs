@@ -0,0 +1,119 @@
+use rusty_v8 as v8;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::error::{ErrorInfo, JSError};
+
+// The functions of a design doc as sent with REGISTER_DDOC, e.g.
+//
+//   {"map": ["function(doc) { emit(doc._id, null); }"],
+//    "filters": {"mine": "function(doc, req) { return doc.owner == req.user; }"},
+//    "lib": {"util": "exports.up = function(s) { return s.toUpperCase(); };"}}
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Functions {
+    // In view order. MAP_DOC emits a group of rows for each.
+    #[serde(default)]
+    pub map: Vec<String>,
+    #[serde(default)]
+    pub filters: BTreeMap<String, String>,
+    // The design doc's views.lib, modules its functions can require as
+    // views/lib/<name>
+    #[serde(default)]
+    pub lib: Option<Value>,
+}
+
+pub fn parse(functions_json: &str) -> Result<Functions, JSError> {
+    let functions: Functions = serde_json::from_str(functions_json).map_err(|err| {
+        JSError::CompileError(ErrorInfo {
+            name: "SyntaxError".to_string(),
+            message: format!("invalid design doc functions: {}", err),
+            line: err.line() as i32,
+            ..ErrorInfo::default()
+        })
+    })?;
+    match &functions.lib {
+        Some(lib) if !lib.is_object() => Err(JSError::CompileError(ErrorInfo {
+            name: "TypeError".to_string(),
+            message: "invalid design doc functions: lib must be an object".to_string(),
+            ..ErrorInfo::default()
+        })),
+        _ => Ok(functions),
+    }
+}
+
+impl Functions {
+    // Each string in lib as a module id and its source, e.g.
+    // ("views/lib/util", "exports.up = ..."). Objects are directories and
+    // anything else isn't a module.
+    pub fn modules(&self) -> Vec<(String, String)> {
+        let mut modules = Vec::new();
+        if let Some(lib) = &self.lib {
+            collect_modules("views/lib", lib, &mut modules);
+        }
+        modules
+    }
+}
+
+fn collect_modules(id: &str, value: &Value, modules: &mut Vec<(String, String)>) {
+    match value {
+        Value::String(source) => modules.push((id.to_string(), source.clone())),
+        Value::Object(entries) => {
+            for (name, value) in entries {
+                collect_modules(&format!("{}/{}", id, name), value, modules);
+            }
+        }
+        _ => (),
+    }
+}
+
+// A design doc's functions compiled into a context of their own so that
+// design docs can't see each other's globals. The handles have to be reset
+// before this is dropped, see FortunaIsolate's Drop.
+pub struct DesignDoc {
+    pub context: v8::Global<v8::Context>,
+    pub maps: Vec<v8::Global<v8::Function>>,
+    pub filters: BTreeMap<String, v8::Global<v8::Function>>,
+    // When it was last registered or run, in its isolate's ticks, for
+    // evicting the least recently used
+    pub used: u64,
+}
+
+impl DesignDoc {
+    pub fn new<'sc>(
+        scope: &mut impl v8::ToLocal<'sc>,
+        context: v8::Local<v8::Context>,
+        maps: &[v8::Local<v8::Function>],
+        filters: &[(String, v8::Local<v8::Function>)],
+    ) -> DesignDoc {
+        let mut ddoc = DesignDoc {
+            context: v8::Global::new(),
+            maps: Vec::with_capacity(maps.len()),
+            filters: BTreeMap::new(),
+            used: 0,
+        };
+        ddoc.context.set(scope, context);
+        for fun in maps {
+            let mut global = v8::Global::new();
+            global.set(scope, *fun);
+            ddoc.maps.push(global);
+        }
+        for (name, fun) in filters {
+            let mut global = v8::Global::new();
+            global.set(scope, *fun);
+            ddoc.filters.insert(name.clone(), global);
+        }
+        ddoc
+    }
+
+    pub fn reset(&mut self, isolate: &mut impl v8::InIsolate) {
+        for fun in self.maps.iter_mut() {
+            fun.reset(isolate);
+        }
+        for fun in self.filters.values_mut() {
+            fun.reset(isolate);
+        }
+        self.context.reset(isolate);
+    }
+}
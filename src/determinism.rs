@@ -0,0 +1,346 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::{ErrorInfo, JSError};
+use crate::host_functions::Clock;
+
+// What's done about map and reduce functions that use APIs giving a
+// different answer on every run. Those corrupt incremental view builds, as
+// rows emitted for a doc earlier don't match what mapping it again emits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Determinism {
+    Allow,
+    // Log the uses found but register the functions anyway
+    Warn,
+    // Fail registering functions that use any with a NondeterminismError
+    Strict,
+}
+
+impl Default for Determinism {
+    fn default() -> Self {
+        Determinism::Allow
+    }
+}
+
+impl FromStr for Determinism {
+    type Err = String;
+
+    fn from_str(determinism: &str) -> Result<Self, Self::Err> {
+        match determinism {
+            "allow" => Ok(Determinism::Allow),
+            "warn" => Ok(Determinism::Warn),
+            "strict" => Ok(Determinism::Strict),
+            _ => Err(format!(
+                "invalid determinism {}, expected allow, warn or strict",
+                determinism
+            )),
+        }
+    }
+}
+
+// The APIs looked for, as object and property, and whether they only read
+// the clock, which a fixed Clock makes deterministic
+const APIS: &[(&str, &str, bool)] = &[
+    ("Math", "random", false),
+    ("crypto", "getRandomValues", false),
+    ("crypto", "randomUUID", false),
+    ("Date", "now", true),
+    ("performance", "now", true),
+];
+
+// A use of a nondeterministic API in a function's source
+#[derive(Clone, Debug, PartialEq)]
+pub struct Violation {
+    // e.g. Math.random or new Date()
+    pub api: String,
+    pub line: i32,
+    reads_clock: bool,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} on line {}", self.api, self.line)
+    }
+}
+
+// Looks through source for nondeterministic APIs. It's only a scan of the
+// tokens, so uses through aliases or computed properties aren't found, but
+// nothing in comments, strings or regexps is taken for one.
+pub fn scan(source: &str) -> Vec<Violation> {
+    let tokens = tokenize(source);
+    let mut violations = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        let name = match &token.kind {
+            Kind::Ident(name) => name.as_str(),
+            _ => continue,
+        };
+        let prev = if i > 0 {
+            Some(&tokens[i - 1].kind)
+        } else {
+            None
+        };
+        // A property of something else, e.g. foo.Math.random
+        if prev == Some(&Kind::Punct('.')) {
+            continue;
+        }
+        let next = tokens.get(i + 1).map(|token| &token.kind);
+        if next == Some(&Kind::Punct('.')) {
+            if let Some(Kind::Ident(prop)) = tokens.get(i + 2).map(|token| &token.kind) {
+                let found = APIS
+                    .iter()
+                    .find(|(object, property, _)| object == &name && property == prop);
+                if let Some((object, property, reads_clock)) = found {
+                    violations.push(Violation {
+                        api: format!("{}.{}", object, property),
+                        line: token.line,
+                        reads_clock: *reads_clock,
+                    });
+                }
+            }
+            continue;
+        }
+        if name != "Date" {
+            continue;
+        }
+        // Date() ignores its arguments, new Date only gives the time without
+        // any
+        let api = if matches!(prev, Some(Kind::Ident(keyword)) if keyword == "new") {
+            let no_args = match next {
+                Some(Kind::Punct('(')) => {
+                    tokens.get(i + 2).map(|token| &token.kind) == Some(&Kind::Punct(')'))
+                }
+                _ => true,
+            };
+            if !no_args {
+                continue;
+            }
+            "new Date()"
+        } else if next == Some(&Kind::Punct('(')) {
+            "Date()"
+        } else {
+            continue;
+        };
+        violations.push(Violation {
+            api: api.to_string(),
+            line: token.line,
+            reads_clock: true,
+        });
+    }
+    violations
+}
+
+// Checks the source of what, e.g. "map function 0", as determinism asks.
+// Date and performance.now are fine with a fixed clock.
+pub fn check(
+    determinism: Determinism,
+    clock: Clock,
+    what: &str,
+    source: &str,
+) -> Result<(), JSError> {
+    if determinism == Determinism::Allow {
+        return Ok(());
+    }
+    let mut violations = scan(source);
+    if let Clock::Fixed(_) = clock {
+        violations.retain(|violation| !violation.reads_clock);
+    }
+    if violations.is_empty() {
+        return Ok(());
+    }
+    let uses: Vec<String> = violations.iter().map(ToString::to_string).collect();
+    let message = format!("{} uses {}", what, uses.join(", "));
+    if determinism == Determinism::Warn {
+        log::warn!("nondeterministic {}", message);
+        return Ok(());
+    }
+    Err(JSError::CompileError(ErrorInfo {
+        name: "NondeterminismError".to_string(),
+        message,
+        line: violations[0].line,
+        ..ErrorInfo::default()
+    }))
+}
+
+#[derive(Debug, PartialEq)]
+enum Kind {
+    Ident(String),
+    Punct(char),
+    // Numbers, strings, template text and regexps
+    Literal,
+}
+
+struct Token {
+    kind: Kind,
+    line: i32,
+}
+
+// Keywords after which a / starts a regexp rather than dividing
+const BEFORE_REGEXP: &[&str] = &[
+    "return",
+    "typeof",
+    "instanceof",
+    "in",
+    "of",
+    "new",
+    "delete",
+    "void",
+    "throw",
+    "case",
+    "do",
+    "else",
+    "yield",
+    "await",
+];
+
+fn is_ident_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_' || c == '$'
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+fn tokenize(source: &str) -> Vec<Token> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut line = 1;
+    let mut i = 0;
+    // For each open brace, whether it started a ${} in a template
+    let mut braces: Vec<bool> = Vec::new();
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        if c == '\n' {
+            line += 1;
+            i += 1;
+        } else if c.is_whitespace() {
+            i += 1;
+        } else if c == '/' && next == Some('/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '/' && next == Some('*') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                if chars[i] == '\n' {
+                    line += 1;
+                }
+                i += 1;
+            }
+            i += 2;
+        } else if c == '"' || c == '\'' {
+            let start = line;
+            i += 1;
+            while i < chars.len() && chars[i] != c && chars[i] != '\n' {
+                if chars[i] == '\\' {
+                    i += 1;
+                    if chars.get(i) == Some(&'\n') {
+                        line += 1;
+                    }
+                }
+                i += 1;
+            }
+            i += 1;
+            tokens.push(Token {
+                kind: Kind::Literal,
+                line: start,
+            });
+        } else if c == '`' || (c == '}' && braces.last() == Some(&true)) {
+            if c == '}' {
+                braces.pop();
+            }
+            let start = line;
+            i = template(&chars, i + 1, &mut line, &mut braces);
+            tokens.push(Token {
+                kind: Kind::Literal,
+                line: start,
+            });
+        } else if c == '/' && regexp_allowed(tokens.last()) {
+            let start = line;
+            let mut class = false;
+            i += 1;
+            while i < chars.len() && chars[i] != '\n' && (class || chars[i] != '/') {
+                match chars[i] {
+                    '\\' => i += 1,
+                    '[' => class = true,
+                    ']' => class = false,
+                    _ => (),
+                }
+                i += 1;
+            }
+            i += 1;
+            while i < chars.len() && is_ident_char(chars[i]) {
+                i += 1;
+            }
+            tokens.push(Token {
+                kind: Kind::Literal,
+                line: start,
+            });
+        } else if is_ident_start(c) {
+            let start = i;
+            while i < chars.len() && is_ident_char(chars[i]) {
+                i += 1;
+            }
+            tokens.push(Token {
+                kind: Kind::Ident(chars[start..i].iter().collect()),
+                line,
+            });
+        } else if c.is_ascii_digit() || (c == '.' && next.map_or(false, |n| n.is_ascii_digit())) {
+            while i < chars.len() && (is_ident_char(chars[i]) || chars[i] == '.') {
+                i += 1;
+            }
+            tokens.push(Token {
+                kind: Kind::Literal,
+                line,
+            });
+        } else {
+            match c {
+                '{' => braces.push(false),
+                '}' => {
+                    braces.pop();
+                }
+                _ => (),
+            }
+            tokens.push(Token {
+                kind: Kind::Punct(c),
+                line,
+            });
+            i += 1;
+        }
+    }
+    tokens
+}
+
+// Skips template text from i, returning where code starts again, after the
+// closing ` or a ${
+fn template(chars: &[char], mut i: usize, line: &mut i32, braces: &mut Vec<bool>) -> usize {
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => {
+                i += 1;
+                if chars.get(i) == Some(&'\n') {
+                    *line += 1;
+                }
+            }
+            '\n' => *line += 1,
+            '`' => return i + 1,
+            '$' if chars.get(i + 1) == Some(&'{') => {
+                braces.push(true);
+                return i + 2;
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+    i
+}
+
+fn regexp_allowed(prev: Option<&Token>) -> bool {
+    match prev.map(|token| &token.kind) {
+        None => true,
+        Some(Kind::Literal) => false,
+        Some(Kind::Ident(name)) => BEFORE_REGEXP.contains(&name.as_str()),
+        Some(Kind::Punct(c)) => !matches!(c, ')' | ']' | '}'),
+    }
+}
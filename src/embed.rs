@@ -0,0 +1,104 @@
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::host_functions::Capabilities;
+use crate::js_server::{Command, Ops};
+use crate::pool::WorkerPool;
+use crate::{JSEnv, JSError, JSResult};
+
+// How an embedded Fortuna runs JS
+#[derive(Clone, Debug)]
+pub struct FortunaConfig {
+    // Workers, each with its own isolate, that commands are spread over
+    pub workers: usize,
+    // Host functions the isolates get, see Capabilities
+    pub capabilities: Capabilities,
+    // How long a command may run, None for no limit
+    pub timeout: Option<Duration>,
+}
+
+impl Default for FortunaConfig {
+    fn default() -> FortunaConfig {
+        FortunaConfig {
+            workers: 1,
+            capabilities: Capabilities::default(),
+            timeout: None,
+        }
+    }
+}
+
+// Runs JS for other Rust programs without the HTTP server, e.g.
+//
+//   fortuna::init_v8();
+//   let fortuna = Fortuna::new(FortunaConfig::default())?;
+//   fortuna.eval("function sum(a, b) { return a + b; }").await?;
+//   assert_eq!(fortuna.call("sum", &[json!(1), json!(2)]).await?, json!(3));
+//
+// V8 has to be initialized once per process first, with init_v8 or
+// init_v8_with. Each command goes to the next idle worker, so state left by
+// eval is only sure to be there for later calls with a single worker.
+// Clones share the workers, which stop once the last one is dropped.
+#[derive(Clone)]
+pub struct Fortuna {
+    pool: Arc<WorkerPool>,
+    timeout: Option<Duration>,
+}
+
+impl Fortuna {
+    pub fn new(config: FortunaConfig) -> Result<Fortuna, String> {
+        if config.workers == 0 {
+            return Err("Fortuna needs at least one worker".to_string());
+        }
+        let js_env = JSEnv::try_new()?;
+        let pool = WorkerPool::new(&js_env, &config.capabilities, config.workers);
+        Ok(Fortuna {
+            pool: Arc::new(pool),
+            timeout: config.timeout,
+        })
+    }
+
+    // Runs src and gives back what it evaluates to, null if that's
+    // undefined
+    pub async fn eval(&self, src: &str) -> Result<Value, JSError> {
+        let result = self
+            .run(Command {
+                operation: Ops::EVAL,
+                payload: src.to_string(),
+                args: vec![],
+                docs: Vec::new(),
+            })
+            .await;
+        parse(result)
+    }
+
+    // Calls the global function name with args and gives back its result
+    pub async fn call(&self, name: &str, args: &[Value]) -> Result<Value, JSError> {
+        let result = self
+            .run(Command {
+                operation: Ops::CALL_JSON,
+                payload: name.to_string(),
+                args: args.iter().map(Value::to_string).collect(),
+                docs: Vec::new(),
+            })
+            .await;
+        parse(result)
+    }
+
+    async fn run(&self, cmd: Command) -> JSResult {
+        let js_client = self.pool.next();
+        match self.timeout {
+            Some(timeout) => js_client.run_timeout(cmd, timeout).await,
+            None => js_client.run(cmd).await,
+        }
+    }
+}
+
+fn parse(result: JSResult) -> Result<Value, JSError> {
+    let json = result?;
+    // A function returning undefined stringifies to nothing
+    if json.is_empty() || json == "undefined" {
+        return Ok(Value::Null);
+    }
+    serde_json::from_str(&json).map_err(|err| JSError::Internal(format!("invalid result: {}", err)))
+}
@@ -0,0 +1,86 @@
+use std::fmt;
+
+// Details of an exception thrown by JS code
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ErrorInfo {
+    pub name: String,
+    pub message: String,
+    pub stack: String,
+    pub line: i32,
+}
+
+#[derive(Debug)]
+pub enum JSError {
+    // The script failed to compile
+    CompileError(ErrorInfo),
+    // The script threw an exception
+    RuntimeError(ErrorInfo),
+    // The script was terminated before it finished
+    Timeout,
+    OutOfMemory,
+    QueueFull,
+    // The script emitted more than its EmitLimits allow
+    EmitLimitExceeded(String),
+    // The request requires a feature this server doesn't have
+    UnsupportedFeature(String),
+    // The script, by hash, crashed workers too often to be run again
+    Quarantined(String),
+    // The request couldn't be read or decoded
+    InvalidRequest(String),
+    // No design doc is registered under this signature on the worker, e.g.
+    // because it was recycled since. Register it again and retry.
+    UnknownDesignDoc(String),
+    // Over one of the server's rate limits, try again later
+    RateLimited(String),
+    // Killed through the admin API, before or while it ran
+    Killed,
+    // The result and emitted rows were over max_result_bytes
+    ResultTooLarge(String),
+    // The caller's deadline passed before it got an answer
+    DeadlineExceeded,
+    // Shed because the worker's queue is too far behind, try again later
+    Overloaded(String),
+    // Missing or wrong token for the Execute endpoints
+    Unauthorized,
+    // The worker thread panicked, or was already gone, before it could
+    // answer.
+    WorkerCrashed,
+    Internal(String),
+}
+
+impl fmt::Display for JSError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JSError::CompileError(info) => write!(f, "compile_error: {}", info),
+            JSError::RuntimeError(info) => write!(f, "runtime_error: {}", info),
+            JSError::Timeout => write!(f, "timeout"),
+            JSError::OutOfMemory => write!(f, "out_of_memory"),
+            JSError::QueueFull => write!(f, "queue_full"),
+            JSError::EmitLimitExceeded(reason) => write!(f, "emit_limit_exceeded: {}", reason),
+            JSError::UnsupportedFeature(name) => write!(f, "unsupported_feature: {}", name),
+            JSError::Quarantined(hash) => write!(f, "quarantined_script: {}", hash),
+            JSError::InvalidRequest(reason) => write!(f, "invalid_request: {}", reason),
+            JSError::UnknownDesignDoc(signature) => {
+                write!(f, "unknown_design_doc: {}", signature)
+            }
+            JSError::RateLimited(reason) => write!(f, "rate_limited: {}", reason),
+            JSError::Killed => write!(f, "killed"),
+            JSError::ResultTooLarge(reason) => write!(f, "result_too_large: {}", reason),
+            JSError::DeadlineExceeded => write!(f, "deadline_exceeded"),
+            JSError::Overloaded(reason) => write!(f, "overloaded: {}", reason),
+            JSError::Unauthorized => write!(f, "unauthorized"),
+            JSError::WorkerCrashed => write!(f, "worker_crashed"),
+            JSError::Internal(reason) => write!(f, "internal: {}", reason),
+        }
+    }
+}
+
+impl fmt::Display for ErrorInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.name, self.message)
+    }
+}
+
+impl std::error::Error for JSError {}
+
+pub type JSResult = Result<String, JSError>;
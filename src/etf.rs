@@ -0,0 +1,111 @@
+use serde_json::{Map, Number, Value};
+
+// Encodes JSON as Erlang External Term Format in the shape jiffy decodes it
+// to, so the Erlang side can use binary_to_term directly:
+//
+//   null, true, false -> atoms
+//   numbers           -> integers or floats
+//   strings           -> binaries
+//   arrays            -> lists
+//   objects           -> {[{Key, Value}, ...]}
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = vec![VERSION];
+    encode_term(value, &mut out);
+    out
+}
+
+const VERSION: u8 = 131;
+const NEW_FLOAT_EXT: u8 = 70;
+const SMALL_INTEGER_EXT: u8 = 97;
+const INTEGER_EXT: u8 = 98;
+const SMALL_TUPLE_EXT: u8 = 104;
+const NIL_EXT: u8 = 106;
+const LIST_EXT: u8 = 108;
+const BINARY_EXT: u8 = 109;
+const SMALL_BIG_EXT: u8 = 110;
+const SMALL_ATOM_UTF8_EXT: u8 = 119;
+
+fn encode_term(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Null => encode_atom("null", out),
+        Value::Bool(true) => encode_atom("true", out),
+        Value::Bool(false) => encode_atom("false", out),
+        Value::Number(n) => encode_number(n, out),
+        Value::String(s) => encode_binary(s, out),
+        Value::Array(items) => encode_list(items, out),
+        Value::Object(members) => encode_object(members, out),
+    }
+}
+
+fn encode_atom(name: &str, out: &mut Vec<u8>) {
+    out.push(SMALL_ATOM_UTF8_EXT);
+    out.push(name.len() as u8);
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn encode_number(n: &Number, out: &mut Vec<u8>) {
+    if let Some(i) = n.as_i64() {
+        encode_integer((i as i128).abs() as u64, i < 0, out);
+    } else if let Some(u) = n.as_u64() {
+        encode_integer(u, false, out);
+    } else {
+        out.push(NEW_FLOAT_EXT);
+        out.extend_from_slice(&n.as_f64().unwrap_or(0.0).to_bits().to_be_bytes());
+    }
+}
+
+fn encode_integer(magnitude: u64, negative: bool, out: &mut Vec<u8>) {
+    if !negative && magnitude <= u8::MAX as u64 {
+        out.push(SMALL_INTEGER_EXT);
+        out.push(magnitude as u8);
+    } else if magnitude <= i32::MAX as u64 {
+        let i = if negative {
+            -(magnitude as i32)
+        } else {
+            magnitude as i32
+        };
+        out.push(INTEGER_EXT);
+        out.extend_from_slice(&i.to_be_bytes());
+    } else {
+        // Little endian digits without the leading zeros
+        let bytes = magnitude.to_le_bytes();
+        let digits = &bytes[..8 - magnitude.leading_zeros() as usize / 8];
+        out.push(SMALL_BIG_EXT);
+        out.push(digits.len() as u8);
+        out.push(negative as u8);
+        out.extend_from_slice(digits);
+    }
+}
+
+fn encode_binary(s: &str, out: &mut Vec<u8>) {
+    out.push(BINARY_EXT);
+    out.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn encode_list(items: &[Value], out: &mut Vec<u8>) {
+    if !items.is_empty() {
+        out.push(LIST_EXT);
+        out.extend_from_slice(&(items.len() as u32).to_be_bytes());
+        for item in items {
+            encode_term(item, out);
+        }
+    }
+    out.push(NIL_EXT);
+}
+
+fn encode_object(members: &Map<String, Value>, out: &mut Vec<u8>) {
+    out.push(SMALL_TUPLE_EXT);
+    out.push(1);
+    if !members.is_empty() {
+        out.push(LIST_EXT);
+        out.extend_from_slice(&(members.len() as u32).to_be_bytes());
+        for (key, value) in members {
+            out.push(SMALL_TUPLE_EXT);
+            out.push(2);
+            encode_binary(key, out);
+            encode_term(value, out);
+        }
+    }
+    out.push(NIL_EXT);
+}
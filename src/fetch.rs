@@ -0,0 +1,128 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::time::Duration;
+
+use reqwest::blocking::Client;
+use reqwest::Method;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::threads;
+
+// How long fetch() waits for a whole response. The worker is blocked until
+// then and a timeout or kill of the command can't interrupt it.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Responses with bodies over this fail rather than filling up the heap
+const MAX_RESPONSE_BYTES: u64 = 1024 * 1024;
+
+// What fetch(url, init) asks for. init is the JSON of the init object, with
+// method, headers and body like the Fetch API's, or null.
+#[derive(Debug)]
+pub struct FetchRequest {
+    method: Method,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FetchResponse {
+    pub status: u16,
+    // Headers that aren't valid UTF-8 are left out
+    pub headers: BTreeMap<String, String>,
+    // Decoded as UTF-8, lossily
+    pub body: String,
+}
+
+impl FetchRequest {
+    pub fn new(url: String, init: &Value) -> Result<FetchRequest, String> {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(format!("fetch only supports http and https, not {}", url));
+        }
+
+        let method = match init.get("method") {
+            None | Some(Value::Null) => Method::GET,
+            Some(Value::String(method)) => Method::from_bytes(method.to_uppercase().as_bytes())
+                .map_err(|_| format!("invalid fetch method {}", method))?,
+            Some(_) => return Err("fetch method must be a string".to_string()),
+        };
+
+        let mut headers = Vec::new();
+        match init.get("headers") {
+            None | Some(Value::Null) => (),
+            Some(Value::Object(members)) => {
+                for (name, value) in members {
+                    match value {
+                        Value::String(value) => headers.push((name.clone(), value.clone())),
+                        _ => return Err(format!("fetch header {} must be a string", name)),
+                    }
+                }
+            }
+            Some(_) => return Err("fetch headers must be an object".to_string()),
+        }
+
+        let body = match init.get("body") {
+            None | Some(Value::Null) => None,
+            Some(Value::String(body)) => Some(body.clone()),
+            Some(_) => return Err("fetch body must be a string".to_string()),
+        };
+
+        Ok(FetchRequest {
+            method,
+            url,
+            headers,
+            body,
+        })
+    }
+
+    // Sends the request from a thread of its own, as reqwest's blocking
+    // client can't be used from within a tokio runtime, e.g. by the repl
+    pub fn send(self) -> Result<FetchResponse, String> {
+        let url = self.url.clone();
+        let fetching = threads::spawn("fortuna-fetch".to_string(), move || self.send_blocking())
+            .map_err(|err| format!("fetch {} failed: {}", url, err))?;
+        match fetching.join() {
+            Ok(result) => result.map_err(|err| format!("fetch {} failed: {}", url, err)),
+            Err(_) => Err(format!("fetch {} failed", url)),
+        }
+    }
+
+    fn send_blocking(self) -> Result<FetchResponse, String> {
+        let client = Client::builder()
+            .timeout(FETCH_TIMEOUT)
+            .build()
+            .map_err(|err| err.to_string())?;
+        let mut request = client.request(self.method, &self.url);
+        for (name, value) in self.headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        if let Some(body) = self.body {
+            request = request.body(body);
+        }
+        let resp = request.send().map_err(|err| err.to_string())?;
+
+        let status = resp.status().as_u16();
+        let headers = resp
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| {
+                let value = value.to_str().ok()?;
+                Some((name.as_str().to_string(), value.to_string()))
+            })
+            .collect();
+        let mut body = Vec::new();
+        resp.take(MAX_RESPONSE_BYTES + 1)
+            .read_to_end(&mut body)
+            .map_err(|err| err.to_string())?;
+        if body.len() as u64 > MAX_RESPONSE_BYTES {
+            return Err(format!("response is over {} bytes", MAX_RESPONSE_BYTES));
+        }
+
+        Ok(FetchResponse {
+            status,
+            headers,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        })
+    }
+}
@@ -0,0 +1,87 @@
+use bytes::{Buf, BytesMut};
+use prost::{DecodeError, Message};
+
+// Frames bigger than this are rejected rather than buffered
+pub const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+// Incrementally splits a byte stream into varint length-prefixed protobuf
+// messages (the same framing as prost's encode_length_delimited). Bytes are
+// fed in as they arrive and complete frames are taken out one at a time.
+pub struct FrameDecoder {
+    buf: BytesMut,
+    max_len: usize,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        FrameDecoder::with_max_len(MAX_FRAME_LEN)
+    }
+}
+
+impl FrameDecoder {
+    pub fn new() -> FrameDecoder {
+        FrameDecoder::default()
+    }
+
+    // Rejects frames bigger than max_len, e.g. the server's max body size
+    pub fn with_max_len(max_len: usize) -> FrameDecoder {
+        FrameDecoder {
+            buf: BytesMut::new(),
+            max_len: max_len.min(MAX_FRAME_LEN),
+        }
+    }
+
+    pub fn extend(&mut self, chunk: &[u8]) {
+        self.buf.extend_from_slice(chunk);
+    }
+
+    // Returns the next message if all of it has been buffered
+    pub fn next_frame<M: Message + Default>(&mut self) -> Result<Option<M>, DecodeError> {
+        let (len, header_len) = match peek_length(&self.buf)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+
+        if len > self.max_len {
+            return Err(DecodeError::new("frame too large"));
+        }
+
+        if self.buf.len() < header_len + len {
+            return Ok(None);
+        }
+
+        self.buf.advance(header_len);
+        let frame = self.buf.split_to(len);
+        M::decode(frame.freeze()).map(Some)
+    }
+
+    // True if there's nothing left over, i.e. the stream didn't end halfway
+    // through a frame.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+}
+
+pub fn encode_frame<M: Message>(msg: &M) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(msg.encoded_len() + 10);
+    msg.encode_length_delimited(&mut buf).unwrap();
+    buf
+}
+
+// Decodes the varint length prefix, returning the length and the number of
+// bytes the prefix used. None means the prefix isn't complete yet.
+fn peek_length(buf: &[u8]) -> Result<Option<(usize, usize)>, DecodeError> {
+    let mut len: u64 = 0;
+    for (i, byte) in buf.iter().enumerate().take(10) {
+        len |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((len as usize, i + 1)));
+        }
+    }
+
+    if buf.len() >= 10 {
+        return Err(DecodeError::new("invalid varint"));
+    }
+
+    Ok(None)
+}
@@ -0,0 +1,86 @@
+use rusty_v8 as v8;
+use std::collections::VecDeque;
+use std::ffi::c_void;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::metrics::GC_PAUSE_SECONDS;
+
+// How many GC events each isolate remembers
+const GC_LOG_SIZE: usize = 32;
+
+#[derive(Clone, Debug)]
+pub struct GcEvent {
+    pub kind: &'static str,
+    pub pause: Duration,
+    pub finished_at: SystemTime,
+}
+
+// Rolling log of the most recent GC pauses of one isolate. The prologue and
+// epilogue callbacks run on the isolate's thread so the start time doesn't
+// need to be matched up against other threads.
+#[derive(Default)]
+pub struct GcLog {
+    started: Mutex<Option<Instant>>,
+    events: Mutex<VecDeque<GcEvent>>,
+}
+
+impl GcLog {
+    pub fn recent(&self) -> Vec<GcEvent> {
+        self.events.lock().unwrap().iter().cloned().collect()
+    }
+
+    fn record(&self, kind: &'static str, pause: Duration) {
+        GC_PAUSE_SECONDS.observe(kind, pause);
+
+        let mut events = self.events.lock().unwrap();
+        if events.len() == GC_LOG_SIZE {
+            events.pop_front();
+        }
+        events.push_back(GcEvent {
+            kind,
+            pause,
+            finished_at: SystemTime::now(),
+        });
+    }
+}
+
+// The GcLog has to outlive the isolate since its address is handed to V8 as
+// the callback data.
+pub fn track(isolate: &mut v8::Isolate, log: &GcLog) {
+    let data = log as *const GcLog as *mut c_void;
+    isolate.add_gc_prologue_callback(gc_prologue, data, v8::GCType::kGCTypeAll);
+    isolate.add_gc_epilogue_callback(gc_epilogue, data, v8::GCType::kGCTypeAll);
+}
+
+fn gc_kind(gc_type: v8::GCType) -> &'static str {
+    match gc_type {
+        v8::GCType::kGCTypeScavenge => "scavenge",
+        v8::GCType::kGCTypeMarkSweepCompact => "mark_sweep_compact",
+        v8::GCType::kGCTypeIncrementalMarking => "incremental_marking",
+        v8::GCType::kGCTypeProcessWeakCallbacks => "process_weak_callbacks",
+        _ => "other",
+    }
+}
+
+extern "C" fn gc_prologue(
+    _isolate: *mut v8::Isolate,
+    _gc_type: v8::GCType,
+    _flags: v8::GCCallbackFlags,
+    data: *mut c_void,
+) {
+    let log = unsafe { &*(data as *const GcLog) };
+    *log.started.lock().unwrap() = Some(Instant::now());
+}
+
+extern "C" fn gc_epilogue(
+    _isolate: *mut v8::Isolate,
+    gc_type: v8::GCType,
+    _flags: v8::GCCallbackFlags,
+    data: *mut c_void,
+) {
+    let log = unsafe { &*(data as *const GcLog) };
+    if let Some(started) = log.started.lock().unwrap().take() {
+        log.record(gc_kind(gc_type), started.elapsed());
+    }
+}
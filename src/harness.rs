@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::ateles::JsRequest;
+use crate::host_functions::Capabilities;
+use crate::js_server::{create_js_env, JSClient};
+use crate::pool::WorkerPool;
+use crate::JSEnv;
+
+// The harnesses a server has besides the built in one, by name
+pub type Harnesses = HashMap<String, Arc<Harness>>;
+
+// A bundle of runtime JS other than the one built into the binary, e.g. a
+// new version of the CouchDB harness being rolled out. Requests ask for it
+// with their harness field and run on workers of its own, created from its
+// snapshot.
+pub struct Harness {
+    name: String,
+    js_env: Arc<JSEnv>,
+    capabilities: Capabilities,
+    pool: WorkerPool,
+}
+
+impl Harness {
+    pub fn new(name: &str, js_env: JSEnv, capabilities: Capabilities, workers: usize) -> Harness {
+        let pool = WorkerPool::new(&js_env, &capabilities, workers.max(1));
+        Harness {
+            name: name.to_string(),
+            js_env: Arc::new(js_env),
+            capabilities,
+            pool,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn js_env(&self) -> &JSEnv {
+        &self.js_env
+    }
+
+    pub fn workers(&self) -> usize {
+        self.pool.size()
+    }
+
+    // Requests with a stream_key stay on one worker, the others are spread
+    // over the harness's workers
+    pub fn client_for(&self, js_request: &JsRequest) -> JSClient {
+        if js_request.stream_key.is_empty() {
+            self.pool.next()
+        } else {
+            self.pool.for_key(&js_request.stream_key)
+        }
+    }
+
+    // A worker of its own, e.g. for a session
+    pub fn create_client(&self) -> JSClient {
+        create_js_env(&self.js_env, &self.capabilities)
+    }
+}
+
+// The harness js_request asks for, None for the built in one
+pub fn lookup<'a>(
+    harnesses: &'a Harnesses,
+    js_request: &JsRequest,
+) -> Result<Option<&'a Harness>, String> {
+    if js_request.harness.is_empty() {
+        return Ok(None);
+    }
+    match harnesses.get(&js_request.harness) {
+        Some(harness) => Ok(Some(harness)),
+        None => Err(format!("unknown harness {}", js_request.harness)),
+    }
+}
+
+// A --harness option, name=dir
+#[derive(Clone, Debug, PartialEq)]
+pub struct HarnessSpec {
+    pub name: String,
+    pub dir: PathBuf,
+}
+
+impl FromStr for HarnessSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut parts = spec.splitn(2, '=');
+        let name = parts.next().unwrap_or("").trim();
+        let dir = parts.next().unwrap_or("").trim();
+        if name.is_empty() || dir.is_empty() {
+            return Err(format!("invalid harness {:?}, expected name=dir", spec));
+        }
+        Ok(HarnessSpec {
+            name: name.to_string(),
+            dir: PathBuf::from(dir),
+        })
+    }
+}
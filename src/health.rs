@@ -0,0 +1,237 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+use crate::http_service::Workers;
+use crate::js_server::{self, Command, JSClient, Ops};
+use crate::throttle;
+
+use grpc_health::health_check_response::ServingStatus;
+use grpc_health::health_server::{Health, HealthServer};
+use grpc_health::{HealthCheckRequest, HealthCheckResponse};
+
+pub mod grpc_health {
+    tonic::include_proto!("grpc.health.v1");
+}
+
+// How long a worker gets to answer the readiness probe
+const READY_TIMEOUT: Duration = Duration::from_millis(500);
+
+// A worker with more than this many commands queued isn't considered ready
+const READY_MAX_QUEUE_DEPTH: usize = 16;
+
+// Past these the server still answers but is degraded: the worker has more
+// than this many commands queued, the probe waited longer than this for the
+// worker or fewer than this fraction of all workers are idle
+const DEGRADED_QUEUE_DEPTH: usize = 4;
+const DEGRADED_QUEUE_WAIT: Duration = Duration::from_millis(100);
+const DEGRADED_MIN_AVAILABLE: f64 = 0.1;
+
+// Service names the gRPC health check answers for. The empty name is the
+// overall server health.
+const SERVICES: &[&str] = &["", "ateles.Ateles"];
+
+// Set by an operator to take the server out of rotation while it keeps
+// serving whatever is still sent to it
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+pub fn set_draining(draining: bool) {
+    DRAINING.store(draining, Ordering::SeqCst);
+}
+
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::SeqCst)
+}
+
+// Set while run_server waits for the pool's workers to start their isolates
+static WARMING: AtomicBool = AtomicBool::new(false);
+
+pub fn set_warming(warming: bool) {
+    WARMING.store(warming, Ordering::SeqCst);
+}
+
+pub fn is_warming() -> bool {
+    WARMING.load(Ordering::SeqCst)
+}
+
+// Ordered from best to worst
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ReadyLevel {
+    Ready,
+    // Still serving but should be drained before it falls over
+    Degraded,
+    NotReady,
+}
+
+impl ReadyLevel {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ReadyLevel::Ready => "ready",
+            ReadyLevel::Degraded => "degraded",
+            ReadyLevel::NotReady => "not_ready",
+        }
+    }
+}
+
+// What readiness is judged on
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReadySignals {
+    // Why the probe script failed, if it did
+    pub probe_error: Option<String>,
+    // Commands queued for the probed worker
+    pub queue_depth: usize,
+    // How long the probe waited for the worker
+    pub queue_wait: Duration,
+    pub workers: usize,
+    // Workers that aren't running anything
+    pub available: usize,
+    pub throttled: bool,
+    pub draining: bool,
+    pub warming: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Readiness {
+    pub level: ReadyLevel,
+    // Why it isn't ready, empty when it is
+    pub reasons: Vec<String>,
+    pub signals: ReadySignals,
+}
+
+impl Readiness {
+    fn worsen(&mut self, level: ReadyLevel, reason: String) {
+        self.level = self.level.max(level);
+        self.reasons.push(reason);
+    }
+}
+
+pub fn assess(signals: ReadySignals) -> Readiness {
+    let mut readiness = Readiness {
+        level: ReadyLevel::Ready,
+        reasons: Vec::new(),
+        signals,
+    };
+    let signals = readiness.signals.clone();
+
+    if signals.draining {
+        readiness.worsen(ReadyLevel::NotReady, "draining".to_string());
+    }
+    if signals.warming {
+        readiness.worsen(ReadyLevel::NotReady, "warming up".to_string());
+    }
+    if let Some(err) = &signals.probe_error {
+        readiness.worsen(ReadyLevel::NotReady, format!("probe failed: {}", err));
+    }
+    if signals.queue_depth > READY_MAX_QUEUE_DEPTH {
+        let reason = format!("{} commands queued", signals.queue_depth);
+        readiness.worsen(ReadyLevel::NotReady, reason);
+    } else if signals.queue_depth > DEGRADED_QUEUE_DEPTH {
+        let reason = format!("{} commands queued", signals.queue_depth);
+        readiness.worsen(ReadyLevel::Degraded, reason);
+    }
+    if signals.queue_wait > DEGRADED_QUEUE_WAIT {
+        let reason = format!("probe queued for {:?}", signals.queue_wait);
+        readiness.worsen(ReadyLevel::Degraded, reason);
+    }
+    if signals.workers > 0
+        && (signals.available as f64) < signals.workers as f64 * DEGRADED_MIN_AVAILABLE
+    {
+        let reason = format!(
+            "{} of {} workers available",
+            signals.available, signals.workers
+        );
+        readiness.worsen(ReadyLevel::Degraded, reason);
+    }
+    if signals.throttled {
+        let reason = "host is saturated, background commands are throttled".to_string();
+        readiness.worsen(ReadyLevel::Degraded, reason);
+    }
+    readiness
+}
+
+// Runs a trivial script on the worker behind js_client with a deadline of
+// READY_TIMEOUT and judges the server on how that went, how backed up the
+// worker is, how many workers are idle and whether the throttle is on.
+pub async fn readiness(js_client: &JSClient) -> Readiness {
+    let queue_depth = js_client.queue_depth();
+    let workers = js_server::workers();
+    let mut signals = ReadySignals {
+        queue_depth,
+        workers: workers.len(),
+        available: workers.iter().filter(|worker| !worker.busy).count(),
+        throttled: throttle::is_throttled(),
+        draining: is_draining(),
+        warming: is_warming(),
+        ..ReadySignals::default()
+    };
+
+    // No point waiting behind all of that
+    if queue_depth <= READY_MAX_QUEUE_DEPTH {
+        let probe = Command {
+            operation: Ops::EVAL,
+            payload: "true;".to_string(),
+            args: vec![],
+            docs: Vec::new(),
+        };
+        let reply = js_client.execute_timeout(probe, READY_TIMEOUT).await;
+        signals.queue_wait = reply.stats.queue_wait;
+        signals.probe_error = match reply.result {
+            Ok(result) if result == "true" => None,
+            Ok(result) => Some(format!("unexpected result {}", result)),
+            Err(err) => Some(err.to_string()),
+        };
+    }
+    assess(signals)
+}
+
+// Degraded still counts as ready
+pub async fn is_ready(js_client: &JSClient) -> bool {
+    readiness(js_client).await.level != ReadyLevel::NotReady
+}
+
+// Answers for the server's own workers, see MakeService::health_service
+pub struct HealthService {
+    workers: Workers,
+}
+
+impl HealthService {
+    pub(crate) fn new(workers: Workers) -> HealthService {
+        HealthService { workers }
+    }
+}
+
+#[tonic::async_trait]
+impl Health for HealthService {
+    async fn check(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<HealthCheckResponse>, Status> {
+        let service = request.into_inner().service;
+        if !SERVICES.contains(&service.as_str()) {
+            return Err(Status::not_found(format!("unknown service {}", service)));
+        }
+
+        let status = if is_ready(&self.workers.client()).await {
+            ServingStatus::Serving
+        } else {
+            ServingStatus::NotServing
+        };
+
+        Ok(Response::new(HealthCheckResponse {
+            status: status as i32,
+        }))
+    }
+}
+
+pub async fn create_health_server(
+    addr: SocketAddr,
+    health: HealthService,
+) -> Result<(), tonic::transport::Error> {
+    Server::builder()
+        .add_service(HealthServer::new(health))
+        .serve(addr)
+        .await
+}
@@ -0,0 +1,837 @@
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use log::Level;
+use rusty_v8 as v8;
+use sha2::{Digest, Sha256, Sha512};
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fmt::Write;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::collate::collate;
+use crate::determinism::Determinism;
+use crate::fetch::FetchRequest;
+
+const CLOCK_JS: &str = include_str!("../js/sandbox/clock.js");
+
+// Messages logged by JS beyond this are dropped for the current command
+const MAX_LOG_LINES: usize = 1000;
+
+thread_local! {
+    // Each worker thread runs one command at a time so captured log lines
+    // can be collected per thread and handed back with the command's result.
+    static CAPTURED_LOGS: RefCell<Vec<LogLine>> = RefCell::new(Vec::new());
+    // Same for emitted rows. Kept out of JS so user code can't touch them.
+    static EMITTED: RefCell<Emitted> = RefCell::new(Emitted::default());
+    // Set when the emit functions are installed. A worker thread only has
+    // the one isolate so the limits can't get mixed up between contexts.
+    static EMIT_LIMITS: Cell<EmitLimits> = Cell::new(EmitLimits::default());
+    // Set when the host functions are installed, like EMIT_LIMITS
+    static CLOCK: Cell<Clock> = Cell::new(Clock::Real);
+    // Where the Command clock stands for the current command
+    static COMMAND_STARTED: Cell<f64> = Cell::new(0.0);
+    // What requestId() gives during the current command
+    static REQUEST_ID: RefCell<Option<String>> = RefCell::new(None);
+}
+
+// Caps on what a single command, e.g. mapping one doc, can emit. Going over
+// any of them fails the command with an emit_limit_exceeded error.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EmitLimits {
+    pub max_rows: usize,
+    // Size of a row's [key, value] JSON
+    pub max_row_bytes: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for EmitLimits {
+    fn default() -> Self {
+        EmitLimits {
+            max_rows: 100_000,
+            max_row_bytes: 1024 * 1024,
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Emitted {
+    groups: Vec<Vec<String>>,
+    rows: usize,
+    bytes: usize,
+    exceeded: Option<String>,
+}
+
+impl Emitted {
+    // Returns why row can't be added, if it can't
+    fn add(&mut self, row: String, limits: &EmitLimits) -> Result<(), String> {
+        if let Some(reason) = &self.exceeded {
+            return Err(reason.clone());
+        }
+
+        let reason = if self.rows + 1 > limits.max_rows {
+            Some(format!("more than {} rows emitted", limits.max_rows))
+        } else if row.len() > limits.max_row_bytes {
+            Some(format!(
+                "emitted row of {} bytes is over the limit of {}",
+                row.len(),
+                limits.max_row_bytes
+            ))
+        } else if self.bytes + row.len() > limits.max_bytes {
+            Some(format!("more than {} bytes emitted", limits.max_bytes))
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            self.exceeded = Some(reason.clone());
+            return Err(reason);
+        }
+
+        self.rows += 1;
+        self.bytes += row.len();
+        if self.groups.is_empty() {
+            self.groups.push(Vec::new());
+        }
+        self.groups.last_mut().unwrap().push(row);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogLine {
+    pub level: Level,
+    pub message: String,
+}
+
+// Returns the lines logged by JS on this thread since the last call
+pub fn take_logs() -> Vec<LogLine> {
+    CAPTURED_LOGS.with(|logs| logs.replace(Vec::new()))
+}
+
+// Returns the rows emitted on this thread since the last call, grouped by
+// emitGroup(). Each row is the JSON of [key, value]. If an emit limit was hit
+// the rows are dropped and the reason is returned instead.
+pub fn take_emitted() -> Result<Vec<Vec<String>>, String> {
+    let emitted = EMITTED.with(|emitted| emitted.replace(Emitted::default()));
+    match emitted.exceeded {
+        Some(reason) => Err(reason),
+        None => Ok(emitted.groups),
+    }
+}
+
+// Starts a new group of emitted rows, like emitGroup() does from JS
+pub fn start_emit_group() {
+    EMITTED.with(|emitted| emitted.borrow_mut().groups.push(Vec::new()));
+}
+
+// Groups started on this thread since the last take_emitted
+pub fn emit_groups() -> usize {
+    EMITTED.with(|emitted| emitted.borrow().groups.len())
+}
+
+// Drops the groups after the first len along with their rows, e.g. those of
+// a doc whose map function threw partway through. A limit hit since the
+// counts were last reset is forgotten too, the rows that hit it are gone.
+pub fn truncate_emit_groups(len: usize) {
+    EMITTED.with(|emitted| {
+        let mut emitted = emitted.borrow_mut();
+        emitted.exceeded = None;
+        if len >= emitted.groups.len() {
+            return;
+        }
+        let dropped: Vec<Vec<String>> = emitted.groups.drain(len..).collect();
+        for row in dropped.iter().flatten() {
+            emitted.rows -= 1;
+            emitted.bytes -= row.len();
+        }
+    });
+}
+
+// Starts counting rows and bytes toward the emit limits from zero while
+// keeping the rows emitted so far, e.g. for the next doc of a batch
+pub fn reset_emit_counts() {
+    EMITTED.with(|emitted| {
+        let mut emitted = emitted.borrow_mut();
+        emitted.rows = 0;
+        emitted.bytes = 0;
+        emitted.exceeded = None;
+    });
+}
+
+// Why an emit limit was hit since the counts were last reset, if one was
+pub fn emit_exceeded() -> Option<String> {
+    EMITTED.with(|emitted| emitted.borrow().exceeded.clone())
+}
+
+// Host functions are implemented in Rust and installed into a context when
+// it is created. Which ones a context gets is decided by the Capabilities it
+// is created with so a tenant only sees the functions it was granted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Capability {
+    // digest(algorithm, data) -> hex string
+    Digest,
+    // tokenize(text) -> array of lowercased words
+    Tokenize,
+    // collate(a, b) -> -1, 0 or 1 using CouchDB view collation
+    Collate,
+    // Locale independent date helpers, always in UTC:
+    //   formatDate(epochMs, fmt) -> string using strftime style fmt
+    //   toISODate(epochMs) -> "2020-04-01T12:00:00.000Z"
+    //   parseISODate(string) -> epochMs
+    DateTime,
+    // log(msg) and console.log/info/warn/error/debug
+    Log,
+    // emit(key, value) collects a row in Rust for the current command and
+    // emitGroup() starts a new group of rows, e.g. one per map function
+    Emit,
+    // The WebAssembly global and WASM_CALL. Contexts without it have no
+    // WebAssembly at all.
+    Wasm,
+    // fetch(url, {method, headers, body}) -> {status, headers, body}, an
+    // HTTP request made synchronously. Unlike the rest it reaches outside
+    // the process so it's only granted when asked for.
+    Fetch,
+}
+
+const ALL_CAPABILITIES: &[Capability] = &[
+    Capability::Digest,
+    Capability::Tokenize,
+    Capability::Collate,
+    Capability::DateTime,
+    Capability::Log,
+    Capability::Emit,
+    Capability::Wasm,
+    Capability::Fetch,
+];
+
+impl Capability {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::Digest => "digest",
+            Capability::Tokenize => "tokenize",
+            Capability::Collate => "collate",
+            Capability::DateTime => "datetime",
+            Capability::Log => "log",
+            Capability::Emit => "emit",
+            Capability::Wasm => "wasm",
+            Capability::Fetch => "fetch",
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Capability {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        ALL_CAPABILITIES
+            .iter()
+            .find(|cap| cap.name() == name)
+            .copied()
+            .ok_or_else(|| format!("unknown capability {}", name))
+    }
+}
+
+// The time Date.now() and new Date() give in a context
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Clock {
+    // The host's
+    Real,
+    // Always this many milliseconds since the epoch, so functions that look
+    // at the time give the same answer on every run
+    Fixed(i64),
+    // Stands still while a command runs, at when it started, so e.g. all of
+    // a doc's map functions see the same time
+    Command,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock::Real
+    }
+}
+
+impl FromStr for Clock {
+    type Err = String;
+
+    // "real", "command", or a fixed time as milliseconds since the epoch or
+    // RFC 3339
+    fn from_str(clock: &str) -> Result<Self, Self::Err> {
+        match clock {
+            "real" => return Ok(Clock::Real),
+            "command" => return Ok(Clock::Command),
+            _ => (),
+        }
+        if let Ok(ms) = clock.parse::<i64>() {
+            return Ok(Clock::Fixed(ms));
+        }
+        DateTime::parse_from_rfc3339(clock)
+            .map(|at| Clock::Fixed(at.timestamp_millis()))
+            .map_err(|_| format!("invalid clock {}, expected real, command or a time", clock))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Capabilities {
+    granted: HashSet<Capability>,
+    emit_limits: EmitLimits,
+    clock: Clock,
+    determinism: Determinism,
+}
+
+impl Capabilities {
+    pub fn none() -> Capabilities {
+        Capabilities::default()
+    }
+
+    pub fn all() -> Capabilities {
+        Capabilities {
+            granted: ALL_CAPABILITIES.iter().copied().collect(),
+            ..Capabilities::default()
+        }
+    }
+
+    // Every capability but fetch, what the server grants unless told
+    // otherwise
+    pub fn local() -> Capabilities {
+        let mut caps = Capabilities::all();
+        caps.granted.remove(&Capability::Fetch);
+        caps
+    }
+
+    // Only used if emit is granted
+    pub fn with_emit_limits(mut self, limits: EmitLimits) -> Capabilities {
+        self.emit_limits = limits;
+        self
+    }
+
+    pub fn emit_limits(&self) -> EmitLimits {
+        self.emit_limits
+    }
+
+    // Not a capability as such, every context gets a Date
+    pub fn with_clock(mut self, clock: Clock) -> Capabilities {
+        self.clock = clock;
+        self
+    }
+
+    pub fn clock(&self) -> Clock {
+        self.clock
+    }
+
+    // What registering map functions and reducing do about functions that
+    // use nondeterministic APIs
+    pub fn with_determinism(mut self, determinism: Determinism) -> Capabilities {
+        self.determinism = determinism;
+        self
+    }
+
+    pub fn determinism(&self) -> Determinism {
+        self.determinism
+    }
+
+    pub fn grant(mut self, cap: Capability) -> Capabilities {
+        self.granted.insert(cap);
+        self
+    }
+
+    pub fn is_granted(&self, cap: Capability) -> bool {
+        self.granted.contains(&cap)
+    }
+
+    // Parses a comma separated list of capability names, e.g.
+    // "digest,collate"
+    pub fn parse(list: &str) -> Result<Capabilities, String> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .try_fold(Capabilities::none(), |caps, name| {
+                Ok(caps.grant(name.parse()?))
+            })
+    }
+}
+
+impl FromStr for Capabilities {
+    type Err = String;
+
+    fn from_str(list: &str) -> Result<Self, Self::Err> {
+        Capabilities::parse(list)
+    }
+}
+
+// Installs the granted host functions as globals in context
+pub fn install<'sc>(
+    scope: &mut impl v8::ToLocal<'sc>,
+    context: v8::Local<v8::Context>,
+    caps: &Capabilities,
+) {
+    let global = context.global(scope);
+    for cap in ALL_CAPABILITIES {
+        if !caps.is_granted(*cap) {
+            continue;
+        }
+
+        match cap {
+            Capability::Digest => set_function(scope, context, global, "digest", digest_callback),
+            Capability::Tokenize => {
+                set_function(scope, context, global, "tokenize", tokenize_callback)
+            }
+            Capability::Collate => {
+                set_function(scope, context, global, "collate", collate_callback)
+            }
+            Capability::DateTime => {
+                set_function(scope, context, global, "formatDate", format_date_callback);
+                set_function(scope, context, global, "toISODate", to_iso_date_callback);
+                set_function(
+                    scope,
+                    context,
+                    global,
+                    "parseISODate",
+                    parse_iso_date_callback,
+                );
+            }
+            Capability::Log => {
+                set_function(scope, context, global, "log", console_info_callback);
+
+                let console = v8::Object::new(scope);
+                set_function(scope, context, console, "log", console_info_callback);
+                set_function(scope, context, console, "info", console_info_callback);
+                set_function(scope, context, console, "warn", console_warn_callback);
+                set_function(scope, context, console, "error", console_error_callback);
+                set_function(scope, context, console, "debug", console_debug_callback);
+                let name = v8::String::new(scope, "console").unwrap();
+                global.set(context, name.into(), console.into());
+            }
+            Capability::Emit => {
+                EMIT_LIMITS.with(|limits| limits.set(caps.emit_limits));
+                set_function(scope, context, global, "emit", emit_callback);
+                set_function(scope, context, global, "emitGroup", emit_group_callback);
+            }
+            // It's already there, it's taken away below if not granted
+            Capability::Wasm => (),
+            Capability::Fetch => set_function(scope, context, global, "fetch", fetch_callback),
+        }
+    }
+
+    if !caps.is_granted(Capability::Wasm) {
+        let name = v8::String::new(scope, "WebAssembly").unwrap();
+        global.delete(context, name.into());
+    }
+
+    // Every context gets it, like Date
+    set_function(scope, context, global, "requestId", request_id_callback);
+
+    CLOCK.with(|clock| clock.set(caps.clock));
+    if caps.clock != Clock::Real {
+        start_command();
+        install_clock(scope, context);
+    }
+}
+
+// Replaces Date with one that takes the time from clock_callback
+fn install_clock<'sc>(scope: &mut impl v8::ToLocal<'sc>, context: v8::Local<v8::Context>) {
+    let source = v8::String::new(scope, CLOCK_JS).unwrap();
+    let shim = v8::Script::compile(scope, context, source, None)
+        .and_then(|mut script| script.run(scope, context))
+        .expect("js/sandbox/clock.js runs");
+    let shim = v8::Local::<v8::Function>::try_from(shim).unwrap();
+    let now = v8::Function::new(scope, context, clock_callback).unwrap();
+    let global = context.global(scope);
+    shim.call(scope, context, global.into(), &[global.into(), now.into()]);
+}
+
+// Called before each command, where the Command clock stops
+pub fn start_command() {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |since| since.as_millis() as f64);
+    COMMAND_STARTED.with(|started| started.set(now));
+}
+
+// Called before each command with the id of the request it's for, if any
+pub fn set_request_id(request_id: Option<String>) {
+    REQUEST_ID.with(|current| *current.borrow_mut() = request_id);
+}
+
+// requestId() -> the id of the request being run, or null outside of one,
+// to put in errors and logs
+fn request_id_callback(
+    scope: v8::FunctionCallbackScope,
+    _args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let value = REQUEST_ID.with(|current| match current.borrow().as_ref() {
+        Some(id) => v8::String::new(scope, id).unwrap().into(),
+        None => v8::null(scope).into(),
+    });
+    rv.set(value);
+}
+
+fn clock_callback(
+    scope: v8::FunctionCallbackScope,
+    _args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let now = match CLOCK.with(Cell::get) {
+        Clock::Fixed(ms) => ms as f64,
+        _ => COMMAND_STARTED.with(Cell::get),
+    };
+    rv.set(v8::Number::new(scope, now).into());
+}
+
+fn set_function<'sc>(
+    scope: &mut impl v8::ToLocal<'sc>,
+    context: v8::Local<v8::Context>,
+    obj: v8::Local<v8::Object>,
+    name: &str,
+    callback: impl v8::MapFnTo<v8::FunctionCallback>,
+) {
+    let name = v8::String::new(scope, name).unwrap();
+    let func = v8::Function::new(scope, context, callback).unwrap();
+    obj.set(context, name.into(), func.into());
+}
+
+fn throw_type_error<'sc>(scope: &mut impl v8::ToLocal<'sc>, message: &str) {
+    let message = v8::String::new(scope, message).unwrap();
+    let exception = v8::Exception::type_error(scope, message);
+    scope.isolate().throw_exception(exception);
+}
+
+fn throw_range_error<'sc>(scope: &mut impl v8::ToLocal<'sc>, message: &str) {
+    let message = v8::String::new(scope, message).unwrap();
+    let exception = v8::Exception::range_error(scope, message);
+    scope.isolate().throw_exception(exception);
+}
+
+fn arg_string<'sc>(
+    scope: &mut impl v8::ToLocal<'sc>,
+    args: &v8::FunctionCallbackArguments,
+    i: i32,
+) -> String {
+    args.get(i)
+        .to_string(scope)
+        .unwrap()
+        .to_rust_string_lossy(scope)
+}
+
+fn digest_callback(
+    scope: v8::FunctionCallbackScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    if args.length() != 2 {
+        throw_type_error(scope, "digest(algorithm, data) takes 2 arguments");
+        return;
+    }
+
+    let algorithm = arg_string(scope, &args, 0);
+    let data = arg_string(scope, &args, 1);
+    let hash = match algorithm.as_str() {
+        "sha256" => Sha256::digest(data.as_bytes()).to_vec(),
+        "sha512" => Sha512::digest(data.as_bytes()).to_vec(),
+        _ => {
+            throw_type_error(scope, "digest algorithm must be sha256 or sha512");
+            return;
+        }
+    };
+
+    let hex: String = hash.iter().map(|b| format!("{:02x}", b)).collect();
+    let result = v8::String::new(scope, hex.as_str()).unwrap();
+    rv.set(result.into());
+}
+
+fn tokenize_callback(
+    scope: v8::FunctionCallbackScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    if args.length() != 1 {
+        throw_type_error(scope, "tokenize(text) takes 1 argument");
+        return;
+    }
+
+    let context = scope.get_current_context().unwrap();
+    let text = arg_string(scope, &args, 0);
+    let tokens: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+
+    let array = v8::Array::new(scope, tokens.len() as i32);
+    for (i, token) in tokens.iter().enumerate() {
+        let index = v8::Integer::new(scope, i as i32);
+        let token = v8::String::new(scope, token).unwrap();
+        array.set(context, index.into(), token.into());
+    }
+    rv.set(array.into());
+}
+
+fn collate_callback(
+    scope: v8::FunctionCallbackScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    if args.length() != 2 {
+        throw_type_error(scope, "collate(a, b) takes 2 arguments");
+        return;
+    }
+
+    let context = scope.get_current_context().unwrap();
+    let mut values = Vec::with_capacity(2);
+    for i in 0..2 {
+        // undefined has no JSON representation so treat it as null
+        let json = match v8::json::stringify(context, args.get(i)) {
+            Some(json) => json.to_rust_string_lossy(scope),
+            None => "null".to_string(),
+        };
+        values.push(serde_json::from_str(json.as_str()).unwrap_or(serde_json::Value::Null));
+    }
+
+    let ord = collate(&values[0], &values[1]) as i32;
+    let result = v8::Integer::new(scope, ord);
+    rv.set(result.into());
+}
+
+// The range of a JS Date, +-100,000,000 days around the epoch
+const MAX_EPOCH_MS: f64 = 8.64e15;
+
+// Throws and returns None for anything that isn't a valid JS Date time.
+// Out of range times must not reach chrono, which panics on them.
+fn arg_date<'sc>(
+    scope: &mut impl v8::ToLocal<'sc>,
+    args: &v8::FunctionCallbackArguments,
+    i: i32,
+    name: &str,
+) -> Option<DateTime<Utc>> {
+    let epoch_ms = match args.get(i).number_value(scope) {
+        Some(epoch_ms) if epoch_ms.is_finite() => epoch_ms,
+        _ => {
+            throw_type_error(
+                scope,
+                format!("{} epochMs must be a finite number", name).as_str(),
+            );
+            return None;
+        }
+    };
+
+    let date = if epoch_ms.abs() <= MAX_EPOCH_MS {
+        Utc.timestamp_millis_opt(epoch_ms as i64).single()
+    } else {
+        None
+    };
+    if date.is_none() {
+        throw_range_error(scope, format!("{} epochMs is out of range", name).as_str());
+    }
+    date
+}
+
+fn format_date_callback(
+    scope: v8::FunctionCallbackScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    if args.length() != 2 {
+        throw_type_error(scope, "formatDate(epochMs, fmt) takes 2 arguments");
+        return;
+    }
+
+    let date = match arg_date(scope, &args, 0, "formatDate") {
+        Some(date) => date,
+        None => return,
+    };
+    let fmt = arg_string(scope, &args, 1);
+
+    // chrono reports unknown specifiers as a formatting error
+    let mut formatted = String::new();
+    if write!(formatted, "{}", date.format(fmt.as_str())).is_err() {
+        throw_type_error(scope, "formatDate fmt is not a valid format string");
+        return;
+    }
+
+    let result = v8::String::new(scope, formatted.as_str()).unwrap();
+    rv.set(result.into());
+}
+
+fn to_iso_date_callback(
+    scope: v8::FunctionCallbackScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let date = match arg_date(scope, &args, 0, "toISODate") {
+        Some(date) => date,
+        None => return,
+    };
+
+    let iso = date.to_rfc3339_opts(SecondsFormat::Millis, true);
+    let result = v8::String::new(scope, iso.as_str()).unwrap();
+    rv.set(result.into());
+}
+
+fn parse_iso_date_callback(
+    scope: v8::FunctionCallbackScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    if args.length() != 1 {
+        throw_type_error(scope, "parseISODate(string) takes 1 argument");
+        return;
+    }
+
+    let iso = arg_string(scope, &args, 0);
+    match DateTime::parse_from_rfc3339(iso.as_str()) {
+        Ok(date) => {
+            let result = v8::Number::new(scope, date.timestamp_millis() as f64);
+            rv.set(result.into());
+        }
+        Err(err) => throw_type_error(scope, format!("invalid ISO-8601 date: {}", err).as_str()),
+    }
+}
+
+// Strings are logged as is and everything else as JSON, like CouchDB's log()
+fn capture_log<'sc>(
+    scope: &mut impl v8::ToLocal<'sc>,
+    args: &v8::FunctionCallbackArguments,
+    level: Level,
+) {
+    let context = scope.get_current_context().unwrap();
+    let mut parts = Vec::with_capacity(args.length() as usize);
+    for i in 0..args.length() {
+        let arg = args.get(i);
+        let part = if arg.is_string() {
+            arg.to_string(scope).unwrap().to_rust_string_lossy(scope)
+        } else {
+            match v8::json::stringify(context, arg) {
+                Some(json) => json.to_rust_string_lossy(scope),
+                None => "undefined".to_string(),
+            }
+        };
+        parts.push(part);
+    }
+
+    CAPTURED_LOGS.with(|logs| {
+        let mut logs = logs.borrow_mut();
+        if logs.len() < MAX_LOG_LINES {
+            logs.push(LogLine {
+                level,
+                message: parts.join(" "),
+            });
+        }
+    });
+}
+
+fn console_debug_callback(
+    scope: v8::FunctionCallbackScope,
+    args: v8::FunctionCallbackArguments,
+    _rv: v8::ReturnValue,
+) {
+    capture_log(scope, &args, Level::Debug);
+}
+
+fn console_info_callback(
+    scope: v8::FunctionCallbackScope,
+    args: v8::FunctionCallbackArguments,
+    _rv: v8::ReturnValue,
+) {
+    capture_log(scope, &args, Level::Info);
+}
+
+fn console_warn_callback(
+    scope: v8::FunctionCallbackScope,
+    args: v8::FunctionCallbackArguments,
+    _rv: v8::ReturnValue,
+) {
+    capture_log(scope, &args, Level::Warn);
+}
+
+fn console_error_callback(
+    scope: v8::FunctionCallbackScope,
+    args: v8::FunctionCallbackArguments,
+    _rv: v8::ReturnValue,
+) {
+    capture_log(scope, &args, Level::Error);
+}
+
+fn fetch_callback(
+    scope: v8::FunctionCallbackScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    if args.length() < 1 || args.length() > 2 {
+        throw_type_error(scope, "fetch(url, init) takes 1 or 2 arguments");
+        return;
+    }
+
+    let context = scope.get_current_context().unwrap();
+    let url = arg_string(scope, &args, 0);
+    let init = match v8::json::stringify(context, args.get(1)) {
+        Some(json) => json.to_rust_string_lossy(scope),
+        None => "null".to_string(),
+    };
+    let init = serde_json::from_str(init.as_str()).unwrap_or(serde_json::Value::Null);
+    let request = match FetchRequest::new(url, &init) {
+        Ok(request) => request,
+        Err(message) => {
+            throw_type_error(scope, message.as_str());
+            return;
+        }
+    };
+
+    let response = match request.send() {
+        Ok(response) => serde_json::to_string(&response).unwrap(),
+        Err(message) => {
+            let message = v8::String::new(scope, message.as_str()).unwrap();
+            let exception = v8::Exception::error(scope, message);
+            scope.isolate().throw_exception(exception);
+            return;
+        }
+    };
+    let response = v8::String::new(scope, response.as_str()).unwrap();
+    if let Some(response) = v8::json::parse(context, response) {
+        rv.set(response);
+    }
+}
+
+fn emit_callback(
+    scope: v8::FunctionCallbackScope,
+    args: v8::FunctionCallbackArguments,
+    _rv: v8::ReturnValue,
+) {
+    let context = scope.get_current_context().unwrap();
+    let row = v8::Array::new(scope, 2);
+    for i in 0..2 {
+        let index = v8::Integer::new(scope, i);
+        row.set(context, index.into(), args.get(i));
+    }
+
+    // Rows that can't be serialized (e.g. cycles) have already thrown
+    let json = match v8::json::stringify(context, row.into()) {
+        Some(json) => json.to_rust_string_lossy(scope),
+        None => return,
+    };
+
+    let limits = EMIT_LIMITS.with(|limits| limits.get());
+    let added = EMITTED.with(|emitted| emitted.borrow_mut().add(json, &limits));
+
+    // Stops the map function. The harness may catch this but the command
+    // still fails once it's done.
+    if let Err(reason) = added {
+        let message = format!("emit_limit_exceeded: {}", reason);
+        throw_range_error(scope, message.as_str());
+    }
+}
+
+fn emit_group_callback(
+    _scope: v8::FunctionCallbackScope,
+    _args: v8::FunctionCallbackArguments,
+    _rv: v8::ReturnValue,
+) {
+    start_emit_group();
+}
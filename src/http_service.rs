@@ -4,68 +4,1069 @@ use hyper::service::Service;
 
 use hyper::{Body, Method, Request, Response, Server, StatusCode};
 
+use bytes::{Bytes, BytesMut};
+use chrono::Utc;
+use futures::stream::{FuturesOrdered, StreamExt};
 use futures_util::future;
 
-use ateles::{JsRequest, JsResponse};
-use hyper::server::conn::AddrIncoming;
+use crate::proto::ateles;
+use crate::proto::{
+    map_doc_requests, map_docs_batch, operation, wasm_module_hash, PROTOCOL_VERSION,
+};
+use ateles::js_request::{Action, EmitFormat, Priority, ResultEncoding};
+use ateles::js_response::ErrorType;
+use ateles::{
+    EmitGroup, ExecStats, JsRequest, JsResponse, LogMessage, MapDocsRequest, ServerInfo,
+    ServerLimits, ViewRow,
+};
+use hyper::body::Sender as BodySender;
+use hyper::header::{
+    HeaderName, HeaderValue, ACCEPT, ACCEPT_ENCODING, AUTHORIZATION, CONNECTION, CONTENT_ENCODING,
+    CONTENT_LENGTH, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE, VARY, WWW_AUTHENTICATE,
+};
+use hyper::server::accept;
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use hyper::server::Builder;
+use hyper::upgrade::OnUpgrade;
 use prost::Message;
+use serde_json::json;
+use std::fs;
+use std::io;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
 
-use crate::js_server::{create_js_env, Command, JSClient, Ops};
-use crate::JSEnv;
-use std::time::Instant;
+use crate::access_log::{AccessEntry, AccessLog, ExecDetails};
+use crate::admin::{self, AdminConfig};
+use crate::audit::AuditLog;
+use crate::auth;
+use crate::batching;
+use crate::builtins;
+use crate::checkpoint::{self, CheckpointConfig};
+use crate::collate::encode_key;
+use crate::compression::{self, Encoding};
+use crate::config;
+use crate::deadline;
+#[cfg(feature = "etf")]
+use crate::etf;
+use crate::framing::{encode_frame, FrameDecoder};
+use crate::harness::{self, Harness, Harnesses};
+use crate::health::{self, readiness, HealthService, ReadyLevel};
+use crate::host_functions::{Capabilities, EmitLimits};
+use crate::js_server::{self, create_js_env, worker_stats, workers, JSClient, Reply};
+use crate::listen::{self, Bind};
+use crate::metrics;
+use crate::mirror::Mirror;
+use crate::msgpack;
+use crate::pool::{Affinity, PoolConfig, WorkerPool};
+use crate::profile;
+use crate::quarantine;
+use crate::rate_limit::{RateLimitConfig, RateLimiter};
+use crate::request_id::{self, REQUEST_ID};
+use crate::result_cache::{ResultCache, ResultCacheConfig};
+use crate::sessions::Sessions;
+use crate::shadow::{Shadow, ShadowConn};
+use crate::slow_requests;
+use crate::threads;
+use crate::throttle;
+#[cfg(feature = "tls")]
+use crate::tls::{self, TlsConfig};
+use crate::trace::Tracer;
+use crate::transcode;
+use crate::v8_config;
+use crate::websocket;
+use crate::{bundle_manifest, snapshot_prebuilt, ErrorInfo, JSEnv, JSError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-pub mod ateles {
-    tonic::include_proto!("ateles"); // The string specified here must match the proto package name
+// How long an idle session is kept by default
+const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(300);
+
+// Largest /Ateles/Execute body accepted by default
+pub const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+// How long /stats waits for each worker to report its heap
+const STATS_TIMEOUT: Duration = Duration::from_millis(250);
+
+// How long after a profile ends a worker can take to hand it over, e.g.
+// because it's finishing a script, before it's left out
+const PROFILE_GRACE: Duration = Duration::from_secs(5);
+
+// How long a worker can take to start on a heap snapshot
+const HEAP_SNAPSHOT_WAIT: Duration = Duration::from_secs(30);
+
+// W3C trace context, continued by the spans of traced requests
+const TRACEPARENT: &str = "traceparent";
+// Asks /metrics for OpenMetrics rather than the Prometheus text format
+const OPENMETRICS: &str = "application/openmetrics-text";
+
+// The operator dashboard served at /admin/ui, built into the binary
+const ADMIN_UI: &str = include_str!("../ui/admin.html");
+
+// Features a request can list in requires
+pub const FEATURES: &[&str] = &[
+    "deadlines",
+    "design_docs",
+    "emit",
+    "emit_limits",
+    "exec_stats",
+    "harness",
+    "json_args",
+    "map_docs_batch",
+    "map_docs_stream",
+    "max_result_bytes",
+    "modules",
+    "msgpack",
+    "pipelining",
+    "priority",
+    "reduce",
+    "sessions",
+    "set_globals",
+    "view_kv",
+    "wasm",
+];
+
+// Features that depend on how the server was built
+const BUILD_FEATURES: &[&str] = &[
+    #[cfg(feature = "etf")]
+    "etf",
+    #[cfg(feature = "tls")]
+    "tls",
+];
+
+pub fn supports(feature: &str) -> bool {
+    FEATURES.contains(&feature) || BUILD_FEATURES.contains(&feature)
 }
 
-impl From<ateles::JsRequest> for Command {
-    fn from(js_request: JsRequest) -> Self {
-        let op = match js_request.action {
-            0 => Ops::REWRITE,
-            1 => Ops::EVAL,
-            2 => Ops::CALL,
-            _ => Ops::EXIT,
+// The result encoding asked for by an Accept header, e.g.
+// "application/x-erlang-binary" for ETF, if it names one the server
+// supports. The highest q wins and ties go to the first listed.
+pub fn accepted_result_encoding(accept: &str) -> Option<ResultEncoding> {
+    let mut best: Option<(f32, ResultEncoding)> = None;
+    for item in accept.split(',') {
+        let mut parts = item.split(';');
+        let media_type = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = parts
+            .find_map(|param| {
+                let param = param.trim();
+                param
+                    .strip_prefix("q=")
+                    .or_else(|| param.strip_prefix("Q="))
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+            })
+            .unwrap_or(1.0);
+        let encoding = match media_type.as_str() {
+            "application/json" => ResultEncoding::Json,
+            "application/x-erlang-binary" if supports("etf") => ResultEncoding::Etf,
+            "application/msgpack" | "application/x-msgpack" => ResultEncoding::Msgpack,
+            _ => continue,
         };
-        Command {
-            operation: op,
-            payload: js_request.script,
-            args: js_request.args,
+        if q > 0.0 && best.map_or(true, |(best_q, _)| q > best_q) {
+            best = Some((q, encoding));
+        }
+    }
+    best.map(|(_, encoding)| encoding)
+}
+
+// Requests that leave result_encoding at JSON get the Accept header's
+fn accept_encoding(result_encoding: &mut i32, accepted: Option<ResultEncoding>) {
+    if let Some(encoding) = accepted {
+        if *result_encoding == ResultEncoding::Json as i32 {
+            *result_encoding = encoding as i32;
+        }
+    }
+}
+
+// The design doc a request runs, by signature
+fn design_doc(js_request: &JsRequest) -> Option<&str> {
+    match Action::from_i32(js_request.action) {
+        Some(Action::RegisterDdoc)
+        | Some(Action::EvictDdoc)
+        | Some(Action::MapDoc)
+        | Some(Action::MapDocs)
+        | Some(Action::Filter) => Some(js_request.script.as_str()),
+        _ => None,
+    }
+}
+
+// Counts js_request against the rate limits, if there are any. peer is the
+// connection's address, whose port is ignored.
+fn rate_limit(
+    rate_limiter: &Option<Arc<RateLimiter>>,
+    peer: &str,
+    js_request: &JsRequest,
+) -> Result<(), JSError> {
+    let rate_limiter = match rate_limiter {
+        Some(rate_limiter) => rate_limiter,
+        None => return Ok(()),
+    };
+    let client_ip = match peer.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip().to_string(),
+        Err(_) => peer.to_string(),
+    };
+    let op = format!("{:?}", operation(js_request)).to_lowercase();
+    rate_limiter
+        .check(&client_ip, &op, design_doc(js_request))
+        .map_err(|reason| {
+            metrics::RATE_LIMITED_TOTAL.inc();
+            JSError::RateLimited(reason)
+        })
+}
+
+// Where a connection's requests are run
+#[derive(Clone)]
+pub(crate) enum Workers {
+    // A worker of the connection's own
+    Pinned(JSClient),
+    // The pool worker the connection keeps to, which other connections may
+    // have been given too. If it gives up the pool hands out another.
+    Assigned(Arc<WorkerPool>, JSClient),
+    Shared(Arc<WorkerPool>),
+    // Shared, but requests with a stream_key stay on one worker
+    Streams(Arc<WorkerPool>),
+}
+
+impl Workers {
+    pub(crate) fn client(&self) -> JSClient {
+        match self {
+            Workers::Assigned(pool, js_client) if js_client.gave_up() => pool.next(),
+            Workers::Pinned(js_client) | Workers::Assigned(_, js_client) => js_client.clone(),
+            Workers::Shared(pool) | Workers::Streams(pool) => pool.next(),
+        }
+    }
+
+    // Whether no other connection's requests run on the worker
+    fn is_pinned(&self) -> bool {
+        matches!(self, Workers::Pinned(_))
+    }
+
+    // The worker js_request should run on
+    fn client_for(&self, js_request: &JsRequest) -> JSClient {
+        match self {
+            Workers::Streams(pool) if !js_request.stream_key.is_empty() => {
+                pool.for_key(&js_request.stream_key)
+            }
+            _ => self.client(),
+        }
+    }
+}
+
+// Keep-alive statistics for one connection. Every in flight request holds a
+// reference so the stats are recorded once the connection has closed and its
+// last request has finished.
+struct ConnStats {
+    peer: String,
+    // Label for the connection metrics, ipv4, ipv6 or unix
+    family: &'static str,
+    opened: Instant,
+    requests: AtomicU64,
+}
+
+impl ConnStats {
+    fn new(peer: String, family: &'static str) -> ConnStats {
+        metrics::CONNECTIONS_TOTAL.inc();
+        metrics::CONNECTIONS_OPEN.inc();
+        log::debug!("connection from {}", peer);
+        ConnStats {
+            peer,
+            family,
+            opened: Instant::now(),
+            requests: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Drop for ConnStats {
+    fn drop(&mut self) {
+        let requests = self.requests.load(Ordering::Relaxed);
+        metrics::CONNECTIONS_OPEN.dec();
+        metrics::CONNECTION_REQUESTS.observe_value(self.family, requests as f64);
+        metrics::CONNECTION_SECONDS.observe(self.family, self.opened.elapsed());
+        log::debug!(
+            "connection from {} closed after {} requests",
+            self.peer,
+            requests
+        );
+    }
+}
+
+// Which routes a listener serves, once the admin endpoints can have one of
+// their own
+#[derive(Clone)]
+enum AdminAccess {
+    // Everything, without a token, as there's no admin listener
+    Open,
+    // Everything but the admin endpoints, which are on the admin listener
+    Hidden,
+    // The admin listener: everything, to anyone with the token
+    Token(Arc<String>),
+}
+
+impl AdminAccess {
+    // The response for req if it can't be served on this listener
+    fn refuse(&self, req: &Request<Body>) -> Option<Response<Body>> {
+        match self {
+            AdminAccess::Open => None,
+            AdminAccess::Hidden if admin::is_admin_path(req.uri().path()) => {
+                let mut not_found = Response::default();
+                *not_found.status_mut() = StatusCode::NOT_FOUND;
+                Some(not_found)
+            }
+            AdminAccess::Hidden => None,
+            AdminAccess::Token(token) => {
+                let access_token = query_param(req, "access_token");
+                if auth::authorized(
+                    header_str(req, AUTHORIZATION),
+                    access_token.as_deref(),
+                    token,
+                ) {
+                    return None;
+                }
+                let mut resp = admin_error(StatusCode::UNAUTHORIZED, "unauthorized");
+                resp.headers_mut()
+                    .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+                Some(resp)
+            }
         }
     }
 }
 
 #[derive(Clone)]
 pub struct Svc {
-    js_client: JSClient,
+    workers: Workers,
+    sessions: Arc<Sessions>,
+    harnesses: Arc<Harnesses>,
+    stats: Arc<ConnStats>,
+    shadow: Option<ShadowConn>,
+    mirror: Option<Arc<Mirror>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    result_cache: Option<Arc<ResultCache>>,
+    access_log: Option<Arc<AccessLog>>,
+    audit_log: Option<Arc<AuditLog>>,
+    tracer: Option<Arc<Tracer>>,
+    // Responses at least this big are compressed if the client accepts it
+    compress_min: Option<usize>,
+    // Largest request body accepted, before and after decompression
+    max_body: usize,
+    emit_limits: EmitLimits,
+    admin: AdminAccess,
+    // Bearer token the Execute endpoints need, if any
+    execute_token: Option<Arc<String>>,
 }
 
 impl Svc {
+    // Checked before the body is read so a process without the token can't
+    // get as far as a worker
+    fn execute_authorized(&self, req: &Request<Body>) -> bool {
+        let token = match &self.execute_token {
+            Some(token) if auth::is_execute_path(req.uri().path()) => token,
+            _ => return true,
+        };
+        let access_token = query_param(req, "access_token");
+        auth::authorized(
+            header_str(req, AUTHORIZATION),
+            access_token.as_deref(),
+            token,
+        )
+    }
+
     pub async fn handle_resp(
         &mut self,
         req: Request<Body>,
     ) -> Result<Response<Body>, hyper::Error> {
+        self.stats.requests.fetch_add(1, Ordering::Relaxed);
+        if let Some(refused) = self.admin.refuse(&req) {
+            return Ok(refused);
+        }
+        if !self.execute_authorized(&req) {
+            let mut resp = error_response(StatusCode::UNAUTHORIZED, JSError::Unauthorized);
+            resp.headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            return Ok(resp);
+        }
         match (req.method(), req.uri().path()) {
             (&Method::GET, "/") => Ok(Response::new(Body::from(
                 "HELLO Ateles on Rust with V8!!!!",
             ))),
-            (&Method::GET, "/Health") => Ok(Response::new(Body::from("OK"))),
+            // /Health is kept as an alias of /live for existing callers
+            (&Method::GET, "/live") | (&Method::GET, "/Health") => {
+                Ok(Response::new(Body::from("OK")))
+            }
+            // Degraded is a 429 so load balancers drain the server while
+            // it can still answer
+            (&Method::GET, "/ready") => {
+                let readiness = readiness(&self.workers.client()).await;
+                let signals = &readiness.signals;
+                let mut resp = json_response(json!({
+                    "status": readiness.level.as_str(),
+                    "reasons": readiness.reasons,
+                    "queue_depth": signals.queue_depth,
+                    "queue_wait_us": signals.queue_wait.as_micros() as u64,
+                    "workers": signals.workers,
+                    "available_workers": signals.available,
+                    "throttled": signals.throttled,
+                    "draining": signals.draining,
+                    "warming": signals.warming,
+                }));
+                *resp.status_mut() = match readiness.level {
+                    ReadyLevel::Ready => StatusCode::OK,
+                    ReadyLevel::Degraded => StatusCode::TOO_MANY_REQUESTS,
+                    ReadyLevel::NotReady => StatusCode::SERVICE_UNAVAILABLE,
+                };
+                Ok(resp)
+            }
+            (&Method::GET, "/metrics") => {
+                metrics::QUEUE_AGE_SECONDS.set(js_server::max_queue_age().as_secs_f64());
+                let openmetrics =
+                    header_str(&req, ACCEPT).map_or(false, |accept| accept.contains(OPENMETRICS));
+                if !openmetrics {
+                    return Ok(Response::new(Body::from(metrics::render())));
+                }
+                let mut resp = Response::new(Body::from(metrics::render_openmetrics()));
+                resp.headers_mut().insert(
+                    hyper::header::CONTENT_TYPE,
+                    HeaderValue::from_static(
+                        "application/openmetrics-text; version=1.0.0; charset=utf-8",
+                    ),
+                );
+                Ok(resp)
+            }
+            // Asks each worker for its heap statistics so this waits for
+            // them to finish what they're running, up to STATS_TIMEOUT
+            (&Method::GET, "/stats") => {
+                let workers: Vec<_> = worker_stats(STATS_TIMEOUT)
+                    .await
+                    .into_iter()
+                    .map(|worker| {
+                        let heap = worker.heap.map(|heap| {
+                            json!({
+                                "used": heap.used,
+                                "total": heap.total,
+                                "limit": heap.limit,
+                                "contexts": heap.contexts,
+                            })
+                        });
+                        json!({
+                            "id": worker.id,
+                            "thread": worker.thread,
+                            "os_thread_id": worker.os_thread_id,
+                            "requests": worker.requests,
+                            "errors": worker.errors,
+                            "cpu_us": worker.cpu.as_micros() as u64,
+                            "recycles": worker.recycles,
+                            "last_error": worker.last_error,
+                            "queue_depth": worker.queue_depth,
+                            "queue_age_ms": worker.queue_age.as_millis() as u64,
+                            "heap": heap,
+                        })
+                    })
+                    .collect();
+                Ok(json_response(json!({
+                    "security_mode": v8_config::security_mode().as_str(),
+                    "workers": workers,
+                })))
+            }
+            (&Method::GET, "/admin/workers") => {
+                let workers: Vec<_> = workers()
+                    .iter()
+                    .map(|worker| {
+                        let recent_gc: Vec<_> = worker
+                            .recent_gc
+                            .iter()
+                            .map(|event| {
+                                let finished_at = event
+                                    .finished_at
+                                    .duration_since(UNIX_EPOCH)
+                                    .unwrap_or_default();
+                                json!({
+                                    "kind": event.kind,
+                                    "pause_us": event.pause.as_micros() as u64,
+                                    "finished_at_ms": finished_at.as_millis() as u64,
+                                })
+                            })
+                            .collect();
+                        json!({
+                            "id": worker.id,
+                            "busy": worker.busy,
+                            "os_thread_id": worker.os_thread_id,
+                            "recent_gc": recent_gc,
+                        })
+                    })
+                    .collect();
+                Ok(json_response(json!({ "workers": workers })))
+            }
+            (&Method::POST, path)
+                if path.starts_with("/admin/workers/") && path.ends_with("/recycle") =>
+            {
+                let id = path
+                    .trim_start_matches("/admin/workers/")
+                    .trim_end_matches("/recycle")
+                    .parse();
+                match id {
+                    Ok(id) if js_server::recycle_worker(id) => {
+                        Ok(json_response(json!({ "ok": true })))
+                    }
+                    _ => {
+                        let mut not_found = json_response(json!({ "error": "not_found" }));
+                        *not_found.status_mut() = StatusCode::NOT_FOUND;
+                        Ok(not_found)
+                    }
+                }
+            }
+            (&Method::GET, "/admin/requests") => {
+                let requests: Vec<_> = js_server::jobs()
+                    .into_iter()
+                    .map(|job| {
+                        json!({
+                            "id": job.id,
+                            "op": job.op,
+                            "request_id": job.request_id,
+                            "worker": job.worker,
+                            "age_us": job.age.as_micros() as u64,
+                            "state": if job.running { "running" } else { "queued" },
+                        })
+                    })
+                    .collect();
+                Ok(json_response(json!({ "requests": requests })))
+            }
+            (&Method::POST, path)
+                if path.starts_with("/admin/requests/") && path.ends_with("/kill") =>
+            {
+                let id = path
+                    .trim_start_matches("/admin/requests/")
+                    .trim_end_matches("/kill")
+                    .parse();
+                match id {
+                    Ok(id) if js_server::kill_job(id) => {
+                        log::warn!("request {} killed through the admin API", id);
+                        Ok(json_response(json!({ "ok": true })))
+                    }
+                    _ => {
+                        let mut not_found = json_response(json!({ "error": "not_found" }));
+                        *not_found.status_mut() = StatusCode::NOT_FOUND;
+                        Ok(not_found)
+                    }
+                }
+            }
+            (&Method::POST, "/admin/drain") => {
+                health::set_draining(true);
+                Ok(json_response(json!({ "ok": true })))
+            }
+            (&Method::DELETE, "/admin/drain") => {
+                health::set_draining(false);
+                Ok(json_response(json!({ "ok": true })))
+            }
+            (&Method::GET, "/admin/threads") => {
+                let threads: Vec<_> = threads::list()
+                    .into_iter()
+                    .map(|thread| json!({ "os_thread_id": thread.os_id, "name": thread.name }))
+                    .collect();
+                Ok(json_response(json!({ "threads": threads })))
+            }
+            (&Method::GET, "/admin/slow") => {
+                let threshold = slow_requests::threshold();
+                Ok(json_response(json!({
+                    "threshold_us": threshold.as_micros() as u64,
+                    "requests": slow_requests::recent(),
+                })))
+            }
+            (&Method::GET, "/admin/ui") => {
+                let mut resp = Response::new(Body::from(ADMIN_UI));
+                resp.headers_mut().insert(
+                    hyper::header::CONTENT_TYPE,
+                    hyper::header::HeaderValue::from_static("text/html; charset=utf-8"),
+                );
+                Ok(resp)
+            }
+            (&Method::GET, "/admin/bundle") => {
+                let files: Vec<_> = bundle_manifest()
+                    .iter()
+                    .map(|file| {
+                        json!({
+                            "name": file.name,
+                            "bytes": file.bytes,
+                            "sha256": file.sha256,
+                        })
+                    })
+                    .collect();
+                Ok(json_response(json!({ "files": files })))
+            }
+            (&Method::GET, "/admin/quarantine") => {
+                let scripts: Vec<_> = quarantine::quarantined()
+                    .into_iter()
+                    .map(|(hash, crashes)| json!({ "hash": hash, "crashes": crashes }))
+                    .collect();
+                Ok(json_response(json!({ "scripts": scripts })))
+            }
+            (&Method::DELETE, "/admin/quarantine") => {
+                quarantine::clear_all();
+                Ok(json_response(json!({ "ok": true })))
+            }
+            (&Method::DELETE, path) if path.starts_with("/admin/quarantine/") => {
+                let hash = path.trim_start_matches("/admin/quarantine/");
+                if quarantine::clear(hash) {
+                    Ok(json_response(json!({ "ok": true })))
+                } else {
+                    let mut not_found = json_response(json!({ "error": "not_found" }));
+                    *not_found.status_mut() = StatusCode::NOT_FOUND;
+                    Ok(not_found)
+                }
+            }
+            (&Method::GET, "/admin/v8") => {
+                let heap = v8_config::current();
+                let max_heap = v8_config::max_heap();
+                let warning = match (max_heap, &heap) {
+                    (Some(limit), Some(config)) => v8_config::check_heap_limit(limit, config),
+                    _ => None,
+                };
+                Ok(json_response(json!({
+                    "heap": heap,
+                    "max_heap": max_heap,
+                    "warning": warning,
+                    "snapshot_prebuilt": snapshot_prebuilt(),
+                    "flags": v8_config::flags(),
+                })))
+            }
+            // For hand-crafting and inspecting frames: decode takes a
+            // JsRequest and returns it as JSON, encode the other way round
+            (&Method::POST, "/admin/decode") => {
+                let body = match read_body(req, self.max_body).await? {
+                    Ok(body) => body,
+                    Err(too_large) => return Ok(too_large),
+                };
+                match JsRequest::decode(body) {
+                    Ok(js_request) => Ok(json_response(transcode::request_to_json(&js_request))),
+                    Err(err) => {
+                        let reason = format!("can't decode JSRequest: {}", err);
+                        Ok(invalid_request(StatusCode::BAD_REQUEST, reason))
+                    }
+                }
+            }
+            (&Method::POST, "/admin/encode") => {
+                let body = match read_body(req, self.max_body).await? {
+                    Ok(body) => body,
+                    Err(too_large) => return Ok(too_large),
+                };
+                let js_request = serde_json::from_slice(&body)
+                    .map_err(|err| err.to_string())
+                    .and_then(|value| transcode::request_from_json(&value));
+                match js_request {
+                    Ok(js_request) => {
+                        let mut encoded = Vec::new();
+                        js_request.encode(&mut encoded).unwrap();
+                        Ok(Response::new(Body::from(encoded)))
+                    }
+                    Err(reason) => Ok(invalid_request(StatusCode::BAD_REQUEST, reason)),
+                }
+            }
+            // Runs V8's CPU profiler on the workers for duration, or on
+            // only one of them with worker=<id>, and returns the .cpuprofile
+            (&Method::GET, "/admin/profile") => {
+                let param = |name| query_param(&req, name);
+                let duration = param("duration")
+                    .map_or(Ok(Duration::from_secs(10)), |d| profile::parse_duration(&d));
+                let interval = param("interval").map_or(Ok(profile::DEFAULT_INTERVAL), |i| {
+                    profile::parse_duration(&i)
+                });
+                let worker = match param("worker").map(|id| id.parse::<usize>()) {
+                    Some(Ok(id)) => Ok(Some(id)),
+                    Some(Err(err)) => Err(format!("invalid worker: {}", err)),
+                    None => Ok(None),
+                };
+                let (duration, interval, only) = match (duration, interval, worker) {
+                    (Ok(duration), Ok(interval), Ok(only))
+                        if duration <= profile::MAX_DURATION
+                            && interval > Duration::from_micros(0) =>
+                    {
+                        (duration, interval, only)
+                    }
+                    (Err(reason), _, _) | (_, Err(reason), _) | (_, _, Err(reason)) => {
+                        return Ok(admin_error(StatusCode::BAD_REQUEST, &reason));
+                    }
+                    _ => {
+                        let reason = format!(
+                            "duration can be at most {}s and interval can't be 0",
+                            profile::MAX_DURATION.as_secs()
+                        );
+                        return Ok(admin_error(StatusCode::BAD_REQUEST, &reason));
+                    }
+                };
+                let _running = match profile::try_start() {
+                    Some(running) => running,
+                    None => return Ok(admin_error(StatusCode::CONFLICT, "already_profiling")),
+                };
+                let profiled =
+                    js_server::profile_workers(duration, interval, PROFILE_GRACE, only).await;
+                let mut profiles = Vec::new();
+                for (id, profile) in profiled {
+                    let profile = profile.and_then(|profile| {
+                        serde_json::from_str::<serde_json::Value>(&profile)
+                            .map_err(|err| err.to_string())
+                    });
+                    match profile {
+                        Ok(profile) => profiles.push((id, profile)),
+                        Err(reason) if only.is_some() => {
+                            return Ok(admin_error(StatusCode::SERVICE_UNAVAILABLE, &reason));
+                        }
+                        Err(reason) => {
+                            log::warn!("worker {} left out of the profile: {}", id, reason);
+                        }
+                    }
+                }
+                match (only, profiles.pop()) {
+                    (Some(_), Some((_, profile))) => Ok(json_response(profile)),
+                    (Some(_), None) => Ok(admin_error(StatusCode::NOT_FOUND, "not_found")),
+                    (None, last) => {
+                        profiles.extend(last);
+                        Ok(json_response(profile::merge(profiles)))
+                    }
+                }
+            }
+            // Streams a .heapsnapshot of worker=<id>'s isolate once it's done
+            // with what it's running
+            (&Method::GET, "/admin/heap_snapshot") => {
+                let id = match query_param(&req, "worker").map(|id| id.parse::<usize>()) {
+                    Some(Ok(id)) => id,
+                    _ => {
+                        return Ok(admin_error(
+                            StatusCode::BAD_REQUEST,
+                            "worker=<id> is needed",
+                        ))
+                    }
+                };
+                let mut chunks = match js_server::heap_snapshot(id) {
+                    Some(chunks) => chunks,
+                    None => return Ok(admin_error(StatusCode::NOT_FOUND, "not_found")),
+                };
+                let first = match tokio::time::timeout(HEAP_SNAPSHOT_WAIT, chunks.next()).await {
+                    Ok(Some(Ok(chunk))) => chunk,
+                    Ok(Some(Err(reason))) => {
+                        return Ok(admin_error(StatusCode::SERVICE_UNAVAILABLE, &reason));
+                    }
+                    Ok(None) => {
+                        let reason = "the worker exited";
+                        return Ok(admin_error(StatusCode::SERVICE_UNAVAILABLE, reason));
+                    }
+                    Err(_) => {
+                        let reason = "the worker is busy";
+                        return Ok(admin_error(StatusCode::SERVICE_UNAVAILABLE, reason));
+                    }
+                };
+                let (mut sender, body) = Body::channel();
+                tokio::spawn(async move {
+                    if sender.send_data(Bytes::from(first)).await.is_err() {
+                        return;
+                    }
+                    while let Some(chunk) = chunks.next().await {
+                        let chunk = match chunk {
+                            Ok(chunk) => chunk,
+                            // Cut short so it doesn't pass for a whole one
+                            Err(reason) => {
+                                log::warn!("heap snapshot of worker {} failed: {}", id, reason);
+                                sender.abort();
+                                return;
+                            }
+                        };
+                        if sender.send_data(Bytes::from(chunk)).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+                let mut resp = Response::new(body);
+                let headers = resp.headers_mut();
+                headers.insert(
+                    hyper::header::CONTENT_TYPE,
+                    hyper::header::HeaderValue::from_static("application/json"),
+                );
+                let disposition = format!("attachment; filename=\"worker-{}.heapsnapshot\"", id);
+                if let Ok(disposition) = hyper::header::HeaderValue::from_str(&disposition) {
+                    headers.insert(hyper::header::CONTENT_DISPOSITION, disposition);
+                }
+                Ok(resp)
+            }
+            (&Method::GET, "/admin/harnesses") => {
+                let mut harnesses: Vec<_> = self
+                    .harnesses
+                    .values()
+                    .map(|harness| {
+                        json!({
+                            "name": harness.name(),
+                            "workers": harness.workers(),
+                            "snapshot_bytes": harness.js_env().startup_data.len(),
+                        })
+                    })
+                    .collect();
+                harnesses.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+                Ok(json_response(json!({ "harnesses": harnesses })))
+            }
+            // A ServerInfo, whatever the body
+            (&Method::GET, "/Ateles/GetServerInfo") | (&Method::POST, "/Ateles/GetServerInfo") => {
+                let mut body = Vec::new();
+                self.server_info().encode(&mut body).unwrap();
+                Ok(Response::new(Body::from(body)))
+            }
+            (&Method::GET, "/admin/features") => {
+                let features: Vec<_> = FEATURES.iter().chain(BUILD_FEATURES).collect();
+                Ok(json_response(json!({ "features": features })))
+            }
+            // Execute for browsers: a websocket of JSON requests, see
+            // websocket_session
+            (&Method::GET, "/ws") => {
+                let upgrade = header_str(&req, UPGRADE).unwrap_or("");
+                let key = match header_str(&req, SEC_WEBSOCKET_KEY) {
+                    Some(key) if upgrade.eq_ignore_ascii_case("websocket") => key.to_string(),
+                    _ => {
+                        let reason = "expected a websocket upgrade".to_string();
+                        return Ok(invalid_request(StatusCode::BAD_REQUEST, reason));
+                    }
+                };
+                let session = websocket_session(
+                    req.into_body().on_upgrade(),
+                    self.workers.clone(),
+                    self.sessions.clone(),
+                    self.harnesses.clone(),
+                    self.rate_limiter.clone(),
+                    self.audit_log.clone(),
+                    self.stats.peer.clone(),
+                    self.max_body,
+                );
+                tokio::spawn(session);
+                let mut resp = Response::new(Body::empty());
+                *resp.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+                let headers = resp.headers_mut();
+                headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+                headers.insert(CONNECTION, HeaderValue::from_static("Upgrade"));
+                // Always valid as it's base64
+                let accept = HeaderValue::from_str(&websocket::accept_key(&key)).unwrap();
+                headers.insert(SEC_WEBSOCKET_ACCEPT, accept);
+                Ok(resp)
+            }
+            // Any number of length-prefixed JsRequest frames in, the same
+            // number of length-prefixed JsResponse frames out in order. The
+            // whole stream runs on one worker.
+            (&Method::POST, "/Ateles/ExecutePipelined") => {
+                let (sender, body) = Body::channel();
+                let js_client = self.workers.client();
+                let pinned = self.workers.is_pinned();
+                let sessions = self.sessions.clone();
+                let harnesses = self.harnesses.clone();
+                let deadline = match deadline::from_headers(req.headers()) {
+                    Ok(deadline) => deadline,
+                    Err(reason) => return Ok(invalid_request(StatusCode::BAD_REQUEST, reason)),
+                };
+                let accepted = header_str(&req, ACCEPT).and_then(accepted_result_encoding);
+                tokio::spawn(execute_pipelined(
+                    js_client,
+                    pinned,
+                    deadline,
+                    sessions,
+                    harnesses,
+                    self.rate_limiter.clone(),
+                    self.audit_log.clone(),
+                    self.stats.peer.clone(),
+                    accepted,
+                    self.max_body,
+                    req.into_body(),
+                    sender,
+                ));
+                Ok(Response::new(body))
+            }
+            // A MapDocsRequest in, a length-prefixed JsResponse frame out
+            // per doc as soon as it's mapped, so neither side has to hold
+            // the rows of the whole batch
+            (&Method::POST, "/Ateles/MapDocs") => {
+                let deadline = match deadline::from_headers(req.headers()) {
+                    Ok(deadline) => deadline,
+                    Err(reason) => return Ok(invalid_request(StatusCode::BAD_REQUEST, reason)),
+                };
+                let accepted = header_str(&req, ACCEPT).and_then(accepted_result_encoding);
+                let full_body = match read_body(req, self.max_body).await? {
+                    Ok(body) => body,
+                    Err(too_large) => return Ok(too_large),
+                };
+                let mut map_docs = match MapDocsRequest::decode(full_body) {
+                    Ok(map_docs) => map_docs,
+                    Err(err) => {
+                        let reason = format!("can't decode MapDocsRequest: {}", err);
+                        return Ok(invalid_request(StatusCode::BAD_REQUEST, reason));
+                    }
+                };
+                accept_encoding(&mut map_docs.result_encoding, accepted);
+                let mapper = self.mapper(deadline);
+                let (mut sender, body) = Body::channel();
+                tokio::spawn(async move {
+                    mapper.map(map_docs, &mut sender).await;
+                });
+                Ok(Response::new(body))
+            }
+            // The same as MapDocs but with a stream of length-prefixed
+            // MapDocsRequest frames in, mapped one at a time as they're
+            // decoded. The first frame sets the signature and options, later
+            // ones only add docs, so a batch too big to buffer only has to
+            // fit max_body a frame at a time.
+            (&Method::POST, "/Ateles/MapDocsStream") => {
+                let deadline = match deadline::from_headers(req.headers()) {
+                    Ok(deadline) => deadline,
+                    Err(reason) => return Ok(invalid_request(StatusCode::BAD_REQUEST, reason)),
+                };
+                let accepted = header_str(&req, ACCEPT).and_then(accepted_result_encoding);
+                let (sender, body) = Body::channel();
+                tokio::spawn(map_docs_stream(
+                    self.mapper(deadline),
+                    accepted,
+                    self.max_body,
+                    req.into_body(),
+                    sender,
+                ));
+                Ok(Response::new(body))
+            }
             (&Method::POST, "/Ateles/Execute") => {
                 let start = Instant::now();
+                let deadline = match deadline::from_headers(req.headers()) {
+                    Ok(deadline) => deadline,
+                    Err(reason) => return Ok(invalid_request(StatusCode::BAD_REQUEST, reason)),
+                };
+                let trace = self.tracer.as_ref().and_then(|tracer| {
+                    tracer.trace(header_str(&req, HeaderName::from_static(TRACEPARENT)))
+                });
 
-                let full_body = hyper::body::to_bytes(req.into_body()).await?;
-                let js_request = JsRequest::decode(full_body).unwrap();
-                let cmd: Command = js_request.clone().into();
-                let resp = self.js_client.run(js_request.into());
-                let js_resp = JsResponse {
-                    status: 0,
-                    result: resp,
+                let accept = header_str(&req, ACCEPT_ENCODING).and_then(compression::negotiate);
+                let accepted = header_str(&req, ACCEPT).and_then(accepted_result_encoding);
+                let header_request_id = header_str(&req, HeaderName::from_static(REQUEST_ID))
+                    .unwrap_or("")
+                    .to_string();
+                let content_encoding = header_str(&req, CONTENT_ENCODING).unwrap_or("").to_string();
+                let full_body = match read_body(req, self.max_body).await? {
+                    Ok(body) => body,
+                    Err(too_large) => return Ok(too_large),
+                };
+                let body_bytes = full_body.len() as u64;
+                // Most bodies aren't compressed and are decoded straight
+                // from the buffer they arrived in
+                let full_body = if compression::is_identity(&content_encoding) {
+                    full_body
+                } else {
+                    match compression::decompress(&content_encoding, &full_body, self.max_body) {
+                        Ok(body) => Bytes::from(body),
+                        Err(reason) => return Ok(invalid_request(StatusCode::BAD_REQUEST, reason)),
+                    }
+                };
+                let mut js_request = match JsRequest::decode(full_body) {
+                    Ok(js_request) => js_request,
+                    Err(err) => {
+                        let reason = format!("can't decode JSRequest: {}", err);
+                        return Ok(invalid_request(StatusCode::BAD_REQUEST, reason));
+                    }
                 };
+                if let Err(err) = unpack_msgpack_args(&mut js_request) {
+                    return Ok(error_response(StatusCode::BAD_REQUEST, err));
+                }
+                accept_encoding(&mut js_request.result_encoding, accepted);
+                if js_request.request_id.is_empty() {
+                    js_request.request_id = header_request_id;
+                }
+                js_request.request_id = request_id::or_generate(&js_request.request_id);
+                let request_id = js_request.request_id.clone();
+                let operation = operation(&js_request);
+                if let Err(err) = rate_limit(&self.rate_limiter, &self.stats.peer, &js_request) {
+                    return Ok(error_response(StatusCode::TOO_MANY_REQUESTS, err));
+                }
+                let shadow_request = self.shadow.as_ref().map(|_| js_request.clone());
+                let mirror_request = match &self.mirror {
+                    Some(mirror) if mirror.sampled() => Some(js_request.clone()),
+                    _ => None,
+                };
+                let (js_client, session_created) = match client_for(
+                    &self.workers.client_for(&js_request),
+                    self.workers.is_pinned(),
+                    &self.sessions,
+                    &self.harnesses,
+                    &js_request,
+                ) {
+                    Ok(client) => client,
+                    Err(err) => return Ok(error_response(StatusCode::BAD_REQUEST, err)),
+                };
+                let op = format!("{:?}", operation).to_lowercase();
+                let cache_key = self.result_cache.as_ref().and_then(|cache| {
+                    cache.key(js_client.id(), js_client.generation(), &op, &js_request)
+                });
+                let cached = match (&self.result_cache, &cache_key) {
+                    (Some(cache), Some(key)) => cache.get(key),
+                    _ => None,
+                };
+                let audit = self
+                    .audit_log
+                    .as_ref()
+                    .and_then(|audit_log| audit_log.begin(&self.stats.peer, &op, &js_request));
+                let sent = SystemTime::now();
+                let mut js_resp = match cached {
+                    Some(js_resp) => js_resp,
+                    None => {
+                        let js_resp = execute(&js_client, js_request, deadline).await;
+                        if let (Some(cache), Some(key)) = (&self.result_cache, cache_key) {
+                            cache.put(key, &op, &js_resp);
+                        }
+                        js_resp
+                    }
+                };
+                js_resp.session_created = session_created;
+                // A cached response has the id of the request that filled it
+                js_resp.request_id = request_id.clone();
+                if let (Some(audit_log), Some(entry)) = (&self.audit_log, audit) {
+                    audit_log.finish(entry, &js_resp);
+                }
+                if let (Some(shadow), Some(request)) = (&self.shadow, shadow_request) {
+                    shadow.send(request, &js_resp, start.elapsed());
+                }
+                if let (Some(mirror), Some(request)) = (&self.mirror, mirror_request) {
+                    mirror.record(&request, &js_resp, start.elapsed());
+                }
 
                 let mut resp: Vec<u8> = Vec::new();
                 js_resp.encode(&mut resp).unwrap();
-                println!("request {:?} took {:?}", cmd.operation, start.elapsed());
-                Ok(Response::new(Body::from(resp)))
+                log::debug!(
+                    "request {} {:?} from {} took {:?}",
+                    request_id,
+                    operation,
+                    self.stats.peer,
+                    start.elapsed()
+                );
+                let error = if js_resp.status == 0 {
+                    None
+                } else {
+                    Some(js_resp.result.clone())
+                };
+                let stats = js_resp.stats.clone().unwrap_or_default();
+                if let (Some(tracer), Some(trace)) = (&self.tracer, trace) {
+                    tracer.record(trace.finish(
+                        &op,
+                        sent,
+                        Duration::from_micros(stats.queue_wait_us.max(0) as u64),
+                        Duration::from_micros(stats.execution_us.max(0) as u64),
+                        error.clone(),
+                    ));
+                }
+                slow_requests::record(&op, &self.stats.peer, start.elapsed(), error);
+                let details = ExecDetails {
+                    op,
+                    request_id: request_id.clone(),
+                    bytes: body_bytes,
+                    queue_wait_us: stats.queue_wait_us.max(0) as u64,
+                    execution_us: stats.execution_us.max(0) as u64,
+                };
+                let mut resp = match (self.compress_min, accept) {
+                    (Some(min), Some(encoding)) if resp.len() >= min => {
+                        compressed_response(encoding, &resp)
+                    }
+                    _ => Response::new(Body::from(resp)),
+                };
+                if let Ok(value) = HeaderValue::from_str(&request_id) {
+                    resp.headers_mut()
+                        .insert(HeaderName::from_static(REQUEST_ID), value);
+                }
+                resp.extensions_mut().insert(details);
+                Ok(resp)
             }
             _ => {
                 let mut not_found = Response::default();
@@ -74,6 +1075,1158 @@ impl Svc {
             }
         }
     }
+
+    fn mapper(&self, deadline: Option<Instant>) -> Mapper {
+        Mapper {
+            workers: self.workers.clone(),
+            deadline,
+            sessions: self.sessions.clone(),
+            harnesses: self.harnesses.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            peer: self.stats.peer.clone(),
+        }
+    }
+
+    fn server_info(&self) -> ServerInfo {
+        let mut harnesses: Vec<String> = self.harnesses.keys().cloned().collect();
+        harnesses.sort();
+        ServerInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            actions: transcode::action_names()
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            features: FEATURES
+                .iter()
+                .chain(BUILD_FEATURES)
+                .map(|feature| feature.to_string())
+                .collect(),
+            limits: Some(ServerLimits {
+                max_body_bytes: self.max_body as i64,
+                default_timeout: config::default_timeout()
+                    .map_or(0, |timeout| timeout.as_millis() as i32),
+                max_result_bytes: config::max_result_bytes() as i64,
+                max_queue_depth: config::max_queue_depth() as i32,
+                max_emitted_rows: self.emit_limits.max_rows as i64,
+                max_emitted_row_bytes: self.emit_limits.max_row_bytes as i64,
+                max_emitted_bytes: self.emit_limits.max_bytes as i64,
+            }),
+            harnesses,
+        }
+    }
+}
+
+// deadline is when the caller stops waiting, see deadline::from_headers
+pub(crate) async fn execute(
+    js_client: &JSClient,
+    js_request: JsRequest,
+    deadline: Option<Instant>,
+) -> JsResponse {
+    execute_with(js_client, js_request, deadline, true).await
+}
+
+// Crashes on shadow workers say nothing about how the script does on the
+// primary ones so they aren't held against it
+pub(crate) async fn execute_shadow(js_client: &JSClient, js_request: JsRequest) -> JsResponse {
+    execute_with(js_client, js_request, None, false).await
+}
+
+async fn execute_with(
+    js_client: &JSClient,
+    mut js_request: JsRequest,
+    deadline: Option<Instant>,
+    record_crashes: bool,
+) -> JsResponse {
+    js_request.request_id = request_id::or_generate(&js_request.request_id);
+    let request_id = js_request.request_id.clone();
+    let js_client = js_client.for_request(&request_id);
+    let mut js_resp = run_request(&js_client, js_request, deadline, record_crashes).await;
+    js_resp.request_id = request_id;
+    js_resp
+}
+
+async fn run_request(
+    js_client: &JSClient,
+    js_request: JsRequest,
+    deadline: Option<Instant>,
+    record_crashes: bool,
+) -> JsResponse {
+    if let Some(missing) = js_request
+        .requires
+        .iter()
+        .find(|feature| !supports(feature))
+    {
+        return JSError::UnsupportedFeature(missing.clone()).into();
+    }
+    if Action::from_i32(js_request.action).is_none() {
+        let reason = format!("unknown action {}", js_request.action);
+        return JSError::InvalidRequest(reason).into();
+    }
+    let features = js_request.requires.clone();
+
+    let encoding =
+        ResultEncoding::from_i32(js_request.result_encoding).unwrap_or(ResultEncoding::Json);
+    if encoding == ResultEncoding::Etf && !supports("etf") {
+        return JSError::UnsupportedFeature("etf".to_string()).into();
+    }
+
+    // Shadows would count every use twice
+    let checkpointed = if record_crashes && checkpoint::is_tracking() {
+        checkpointed(&js_request)
+    } else {
+        None
+    };
+
+    let script_hash = quarantine_hash(&js_request);
+    if quarantine::is_quarantined(&script_hash) {
+        return JSError::Quarantined(script_hash).into();
+    }
+
+    let max_queue_depth = config::max_queue_depth();
+    if max_queue_depth > 0 && js_client.queue_depth() >= max_queue_depth {
+        return JSError::QueueFull.into();
+    }
+
+    let background = js_request.priority == Priority::Background as i32;
+    if let Some(max_age) = config::shed_queue_age().filter(|_| background) {
+        let age = js_client.queue_age();
+        if age > max_age {
+            metrics::SHED_COMMANDS_TOTAL.inc();
+            let reason = format!("commands have been queued for {:?}", age);
+            return JSError::Overloaded(reason).into();
+        }
+    }
+
+    throttle::admit(background).await;
+
+    let batch_size = js_request.batch_size.max(0) as u32;
+    let view_kv = js_request.emit_format == EmitFormat::ViewKv as i32;
+    let doc_id = js_request.doc_id.clone();
+    let max_result_bytes = result_limit(js_request.max_result_bytes);
+    let timeout = if js_request.timeout > 0 {
+        Some(Duration::from_millis(js_request.timeout as u64))
+    } else {
+        config::default_timeout()
+    };
+    // A timeout that ends before the deadline still fails with TIMEOUT
+    let deadline = deadline.filter(|deadline| match timeout {
+        Some(timeout) => Instant::now() + timeout >= *deadline,
+        None => true,
+    });
+    if deadline.map_or(false, |deadline| deadline <= Instant::now()) {
+        return JSError::DeadlineExceeded.into();
+    }
+    let builtin = builtin_reduce(&js_request);
+    // Builtin reduces don't need a worker
+    let worker = if builtin.is_some() { 0 } else { js_client.id() };
+    let retry = if builtin.is_none() && config::retry_crashed() && is_idempotent(&js_request) {
+        Some(js_request.clone())
+    } else {
+        None
+    };
+    let mut reply = match builtin {
+        Some(reply) => reply,
+        None => dispatch(js_client, js_request, deadline, timeout).await,
+    };
+    let crashed = reply.crashed || matches!(reply.result, Err(JSError::OutOfMemory));
+    if crashed && record_crashes {
+        quarantine::record_crash(&script_hash);
+    }
+    // Sent once more to the isolate that replaced the crashed one, unless
+    // that crash was one too many for the script
+    let lost = matches!(
+        reply.result,
+        Err(JSError::WorkerCrashed) | Err(JSError::OutOfMemory)
+    );
+    let mut retried = false;
+    if let Some(js_request) = retry.filter(|_| lost && !quarantine::is_quarantined(&script_hash)) {
+        log::warn!(
+            "retrying request {} lost to worker {} crashing",
+            js_request.request_id,
+            worker
+        );
+        metrics::RETRIED_COMMANDS_TOTAL.inc();
+        retried = true;
+        reply = dispatch(js_client, js_request, deadline, timeout).await;
+        let crashed = reply.crashed || matches!(reply.result, Err(JSError::OutOfMemory));
+        if crashed && record_crashes {
+            quarantine::record_crash(&script_hash);
+        }
+    }
+    if let (Some((signature, functions)), Ok(_)) = (&checkpointed, &reply.result) {
+        match functions {
+            Some(functions) => checkpoint::record_registered(signature, functions),
+            None => checkpoint::record_use(signature),
+        }
+    }
+    if let Some(limit) = max_result_bytes {
+        if let Some(reason) = over_result_limit(&reply, limit) {
+            reply.result = Err(JSError::ResultTooLarge(reason));
+            reply.emitted = Vec::new();
+        }
+    }
+    let mut js_resp = match reply.result {
+        Ok(result) => JsResponse {
+            status: 0,
+            result,
+            ..JsResponse::default()
+        },
+        Err(err) => err.into(),
+    };
+    js_resp.logs = reply
+        .logs
+        .into_iter()
+        .map(|line| LogMessage {
+            level: line.level.to_string().to_lowercase(),
+            message: line.message,
+        })
+        .collect();
+    let encoded = encode_emitted(reply.emitted, view_kv, encoding, &doc_id).and_then(|emitted| {
+        js_resp.emitted = emitted;
+        if js_resp.status != 0 {
+            return Ok(());
+        }
+        match encoding {
+            ResultEncoding::Json => (),
+            ResultEncoding::Etf => js_resp.result_etf = to_etf(&js_resp.result)?,
+            ResultEncoding::Msgpack => js_resp.result_msgpack = to_msgpack(&js_resp.result)?,
+        }
+        if encoding != ResultEncoding::Json {
+            js_resp.result.clear();
+        }
+        Ok(())
+    });
+    if let Err(reason) = encoded {
+        let logs = js_resp.logs;
+        js_resp = JSError::Internal(reason).into();
+        js_resp.logs = logs;
+    }
+    js_resp.features = features;
+    js_resp.worker = worker as i32;
+    js_resp.suggested_batch_size = if js_resp.error_type == ErrorType::Timeout as i32 {
+        // Didn't finish so there's no telling how long it would have taken
+        (batch_size / 2).max(1).min(batch_size) as i32
+    } else {
+        batching::suggest(
+            batch_size,
+            reply.stats.execution,
+            js_client.queue_depth(),
+            throttle::is_throttled(),
+        ) as i32
+    };
+    js_resp.stats = Some(ExecStats {
+        execution_us: reply.stats.execution.as_micros() as i64,
+        cpu_us: reply.stats.cpu.as_micros() as i64,
+        queue_wait_us: reply.stats.queue_wait.as_micros() as i64,
+        heap_used: reply.stats.heap_used as i64,
+        retried,
+    });
+    js_resp
+}
+
+// Runs js_request on js_client's worker, giving up at deadline or after
+// timeout
+async fn dispatch(
+    js_client: &JSClient,
+    js_request: JsRequest,
+    deadline: Option<Instant>,
+    timeout: Option<Duration>,
+) -> Reply {
+    if let Some(deadline) = deadline {
+        js_client
+            .execute_deadline(js_request.into(), deadline)
+            .await
+    } else if let Some(timeout) = timeout {
+        js_client.execute_timeout(js_request.into(), timeout).await
+    } else {
+        js_client.execute(js_request.into()).await
+    }
+}
+
+// Whether running js_request twice gives the same answer and leaves nothing
+// behind on the worker, so one lost to a crash can be sent again. Maps
+// aren't, the isolate that replaced the crashed one has no design docs.
+fn is_idempotent(js_request: &JsRequest) -> bool {
+    matches!(Action::from_i32(js_request.action), Some(Action::Rewrite))
+}
+
+// The design doc js_request uses, with its functions if it's registering
+// it, for checkpointing
+fn checkpointed(js_request: &JsRequest) -> Option<(String, Option<String>)> {
+    match Action::from_i32(js_request.action) {
+        Some(Action::RegisterDdoc) => {
+            Some((js_request.script.clone(), js_request.args.first().cloned()))
+        }
+        Some(Action::MapDoc) | Some(Action::MapDocs) | Some(Action::Filter) => {
+            Some((js_request.script.clone(), None))
+        }
+        _ => None,
+    }
+}
+
+// The smaller of the server's max_result_bytes and the request's, if
+// either has one
+fn result_limit(requested: i32) -> Option<usize> {
+    let limits = [config::max_result_bytes(), requested.max(0) as usize];
+    limits.iter().filter(|limit| **limit > 0).min().copied()
+}
+
+// Why reply's result and emitted rows won't fit in limit bytes, if they
+// don't. Stops counting as soon as they're over, before anything is
+// encoded.
+fn over_result_limit(reply: &Reply, limit: usize) -> Option<String> {
+    let mut total = match &reply.result {
+        Ok(result) => result.len(),
+        Err(_) => 0,
+    };
+    for row in reply.emitted.iter().flatten() {
+        if total > limit {
+            break;
+        }
+        total += row.len();
+    }
+    if total > limit {
+        Some(format!("more than {} bytes of results", limit))
+    } else {
+        None
+    }
+}
+
+// The hash quarantining goes by. It identifies the function a request runs
+// and not its input, so a function that crashes workers on every doc gets
+// quarantined rather than counted afresh for each one.
+pub fn quarantine_hash(js_request: &JsRequest) -> String {
+    match Action::from_i32(js_request.action) {
+        Some(Action::WasmCall) => {
+            let module = [wasm_module_hash(&js_request.wasm_module)];
+            quarantine::script_hash(&js_request.script, Some(&module[..]))
+        }
+        _ => quarantine::script_hash(&js_request.script, None),
+    }
+}
+
+// The hash of a request's function along with its input, that the result
+// cache keys by. Calls and design doc functions include their arguments,
+// everything else is identified by its script alone.
+pub(crate) fn request_hash(js_request: &JsRequest) -> String {
+    match Action::from_i32(js_request.action) {
+        Some(Action::Call) | Some(Action::Rewrite) => {
+            let args = if js_request.json_args.is_empty() {
+                &js_request.args
+            } else {
+                &js_request.json_args
+            };
+            quarantine::script_hash(&js_request.script, Some(args.as_slice()))
+        }
+        Some(Action::MapDoc) | Some(Action::MapDocs) | Some(Action::Filter) => {
+            quarantine::script_hash(&js_request.script, Some(js_request.args.as_slice()))
+        }
+        Some(Action::WasmCall) => {
+            let mut args = vec![wasm_module_hash(&js_request.wasm_module)];
+            args.extend(js_request.json_args.iter().cloned());
+            quarantine::script_hash(&js_request.script, Some(args.as_slice()))
+        }
+        _ => quarantine::script_hash(&js_request.script, None),
+    }
+}
+
+// Builtin reduces like _sum are run here rather than on the worker
+fn builtin_reduce(js_request: &JsRequest) -> Option<Reply> {
+    let rereduce = match Action::from_i32(js_request.action) {
+        Some(Action::Reduce) => false,
+        Some(Action::Rereduce) => true,
+        _ => return None,
+    };
+    if !builtins::is_builtin(&js_request.script) {
+        return None;
+    }
+
+    let started = Instant::now();
+    let input = js_request.args.first().map_or("[]", String::as_str);
+    let result = match serde_json::from_str::<Vec<serde_json::Value>>(input) {
+        Ok(input) if rereduce => builtins::rereduce(&js_request.script, &input),
+        Ok(input) => builtins::reduce(&js_request.script, &input),
+        Err(err) => Err(format!("invalid reduce input: {}", err)),
+    };
+    let result = result.map(|value| value.to_string()).map_err(|message| {
+        JSError::RuntimeError(ErrorInfo {
+            name: "builtin_reduce_error".to_string(),
+            message,
+            ..ErrorInfo::default()
+        })
+    });
+
+    Some(Reply {
+        result,
+        logs: Vec::new(),
+        emitted: Vec::new(),
+        stats: js_server::ExecStats {
+            execution: started.elapsed(),
+            ..Default::default()
+        },
+        crashed: false,
+    })
+}
+
+fn encode_emitted(
+    emitted: Vec<Vec<String>>,
+    view_kv: bool,
+    encoding: ResultEncoding,
+    doc_id: &str,
+) -> Result<Vec<EmitGroup>, String> {
+    emitted
+        .into_iter()
+        .map(|rows| {
+            if view_kv {
+                Ok(EmitGroup {
+                    kv_rows: view_rows(doc_id, rows)?,
+                    ..EmitGroup::default()
+                })
+            } else if encoding == ResultEncoding::Etf {
+                Ok(EmitGroup {
+                    etf_rows: rows
+                        .iter()
+                        .map(|row| to_etf(row))
+                        .collect::<Result<_, _>>()?,
+                    ..EmitGroup::default()
+                })
+            } else if encoding == ResultEncoding::Msgpack {
+                Ok(EmitGroup {
+                    msgpack_rows: rows
+                        .iter()
+                        .map(|row| to_msgpack(row))
+                        .collect::<Result<_, _>>()?,
+                    ..EmitGroup::default()
+                })
+            } else {
+                Ok(EmitGroup {
+                    rows,
+                    ..EmitGroup::default()
+                })
+            }
+        })
+        .collect()
+}
+
+#[cfg(feature = "etf")]
+fn to_etf(json: &str) -> Result<Vec<u8>, String> {
+    // What a function that returns undefined gives back
+    if json == "undefined" {
+        return Ok(etf::encode(&serde_json::Value::Null));
+    }
+    let value: serde_json::Value =
+        serde_json::from_str(json).map_err(|err| format!("invalid JSON result: {}", err))?;
+    Ok(etf::encode(&value))
+}
+
+// Requests for ETF are rejected before they get this far
+#[cfg(not(feature = "etf"))]
+fn to_etf(_json: &str) -> Result<Vec<u8>, String> {
+    Err("built without the etf feature".to_string())
+}
+
+fn to_msgpack(json: &str) -> Result<Vec<u8>, String> {
+    // What a function that returns undefined gives back
+    if json == "undefined" {
+        return Ok(msgpack::encode(&serde_json::Value::Null));
+    }
+    let value: serde_json::Value =
+        serde_json::from_str(json).map_err(|err| format!("invalid JSON result: {}", err))?;
+    Ok(msgpack::encode(&value))
+}
+
+// MessagePack args are turned into json_args as soon as a request arrives,
+// so everything after only has to know about the one
+fn unpack_msgpack_args(js_request: &mut JsRequest) -> Result<(), JSError> {
+    if js_request.msgpack_args.is_empty() {
+        return Ok(());
+    }
+    if !js_request.json_args.is_empty() {
+        let reason = "json_args and msgpack_args can't both be given".to_string();
+        return Err(JSError::InvalidRequest(reason));
+    }
+    for (i, arg) in js_request.msgpack_args.drain(..).enumerate() {
+        let value = msgpack::decode(&arg).map_err(|err| {
+            JSError::InvalidRequest(format!("invalid msgpack_args[{}]: {}", i, err))
+        })?;
+        js_request.json_args.push(value.to_string());
+    }
+    Ok(())
+}
+
+// Turns emitted [key, value] rows into view rows for doc_id with their keys
+// already collation encoded
+fn view_rows(doc_id: &str, rows: Vec<String>) -> Result<Vec<ViewRow>, String> {
+    rows.into_iter()
+        .map(|row| {
+            let row: serde_json::Value =
+                serde_json::from_str(&row).map_err(|err| format!("invalid row: {}", err))?;
+            match row {
+                serde_json::Value::Array(mut pair) if pair.len() == 2 => {
+                    let value = pair.pop().unwrap();
+                    let key = pair.pop().unwrap();
+                    Ok(ViewRow {
+                        id: doc_id.to_string(),
+                        key: encode_key(&key),
+                        value: value.to_string(),
+                    })
+                }
+                _ => Err(format!("invalid row: {}", row)),
+            }
+        })
+        .collect()
+}
+
+// Requests that name a session run on its worker, everything else on
+// js_client. Also says whether the session had to be started. pinned says
+// js_client is the connection's own worker, which like a session's is the
+// only kind RESET may start over without wiping out other clients' state.
+fn client_for(
+    js_client: &JSClient,
+    pinned: bool,
+    sessions: &Sessions,
+    harnesses: &Harnesses,
+    js_request: &JsRequest,
+) -> Result<(JSClient, bool), JSError> {
+    let harness = harness::lookup(harnesses, js_request).map_err(JSError::InvalidRequest)?;
+    if js_request.session_id.is_empty() {
+        if js_request.action == Action::Reset as i32 && (!pinned || harness.is_some()) {
+            let reason = "RESET on a shared worker needs a session".to_string();
+            return Err(JSError::InvalidRequest(reason));
+        }
+        let js_client = match harness {
+            Some(harness) => harness.client_for(js_request),
+            None => js_client.clone(),
+        };
+        return Ok((js_client, false));
+    }
+    let ttl = Duration::from_secs(js_request.session_ttl.max(0) as u64);
+    Ok(sessions.harness_client(&js_request.session_id, ttl, harness))
+}
+
+// Requests are sent to the worker as soon as their frame has arrived while
+// the responses are written back in request order. A frame that can't be
+// decoded ends the stream with an error response.
+async fn execute_pipelined(
+    js_client: JSClient,
+    pinned: bool,
+    deadline: Option<Instant>,
+    sessions: Arc<Sessions>,
+    harnesses: Arc<Harnesses>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    audit_log: Option<Arc<AuditLog>>,
+    peer: String,
+    accepted: Option<ResultEncoding>,
+    max_frame: usize,
+    mut body: Body,
+    mut sender: BodySender,
+) {
+    let mut decoder = FrameDecoder::with_max_len(max_frame);
+    let mut pending = FuturesOrdered::new();
+    let mut body_done = false;
+
+    loop {
+        tokio::select! {
+            chunk = body.next(), if !body_done => {
+                match chunk {
+                    Some(Ok(chunk)) => decoder.extend(&chunk),
+                    Some(Err(_)) => return,
+                    None => {
+                        body_done = true;
+                        if !decoder.is_empty() {
+                            let err = JSError::Internal("truncated frame".to_string());
+                            let js_resp = JsResponse::from(err);
+                            pending.push(future::Either::Left(future::ready(js_resp)));
+                        }
+                    }
+                }
+
+                loop {
+                    match decoder.next_frame::<JsRequest>() {
+                        Ok(Some(mut js_request)) => {
+                            accept_encoding(&mut js_request.result_encoding, accepted);
+                            let admitted = unpack_msgpack_args(&mut js_request)
+                                .and_then(|_| rate_limit(&rate_limiter, &peer, &js_request))
+                                .and_then(|_| {
+                                    client_for(
+                                        &js_client,
+                                        pinned,
+                                        &sessions,
+                                        &harnesses,
+                                        &js_request,
+                                    )
+                                });
+                            let (client, session_created) = match admitted {
+                                Ok(client) => client,
+                                Err(err) => {
+                                    let js_resp = JsResponse::from(err);
+                                    pending.push(future::Either::Left(future::ready(js_resp)));
+                                    continue;
+                                }
+                            };
+                            let op = format!("{:?}", operation(&js_request)).to_lowercase();
+                            let audit_log = audit_log.clone();
+                            let audit = audit_log
+                                .as_ref()
+                                .and_then(|audit_log| audit_log.begin(&peer, &op, &js_request));
+                            let fut = async move {
+                                let mut js_resp = execute(&client, js_request, deadline).await;
+                                js_resp.session_created = session_created;
+                                if let (Some(audit_log), Some(entry)) = (audit_log, audit) {
+                                    audit_log.finish(entry, &js_resp);
+                                }
+                                js_resp
+                            };
+                            pending.push(future::Either::Right(fut));
+                        }
+                        Ok(None) => break,
+                        Err(err) => {
+                            let err = JSError::Internal(format!("invalid frame: {}", err));
+                            let js_resp = JsResponse::from(err);
+                            pending.push(future::Either::Left(future::ready(js_resp)));
+                            body_done = true;
+                            break;
+                        }
+                    }
+                }
+            }
+            js_resp = pending.next(), if !pending.is_empty() => {
+                let frame = encode_frame(&js_resp.unwrap());
+                if sender.send_data(frame.into()).await.is_err() {
+                    return;
+                }
+            }
+            else => break,
+        }
+    }
+}
+
+// Serves a /ws connection once it's upgraded. Each text message is a
+// JsRequest as JSON, the same as /admin/encode takes, and they're run one
+// at a time. A request's log lines come back as messages of their own,
+// {"type": "log", "request_id", "level", "message"}, then its response as
+// {"type": "response", "response"} with the response as
+// transcode::response_to_json gives it.
+async fn websocket_session(
+    upgrade: OnUpgrade,
+    workers: Workers,
+    sessions: Arc<Sessions>,
+    harnesses: Arc<Harnesses>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    audit_log: Option<Arc<AuditLog>>,
+    peer: String,
+    max_message: usize,
+) {
+    let mut upgraded = match upgrade.await {
+        Ok(upgraded) => upgraded,
+        Err(err) => {
+            log::warn!("websocket upgrade from {} failed: {}", peer, err);
+            return;
+        }
+    };
+    let mut decoder = websocket::Decoder::new(max_message as u64);
+    let mut chunk = vec![0u8; 16 * 1024];
+    let mut message = Vec::new();
+    loop {
+        let frame = match decoder.next_frame() {
+            Ok(Some(frame)) => frame,
+            Ok(None) => match upgraded.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(read) => {
+                    decoder.extend(&chunk[..read]);
+                    continue;
+                }
+            },
+            Err(err) => {
+                log::warn!("websocket from {} failed: {}", peer, err);
+                return;
+            }
+        };
+        let replies = match frame.opcode {
+            websocket::CONTINUATION | websocket::TEXT => {
+                message.extend_from_slice(&frame.payload);
+                if message.len() > max_message {
+                    log::warn!("websocket from {} failed: message too large", peer);
+                    return;
+                }
+                if !frame.fin {
+                    continue;
+                }
+                let text = std::mem::replace(&mut message, Vec::new());
+                let js_request = serde_json::from_slice(&text)
+                    .map_err(|err| err.to_string())
+                    .and_then(|value| transcode::request_from_json(&value));
+                let js_resp = match js_request {
+                    Ok(js_request) => {
+                        websocket_execute(
+                            &workers,
+                            &sessions,
+                            &harnesses,
+                            &rate_limiter,
+                            &audit_log,
+                            &peer,
+                            js_request,
+                        )
+                        .await
+                    }
+                    Err(reason) => JSError::InvalidRequest(reason).into(),
+                };
+                websocket_replies(js_resp)
+            }
+            websocket::CLOSE => {
+                let close = websocket::encode_frame(websocket::CLOSE, &frame.payload);
+                let _ = upgraded.write_all(&close).await;
+                return;
+            }
+            websocket::PING => vec![websocket::encode_frame(websocket::PONG, &frame.payload)],
+            _ => continue,
+        };
+        for reply in replies {
+            if upgraded.write_all(&reply).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+async fn websocket_execute(
+    workers: &Workers,
+    sessions: &Sessions,
+    harnesses: &Harnesses,
+    rate_limiter: &Option<Arc<RateLimiter>>,
+    audit_log: &Option<Arc<AuditLog>>,
+    peer: &str,
+    mut js_request: JsRequest,
+) -> JsResponse {
+    // Results go back as JSON text
+    js_request.result_encoding = ResultEncoding::Json as i32;
+    let admitted = unpack_msgpack_args(&mut js_request)
+        .and_then(|_| rate_limit(rate_limiter, peer, &js_request))
+        .and_then(|_| {
+            client_for(
+                &workers.client_for(&js_request),
+                workers.is_pinned(),
+                sessions,
+                harnesses,
+                &js_request,
+            )
+        });
+    match admitted {
+        Ok((js_client, session_created)) => {
+            let op = format!("{:?}", operation(&js_request)).to_lowercase();
+            let audit = audit_log
+                .as_ref()
+                .and_then(|audit_log| audit_log.begin(peer, &op, &js_request));
+            let mut js_resp = execute(&js_client, js_request, None).await;
+            js_resp.session_created = session_created;
+            if let (Some(audit_log), Some(entry)) = (audit_log, audit) {
+                audit_log.finish(entry, &js_resp);
+            }
+            js_resp
+        }
+        Err(err) => err.into(),
+    }
+}
+
+// The text frames sent for js_resp: one per log line, then the response
+fn websocket_replies(mut js_resp: JsResponse) -> Vec<Vec<u8>> {
+    let logs = std::mem::replace(&mut js_resp.logs, Vec::new());
+    let request_id = js_resp.request_id.clone();
+    let mut replies: Vec<serde_json::Value> = logs
+        .into_iter()
+        .map(|log| {
+            json!({
+                "type": "log",
+                "request_id": request_id,
+                "level": log.level,
+                "message": log.message,
+            })
+        })
+        .collect();
+    replies.push(json!({
+        "type": "response",
+        "response": transcode::response_to_json(&js_resp),
+    }));
+    replies
+        .iter()
+        .map(|reply| websocket::encode_frame(websocket::TEXT, reply.to_string().as_bytes()))
+        .collect()
+}
+
+// What mapping the docs of a MapDocsRequest needs
+#[derive(Clone)]
+struct Mapper {
+    workers: Workers,
+    deadline: Option<Instant>,
+    sessions: Arc<Sessions>,
+    harnesses: Arc<Harnesses>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    peer: String,
+}
+
+impl Mapper {
+    // Sends a JsResponse frame per doc of map_docs. False once the caller
+    // hung up.
+    async fn map(&self, map_docs: MapDocsRequest, sender: &mut BodySender) -> bool {
+        if map_docs.batch {
+            let js_client = self.workers.client_for(&map_docs_batch(&map_docs));
+            return map_batch(
+                js_client,
+                self.deadline,
+                self.sessions.clone(),
+                self.harnesses.clone(),
+                self.rate_limiter.clone(),
+                self.peer.clone(),
+                map_docs,
+                sender,
+            )
+            .await;
+        }
+        let js_requests = map_doc_requests(map_docs);
+        let js_client = match js_requests.first() {
+            Some(js_request) => self.workers.client_for(js_request),
+            None => self.workers.client(),
+        };
+        map_docs(
+            js_client,
+            self.deadline,
+            self.sessions.clone(),
+            self.harnesses.clone(),
+            self.rate_limiter.clone(),
+            self.peer.clone(),
+            js_requests,
+            sender,
+        )
+        .await
+    }
+}
+
+// Maps the docs of each MapDocsRequest frame in body before decoding the
+// next, so only a frame and the doc being mapped are held at a time. Frames
+// after the first take everything but their docs and doc_ids from it.
+async fn map_docs_stream(
+    mapper: Mapper,
+    accepted: Option<ResultEncoding>,
+    max_frame: usize,
+    mut body: Body,
+    mut sender: BodySender,
+) {
+    let mut decoder = FrameDecoder::with_max_len(max_frame);
+    let mut first: Option<MapDocsRequest> = None;
+    loop {
+        loop {
+            let frame = match decoder.next_frame::<MapDocsRequest>() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(err) => {
+                    let err = JSError::InvalidRequest(format!("invalid frame: {}", err));
+                    let _ = sender
+                        .send_data(encode_frame(&JsResponse::from(err)).into())
+                        .await;
+                    return;
+                }
+            };
+            let map_docs = match &first {
+                Some(first) => MapDocsRequest {
+                    docs: frame.docs,
+                    doc_ids: frame.doc_ids,
+                    ..first.clone()
+                },
+                None => {
+                    let mut options = frame;
+                    accept_encoding(&mut options.result_encoding, accepted);
+                    let docs = std::mem::replace(&mut options.docs, Vec::new());
+                    let doc_ids = std::mem::replace(&mut options.doc_ids, Vec::new());
+                    first = Some(options.clone());
+                    MapDocsRequest {
+                        docs,
+                        doc_ids,
+                        ..options
+                    }
+                }
+            };
+            if !mapper.map(map_docs, &mut sender).await {
+                return;
+            }
+        }
+        match body.next().await {
+            Some(Ok(chunk)) => decoder.extend(&chunk),
+            Some(Err(_)) => return,
+            None => {
+                if !decoder.is_empty() {
+                    let err = JSError::InvalidRequest("truncated frame".to_string());
+                    let _ = sender
+                        .send_data(encode_frame(&JsResponse::from(err)).into())
+                        .await;
+                }
+                return;
+            }
+        }
+    }
+}
+
+// Maps each doc in turn on js_client's worker, or its session's, and sends
+// each response as soon as it's ready. Stops once the caller hangs up.
+async fn map_docs(
+    js_client: JSClient,
+    deadline: Option<Instant>,
+    sessions: Arc<Sessions>,
+    harnesses: Arc<Harnesses>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    peer: String,
+    js_requests: Vec<JsRequest>,
+    sender: &mut BodySender,
+) -> bool {
+    for js_request in js_requests {
+        let routed = rate_limit(&rate_limiter, &peer, &js_request)
+            .and_then(|_| client_for(&js_client, false, &sessions, &harnesses, &js_request));
+        let js_resp = match routed {
+            Ok((client, session_created)) => {
+                let mut js_resp = execute(&client, js_request, deadline).await;
+                js_resp.session_created = session_created;
+                js_resp
+            }
+            Err(err) => JsResponse::from(err),
+        };
+        let frame = encode_frame(&js_resp);
+        if sender.send_data(frame.into()).await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+// Maps all of map_docs' docs with a single MAP_DOCS command, then sends the
+// response each doc would have had from a MAP_DOC of its own
+async fn map_batch(
+    js_client: JSClient,
+    deadline: Option<Instant>,
+    sessions: Arc<Sessions>,
+    harnesses: Arc<Harnesses>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    peer: String,
+    map_docs: MapDocsRequest,
+    sender: &mut BodySender,
+) -> bool {
+    if map_docs.docs.is_empty() {
+        return true;
+    }
+    let encoding =
+        ResultEncoding::from_i32(map_docs.result_encoding).unwrap_or(ResultEncoding::Json);
+    let js_request = map_docs_batch(&map_docs);
+    let routed = rate_limit(&rate_limiter, &peer, &js_request)
+        .and_then(|_| client_for(&js_client, false, &sessions, &harnesses, &js_request));
+    let batch = match routed {
+        // Rows are only encoded once split up so the worker is never asked
+        // for ETF
+        Ok(_) if encoding == ResultEncoding::Etf && !supports("etf") => {
+            JSError::UnsupportedFeature("etf".to_string()).into()
+        }
+        Ok((client, session_created)) => {
+            let mut batch = execute(&client, js_request, deadline).await;
+            batch.session_created = session_created;
+            batch
+        }
+        Err(err) => JsResponse::from(err),
+    };
+    for js_resp in split_batch(batch, &map_docs) {
+        let frame = encode_frame(&js_resp);
+        if sender.send_data(frame.into()).await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+// A response per doc from the one to a batch's MAP_DOCS request. An error
+// for the whole batch is every doc's error. The logs go with the first
+// doc's response and every response has the batch's stats.
+fn split_batch(mut batch: JsResponse, map_docs: &MapDocsRequest) -> Vec<JsResponse> {
+    let docs = map_docs.docs.len();
+    if docs == 0 {
+        return Vec::new();
+    }
+    let mut logs = std::mem::replace(&mut batch.logs, Vec::new());
+    let (worker, request_id) = (batch.worker, batch.request_id.clone());
+    let results: Vec<serde_json::Value> = if batch.status == 0 {
+        match serde_json::from_str(&batch.result) {
+            Ok(results) => results,
+            Err(err) => {
+                let reason = format!("invalid MAP_DOCS result: {}", err);
+                batch = JSError::Internal(reason).into();
+                Vec::new()
+            }
+        }
+    } else {
+        Vec::new()
+    };
+    if batch.status != 0 || results.len() != docs {
+        if batch.status == 0 {
+            let reason = format!("MAP_DOCS mapped {} of {} docs", results.len(), docs);
+            batch = JSError::Internal(reason).into();
+        }
+        batch.worker = worker;
+        batch.request_id = request_id;
+        let mut responses = vec![batch; docs];
+        responses[0].logs = logs;
+        return responses;
+    }
+
+    let view_kv = map_docs.emit_format == EmitFormat::ViewKv as i32;
+    let encoding =
+        ResultEncoding::from_i32(map_docs.result_encoding).unwrap_or(ResultEncoding::Json);
+    let per_doc = batch.emitted.len() / docs;
+    let (stats, features) = (batch.stats, batch.features);
+    let mut session_created = batch.session_created;
+    let mut groups = batch.emitted.into_iter();
+    let mut doc_ids = map_docs.doc_ids.iter();
+    results
+        .into_iter()
+        .map(|result| {
+            let doc_id = doc_ids.next().map_or("", String::as_str);
+            let rows: Vec<Vec<String>> = groups.by_ref().take(per_doc).map(|g| g.rows).collect();
+            let mut js_resp = if result.is_null() {
+                match mapped_response(rows, view_kv, encoding, doc_id) {
+                    Ok(js_resp) => js_resp,
+                    Err(reason) => JSError::Internal(reason).into(),
+                }
+            } else {
+                let field = |name: &str| result[name].as_str().unwrap_or("").to_string();
+                JSError::RuntimeError(ErrorInfo {
+                    name: field("name"),
+                    message: field("message"),
+                    stack: field("stack"),
+                    line: result["line"].as_i64().unwrap_or(0) as i32,
+                })
+                .into()
+            };
+            js_resp.logs = std::mem::replace(&mut logs, Vec::new());
+            js_resp.session_created = std::mem::replace(&mut session_created, false);
+            js_resp.stats = stats.clone();
+            js_resp.features = features.clone();
+            js_resp.worker = worker;
+            js_resp.request_id = request_id.clone();
+            js_resp
+        })
+        .collect()
+}
+
+// What a MAP_DOC that emitted rows gives back, encoded as it would be
+fn mapped_response(
+    rows: Vec<Vec<String>>,
+    view_kv: bool,
+    encoding: ResultEncoding,
+    doc_id: &str,
+) -> Result<JsResponse, String> {
+    let mut js_resp = JsResponse {
+        emitted: encode_emitted(rows, view_kv, encoding, doc_id)?,
+        ..JsResponse::default()
+    };
+    match encoding {
+        ResultEncoding::Json => js_resp.result = "true".to_string(),
+        ResultEncoding::Etf => js_resp.result_etf = to_etf("true")?,
+        ResultEncoding::Msgpack => js_resp.result_msgpack = to_msgpack("true")?,
+    }
+    Ok(js_resp)
+}
+
+// Reads the whole body, or gives the response to send instead if it's over
+// limit bytes. The body is checked as it arrives so a huge one is never
+// buffered. A body that arrived in one chunk is returned without copying.
+async fn read_body(
+    req: Request<Body>,
+    limit: usize,
+) -> Result<Result<Bytes, Response<Body>>, hyper::Error> {
+    let too_large = || {
+        let reason = format!("body is over {} bytes", limit);
+        invalid_request(StatusCode::PAYLOAD_TOO_LARGE, reason)
+    };
+
+    let length = header_str(&req, CONTENT_LENGTH).and_then(|length| length.parse::<u64>().ok());
+    if matches!(length, Some(length) if length > limit as u64) {
+        return Ok(Err(too_large()));
+    }
+
+    let mut body = req.into_body();
+    let mut first: Option<Bytes> = None;
+    let mut full_body = BytesMut::new();
+    let mut read = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        read += chunk.len();
+        if read > limit {
+            return Ok(Err(too_large()));
+        }
+        match first.take() {
+            None if full_body.is_empty() => first = Some(chunk),
+            None => full_body.extend_from_slice(&chunk),
+            Some(first) => {
+                full_body.reserve(length.map_or(read, |length| length as usize));
+                full_body.extend_from_slice(&first);
+                full_body.extend_from_slice(&chunk);
+            }
+        }
+    }
+    Ok(Ok(first.unwrap_or_else(|| full_body.freeze())))
+}
+
+// The JsResponse for a request that never got as far as a worker
+fn invalid_request(status: StatusCode, reason: String) -> Response<Body> {
+    error_response(status, JSError::InvalidRequest(reason))
+}
+
+fn error_response(status: StatusCode, err: JSError) -> Response<Body> {
+    let js_resp = JsResponse::from(err);
+    let mut body = Vec::new();
+    js_resp.encode(&mut body).unwrap();
+    let mut resp = Response::new(Body::from(body));
+    *resp.status_mut() = status;
+    resp
+}
+
+fn header_str(req: &Request<Body>, name: HeaderName) -> Option<&str> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+}
+
+fn compressed_response(encoding: Encoding, body: &[u8]) -> Response<Body> {
+    let mut resp = Response::new(Body::from(compression::compress(encoding, body)));
+    resp.headers_mut().insert(
+        CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+    resp.headers_mut()
+        .insert(VARY, HeaderValue::from_static("accept-encoding"));
+    resp
+}
+
+fn admin_error(status: StatusCode, error: &str) -> Response<Body> {
+    let mut resp = json_response(json!({ "error": error }));
+    *resp.status_mut() = status;
+    resp
+}
+
+// The value of name in the query string, which is taken as is
+fn query_param(req: &Request<Body>, name: &str) -> Option<String> {
+    req.uri().query()?.split('&').find_map(|pair| {
+        let mut parts = pair.splitn(2, '=');
+        if parts.next()? == name {
+            Some(parts.next().unwrap_or("").to_string())
+        } else {
+            None
+        }
+    })
+}
+
+fn json_response(value: serde_json::Value) -> Response<Body> {
+    let mut resp = Response::new(Body::from(value.to_string()));
+    resp.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/json"),
+    );
+    resp
 }
 
 impl Service<Request<Body>> for Svc {
@@ -87,24 +2240,366 @@ impl Service<Request<Body>> for Svc {
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
         let mut me = self.clone();
-        let fut = async move { me.handle_resp(req).await };
+        let fut = async move {
+            let access_log = match me.access_log.clone() {
+                Some(access_log) => access_log,
+                None => return me.handle_resp(req).await,
+            };
+            let started = Instant::now();
+            let mut entry = AccessEntry {
+                ts: Utc::now(),
+                peer: me.stats.peer.clone(),
+                method: req.method().to_string(),
+                path: req.uri().path().to_string(),
+                version: format!("{:?}", req.version()),
+                bytes: header_str(&req, CONTENT_LENGTH)
+                    .and_then(|len| len.parse().ok())
+                    .unwrap_or(0),
+                status: 0,
+                took_us: 0,
+                op: None,
+                queue_us: None,
+                exec_us: None,
+                request_id: None,
+            };
+            let mut resp = me.handle_resp(req).await?;
+            entry.status = resp.status().as_u16();
+            entry.took_us = started.elapsed().as_micros() as u64;
+            if let Some(details) = resp.extensions_mut().remove::<ExecDetails>() {
+                entry.exec(details);
+            }
+            access_log.log(&entry);
+            Ok(resp)
+        };
         Box::pin(fut)
     }
 }
 
+// Cheap to clone, the clones share the same pool and shadow so one can serve
+// several listeners
+#[derive(Clone)]
 pub struct MakeService {
-    js_env: JSEnv,
+    js_env: Arc<JSEnv>,
+    capabilities: Capabilities,
+    pool: Option<(Arc<WorkerPool>, Affinity)>,
+    shadow: Option<Arc<Shadow>>,
+    mirror: Option<Arc<Mirror>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    result_cache: Option<Arc<ResultCache>>,
+    access_log: Option<Arc<AccessLog>>,
+    audit_log: Option<Arc<AuditLog>>,
+    tracer: Option<Arc<Tracer>>,
+    sessions: Arc<Sessions>,
+    harnesses: Arc<Harnesses>,
+    compress_min: Option<usize>,
+    max_body: usize,
+    http2: Http2Config,
+    checkpoint: Option<CheckpointConfig>,
+    // TCP listeners accepting connections for each bind address
+    acceptors: usize,
+    admin: Option<AdminConfig>,
+    admin_access: AdminAccess,
+    execute_token: Option<Arc<String>>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+}
+
+// How connections speaking HTTP/2 are tuned. Without TLS clients have to
+// use prior knowledge, i.e. start with the HTTP/2 preface rather than
+// upgrade, which lets e.g. the Erlang client multiplex many Execute calls on
+// one connection. Unset values are left to hyper.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Http2Config {
+    // Refuse HTTP/1.1
+    pub only: bool,
+    // Flow control windows in bytes
+    pub initial_stream_window: Option<u32>,
+    pub initial_connection_window: Option<u32>,
+    pub max_concurrent_streams: Option<u32>,
+    // Ping idle connections this often and close them if the ping isn't
+    // answered within keep_alive_timeout
+    pub keep_alive_interval: Option<Duration>,
+    pub keep_alive_timeout: Duration,
+}
+
+impl Default for Http2Config {
+    fn default() -> Self {
+        Http2Config {
+            only: false,
+            initial_stream_window: None,
+            initial_connection_window: None,
+            max_concurrent_streams: None,
+            keep_alive_interval: None,
+            keep_alive_timeout: Duration::from_secs(20),
+        }
+    }
+}
+
+impl Http2Config {
+    fn configure<I>(&self, builder: Builder<I>) -> Builder<I> {
+        builder
+            .http2_only(self.only)
+            .http2_initial_stream_window_size(self.initial_stream_window)
+            .http2_initial_connection_window_size(self.initial_connection_window)
+            .http2_max_concurrent_streams(self.max_concurrent_streams)
+            .http2_keep_alive_interval(self.keep_alive_interval)
+            .http2_keep_alive_timeout(self.keep_alive_timeout)
+    }
 }
 
 impl MakeService {
     pub fn new() -> MakeService {
+        MakeService::with_capabilities(Capabilities::local())
+    }
+
+    // Every connection's context is created with only these host functions
+    pub fn with_capabilities(capabilities: Capabilities) -> MakeService {
+        let js_env = Arc::new(JSEnv::new());
+        let sessions = Sessions::start(js_env.clone(), capabilities.clone(), DEFAULT_SESSION_TTL);
         MakeService {
-            js_env: JSEnv::new(),
+            js_env,
+            capabilities,
+            pool: None,
+            shadow: None,
+            mirror: None,
+            rate_limiter: None,
+            result_cache: None,
+            access_log: None,
+            audit_log: None,
+            tracer: None,
+            sessions,
+            harnesses: Arc::new(Harnesses::new()),
+            compress_min: None,
+            max_body: DEFAULT_MAX_BODY_SIZE,
+            http2: Http2Config::default(),
+            checkpoint: None,
+            acceptors: 1,
+            #[cfg(feature = "tls")]
+            tls: None,
+            admin: None,
+            admin_access: AdminAccess::Open,
+            execute_token: None,
+        }
+    }
+
+    // By default every connection gets a worker of its own. With a pool,
+    // connections share size workers instead and affinity decides whether a
+    // connection sticks to one of them.
+    pub fn with_pool(mut self, size: usize, affinity: Affinity) -> MakeService {
+        let pool = WorkerPool::new(&self.js_env, &self.capabilities, size);
+        self.pool = Some((Arc::new(pool), affinity));
+        self
+    }
+
+    // Like with_pool but the pool grows and shrinks between config's
+    // min_size and max_size, see WorkerPool
+    pub fn with_pool_config(mut self, config: PoolConfig, affinity: Affinity) -> MakeService {
+        let pool = WorkerPool::start(&self.js_env, &self.capabilities, config);
+        self.pool = Some((pool, affinity));
+        self
+    }
+
+    // Also runs a sample of connections on shadow's workers, see Shadow
+    pub fn with_shadow(mut self, shadow: Shadow) -> MakeService {
+        self.shadow = Some(Arc::new(shadow));
+        self
+    }
+
+    // Also writes a sample of /Ateles/Execute requests to mirror's corpus,
+    // see Mirror
+    pub fn with_mirror(mut self, mirror: Mirror) -> MakeService {
+        self.mirror = Some(Arc::new(mirror));
+        self
+    }
+
+    // /Ateles/Execute requests over one of config's limits are rejected
+    // with RATE_LIMITED, see RateLimiter
+    pub fn with_rate_limits(mut self, config: RateLimitConfig) -> MakeService {
+        self.rate_limiter = Some(Arc::new(RateLimiter::new(config)));
+        self
+    }
+
+    // Successful /Ateles/Execute results of the operations in config are
+    // reused for identical requests to the same worker until they expire or
+    // the worker's state changes, see ResultCache. Pipelined requests are
+    // always run.
+    pub fn with_result_cache(mut self, config: ResultCacheConfig) -> MakeService {
+        self.result_cache = Some(Arc::new(ResultCache::new(config)));
+        self
+    }
+
+    // Logs every request to access_log
+    pub fn with_access_log(mut self, access_log: AccessLog) -> MakeService {
+        self.access_log = Some(Arc::new(access_log));
+        self
+    }
+
+    // Most REWRITE results kept by the workers' rewrite cache, 0 to turn it
+    // off. See RewriteCache.
+    pub fn with_rewrite_cache_size(self, max_entries: usize) -> MakeService {
+        self.js_env.rewrite_cache.set_max_entries(max_entries);
+        self
+    }
+
+    // Records every request that runs code of its own to audit_log, see
+    // audit::AUDITED_OPS
+    pub fn with_audit_log(mut self, audit_log: AuditLog) -> MakeService {
+        self.audit_log = Some(Arc::new(audit_log));
+        self
+    }
+
+    // Traces /Ateles/Execute requests, see Tracer
+    pub fn with_tracer(mut self, tracer: Tracer) -> MakeService {
+        self.tracer = Some(Arc::new(tracer));
+        self
+    }
+
+    pub fn js_env(&self) -> &JSEnv {
+        &self.js_env
+    }
+
+    // Requests whose harness field is name run on workers started from
+    // js_env instead of the built in bundle, see Harness
+    pub fn with_harness(mut self, name: &str, js_env: JSEnv, workers: usize) -> MakeService {
+        let harness = Harness::new(name, js_env, self.capabilities.clone(), workers);
+        Arc::make_mut(&mut self.harnesses).insert(name.to_string(), Arc::new(harness));
+        self
+    }
+
+    // How long sessions that don't ask for a TTL of their own live once idle
+    pub fn with_session_ttl(mut self, ttl: Duration) -> MakeService {
+        self.sessions = Sessions::start(self.js_env.clone(), self.capabilities.clone(), ttl);
+        self
+    }
+
+    // Compresses /Ateles/Execute responses of at least min_size bytes for
+    // clients that send Accept-Encoding with gzip or deflate
+    pub fn with_compression(mut self, min_size: usize) -> MakeService {
+        self.compress_min = Some(min_size);
+        self
+    }
+
+    // Requests with bigger bodies are rejected with a 413
+    pub fn with_max_body_size(mut self, bytes: usize) -> MakeService {
+        self.max_body = bytes;
+        self
+    }
+
+    // Tunes HTTP/2 connections to the servers make_service is run on
+    pub fn with_http2(mut self, config: Http2Config) -> MakeService {
+        self.http2 = config;
+        self
+    }
+
+    // Saves the most used design docs as they're registered and, once
+    // run_server has warmed the pool, registers those saved by the last run
+    // on every pool worker before the server is ready
+    pub fn with_checkpoint(mut self, config: CheckpointConfig) -> MakeService {
+        self.checkpoint = Some(config);
+        self
+    }
+
+    // Has run_server bind each TCP address acceptors times with SO_REUSEPORT
+    // and accept on every socket at once, for when a single acceptor can't
+    // keep up with new connections. The kernel spreads connections over
+    // them. 1 binds once, without SO_REUSEPORT.
+    pub fn with_acceptors(mut self, acceptors: usize) -> MakeService {
+        self.acceptors = acceptors.max(1);
+        self
+    }
+
+    // Has run_server's TCP listeners, the admin one included, serve HTTPS.
+    // Unix sockets stay plain.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, tls: TlsConfig) -> MakeService {
+        self.tls = Some(tls);
+        self
+    }
+
+    // Moves /stats and /admin/ off the listeners run_server is given onto
+    // config.bind, where every request needs config.token
+    pub fn with_admin(mut self, config: AdminConfig) -> MakeService {
+        self.admin = Some(config);
+        self.admin_access = AdminAccess::Hidden;
+        self
+    }
+
+    // What serves the admin listener, sharing everything with self, if
+    // there's to be one. The admin token stands in for the Execute token
+    // there, so the admin UI's console works with just the one.
+    pub fn admin(&self) -> Option<MakeService> {
+        let config = self.admin.as_ref()?;
+        Some(MakeService {
+            admin_access: AdminAccess::Token(Arc::new(config.token.clone())),
+            execute_token: None,
+            ..self.clone()
+        })
+    }
+
+    // Has /Ateles/ and /ws answer 401 with an UNAUTHORIZED JsResponse unless
+    // the request has token, as a bearer Authorization header or the
+    // access_token query parameter
+    pub fn with_execute_token(mut self, token: String) -> MakeService {
+        self.execute_token = Some(Arc::new(token));
+        self
+    }
+
+    // What serves gRPC health checks, probing the pool's workers like /ready
+    // does. Without a pool connections have workers of their own so it gets
+    // one too, from the same snapshot and with the same capabilities.
+    pub fn health_service(&self) -> HealthService {
+        let workers = match &self.pool {
+            None => Workers::Pinned(create_js_env(&self.js_env, &self.capabilities)),
+            Some((pool, _)) => Workers::Shared(pool.clone()),
+        };
+        HealthService::new(workers)
+    }
+
+    fn connect(&self, peer: String, family: &'static str) -> Svc {
+        let workers = match &self.pool {
+            None => Workers::Pinned(create_js_env(&self.js_env, &self.capabilities)),
+            Some((pool, Affinity::Connection)) => Workers::Assigned(pool.clone(), pool.next()),
+            Some((pool, Affinity::Request)) => Workers::Shared(pool.clone()),
+            Some((pool, Affinity::Stream)) => Workers::Streams(pool.clone()),
+        };
+        Svc {
+            workers,
+            sessions: self.sessions.clone(),
+            harnesses: self.harnesses.clone(),
+            stats: Arc::new(ConnStats::new(peer, family)),
+            shadow: self.shadow.as_ref().and_then(|shadow| shadow.connect()),
+            mirror: self.mirror.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            result_cache: self.result_cache.clone(),
+            access_log: self.access_log.clone(),
+            audit_log: self.audit_log.clone(),
+            tracer: self.tracer.clone(),
+            compress_min: self.compress_min,
+            max_body: self.max_body,
+            emit_limits: self.capabilities.emit_limits(),
+            admin: self.admin_access.clone(),
+            execute_token: self.execute_token.clone(),
         }
     }
 }
 
-impl<T> Service<T> for MakeService {
+impl<'a> Service<&'a AddrStream> for MakeService {
+    type Response = Svc;
+    type Error = std::io::Error;
+    type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, conn: &'a AddrStream) -> Self::Future {
+        let peer = listen::peer_addr(conn.remote_addr());
+        future::ok(self.connect(peer.to_string(), listen::family(&peer)))
+    }
+}
+
+#[cfg(feature = "tls")]
+impl<'a> Service<&'a tls::TlsStream<tokio::net::TcpStream>> for MakeService {
     type Response = Svc;
     type Error = std::io::Error;
     type Future = future::Ready<Result<Self::Response, Self::Error>>;
@@ -113,14 +2608,285 @@ impl<T> Service<T> for MakeService {
         Ok(()).into()
     }
 
-    fn call(&mut self, _: T) -> Self::Future {
-        let svc = Svc {
-            js_client: create_js_env(&self.js_env),
+    fn call(&mut self, conn: &'a tls::TlsStream<tokio::net::TcpStream>) -> Self::Future {
+        let peer = match conn.get_ref().0.peer_addr() {
+            Ok(addr) => listen::peer_addr(addr),
+            Err(err) => return future::err(err),
         };
-        future::ok(svc)
+        future::ok(self.connect(peer.to_string(), listen::family(&peer)))
+    }
+}
+
+impl<'a> Service<&'a UnixStream> for MakeService {
+    type Response = Svc;
+    type Error = std::io::Error;
+    type Future = future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, _conn: &'a UnixStream) -> Self::Future {
+        // Unix socket clients are almost always unnamed
+        future::ok(self.connect("unix socket".to_string(), "unix"))
+    }
+}
+
+// Serves fortuna from another program with settings of its own, e.g.
+//
+//   fortuna::init_v8();
+//   ServerBuilder::new()
+//       .bind("127.0.0.1:8444".parse()?)
+//       .pool_size(8)
+//       .max_body(16 * 1024 * 1024)
+//       .timeout(Duration::from_secs(5))
+//       .tls(TlsConfig::from_pem_files(&cert, &key)?)
+//       .build()
+//       .await?;
+//
+// tls needs the tls feature. Anything else MakeService can be set up with
+// goes through service. The future runs the server like run_server does,
+// until a listener fails.
+pub struct ServerBuilder {
+    binds: Vec<Bind>,
+    v6_only: bool,
+    make_service: Option<MakeService>,
+    pool: Option<(usize, Affinity)>,
+    max_body: Option<usize>,
+    timeout: Option<Duration>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+}
+
+impl ServerBuilder {
+    pub fn new() -> ServerBuilder {
+        ServerBuilder {
+            binds: Vec::new(),
+            v6_only: false,
+            make_service: None,
+            pool: None,
+            max_body: None,
+            timeout: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    // Listens on bind too, a TCP address or unix: socket. At least one is
+    // needed.
+    pub fn bind(mut self, bind: Bind) -> ServerBuilder {
+        self.binds.push(bind);
+        self
+    }
+
+    // IPv6 listeners don't also accept IPv4 connections
+    pub fn v6_only(mut self, v6_only: bool) -> ServerBuilder {
+        self.v6_only = v6_only;
+        self
+    }
+
+    // Serves make_service rather than MakeService::new(), for the settings
+    // this has no method for
+    pub fn service(mut self, make_service: MakeService) -> ServerBuilder {
+        self.make_service = Some(make_service);
+        self
+    }
+
+    // Shares size workers between every connection, see
+    // MakeService::with_pool, rather than giving each connection its own
+    pub fn pool_size(self, size: usize) -> ServerBuilder {
+        self.pool(size, Affinity::Connection)
+    }
+
+    pub fn pool(mut self, size: usize, affinity: Affinity) -> ServerBuilder {
+        self.pool = Some((size, affinity));
+        self
+    }
+
+    // Largest request body accepted, before and after decompression
+    pub fn max_body(mut self, bytes: usize) -> ServerBuilder {
+        self.max_body = Some(bytes);
+        self
+    }
+
+    // Timeout of requests that don't give one. Like default_timeout_ms in
+    // the config file it's the process's, so it applies to every server in
+    // it once built.
+    pub fn timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    // Serves HTTPS on the TCP listeners, see MakeService::with_tls. Needs the
+    // tls feature.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, tls: TlsConfig) -> ServerBuilder {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub fn build(self) -> impl std::future::Future<Output = io::Result<()>> {
+        let mut make_service = self.make_service.unwrap_or_else(MakeService::new);
+        if let Some((size, affinity)) = self.pool {
+            make_service = make_service.with_pool(size, affinity);
+        }
+        if let Some(bytes) = self.max_body {
+            make_service = make_service.with_max_body_size(bytes);
+        }
+        if let Some(timeout) = self.timeout {
+            config::set_default_timeout(Some(timeout));
+        }
+        #[cfg(feature = "tls")]
+        {
+            if let Some(tls) = self.tls {
+                make_service = make_service.with_tls(tls);
+            }
+        }
+        let binds = self.binds;
+        let v6_only = self.v6_only;
+        async move { run_server(&binds, v6_only, make_service).await }
     }
 }
 
-pub fn create_server(addr: &SocketAddr) -> Server<AddrIncoming, MakeService> {
-    Server::bind(&addr).serve(MakeService::new())
+// addr can be IPv4 or IPv6. An IPv6 listener also accepts IPv4 connections
+// unless v6_only is set. ServerBuilder is there for anything more.
+pub fn create_server(
+    addr: &SocketAddr,
+    v6_only: bool,
+) -> io::Result<Server<AddrIncoming, MakeService>> {
+    create_server_with(addr, v6_only, MakeService::new())
+}
+
+pub fn create_server_with(
+    addr: &SocketAddr,
+    v6_only: bool,
+    make_service: MakeService,
+) -> io::Result<Server<AddrIncoming, MakeService>> {
+    serve_tcp(listen::bind(addr, v6_only)?, make_service)
+}
+
+fn serve_tcp(
+    listener: std::net::TcpListener,
+    make_service: MakeService,
+) -> io::Result<Server<AddrIncoming, MakeService>> {
+    let builder =
+        Server::from_tcp(listener).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    let builder = make_service.http2.configure(builder.tcp_nodelay(true));
+    Ok(builder.serve(make_service))
+}
+
+type ServerHandle = tokio::task::JoinHandle<Result<(), hyper::Error>>;
+
+// Serves make_service on listener, over TLS if it has some
+fn spawn_tcp(
+    listener: std::net::TcpListener,
+    make_service: MakeService,
+) -> io::Result<ServerHandle> {
+    #[cfg(feature = "tls")]
+    {
+        if let Some(tls) = make_service.tls.clone() {
+            let incoming = tls::accept(listener, &tls)?;
+            let builder = Server::builder(accept::from_stream(incoming));
+            let builder = make_service.http2.configure(builder);
+            return Ok(tokio::spawn(builder.serve(make_service)));
+        }
+    }
+    Ok(tokio::spawn(serve_tcp(listener, make_service)?))
+}
+
+// Serves make_service on a single listener for bind
+fn spawn_server(bind: &Bind, v6_only: bool, make_service: MakeService) -> io::Result<ServerHandle> {
+    match bind {
+        Bind::Tcp(addr) => spawn_tcp(listen::bind(addr, v6_only)?, make_service),
+        Bind::Unix(path) => {
+            // A socket left behind by an earlier run would make bind fail
+            let _ = fs::remove_file(path);
+            let listener = UnixListener::bind(path)?;
+            let builder = Server::builder(accept::from_stream(listener));
+            let builder = make_service.http2.configure(builder);
+            Ok(tokio::spawn(builder.serve(make_service)))
+        }
+    }
+}
+
+// Serves make_service on every one of binds until one of them fails. They
+// all share make_service's workers.
+pub async fn run_server(
+    binds: &[Bind],
+    v6_only: bool,
+    make_service: MakeService,
+) -> io::Result<()> {
+    // /ready stays not_ready until the pool's isolates are all up, so
+    // nothing is sent this way before they can take it
+    health::set_warming(make_service.pool.is_some());
+
+    let mut servers = Vec::with_capacity(binds.len());
+    for bind in binds {
+        let make_service = make_service.clone();
+        match bind {
+            Bind::Tcp(addr) if make_service.acceptors > 1 => {
+                let listeners = listen::bind_reuse_port(addr, v6_only, make_service.acceptors)?;
+                for listener in listeners {
+                    servers.push(spawn_tcp(listener, make_service.clone())?);
+                }
+                log::info!("{} has {} acceptors", bind, make_service.acceptors);
+            }
+            _ => servers.push(spawn_server(bind, v6_only, make_service)?),
+        }
+        println!("Listening on {}", bind);
+    }
+    if let (Some(config), Some(admin)) = (&make_service.admin, make_service.admin()) {
+        servers.push(spawn_server(&config.bind, v6_only, admin)?);
+        println!("Admin endpoints on {}", config.bind);
+    }
+
+    if servers.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "nothing to listen on",
+        ));
+    }
+
+    let saved = match &make_service.checkpoint {
+        Some(config) => checkpoint::start(config)?,
+        None => Vec::new(),
+    };
+    if let Some((pool, _)) = &make_service.pool {
+        let start = Instant::now();
+        match pool.warm().await {
+            Ok(warmed) => log::info!("warmed {} workers in {:?}", warmed, start.elapsed()),
+            Err(err) => log::warn!("pool warmup failed: {}", err),
+        }
+        if !saved.is_empty() {
+            let start = Instant::now();
+            let ddocs: Vec<(String, String)> = saved
+                .into_iter()
+                .map(|ddoc| (ddoc.signature, ddoc.functions))
+                .collect();
+            let failed = pool.register_ddocs(&ddocs).await;
+            log::info!(
+                "registered {} checkpointed design docs on {} workers in {:?}, {} failed",
+                ddocs.len(),
+                pool.size(),
+                start.elapsed(),
+                failed
+            );
+        }
+        health::set_warming(false);
+    } else if !saved.is_empty() {
+        log::warn!("checkpointed design docs are only registered ahead of time on a pool");
+    }
+
+    // Servers only stop when they fail
+    let (stopped, _, _) = future::select_all(servers).await;
+    match stopped {
+        Ok(result) => result.map_err(|err| io::Error::new(io::ErrorKind::Other, err)),
+        Err(err) => Err(io::Error::new(io::ErrorKind::Other, err)),
+    }
 }
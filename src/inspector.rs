@@ -0,0 +1,489 @@
+use crossbeam::crossbeam_channel::{unbounded, Receiver, Sender};
+use lazy_static::lazy_static;
+use rusty_v8 as v8;
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use v8::inspector::{
+    ChannelBase, ChannelImpl, StringBuffer, StringView, V8Inspector, V8InspectorClientBase,
+    V8InspectorClientImpl, V8InspectorSession,
+};
+
+use crate::threads;
+use crate::websocket::{self, Decoder};
+
+pub use crate::websocket::accept_key;
+
+// Every context of an isolate is in the same group, a session sees them all
+const CONTEXT_GROUP_ID: i32 = 1;
+
+// Largest protocol message taken from DevTools
+const MAX_MESSAGE: u64 = 64 * 1024 * 1024;
+
+// What the DevTools connection sends the inspected worker
+#[derive(Debug)]
+pub enum Inbound {
+    // A connection was opened, protocol messages for it go to the sender
+    Connect(Sender<String>),
+    Message(String),
+    Disconnect,
+}
+
+#[derive(Clone, Debug)]
+pub struct InspectConfig {
+    pub addr: SocketAddr,
+    // Id of the worker to inspect, workers are numbered from 1 as they
+    // start
+    pub worker: usize,
+}
+
+struct Target {
+    worker: usize,
+    addr: SocketAddr,
+    inbound: Receiver<Inbound>,
+}
+
+lazy_static! {
+    static ref TARGET: Mutex<Option<Target>> = Mutex::new(None);
+}
+
+// Only one DevTools session at a time
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+
+// Serves the DevTools protocol for config.worker on config.addr. Returns the
+// address it's listening on, which has the actual port if config.addr's was
+// 0.
+pub fn start(config: &InspectConfig) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(config.addr)?;
+    let addr = listener.local_addr()?;
+    let (sender, receiver) = unbounded();
+    *TARGET.lock().unwrap() = Some(Target {
+        worker: config.worker,
+        addr,
+        inbound: receiver,
+    });
+    let worker = config.worker;
+    threads::spawn("fortuna-inspect".to_string(), move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    log::warn!("inspector accept failed: {}", err);
+                    continue;
+                }
+            };
+            let sender = sender.clone();
+            let spawned = threads::spawn("fortuna-inspect-conn".to_string(), move || {
+                if let Err(err) = serve(stream, addr, worker, sender) {
+                    log::warn!("inspector connection failed: {}", err);
+                }
+            });
+            if let Err(err) = spawned {
+                log::warn!("can't start an inspector connection: {}", err);
+            }
+        }
+    })?;
+    Ok(addr)
+}
+
+// The inbound messages for worker if it's the one being inspected. Called
+// each time the worker creates an isolate.
+pub fn claim(worker: usize) -> Option<Receiver<Inbound>> {
+    match TARGET.lock().unwrap().as_ref() {
+        Some(target) if target.worker == worker => Some(target.inbound.clone()),
+        _ => None,
+    }
+}
+
+// Where DevTools connects to, if a worker is being inspected
+pub fn websocket_url() -> Option<String> {
+    TARGET
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|target| format!("ws://{}/ws", target.addr))
+}
+
+// Answers the discovery requests chrome://inspect makes and hands a
+// websocket on /ws over to the worker
+fn serve(
+    stream: TcpStream,
+    addr: SocketAddr,
+    worker: usize,
+    inbound: Sender<Inbound>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+    let mut key = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        if name == "sec-websocket-key" {
+            key = parts.next().map(|value| value.trim().to_string());
+        }
+    }
+
+    let ws = format!("{}/ws", addr);
+    let mut out = stream;
+    match (path, key) {
+        ("/json", _) | ("/json/list", _) => {
+            let targets = json!([{
+                "description": format!("fortuna worker {}", worker),
+                "devtoolsFrontendUrl": format!(
+                    "devtools://devtools/bundled/js_app.html?experiments=true&v8only=true&ws={}",
+                    ws
+                ),
+                "id": format!("fortuna-worker-{}", worker),
+                "title": format!("fortuna worker {}", worker),
+                "type": "node",
+                "url": format!("fortuna://worker/{}", worker),
+                "webSocketDebuggerUrl": format!("ws://{}", ws),
+            }]);
+            respond(&mut out, "200 OK", &targets.to_string())
+        }
+        ("/json/version", _) => {
+            let version = json!({
+                "Browser": format!("fortuna/{}", env!("CARGO_PKG_VERSION")),
+                "Protocol-Version": "1.3",
+                "V8-Version": v8::V8::get_version(),
+            });
+            respond(&mut out, "200 OK", &version.to_string())
+        }
+        ("/ws", Some(key)) => {
+            if CONNECTED.swap(true, Ordering::SeqCst) {
+                return respond(&mut out, "409 Conflict", "already being inspected\n");
+            }
+            let session = websocket(reader, out, &key, &inbound);
+            let _ = inbound.send(Inbound::Disconnect);
+            CONNECTED.store(false, Ordering::SeqCst);
+            session
+        }
+        _ => respond(&mut out, "404 Not Found", "not found\n"),
+    }
+}
+
+fn respond(out: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    write!(
+        out,
+        "HTTP/1.1 {}\r\nContent-Type: application/json; charset=UTF-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+fn websocket(
+    mut reader: BufReader<TcpStream>,
+    mut out: TcpStream,
+    key: &str,
+    inbound: &Sender<Inbound>,
+) -> io::Result<()> {
+    write!(
+        out,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    )?;
+
+    // The worker's replies are written from a thread of their own so a
+    // slow DevTools never holds up the worker
+    let out = Arc::new(Mutex::new(out));
+    let (outbound, replies) = unbounded::<String>();
+    let writer = out.clone();
+    threads::spawn("fortuna-inspect-out".to_string(), move || {
+        for reply in replies.iter() {
+            let mut out = writer.lock().unwrap();
+            if write_frame(&mut *out, websocket::TEXT, reply.as_bytes()).is_err() {
+                break;
+            }
+        }
+    })?;
+    let _ = inbound.send(Inbound::Connect(outbound));
+
+    let mut decoder = Decoder::new(MAX_MESSAGE);
+    let mut chunk = [0u8; 8192];
+    let mut message = Vec::new();
+    loop {
+        let frame = match decoder.next_frame()? {
+            Some(frame) => frame,
+            None => {
+                let read = reader.read(&mut chunk)?;
+                if read == 0 {
+                    return Ok(());
+                }
+                decoder.extend(&chunk[..read]);
+                continue;
+            }
+        };
+        match frame.opcode {
+            websocket::CONTINUATION | websocket::TEXT => {
+                message.extend_from_slice(&frame.payload);
+                if message.len() as u64 > MAX_MESSAGE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "message too large",
+                    ));
+                }
+                if frame.fin {
+                    let text = String::from_utf8_lossy(&message).into_owned();
+                    message.clear();
+                    if inbound.send(Inbound::Message(text)).is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+            websocket::CLOSE => {
+                let _ = write_frame(&mut *out.lock().unwrap(), websocket::CLOSE, &frame.payload);
+                return Ok(());
+            }
+            websocket::PING => {
+                write_frame(&mut *out.lock().unwrap(), websocket::PONG, &frame.payload)?
+            }
+            _ => (),
+        }
+    }
+}
+
+fn write_frame<W: Write>(out: &mut W, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    out.write_all(&websocket::encode_frame(opcode, payload))?;
+    out.flush()
+}
+
+// V8's inspector attached to an isolate. V8 holds on to pointers to this and
+// its sessions so they're all boxed and never move.
+pub(crate) struct Inspector {
+    // Dropped in this order: the sessions, the inspector and then the
+    // client they point to
+    session: Option<Box<Session>>,
+    // The server's own, for the CPU and heap profilers, along with where
+    // its messages end up
+    local: Option<(Box<Session>, Receiver<String>)>,
+    inspector: Option<v8::UniqueRef<V8Inspector>>,
+    base: V8InspectorClientBase,
+    // None unless DevTools is inspecting the isolate
+    inbound: Option<Receiver<Inbound>>,
+    paused: bool,
+    profiling: bool,
+}
+
+impl Inspector {
+    pub(crate) fn new(
+        isolate: &mut v8::Isolate,
+        inbound: Option<Receiver<Inbound>>,
+    ) -> Box<Inspector> {
+        let mut inspector = Box::new(Inspector {
+            session: None,
+            local: None,
+            inspector: None,
+            base: V8InspectorClientBase::new::<Inspector>(),
+            inbound,
+            paused: false,
+            profiling: false,
+        });
+        let v8_inspector = V8Inspector::create(isolate, &mut *inspector);
+        inspector.inspector = Some(v8_inspector);
+        inspector
+    }
+
+    pub(crate) fn set_inbound(&mut self, inbound: Receiver<Inbound>) {
+        self.inbound = Some(inbound);
+    }
+
+    // Makes context's scripts visible to DevTools
+    pub(crate) fn context_created(&mut self, context: v8::Local<v8::Context>, name: &str) {
+        let name = StringView::from(name.as_bytes());
+        if let Some(inspector) = self.inspector.as_mut() {
+            inspector.context_created(context, CONTEXT_GROUP_ID, name);
+        }
+    }
+
+    fn connect(&mut self, outbound: Sender<String>) -> Box<Session> {
+        let mut session = Box::new(Session {
+            v8_session: None,
+            base: ChannelBase::new::<Session>(),
+            outbound,
+        });
+        if let Some(inspector) = self.inspector.as_mut() {
+            let state = StringView::empty();
+            session.v8_session = Some(inspector.connect(CONTEXT_GROUP_ID, &mut *session, &state));
+        }
+        session
+    }
+
+    pub(crate) fn handle(&mut self, inbound: Inbound) {
+        match inbound {
+            Inbound::Connect(outbound) => {
+                self.session = None;
+                self.session = Some(self.connect(outbound));
+            }
+            Inbound::Message(message) => {
+                if let Some(session) = self.session.as_mut() {
+                    session.dispatch(&message);
+                }
+            }
+            // Also lets a paused script carry on
+            Inbound::Disconnect => {
+                self.session = None;
+                self.paused = false;
+            }
+        }
+    }
+
+    // Samples what the isolate runs, every interval, until stop_profiling
+    pub(crate) fn start_profiling(&mut self, interval: Duration) -> Result<(), String> {
+        if self.profiling {
+            return Err("already profiling".to_string());
+        }
+        let interval = interval.as_micros() as u64;
+        self.call("Profiler.enable", json!({}), |_, _| ())?;
+        self.call(
+            "Profiler.setSamplingInterval",
+            json!({ "interval": interval }),
+            |_, _| (),
+        )?;
+        self.call("Profiler.start", json!({}), |_, _| ())?;
+        self.profiling = true;
+        Ok(())
+    }
+
+    // The profile as .cpuprofile JSON
+    pub(crate) fn stop_profiling(&mut self) -> Result<String, String> {
+        if !self.profiling {
+            return Err("not profiling".to_string());
+        }
+        self.profiling = false;
+        let mut stopped = self.call("Profiler.stop", json!({}), |_, _| ())?;
+        Ok(stopped["profile"].take().to_string())
+    }
+
+    // Hands over a .heapsnapshot of the isolate a chunk at a time
+    pub(crate) fn heap_snapshot(&mut self, mut chunk: impl FnMut(String)) -> Result<(), String> {
+        let params = json!({ "reportProgress": false });
+        self.call(
+            "HeapProfiler.takeHeapSnapshot",
+            params,
+            |method, mut params| {
+                if method == "HeapProfiler.addHeapSnapshotChunk" {
+                    if let Value::String(data) = params["chunk"].take() {
+                        chunk(data);
+                    }
+                }
+            },
+        )?;
+        Ok(())
+    }
+
+    // Runs a protocol method on the local session and returns its result.
+    // The session answers before dispatching returns, notifications it sent
+    // on the way are passed to notified.
+    fn call(
+        &mut self,
+        method: &str,
+        params: Value,
+        mut notified: impl FnMut(&str, Value),
+    ) -> Result<Value, String> {
+        if self.local.is_none() {
+            let (outbound, messages) = unbounded();
+            let session = self.connect(outbound);
+            self.local = Some((session, messages));
+        }
+        let (session, messages) = self.local.as_mut().unwrap();
+        let message = json!({ "id": 1, "method": method, "params": params });
+        session.dispatch(&message.to_string());
+        for message in messages.try_iter() {
+            let mut message: Value =
+                serde_json::from_str(&message).map_err(|err| err.to_string())?;
+            if message["id"] == 1 {
+                return match message.get("error") {
+                    Some(error) => Err(format!("{} failed: {}", method, error)),
+                    None => Ok(message["result"].take()),
+                };
+            }
+            if let Some(notification) = message["method"].as_str() {
+                let notification = notification.to_string();
+                notified(&notification, message["params"].take());
+            }
+        }
+        Err(format!("{} wasn't answered", method))
+    }
+}
+
+impl V8InspectorClientImpl for Inspector {
+    fn base(&self) -> &V8InspectorClientBase {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut V8InspectorClientBase {
+        &mut self.base
+    }
+
+    // Called on the worker thread when a script hits a breakpoint. The
+    // worker is stuck here, serving DevTools, until it's told to resume.
+    fn run_message_loop_on_pause(&mut self, _context_group_id: i32) {
+        let inbound = match self.inbound.clone() {
+            Some(inbound) => inbound,
+            None => return,
+        };
+        self.paused = true;
+        while self.paused {
+            match inbound.recv() {
+                Ok(inbound) => self.handle(inbound),
+                Err(_) => break,
+            }
+        }
+    }
+
+    fn quit_message_loop_on_pause(&mut self) {
+        self.paused = false;
+    }
+
+    fn run_if_waiting_for_debugger(&mut self, _context_group_id: i32) {}
+}
+
+struct Session {
+    v8_session: Option<v8::UniqueRef<V8InspectorSession>>,
+    base: ChannelBase,
+    outbound: Sender<String>,
+}
+
+impl Session {
+    fn dispatch(&mut self, message: &str) {
+        if let Some(v8_session) = self.v8_session.as_mut() {
+            let message = StringView::from(message.as_bytes());
+            v8_session.dispatch_protocol_message(&message);
+        }
+    }
+
+    fn send(&self, message: v8::UniquePtr<StringBuffer>) {
+        let message = message.unwrap().string().to_string();
+        let _ = self.outbound.send(message);
+    }
+}
+
+impl ChannelImpl for Session {
+    fn base(&self) -> &ChannelBase {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut ChannelBase {
+        &mut self.base
+    }
+
+    fn send_response(&mut self, _call_id: i32, message: v8::UniquePtr<StringBuffer>) {
+        self.send(message);
+    }
+
+    fn send_notification(&mut self, message: v8::UniquePtr<StringBuffer>) {
+        self.send(message);
+    }
+
+    fn flush_protocol_notifications(&mut self) {}
+}
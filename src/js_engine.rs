@@ -1,90 +1,404 @@
+use crossbeam::crossbeam_channel::Receiver;
+use lazy_static::lazy_static;
 use rusty_v8 as v8;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-// This is created in build.rs and is all the required js code added into
-// a byte array
+use crate::affinity;
+use crate::config;
+use crate::design_docs::{self, DesignDoc};
+use crate::determinism;
+use crate::error::{ErrorInfo, JSError, JSResult};
+use crate::gc::{self, GcLog};
+use crate::host_functions::{self, Capabilities};
+use crate::inspector::{Inbound, Inspector};
+use crate::js_server::{self, HeapStats};
+use crate::metrics;
+use crate::modules;
+use crate::rewrite_cache::RewriteCache;
+use crate::semaphore::Semaphore;
+use crate::v8_config::{self, SecurityMode};
+
+// This is created in build.rs and lists every file in js/ as (name, source)
+// in JS_FILES and every ES module in js/modules/ in JS_MODULES. With the
+// prebuilt-snapshot feature PREBUILT_SNAPSHOT is the snapshot of JS_FILES.
 include!(concat!(env!("OUT_DIR"), "/js_startup_code.rs"));
 
+// The js/ files the runtime can't do without
+const REQUIRED_JS_FILES: &[&str] = &["escodegen.js", "esprima.js", "rewrite_anon_fun.js"];
+
+// Globals those files must have defined once they've been loaded
+const REQUIRED_GLOBALS: &[&str] = &["esprima", "escodegen", "rewriteFun", "rewriteFuns"];
+
+// Run after JS_FILES when creating the snapshot. Strips globals we don't want
+// user code to have, freezes the intrinsics and locks the runtime functions.
+const HARDEN_JS: &str = include_str!("../js/sandbox/harden.js");
+// CouchDB's view server globals, run again in each design doc's context
+const VIEW_SERVER_JS: &str = include_str!("../js/view_server.js");
+
+// Calls a reduce function the way CouchDB does. input is the [key, value]
+// rows to reduce, or the values to rereduce.
+const REDUCE_JS: &str = "(fun, input, rereduce) => rereduce
+    ? fun(null, input, true)
+    : fun(input.map((row) => row[0]), input.map((row) => row[1]), false)";
+
+// Compiles and instantiates a WebAssembly module given as hex. Only contexts
+// granted the wasm capability have WebAssembly.
+const WASM_INSTANTIATE_JS: &str = "(hex) => {
+    if (typeof WebAssembly === 'undefined') {
+        throw new Error('the wasm capability is not granted');
+    }
+    const bytes = new Uint8Array(hex.length / 2);
+    for (let i = 0; i < bytes.length; i++) {
+        bytes[i] = parseInt(hex.substr(i * 2, 2), 16);
+    }
+    return new WebAssembly.Instance(new WebAssembly.Module(bytes), {}).exports;
+}";
+
+// WebAssembly instances kept per isolate. Past this the oldest is dropped.
+const MAX_WASM_INSTANCES: usize = 16;
+
 // TODO: Handle errors properly
 
+// Building a snapshot needs a whole extra isolate plus the serialized blob so
+// only allow this many at once. Anyone else asking for a snapshot waits their
+// turn.
+const MAX_SNAPSHOT_CREATORS: usize = 1;
+
+// Design doc contexts over every isolate, for max_contexts
+static DESIGN_DOC_CONTEXTS: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref SNAPSHOT_CREATORS: Semaphore = Semaphore::new(MAX_SNAPSHOT_CREATORS);
+}
+
 pub struct FortunaIsolate {
     isolate: v8::OwnedIsolate,
+    handle: v8::IsolateHandle,
     global_context: v8::Global<v8::Context>,
+    // Granted to the global context and to each design doc's context
+    caps: Capabilities,
+    // By signature, see register_ddoc
+    design_docs: HashMap<String, DesignDoc>,
+    // Counts design docs being registered or run, see DesignDoc::used
+    ddoc_tick: u64,
+    // The exports of each instantiated WebAssembly module by its hash,
+    // oldest first
+    wasm_instances: Vec<(String, v8::Global<v8::Object>)>,
+    // Must be dropped after the isolate since V8 holds a pointer to it
+    gc_log: Arc<GcLog>,
+    // Attached for DevTools or the CPU profiler, the first time either is
+    // wanted
+    inspector: Option<Box<Inspector>>,
 }
 
 pub struct JSEnv {
     pub startup_data: Vec<u8>,
+    // Shared by every worker started from startup_data
+    pub rewrite_cache: Arc<RewriteCache>,
+}
+
+// What it takes to start a worker, see JSEnv::measure_startup
+#[derive(Clone, Debug)]
+pub struct StartupCost {
+    pub snapshot_bytes: usize,
+    pub create: Duration,
+    pub first_eval: Duration,
 }
 
-pub fn print() {
-    println!("hello");
+// A file from js/ that is loaded into every snapshot
+#[derive(Clone, Debug)]
+pub struct BundleFile {
+    pub name: &'static str,
+    pub bytes: usize,
+    pub sha256: String,
 }
 
-// fn print_callback(
-//     scope: v8::FunctionCallbackScope,
-//     args: v8::FunctionCallbackArguments,
-//     mut rv: v8::ReturnValue,
-// ) {
-//     for i in 0..args.length() {
-//         let arg1 = args.get(i).to_string(scope).unwrap();
-//         println!("{:?}", arg1.to_rust_string_lossy(scope));
-//     }
-//     rv.set(v8::Boolean::new(scope, true).into())
-// }
+// A doc to map: JSON for the isolate to parse, or a doc already parsed in
+// Rust, e.g. those of a MAP_DOCS batch, which is built straight into V8
+// values without going through JSON.parse
+#[derive(Clone, Copy, Debug)]
+pub enum Doc<'a> {
+    Json(&'a str),
+    Value(&'a serde_json::Value),
+}
+
+pub fn bundle_manifest() -> Vec<BundleFile> {
+    JS_FILES
+        .iter()
+        .map(|(name, source)| BundleFile {
+            name,
+            bytes: source.len(),
+            sha256: Sha256::digest(source.as_bytes())
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+        })
+        .collect()
+}
+
+// Checks the js/ bundle that was built in has everything the runtime needs.
+// Only looks at the files, create_startup_data checks what they define.
+pub fn check_bundle() -> Result<(), String> {
+    check_files(JS_FILES)
+}
+
+fn check_files(files: &[(&str, &str)]) -> Result<(), String> {
+    let missing: Vec<&str> = REQUIRED_JS_FILES
+        .iter()
+        .filter(|required| {
+            !files
+                .iter()
+                .any(|(name, source)| name == *required && !source.trim().is_empty())
+        })
+        .copied()
+        .collect();
+
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let loaded: Vec<&str> = files.iter().map(|(name, _)| *name).collect();
+    Err(format!(
+        "the JS bundle is missing or has empty {}. Expected {} in js/ but the build found [{}]",
+        missing.join(", "),
+        REQUIRED_JS_FILES.join(", "),
+        loaded.join(", ")
+    ))
+}
+
+// Whether the snapshot was built along with the binary rather than at
+// startup
+pub fn snapshot_prebuilt() -> bool {
+    PREBUILT_SNAPSHOT.is_some()
+}
+
+// Modules are loaded into each isolate rather than the snapshot so try them
+// once to fail early
+fn check_modules(startup_data: &[u8]) -> Result<(), String> {
+    if JS_MODULES.is_empty() {
+        return Ok(());
+    }
+    let mut isolate = FortunaIsolate::create_isolate(startup_data.to_vec(), &Capabilities::none());
+    isolate
+        .load_modules(JS_MODULES)
+        .map_err(|err| format!("failed to load js/modules: {}", err))
+}
 
 impl JSEnv {
+    // Panics if the JS bundle is unusable, see try_new
     pub fn new() -> JSEnv {
-        let startup_data = JSEnv::create_startup_data();
-        JSEnv {
+        JSEnv::try_new().unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    // Fails rather than handing out isolates whose runtime functions don't
+    // exist
+    pub fn try_new() -> Result<JSEnv, String> {
+        let js_env = match PREBUILT_SNAPSHOT {
+            Some(snapshot) => JSEnv::from_prebuilt(snapshot)?,
+            None => JSEnv::from_files(JS_FILES)?,
+        };
+        metrics::SNAPSHOT_BYTES.set(js_env.startup_data.len() as f64);
+        Ok(js_env)
+    }
+
+    // Builds the snapshot from the .js files in dir instead of the bundle
+    // that was built in, e.g. to try out a new version of the runtime JS.
+    // The files are loaded in name order like the ones in js/.
+    pub fn from_dir(dir: &Path) -> Result<JSEnv, String> {
+        let read_err = |err: io::Error| format!("failed to read {}: {}", dir.display(), err);
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir).map_err(read_err)? {
+            let path = entry.map_err(read_err)?.path();
+            if !path.is_file() || path.extension() != Some(OsStr::new("js")) {
+                continue;
+            }
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            let source = fs::read_to_string(&path).map_err(read_err)?;
+            files.push((name, source));
+        }
+        files.sort();
+
+        let files: Vec<(&str, &str)> = files
+            .iter()
+            .map(|(name, source)| (name.as_str(), source.as_str()))
+            .collect();
+        JSEnv::from_files(&files)
+    }
+
+    fn from_files(files: &[(&str, &str)]) -> Result<JSEnv, String> {
+        check_files(files)?;
+        let startup_data = JSEnv::create_startup_data(files)?;
+        check_modules(&startup_data)?;
+
+        Ok(JSEnv {
             startup_data: startup_data.to_vec(),
+            rewrite_cache: Arc::new(RewriteCache::default()),
+        })
+    }
+
+    // The snapshot build.rs made from JS_FILES. The bundle ran there but
+    // wasn't checked for the globals the runtime needs.
+    fn from_prebuilt(snapshot: &[u8]) -> Result<JSEnv, String> {
+        check_files(JS_FILES)?;
+        let mut isolate = FortunaIsolate::create_isolate(snapshot.to_vec(), &Capabilities::none());
+        let missing: Vec<&str> = REQUIRED_GLOBALS
+            .iter()
+            .filter(|name| {
+                let script = format!("typeof {} !== 'undefined';", name);
+                match isolate.eval(&script, &[]) {
+                    Ok(defined) => defined != "true",
+                    Err(_) => true,
+                }
+            })
+            .copied()
+            .collect();
+        if !missing.is_empty() {
+            return Err(format!(
+                "the prebuilt snapshot doesn't define {}",
+                missing.join(", ")
+            ));
+        }
+        check_modules(snapshot)?;
+
+        Ok(JSEnv {
+            startup_data: snapshot.to_vec(),
+            rewrite_cache: Arc::new(RewriteCache::default()),
+        })
+    }
+
+    // Creates an isolate and runs a first eval on it to see how long a new
+    // worker takes to get going with this snapshot
+    pub fn measure_startup(&self) -> StartupCost {
+        let start = Instant::now();
+        let mut isolate = self.create_isolate_with_capabilities(&Capabilities::none());
+        let create = start.elapsed();
+
+        let start = Instant::now();
+        let _ = isolate.eval("1;", &[]);
+        StartupCost {
+            snapshot_bytes: self.startup_data.len(),
+            create,
+            first_eval: start.elapsed(),
         }
     }
 
+    pub fn create_isolate(&self) -> FortunaIsolate {
+        FortunaIsolate::new_from_snapshot(self.startup_data.as_slice())
+    }
+
+    pub fn create_isolate_with_capabilities(&self, caps: &Capabilities) -> FortunaIsolate {
+        FortunaIsolate::new_from_snapshot_with_capabilities(self.startup_data.as_slice(), caps)
+    }
+
     // adapted from Deno https://github.com/denoland/rusty_v8/blob/master/tests/test_api.rs#L1714
-    fn create_startup_data() -> v8::StartupData {
+    fn create_startup_data(files: &[(&str, &str)]) -> Result<v8::StartupData, String> {
+        let _permit = SNAPSHOT_CREATORS.acquire();
         let mut snapshot_creator = v8::SnapshotCreator::new(None);
         {
             // TODO(ry) this shouldn't be necessary. workaround unfinished business in
             // the scope type system.
             let mut isolate = unsafe { snapshot_creator.get_owned_isolate() };
 
-            let mut hs = v8::HandleScope::new(&mut isolate);
-            let scope = hs.enter();
-
-            let context = v8::Context::new(scope);
-            let mut cs = v8::ContextScope::new(scope, context);
-            let scope = cs.enter();
-            let source = v8::String::new(scope, JS_CODE).unwrap();
-            let mut script = v8::Script::compile(scope, context, source, None).unwrap();
-            script.run(scope, context).unwrap();
-
-            snapshot_creator.set_default_context(context);
+            let loaded = JSEnv::load_bundle(&mut isolate, &mut snapshot_creator, files);
             std::mem::forget(isolate); // TODO(ry) this shouldn't be necessary.
+            loaded?;
         }
 
         snapshot_creator
             .create_blob(v8::FunctionCodeHandling::Clear)
-            .unwrap()
+            .ok_or_else(|| "failed to create the snapshot".to_string())
+    }
+
+    // Runs the bundle and then the hardening script in a new context and
+    // makes it the snapshot's default context
+    fn load_bundle(
+        isolate: &mut v8::OwnedIsolate,
+        snapshot_creator: &mut v8::SnapshotCreator,
+        files: &[(&str, &str)],
+    ) -> Result<(), String> {
+        let mut hs = v8::HandleScope::new(isolate);
+        let scope = hs.enter();
+
+        let context = v8::Context::new(scope);
+        let mut cs = v8::ContextScope::new(scope, context);
+        let scope = cs.enter();
+        let mut try_catch = v8::TryCatch::new(scope);
+        let tc = try_catch.enter();
+
+        let files = files.iter().chain(&[("sandbox/harden.js", HARDEN_JS)]);
+        for (name, code) in files {
+            let source = v8::String::new(scope, code).unwrap();
+            let ran = v8::Script::compile(scope, context, source, None)
+                .and_then(|mut script| script.run(scope, context));
+            if ran.is_none() {
+                let info = error_info(scope, context, tc);
+                return Err(format!(
+                    "failed to load js/{}: {}: {} (line {})",
+                    name, info.name, info.message, info.line
+                ));
+            }
+        }
+
+        let global = context.global(scope);
+        let missing: Vec<&str> = REQUIRED_GLOBALS
+            .iter()
+            .filter(|name| {
+                let key = v8::String::new(scope, name).unwrap();
+                match global.get(scope, context, key.into()) {
+                    Some(value) => value.is_undefined(),
+                    None => true,
+                }
+            })
+            .copied()
+            .collect();
+        if !missing.is_empty() {
+            return Err(format!(
+                "the JS bundle loaded but doesn't define {}",
+                missing.join(", ")
+            ));
+        }
+
+        snapshot_creator.set_default_context(context);
+        Ok(())
     }
 }
 
 impl FortunaIsolate {
     pub fn new_from_snapshot(data: &[u8]) -> FortunaIsolate {
-        // let start = Instant::now();
-        let isolate = FortunaIsolate::create_isolate(data.to_vec());
-        // println!(
-        //     "Time elapsed in to create isolate is: {:?}",
-        //     start.elapsed()
-        // );
+        FortunaIsolate::new_from_snapshot_with_capabilities(data, &Capabilities::all())
+    }
+
+    // Only the host functions granted by caps are installed in the context
+    pub fn new_from_snapshot_with_capabilities(data: &[u8], caps: &Capabilities) -> FortunaIsolate {
+        let start = Instant::now();
+        let mut isolate = FortunaIsolate::create_isolate(data.to_vec(), caps);
+        if !JS_MODULES.is_empty() {
+            if let Err(err) = isolate.load_modules(JS_MODULES) {
+                panic!("failed to load js/modules: {}", err);
+            }
+        }
+        metrics::ISOLATE_CREATE_SECONDS.observe("snapshot", start.elapsed());
         isolate
     }
 
-    fn create_isolate(startup_data: Vec<u8>) -> FortunaIsolate {
+    fn create_isolate(startup_data: Vec<u8>, caps: &Capabilities) -> FortunaIsolate {
         // let safe_obj: v8::PropertyAttribute = v8::DONT_DELETE + v8::DONT_ENUM + v8::READ_ONLY;
 
         let mut global_context = v8::Global::<v8::Context>::new();
-        let create_params = v8::Isolate::create_params().snapshot_blob(startup_data);
+        let create_params = v8_config::create_params().snapshot_blob(startup_data);
         let mut isolate = v8::Isolate::new(create_params);
+        let handle = isolate.thread_safe_handle();
+        let gc_log = Arc::new(GcLog::default());
+        gc::track(&mut isolate, &gc_log);
 
         let mut handle_scope = v8::HandleScope::new(&mut isolate);
         let scope = handle_scope.enter();
@@ -99,71 +413,1061 @@ impl FortunaIsolate {
         // let mut script = v8::Script::compile(scope, context, source, None).unwrap();
         // script.run(scope, context).unwrap();
 
+        let mut cs = v8::ContextScope::new(scope, context);
+        let scope = cs.enter();
+        host_functions::install(scope, context, caps);
+
         global_context.set(scope, context);
 
         FortunaIsolate {
             isolate,
+            handle,
             global_context,
+            caps: caps.clone(),
+            design_docs: HashMap::new(),
+            ddoc_tick: 0,
+            wasm_instances: Vec::new(),
+            gc_log,
+            inspector: None,
+        }
+    }
+
+    pub fn gc_log(&self) -> Arc<GcLog> {
+        self.gc_log.clone()
+    }
+
+    // Bytes of V8 heap currently in use
+    pub fn heap_used(&mut self) -> usize {
+        let mut stats = v8::HeapStatistics::default();
+        self.isolate.get_heap_statistics(&mut stats);
+        stats.used_heap_size()
+    }
+
+    pub fn heap_stats(&mut self) -> HeapStats {
+        let mut stats = v8::HeapStatistics::default();
+        self.isolate.get_heap_statistics(&mut stats);
+        HeapStats {
+            used: stats.used_heap_size(),
+            total: stats.total_heap_size(),
+            limit: stats.heap_size_limit(),
+            contexts: stats.number_of_native_contexts(),
+        }
+    }
+
+    // Lets DevTools, connected through inbound, set breakpoints in and step
+    // through anything this isolate runs from now on
+    pub(crate) fn attach_inspector(&mut self, inbound: Receiver<Inbound>) {
+        self.inspector().set_inbound(inbound);
+    }
+
+    fn inspector(&mut self) -> &mut Inspector {
+        if self.inspector.is_none() {
+            let mut inspector = Inspector::new(&mut self.isolate, None);
+            let mut hs = v8::HandleScope::new(&mut self.isolate);
+            let scope = hs.enter();
+            let context = self.global_context.get(scope).unwrap();
+            inspector.context_created(context, "global");
+            for (signature, ddoc) in self.design_docs.iter() {
+                let context = ddoc.context.get(scope).unwrap();
+                inspector.context_created(context, &format!("ddoc {}", signature));
+            }
+            self.inspector = Some(inspector);
         }
+        self.inspector.as_mut().unwrap()
+    }
+
+    // Starts V8's CPU profiler, sampling every interval
+    pub fn start_profiling(&mut self, interval: Duration) -> Result<(), String> {
+        self.inspector().start_profiling(interval)
     }
 
-    pub fn eval(&mut self, script_str: &str, _args: &[String]) -> String {
-        // println!("script {:?}", script_str);
+    // Writes a .heapsnapshot of the isolate, what Chrome DevTools loads, to
+    // chunk a piece at a time
+    pub fn heap_snapshot(&mut self, chunk: impl FnMut(String)) -> Result<(), String> {
+        self.inspector().heap_snapshot(chunk)
+    }
+
+    // Stops the CPU profiler and returns what it saw as .cpuprofile JSON,
+    // what Chrome DevTools and speedscope load
+    pub fn stop_profiling(&mut self) -> Result<String, String> {
+        match self.inspector.as_mut() {
+            Some(inspector) => inspector.stop_profiling(),
+            None => Err("not profiling".to_string()),
+        }
+    }
+
+    pub(crate) fn inspect(&mut self, inbound: Inbound) {
+        if let Some(inspector) = self.inspector.as_mut() {
+            inspector.handle(inbound);
+        }
+    }
+
+    // Handle that other threads can use to terminate the running script
+    pub fn thread_safe_handle(&self) -> v8::IsolateHandle {
+        self.isolate.thread_safe_handle()
+    }
+
+    pub fn eval(&mut self, script_str: &str, _args: &[String]) -> JSResult {
         let mut hs = v8::HandleScope::new(&mut self.isolate);
         let scope = hs.enter();
         let context = self.global_context.get(scope).unwrap();
         // let context = v8::Context::new(scope);
         let mut cs = v8::ContextScope::new(scope, context);
         let scope = cs.enter();
+        let mut try_catch = v8::TryCatch::new(scope);
+        let tc = try_catch.enter();
+
         let source = v8::String::new(scope, script_str).unwrap();
-        let mut script = v8::Script::compile(scope, context, source, None).unwrap();
-        let result = script.run(scope, context).unwrap();
-        let result_json_string = v8::json::stringify(context, result).unwrap();
+        let mut script = match v8::Script::compile(scope, context, source, None) {
+            Some(script) => script,
+            None => return Err(JSError::CompileError(error_info(scope, context, tc))),
+        };
+        let result = match script.run(scope, context) {
+            Some(result) => result,
+            None => return Err(caught_error(&self.handle, scope, context, tc)),
+        };
+        let result_json_string = match v8::json::stringify(context, result) {
+            Some(json) => json,
+            None => return Err(caught_error(&self.handle, scope, context, tc)),
+        };
         let result_string = result_json_string.to_rust_string_lossy(scope);
-        // println!("result eval: {}", result_string);
 
         if result_string == "undefined" {
-            return "null".to_string();
+            return Ok("null".to_string());
         }
-        result_string
+        Ok(result_string)
     }
 
-    pub fn call(&mut self, raw_fun_name: &str, args: &[String]) -> String {
+    pub fn call(&mut self, raw_fun_name: &str, args: &[String]) -> JSResult {
+        self.call_with(raw_fun_name, args, false)
+    }
 
+    // Like call but each of args is JSON and the function gets the parsed
+    // value instead of the string
+    pub fn call_json(&mut self, raw_fun_name: &str, args: &[String]) -> JSResult {
+        self.call_with(raw_fun_name, args, true)
+    }
+
+    fn call_with(&mut self, raw_fun_name: &str, args: &[String], json: bool) -> JSResult {
         let mut hs = v8::HandleScope::new(&mut self.isolate);
         let scope = hs.enter();
         let context = self.global_context.get(scope).unwrap();
         let mut cs = v8::ContextScope::new(scope, context);
         let scope = cs.enter();
+        let mut try_catch = v8::TryCatch::new(scope);
+        let tc = try_catch.enter();
 
         let global = context.global(scope);
         let name = v8::String::new(scope, raw_fun_name).unwrap();
         let val_func = global.get(scope, context, name.into()).unwrap();
-        let func = v8::Local::<v8::Function>::try_from(val_func).unwrap();
+        let func = match v8::Local::<v8::Function>::try_from(val_func) {
+            Ok(func) => func,
+            Err(_) => {
+                return Err(JSError::RuntimeError(ErrorInfo {
+                    name: "TypeError".to_string(),
+                    message: format!("{} is not a function", raw_fun_name),
+                    ..ErrorInfo::default()
+                }))
+            }
+        };
         let receiver = context.global(scope);
 
-        let val_args: Vec<v8::Local<v8::Value>> = args
-            .iter()
-            .map(|arg| {
-                let v8_arg = v8::String::new(scope, arg).unwrap();
-                v8::Local::<v8::Value>::try_from(v8_arg).unwrap()
-            })
-            .collect();
+        let mut val_args: Vec<v8::Local<v8::Value>> = Vec::with_capacity(args.len());
+        for arg in args {
+            let v8_arg = v8::String::new(scope, arg).unwrap();
+            let val_arg = if json {
+                match v8::json::parse(context, v8_arg) {
+                    Some(val_arg) => val_arg,
+                    None => return Err(caught_error(&self.handle, scope, context, tc)),
+                }
+            } else {
+                v8_arg.into()
+            };
+            val_args.push(val_arg);
+        }
 
-        let resp = func
-            .call(scope, context, receiver.into(), val_args.as_slice())
-            .unwrap();
-        let result = v8::json::stringify(context, resp).unwrap();
+        let resp = match func.call(scope, context, receiver.into(), val_args.as_slice()) {
+            Some(resp) => resp,
+            None => return Err(caught_error(&self.handle, scope, context, tc)),
+        };
+        let result = match v8::json::stringify(context, resp) {
+            Some(json) => json,
+            None => return Err(caught_error(&self.handle, scope, context, tc)),
+        };
         let result_string = result.to_rust_string_lossy(scope);
-        // println!("result: {}", result_string);
-        result_string
+        Ok(result_string)
+    }
+
+    // Runs the reduce function in source over input, a JSON array of rows or
+    // of values when rereducing
+    pub fn reduce(&mut self, source: &str, input: &str, rereduce: bool) -> JSResult {
+        // Reduce functions aren't registered, so they're checked every time
+        let (determinism, clock) = (self.caps.determinism(), self.caps.clock());
+        determinism::check(determinism, clock, "reduce function", source)?;
+
+        let mut hs = v8::HandleScope::new(&mut self.isolate);
+        let scope = hs.enter();
+        let context = self.global_context.get(scope).unwrap();
+        let mut cs = v8::ContextScope::new(scope, context);
+        let scope = cs.enter();
+        let mut try_catch = v8::TryCatch::new(scope);
+        let tc = try_catch.enter();
+
+        let fun = compile_function(&self.handle, scope, context, tc, source, "reduce source")?;
+
+        let input = v8::String::new(scope, input).unwrap();
+        let input = match v8::json::parse(context, input) {
+            Some(input) => input,
+            None => return Err(caught_error(&self.handle, scope, context, tc)),
+        };
+
+        let apply_source = v8::String::new(scope, REDUCE_JS).unwrap();
+        let mut apply_script = v8::Script::compile(scope, context, apply_source, None).unwrap();
+        let apply_value = apply_script.run(scope, context).unwrap();
+        let apply = v8::Local::<v8::Function>::try_from(apply_value).unwrap();
+
+        let rereduce = v8::Boolean::new(scope, rereduce);
+        let receiver = context.global(scope);
+        let resp = match apply.call(
+            scope,
+            context,
+            receiver.into(),
+            &[fun.into(), input, rereduce.into()],
+        ) {
+            Some(resp) => resp,
+            None => return Err(caught_error(&self.handle, scope, context, tc)),
+        };
+        let result = match v8::json::stringify(context, resp) {
+            Some(json) => json,
+            None => return Err(caught_error(&self.handle, scope, context, tc)),
+        };
+        Ok(result.to_rust_string_lossy(scope))
     }
+
+    // Calls fun_name exported by a WebAssembly module. args are the module's
+    // hash, the module as hex and then the JSON of each argument. Modules are
+    // only compiled the first time their hash is seen, after that the cached
+    // instance is called, so its memory lives on between calls.
+    pub fn wasm_call(&mut self, fun_name: &str, args: &[String]) -> JSResult {
+        let (hash, module_hex, args) = match args {
+            [hash, module_hex, args @ ..] => (hash, module_hex, args),
+            _ => return Err(JSError::Internal("wasm call without a module".to_string())),
+        };
+
+        let cached = self.wasm_instances.iter().any(|(h, _)| h == hash);
+        if !cached && self.wasm_instances.len() >= MAX_WASM_INSTANCES {
+            let (_, mut oldest) = self.wasm_instances.remove(0);
+            oldest.reset(&mut *self.isolate);
+        }
+
+        let mut hs = v8::HandleScope::new(&mut self.isolate);
+        let scope = hs.enter();
+        let context = self.global_context.get(scope).unwrap();
+        let mut cs = v8::ContextScope::new(scope, context);
+        let scope = cs.enter();
+        let mut try_catch = v8::TryCatch::new(scope);
+        let tc = try_catch.enter();
+
+        let receiver = context.global(scope);
+        let exports = match self.wasm_instances.iter().find(|(h, _)| h == hash) {
+            Some((_, exports)) => exports.get(scope).unwrap(),
+            None => {
+                let source = v8::String::new(scope, WASM_INSTANTIATE_JS).unwrap();
+                let mut script = v8::Script::compile(scope, context, source, None).unwrap();
+                let instantiate = script.run(scope, context).unwrap();
+                let instantiate = v8::Local::<v8::Function>::try_from(instantiate).unwrap();
+                let module_hex = v8::String::new(scope, module_hex).unwrap();
+                let exports =
+                    match instantiate.call(scope, context, receiver.into(), &[module_hex.into()]) {
+                        Some(exports) => exports.to_object(scope).unwrap(),
+                        None => return Err(caught_error(&self.handle, scope, context, tc)),
+                    };
+                let mut global = v8::Global::new();
+                global.set(scope, exports);
+                self.wasm_instances.push((hash.clone(), global));
+                exports
+            }
+        };
+
+        let name = v8::String::new(scope, fun_name).unwrap();
+        let fun = exports
+            .get(scope, context, name.into())
+            .and_then(|fun| v8::Local::<v8::Function>::try_from(fun).ok());
+        let fun = match fun {
+            Some(fun) => fun,
+            None => {
+                return Err(JSError::RuntimeError(ErrorInfo {
+                    name: "TypeError".to_string(),
+                    message: format!("{} is not an exported function", fun_name),
+                    ..ErrorInfo::default()
+                }))
+            }
+        };
+
+        let mut val_args = Vec::with_capacity(args.len());
+        for arg in args {
+            let arg = v8::String::new(scope, arg).unwrap();
+            match v8::json::parse(context, arg) {
+                Some(arg) => val_args.push(arg),
+                None => return Err(caught_error(&self.handle, scope, context, tc)),
+            }
+        }
+        let result = match fun.call(scope, context, receiver.into(), val_args.as_slice()) {
+            Some(result) => result,
+            None => return Err(caught_error(&self.handle, scope, context, tc)),
+        };
+        // Functions without results return undefined
+        if result.is_undefined() {
+            return Ok("null".to_string());
+        }
+        match v8::json::stringify(context, result) {
+            Some(json) => Ok(json.to_rust_string_lossy(scope)),
+            None => Err(caught_error(&self.handle, scope, context, tc)),
+        }
+    }
+
+    // Compiles a design doc's functions, see design_docs::Functions, into a
+    // context of their own and keeps them under signature for MAP_DOC and
+    // FILTER. Registering a signature again replaces its functions.
+    pub fn register_ddoc(&mut self, signature: &str, functions_json: &str) -> JSResult {
+        let functions = design_docs::parse(functions_json)?;
+        for (i, source) in functions.map.iter().enumerate() {
+            let what = format!("map function {}", i);
+            determinism::check(self.caps.determinism(), self.caps.clock(), &what, source)?;
+        }
+
+        let mut ddoc = {
+            let mut hs = v8::HandleScope::new(&mut self.isolate);
+            let scope = hs.enter();
+            let context = v8::Context::new(scope);
+            let mut cs = v8::ContextScope::new(scope, context);
+            let scope = cs.enter();
+            host_functions::install(scope, context, &self.caps);
+            if let Some(inspector) = self.inspector.as_mut() {
+                inspector.context_created(context, &format!("ddoc {}", signature));
+            }
+            // Named so DevTools and CPU profiles list them by design doc,
+            // V8 picks the comment up wherever it is
+            let debugging = self.inspector.is_some();
+            let named = |source: &str, what: &str| {
+                if debugging {
+                    let url = what.replace(' ', "_");
+                    format!("{}\n//# sourceURL=ddoc/{}/{}\n", source, signature, url)
+                } else {
+                    source.to_string()
+                }
+            };
+            let mut try_catch = v8::TryCatch::new(scope);
+            let tc = try_catch.enter();
+
+            let source = v8::String::new(scope, VIEW_SERVER_JS).unwrap();
+            let ran = v8::Script::compile(scope, context, source, None)
+                .and_then(|mut script| script.run(scope, context));
+            if ran.is_none() {
+                return Err(caught_error(&self.handle, scope, context, tc));
+            }
+
+            // views.lib modules are compiled up front, each in a function
+            // scope of its own, and only run when first required
+            let modules = functions.modules();
+            if !modules.is_empty() {
+                let compiled = v8::Object::new(scope);
+                for (id, source) in modules.iter() {
+                    // The trailing newline ends a last line comment
+                    let wrapped = format!("function(module, exports, require) {{{}\n}}", source);
+                    let wrapped = named(&wrapped, id);
+                    let what = format!("module {}", id);
+                    let fun = compile_function(&self.handle, scope, context, tc, &wrapped, &what)?;
+                    let key = v8::String::new(scope, id).unwrap();
+                    compiled.set(context, key.into(), fun.into());
+                }
+                let global = context.global(scope);
+                let make_require = get_function(scope, context, "", "makeRequire");
+                let require =
+                    match make_require.call(scope, context, global.into(), &[compiled.into()]) {
+                        Some(require) => require,
+                        None => return Err(caught_error(&self.handle, scope, context, tc)),
+                    };
+                let key = v8::String::new(scope, "require").unwrap();
+                global.set(context, key.into(), require);
+            }
+
+            let mut maps = Vec::with_capacity(functions.map.len());
+            for (i, source) in functions.map.iter().enumerate() {
+                let what = format!("map function {}", i);
+                maps.push(compile_function(
+                    &self.handle,
+                    scope,
+                    context,
+                    tc,
+                    &named(source, &what),
+                    &what,
+                )?);
+            }
+            let mut filters = Vec::with_capacity(functions.filters.len());
+            for (name, source) in functions.filters.iter() {
+                let what = format!("filter {}", name);
+                let source = named(source, &what);
+                let fun = compile_function(&self.handle, scope, context, tc, &source, &what)?;
+                filters.push((name.clone(), fun));
+            }
+            DesignDoc::new(scope, context, &maps, &filters)
+        };
+
+        // Only once it compiled, so a bad design doc doesn't evict good ones
+        if !self.design_docs.contains_key(signature) {
+            if let Err(err) = self.make_room() {
+                ddoc.reset(&mut *self.isolate);
+                return Err(err);
+            }
+        }
+
+        self.ddoc_tick += 1;
+        ddoc.used = self.ddoc_tick;
+        // A new one's context was already counted by make_room
+        if let Some(mut old) = self.design_docs.insert(signature.to_string(), ddoc) {
+            old.reset(&mut *self.isolate);
+        }
+        Ok("true".to_string())
+    }
+
+    // Evicts least recently used design docs until there's room for one
+    // more under max_contexts_per_isolate and max_contexts. An isolate can
+    // only evict its own, so once it has none left registering is turned
+    // away with OVERLOADED until other isolates evict or drop theirs. The
+    // new context is counted here, so isolates registering at the same time
+    // can't all take the last one.
+    fn make_room(&mut self) -> Result<(), JSError> {
+        let per_isolate = config::max_contexts_per_isolate();
+        while per_isolate > 0 && self.design_docs.len() >= per_isolate {
+            if !self.evict_least_recently_used() {
+                break;
+            }
+        }
+        let max = config::max_contexts();
+        while !reserve_context(max) {
+            if !self.evict_least_recently_used() {
+                return Err(JSError::Overloaded(format!(
+                    "{} design doc contexts are open, the most allowed",
+                    max
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // Returns whether there was a design doc to evict
+    fn evict_least_recently_used(&mut self) -> bool {
+        let oldest = self
+            .design_docs
+            .iter()
+            .min_by_key(|(_, ddoc)| ddoc.used)
+            .map(|(signature, _)| signature.clone());
+        let signature = match oldest {
+            Some(signature) => signature,
+            None => return false,
+        };
+        if let Some(mut ddoc) = self.design_docs.remove(&signature) {
+            ddoc.reset(&mut *self.isolate);
+            contexts_removed(1);
+            metrics::EVICTED_CONTEXTS_TOTAL.inc();
+            log::debug!("evicted design doc {} to make room", signature);
+        }
+        true
+    }
+
+    // Returns whether signature was registered
+    pub fn evict_ddoc(&mut self, signature: &str) -> JSResult {
+        match self.design_docs.remove(signature) {
+            Some(mut ddoc) => {
+                ddoc.reset(&mut *self.isolate);
+                contexts_removed(1);
+                Ok("true".to_string())
+            }
+            None => Ok("false".to_string()),
+        }
+    }
+
+    pub fn design_doc_count(&self) -> usize {
+        self.design_docs.len()
+    }
+
+    // Runs each map function of the design doc over the doc, given as JSON.
+    // Every map function gets an emit group, even if it emits nothing.
+    pub fn map_doc(&mut self, signature: &str, doc_json: &str) -> JSResult {
+        self.map_parsed_doc(signature, Doc::Json(doc_json))
+    }
+
+    // Like map_doc for a doc that's either JSON or already parsed
+    pub fn map_parsed_doc(&mut self, signature: &str, doc: Doc) -> JSResult {
+        let ddoc = used_ddoc(&mut self.design_docs, &mut self.ddoc_tick, signature)?;
+
+        let mut hs = v8::HandleScope::new(&mut self.isolate);
+        let scope = hs.enter();
+        let context = ddoc.context.get(scope).unwrap();
+        let mut cs = v8::ContextScope::new(scope, context);
+        let scope = cs.enter();
+        let mut try_catch = v8::TryCatch::new(scope);
+        let tc = try_catch.enter();
+
+        // Made once and shared by all the map functions
+        let doc = match doc_to_v8(scope, context, doc) {
+            Some(doc) => doc,
+            None => return Err(caught_error(&self.handle, scope, context, tc)),
+        };
+
+        let receiver = context.global(scope);
+        for map in ddoc.maps.iter() {
+            let map = map.get(scope).unwrap();
+            host_functions::start_emit_group();
+            if map.call(scope, context, receiver.into(), &[doc]).is_none() {
+                return Err(caught_error(&self.handle, scope, context, tc));
+            }
+        }
+        Ok("true".to_string())
+    }
+
+    // Like map_doc for each of docs in one go. Every doc gets a group of
+    // emitted rows per map function, doc by doc, and the emit limits apply
+    // to each doc on its own. One that doesn't parse, whose map function
+    // throws or that goes over an emit limit has its groups left empty and
+    // doesn't stop the rest. Returns the JSON of an array with null for each
+    // doc that was mapped and the error of each that wasn't.
+    pub fn map_docs(&mut self, signature: &str, docs: &[String]) -> JSResult {
+        let docs: Vec<Doc> = docs.iter().map(|doc| Doc::Json(doc)).collect();
+        self.map_parsed_docs(signature, &docs)
+    }
+
+    // Like map_docs for docs that are each either JSON or already parsed
+    pub fn map_parsed_docs(&mut self, signature: &str, docs: &[Doc]) -> JSResult {
+        let ddoc = used_ddoc(&mut self.design_docs, &mut self.ddoc_tick, signature)?;
+
+        let mut hs = v8::HandleScope::new(&mut self.isolate);
+        let scope = hs.enter();
+        let context = ddoc.context.get(scope).unwrap();
+        let mut cs = v8::ContextScope::new(scope, context);
+        let scope = cs.enter();
+        let receiver = context.global(scope);
+
+        let mut errors = Vec::with_capacity(docs.len());
+        for doc in docs {
+            let mut try_catch = v8::TryCatch::new(scope);
+            let tc = try_catch.enter();
+            host_functions::reset_emit_counts();
+            let start = host_functions::emit_groups();
+
+            let mapped = doc_to_v8(scope, context, *doc).and_then(|doc| {
+                for map in ddoc.maps.iter() {
+                    let map = map.get(scope).unwrap();
+                    host_functions::start_emit_group();
+                    map.call(scope, context, receiver.into(), &[doc])?;
+                }
+                Some(())
+            });
+            // Set even if the map function caught what emit() threw
+            let exceeded = host_functions::emit_exceeded();
+            if mapped.is_some() && exceeded.is_none() {
+                errors.push(serde_json::Value::Null);
+                continue;
+            }
+
+            // Timeouts and kills end the whole batch
+            let mut info = ErrorInfo::default();
+            if mapped.is_none() {
+                info = match caught_error(&self.handle, scope, context, tc) {
+                    JSError::RuntimeError(info) => info,
+                    err => return Err(err),
+                };
+            }
+            if let Some(reason) = exceeded {
+                info = ErrorInfo {
+                    name: "RangeError".to_string(),
+                    message: format!("emit_limit_exceeded: {}", reason),
+                    ..ErrorInfo::default()
+                };
+            }
+            host_functions::truncate_emit_groups(start);
+            for _ in ddoc.maps.iter() {
+                host_functions::start_emit_group();
+            }
+            errors.push(serde_json::json!({
+                "name": info.name,
+                "message": info.message,
+                "stack": info.stack,
+                "line": info.line,
+            }));
+        }
+        Ok(serde_json::to_string(&errors).unwrap())
+    }
+
+    // Runs the design doc's filter called name over docs, the JSON of an
+    // array of docs, and returns the JSON of whether each one passed
+    pub fn filter_docs(
+        &mut self,
+        signature: &str,
+        name: &str,
+        docs_json: &str,
+        req_json: &str,
+    ) -> JSResult {
+        let ddoc = used_ddoc(&mut self.design_docs, &mut self.ddoc_tick, signature)?;
+        let filter = match ddoc.filters.get(name) {
+            Some(filter) => filter,
+            None => {
+                return Err(JSError::RuntimeError(ErrorInfo {
+                    name: "ReferenceError".to_string(),
+                    message: format!("design doc has no filter {}", name),
+                    ..ErrorInfo::default()
+                }))
+            }
+        };
+
+        let mut hs = v8::HandleScope::new(&mut self.isolate);
+        let scope = hs.enter();
+        let context = ddoc.context.get(scope).unwrap();
+        let mut cs = v8::ContextScope::new(scope, context);
+        let scope = cs.enter();
+        let mut try_catch = v8::TryCatch::new(scope);
+        let tc = try_catch.enter();
+
+        let docs = v8::String::new(scope, docs_json).unwrap();
+        let docs = match v8::json::parse(context, docs) {
+            Some(docs) => docs,
+            None => return Err(caught_error(&self.handle, scope, context, tc)),
+        };
+        let docs = match v8::Local::<v8::Array>::try_from(docs) {
+            Ok(docs) => docs,
+            Err(_) => {
+                return Err(JSError::RuntimeError(ErrorInfo {
+                    name: "TypeError".to_string(),
+                    message: "filter docs must be an array".to_string(),
+                    ..ErrorInfo::default()
+                }))
+            }
+        };
+        let req = v8::String::new(scope, req_json).unwrap();
+        let req = match v8::json::parse(context, req) {
+            Some(req) => req,
+            None => return Err(caught_error(&self.handle, scope, context, tc)),
+        };
+
+        let filter = filter.get(scope).unwrap();
+        let receiver = context.global(scope);
+        let mut passed = Vec::with_capacity(docs.length() as usize);
+        for i in 0..docs.length() {
+            let index = v8::Integer::new(scope, i as i32);
+            let doc = docs.get(scope, context, index.into()).unwrap();
+            match filter.call(scope, context, receiver.into(), &[doc, req]) {
+                Some(result) => passed.push(result.boolean_value(scope)),
+                None => return Err(caught_error(&self.handle, scope, context, tc)),
+            }
+        }
+        Ok(serde_json::to_string(&passed).unwrap())
+    }
+
+    // Replaces the global context with a fresh one from the snapshot and
+    // forgets every design doc and WebAssembly instance, leaving the
+    // isolate as it was when it was created
+    pub fn reset(&mut self) -> JSResult {
+        contexts_removed(self.design_docs.len());
+        for (_, mut ddoc) in self.design_docs.drain() {
+            ddoc.reset(&mut *self.isolate);
+        }
+        for (_, mut exports) in self.wasm_instances.drain(..) {
+            exports.reset(&mut *self.isolate);
+        }
+        {
+            let mut hs = v8::HandleScope::new(&mut self.isolate);
+            let scope = hs.enter();
+            let context = v8::Context::new(scope);
+            let mut cs = v8::ContextScope::new(scope, context);
+            let scope = cs.enter();
+            host_functions::install(scope, context, &self.caps);
+            if let Some(inspector) = self.inspector.as_mut() {
+                inspector.context_created(context, "global");
+            }
+            self.global_context.set(scope, context);
+        }
+        if !JS_MODULES.is_empty() {
+            self.load_modules(JS_MODULES)?;
+        }
+        Ok("true".to_string())
+    }
+
+    // Compiles and evaluates ES modules, given as (name, source). Modules
+    // import each other by name, e.g. `import { emitRow } from "./util.js"`,
+    // and whatever they export becomes a global.
+    pub fn load_modules(&mut self, modules: &[(&str, &str)]) -> Result<(), JSError> {
+        let mut hs = v8::HandleScope::new(&mut self.isolate);
+        let scope = hs.enter();
+        let context = self.global_context.get(scope).unwrap();
+        let mut cs = v8::ContextScope::new(scope, context);
+        let scope = cs.enter();
+        modules::load(scope, context, modules)
+    }
+
+    // Installs each member of a JSON object as a deeply frozen, read-only
+    // global. The JSON is parsed in Rust so nothing is compiled, which keeps
+    // big lookup tables cheap compared to EVALing them as a script.
+    pub fn set_globals(&mut self, globals_json: &str) -> JSResult {
+        let globals = match serde_json::from_str::<serde_json::Value>(globals_json) {
+            Ok(serde_json::Value::Object(globals)) => globals,
+            Ok(_) => {
+                return Err(JSError::RuntimeError(ErrorInfo {
+                    name: "TypeError".to_string(),
+                    message: "globals must be a JSON object".to_string(),
+                    ..ErrorInfo::default()
+                }))
+            }
+            Err(err) => {
+                return Err(JSError::CompileError(ErrorInfo {
+                    name: "SyntaxError".to_string(),
+                    message: err.to_string(),
+                    line: err.line() as i32,
+                    ..ErrorInfo::default()
+                }))
+            }
+        };
+
+        let mut hs = v8::HandleScope::new(&mut self.isolate);
+        let scope = hs.enter();
+        let context = self.global_context.get(scope).unwrap();
+        let mut cs = v8::ContextScope::new(scope, context);
+        let scope = cs.enter();
+        let mut try_catch = v8::TryCatch::new(scope);
+        let tc = try_catch.enter();
+
+        let global = context.global(scope);
+        let freeze = get_function(scope, context, "Object", "freeze");
+        let lock_globals = get_function(scope, context, "", "lockGlobals");
+
+        for (name, value) in globals.iter() {
+            let key = v8::String::new(scope, name).unwrap();
+            let value = match json_to_v8(scope, context, Some(freeze), value) {
+                Some(value) => value,
+                None => return Err(caught_error(&self.handle, scope, context, tc)),
+            };
+            global.set(context, key.into(), value);
+        }
+
+        let names = serde_json::to_string(&globals.keys().collect::<Vec<_>>()).unwrap();
+        let names = v8::String::new(scope, names.as_str()).unwrap();
+        if lock_globals
+            .call(scope, context, global.into(), &[names.into()])
+            .is_none()
+        {
+            return Err(caught_error(&self.handle, scope, context, tc));
+        }
+
+        Ok("true".to_string())
+    }
+}
+
+// Compiles source, which must evaluate to a function. what names it in the
+// error if it doesn't.
+fn compile_function<'sc>(
+    handle: &v8::IsolateHandle,
+    scope: &mut impl v8::ToLocal<'sc>,
+    context: v8::Local<v8::Context>,
+    tc: &v8::TryCatch,
+    source: &str,
+    what: &str,
+) -> Result<v8::Local<'sc, v8::Function>, JSError> {
+    // Wrapped in parens so a function declaration is an expression
+    let source = v8::String::new(scope, &format!("({})", source)).unwrap();
+    let mut script = match v8::Script::compile(scope, context, source, None) {
+        Some(script) => script,
+        None => return Err(JSError::CompileError(error_info(scope, context, tc))),
+    };
+    let value = match script.run(scope, context) {
+        Some(value) => value,
+        None => return Err(caught_error(handle, scope, context, tc)),
+    };
+    v8::Local::<v8::Function>::try_from(value).map_err(|_| {
+        JSError::RuntimeError(ErrorInfo {
+            name: "TypeError".to_string(),
+            message: format!("{} is not a function", what),
+            ..ErrorInfo::default()
+        })
+    })
+}
+
+// Looks up obj_name.fun_name on the global object, or just fun_name if
+// obj_name is empty. Only used for functions hardened in the snapshot.
+fn get_function<'sc>(
+    scope: &mut impl v8::ToLocal<'sc>,
+    context: v8::Local<v8::Context>,
+    obj_name: &str,
+    fun_name: &str,
+) -> v8::Local<'sc, v8::Function> {
+    let mut obj = context.global(scope);
+    if !obj_name.is_empty() {
+        let key = v8::String::new(scope, obj_name).unwrap();
+        let value = obj.get(scope, context, key.into()).unwrap();
+        obj = value.to_object(scope).unwrap();
+    }
+    let key = v8::String::new(scope, fun_name).unwrap();
+    let value = obj.get(scope, context, key.into()).unwrap();
+    v8::Local::<v8::Function>::try_from(value).unwrap()
+}
+
+// Builds the V8 version of value, freezing every object and array on the way
+// if given Object.freeze
+fn json_to_v8<'sc>(
+    scope: &mut impl v8::ToLocal<'sc>,
+    context: v8::Local<v8::Context>,
+    freeze: Option<v8::Local<v8::Function>>,
+    value: &serde_json::Value,
+) -> Option<v8::Local<'sc, v8::Value>> {
+    let value: v8::Local<v8::Value> = match value {
+        serde_json::Value::Null => v8::null(scope).into(),
+        serde_json::Value::Bool(b) => v8::Boolean::new(scope, *b).into(),
+        serde_json::Value::Number(n) => v8::Number::new(scope, n.as_f64()?).into(),
+        serde_json::Value::String(s) => v8::String::new(scope, s)?.into(),
+        serde_json::Value::Array(items) => {
+            let array = v8::Array::new(scope, items.len() as i32);
+            for (i, item) in items.iter().enumerate() {
+                let index = v8::Integer::new(scope, i as i32);
+                let item = json_to_v8(scope, context, freeze, item)?;
+                array.set(context, index.into(), item)?;
+            }
+            array.into()
+        }
+        serde_json::Value::Object(members) => {
+            let obj = v8::Object::new(scope);
+            for (key, member) in members.iter() {
+                let key = v8::String::new(scope, key)?;
+                let member = json_to_v8(scope, context, freeze, member)?;
+                // Like JSON.parse, so "__proto__" is a key like any other
+                obj.create_data_property(context, key.into(), member)?;
+            }
+            obj.into()
+        }
+    };
+
+    if let Some(freeze) = freeze.filter(|_| value.is_object()) {
+        let receiver = context.global(scope);
+        freeze.call(scope, context, receiver.into(), &[value])?;
+    }
+    Some(value)
+}
+
+fn doc_to_v8<'sc>(
+    scope: &mut impl v8::ToLocal<'sc>,
+    context: v8::Local<'sc, v8::Context>,
+    doc: Doc,
+) -> Option<v8::Local<'sc, v8::Value>> {
+    match doc {
+        Doc::Json(json) => {
+            let json = v8::String::new(scope, json)?;
+            v8::json::parse(context, json)
+        }
+        Doc::Value(value) => json_to_v8(scope, context, None, value),
+    }
+}
+
+// A script that stopped without an exception was terminated, either because
+// it ran out of time or because its caller went away.
+fn caught_error<'sc>(
+    handle: &v8::IsolateHandle,
+    scope: &mut impl v8::ToLocal<'sc>,
+    context: v8::Local<v8::Context>,
+    tc: &v8::TryCatch,
+) -> JSError {
+    if handle.is_execution_terminating() || !tc.has_caught() {
+        return JSError::Timeout;
+    }
+
+    JSError::RuntimeError(error_info(scope, context, tc))
+}
+
+pub(crate) fn error_info<'sc>(
+    scope: &mut impl v8::ToLocal<'sc>,
+    context: v8::Local<v8::Context>,
+    tc: &v8::TryCatch,
+) -> ErrorInfo {
+    let mut info = ErrorInfo::default();
+
+    if let Some(exception) = tc.exception() {
+        if exception.is_object() {
+            let obj = exception.to_object(scope).unwrap();
+            info.name = get_string(scope, context, obj, "name");
+            info.message = get_string(scope, context, obj, "message");
+        } else {
+            // Something like `throw "oops"`
+            info.name = "Error".to_string();
+            info.message = exception
+                .to_string(scope)
+                .map(|s| s.to_rust_string_lossy(scope))
+                .unwrap_or_default();
+        }
+    }
+
+    if let Some(stack) = tc.stack_trace(scope, context) {
+        if let Some(stack) = stack.to_string(scope) {
+            info.stack = stack.to_rust_string_lossy(scope);
+        }
+    }
+
+    if let Some(message) = tc.message() {
+        info.line = message.get_line_number(context).unwrap_or(0) as i32;
+    }
+
+    info
+}
+
+fn get_string<'sc>(
+    scope: &mut impl v8::ToLocal<'sc>,
+    context: v8::Local<v8::Context>,
+    obj: v8::Local<v8::Object>,
+    key: &str,
+) -> String {
+    let key = v8::String::new(scope, key).unwrap();
+    match obj.get(scope, context, key.into()) {
+        Some(value) if !value.is_undefined() => value
+            .to_string(scope)
+            .map(|s| s.to_rust_string_lossy(scope))
+            .unwrap_or_default(),
+        _ => String::new(),
+    }
+}
+
+// The design doc registered under signature, marked as just used
+fn used_ddoc<'a>(
+    design_docs: &'a mut HashMap<String, DesignDoc>,
+    tick: &mut u64,
+    signature: &str,
+) -> Result<&'a DesignDoc, JSError> {
+    match design_docs.get_mut(signature) {
+        Some(ddoc) => {
+            *tick += 1;
+            ddoc.used = *tick;
+            Ok(ddoc)
+        }
+        None => Err(JSError::UnknownDesignDoc(signature.to_string())),
+    }
+}
+
+// Counts one more context unless max, if there is one, are already open
+fn reserve_context(max: usize) -> bool {
+    let reserved = DESIGN_DOC_CONTEXTS.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+        if max > 0 && open >= max {
+            None
+        } else {
+            Some(open + 1)
+        }
+    });
+    match reserved {
+        Ok(open) => {
+            metrics::DESIGN_DOC_CONTEXTS.set((open + 1) as f64);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+fn contexts_removed(count: usize) {
+    let contexts = DESIGN_DOC_CONTEXTS.fetch_sub(count, Ordering::SeqCst) - count;
+    metrics::DESIGN_DOC_CONTEXTS.set(contexts as f64);
+}
+
+// Design doc contexts kept over every isolate
+pub fn design_doc_contexts() -> usize {
+    DESIGN_DOC_CONTEXTS.load(Ordering::SeqCst)
+}
+
+// The Global context handle has to be released while its isolate is still
+// alive. Relying on field drop order would dispose the isolate first and then
+// drop a Global that still points into it.
+impl Drop for FortunaIsolate {
+    fn drop(&mut self) {
+        drop(self.inspector.take());
+        contexts_removed(self.design_docs.len());
+        for ddoc in self.design_docs.values_mut() {
+            ddoc.reset(&mut *self.isolate);
+        }
+        for (_, exports) in self.wasm_instances.iter_mut() {
+            exports.reset(&mut *self.isolate);
+        }
+        self.global_context.reset(&mut *self.isolate);
+        debug_assert!(self.global_context.is_empty());
+    }
+}
+
+// How V8 shares the machine with tokio and the JS workers
+#[derive(Clone, Debug, Default)]
+pub struct PlatformConfig {
+    // Don't start any V8 background threads; GC and compilation tasks run on
+    // the worker thread that needs them. The size of the background pool
+    // can't be set otherwise so use platform_cores to rein it in.
+    pub single_threaded: bool,
+    // Cores V8's background threads may run on. Empty means any.
+    pub platform_cores: Vec<usize>,
+    // Cores the JS worker threads are pinned to, spread round robin. Empty
+    // means they aren't pinned.
+    pub worker_cores: Vec<usize>,
+    // Most heap each isolate may use in bytes. None leaves it to V8.
+    pub max_heap: Option<usize>,
+    // Passed on to V8 as is, e.g. "--max_old_space_size=512 --jitless"
+    pub v8_flags: String,
+    // Adds the flags of the mode to v8_flags
+    pub security_mode: SecurityMode,
+    // The timezone Date works in, e.g. "UTC" or "Europe/Paris". It's set as
+    // TZ for the whole process. None leaves it to the host.
+    pub timezone: Option<String>,
+    // The default locale of Intl and toLocaleString, e.g. "en-US". None
+    // leaves it to the host.
+    pub locale: Option<String>,
 }
 
 pub fn init() {
+    init_with(&PlatformConfig::default()).unwrap();
+}
+
+pub fn init_with(config: &PlatformConfig) -> Result<(), String> {
+    let mut flags: Vec<String> = config
+        .security_mode
+        .v8_flags()
+        .iter()
+        .map(|flag| flag.to_string())
+        .collect();
+    flags.extend(v8_config::parse_flags(&config.v8_flags)?);
+    if let Some(locale) = &config.locale {
+        flags.push(format!("--icu-locale={}", locale));
+    }
+    v8_config::set_flags(flags);
+    // V8 looks TZ up as it starts
+    if let Some(timezone) = &config.timezone {
+        std::env::set_var("TZ", timezone);
+    }
+    v8_config::set_security_mode(config.security_mode);
+    if config.single_threaded {
+        v8::V8::set_flags_from_string("--single-threaded");
+    }
+
+    // The platform's threads inherit the affinity of the thread creating
+    // them so narrow it down just while the platform starts
+    let saved_cores = if config.platform_cores.is_empty() {
+        None
+    } else {
+        let saved = affinity::current_cores().map_err(|err| err.to_string())?;
+        affinity::pin_current_thread(&config.platform_cores)
+            .map_err(|err| format!("can't pin V8 platform threads: {}", err))?;
+        Some(saved)
+    };
+
     let platform = v8::new_default_platform().unwrap();
     v8::V8::initialize_platform(platform);
     v8::V8::initialize();
+
+    if let Some(saved) = saved_cores {
+        affinity::pin_current_thread(&saved).map_err(|err| err.to_string())?;
+    }
+
+    js_server::set_worker_cores(config.worker_cores.clone());
+    if let Some(warning) = v8_config::configure(config.max_heap) {
+        log::warn!("{}", warning);
+    }
+    Ok(())
 }
 
 // Not really needed
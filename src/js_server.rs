@@ -1,24 +1,90 @@
 use crossbeam::crossbeam_channel::{
-    select, unbounded as cross_unbounded, Receiver as CrossReceiver, RecvError,
+    never, select, unbounded as cross_unbounded, Receiver as CrossReceiver, RecvError,
     Sender as CrossSender,
 };
+use futures::channel::{mpsc, oneshot};
+use futures::future;
+use lazy_static::lazy_static;
+use rusty_v8 as v8;
 
-use crate::{FortunaIsolate, JSEnv};
+use crate::affinity;
+use crate::error::{JSError, JSResult};
+use crate::gc::{GcEvent, GcLog};
+use crate::host_functions::{self, Capabilities, LogLine};
+use crate::inspector;
+use crate::metrics;
+use crate::rewrite_cache::RewriteCache;
+use crate::threads;
+use crate::{Doc, FortunaIsolate, JSEnv};
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt::Debug;
-use std::thread;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-type ServerTx = CrossSender<String>;
-type ServerRx = CrossReceiver<Command>;
+type ServerTx = oneshot::Sender<Reply>;
+type ServerRx = CrossReceiver<Job>;
 
-type ClientTx = CrossSender<Command>;
-type ClientRx = CrossReceiver<String>;
+type ClientTx = CrossSender<Job>;
 
+type ControlTx = CrossSender<Control>;
+type ControlRx = CrossReceiver<Control>;
+
+static NEXT_JOB_ID: AtomicU64 = AtomicU64::new(1);
+static NEXT_WORKER_ID: AtomicUsize = AtomicUsize::new(1);
+
+// Failing to create an isolate is retried after SPAWN_BACKOFF, doubling each
+// time, and the worker gives up after MAX_SPAWN_FAILURES in a row
+const SPAWN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_SPAWN_FAILURES: u32 = 5;
+
+lazy_static! {
+    // Every live worker, for the admin API
+    static ref WORKERS: Mutex<BTreeMap<usize, SharedWorkerState>> = Mutex::new(BTreeMap::new());
+    // Every command queued or running on one of them, by job id
+    static ref JOBS: Mutex<BTreeMap<u64, InFlight>> = Mutex::new(BTreeMap::new());
+    // Cores new workers are pinned to, by worker id round robin
+    static ref WORKER_CORES: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+    // Payloads whose commands panic the worker running them, and how many
+    // more times, see test_support::crash_on
+    static ref CRASH_ON: Mutex<BTreeMap<String, usize>> = Mutex::new(BTreeMap::new());
+}
+
+// Set once anything was added to CRASH_ON, so commands don't take its lock
+// outside of tests
+static CRASHES_ARMED: AtomicBool = AtomicBool::new(false);
+
+#[allow(non_camel_case_types)]
 #[derive(Debug)]
 pub enum Ops {
     REWRITE,
     EVAL,
     CALL,
-    EXIT,
+    // Like CALL but each of args is JSON, passed to the function parsed
+    CALL_JSON,
+    // payload is a JSON object whose members become frozen globals
+    GLOBALS,
+    // payload is the reduce function and args[0] the JSON of the rows, or
+    // of the values for REREDUCE
+    REDUCE,
+    REREDUCE,
+    // payload is a design doc signature, args[0] the JSON of its functions
+    REGISTER_DDOC,
+    EVICT_DDOC,
+    // payload is a design doc signature, args[0] the JSON of the doc
+    MAP_DOC,
+    // payload is a design doc signature, args the JSON of each doc
+    MAP_DOCS,
+    // payload is a design doc signature, args are the filter's name, the
+    // JSON of the docs and optionally the JSON of the request
+    FILTER,
+    // payload is the name of an exported function, args are the module's
+    // hash, the module as hex and the JSON of each argument
+    WASM_CALL,
+    // Resets the worker's context to how it was created, see
+    // FortunaIsolate::reset
+    RESET,
 }
 
 #[derive(Debug)]
@@ -26,94 +92,1026 @@ pub struct Command {
     pub operation: Ops,
     pub payload: String,
     pub args: Vec<String>,
+    // MAP_DOCS docs already parsed, mapped instead of those in args
+    pub docs: Vec<serde_json::Value>,
+}
+
+// The outcome of a command along with anything the script logged or
+// emitted while running it
+#[derive(Debug)]
+pub struct Reply {
+    pub result: JSResult,
+    pub logs: Vec<LogLine>,
+    pub emitted: Vec<Vec<String>>,
+    pub stats: ExecStats,
+    // The worker crashed while running this command, rather than failing it
+    // because the worker gave up on creating an isolate
+    pub crashed: bool,
+}
+
+// What running a command cost. Left at zero for commands that never ran.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExecStats {
+    // Time between the command being queued and the worker picking it up
+    pub queue_wait: Duration,
+    // Wall time spent running it
+    pub execution: Duration,
+    // CPU time the worker thread spent running it
+    pub cpu: Duration,
+    // Size of the V8 heap in use afterwards
+    pub heap_used: usize,
+}
+
+impl From<JSError> for Reply {
+    fn from(err: JSError) -> Self {
+        Reply {
+            result: Err(err),
+            logs: Vec::new(),
+            emitted: Vec::new(),
+            stats: ExecStats::default(),
+            crashed: false,
+        }
+    }
+}
+
+// Sent to a worker outside of the command queue. It's handled between
+// commands so it never waits behind anything but the running one.
+enum Control {
+    Stats(oneshot::Sender<HeapStats>),
+    // Replace the isolate with a fresh one once the running command is done
+    Recycle,
+    // Sample with V8's CPU profiler every interval until StopProfile
+    StartProfile(Duration),
+    StopProfile(oneshot::Sender<Result<String, String>>),
+    // A .heapsnapshot a chunk at a time, ending with the reason if taking it
+    // failed
+    HeapSnapshot(mpsc::UnboundedSender<Result<String, String>>),
+}
+
+// V8's view of a worker's isolate
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HeapStats {
+    pub used: usize,
+    pub total: usize,
+    pub limit: usize,
+    pub contexts: usize,
+}
+
+// Each command carries its own reply channel so that a client that gives up
+// waiting (e.g. a readiness probe with a deadline) can't leave a stale
+// response behind for the next command.
+#[derive(Debug)]
+struct Job {
+    id: u64,
+    cmd: Command,
+    reply: ServerTx,
+    cancelled: Arc<AtomicBool>,
+    // Set through the admin API, the caller gets JSError::Killed
+    killed: Arc<AtomicBool>,
+    queued_at: Instant,
+    // Not run at all if it's only picked up after this
+    deadline: Option<Instant>,
+    request_id: Option<String>,
+}
+
+// A job as the admin API sees it
+struct InFlight {
+    op: String,
+    request_id: Option<String>,
+    worker: SharedWorkerState,
+    worker_id: usize,
+    killed: Arc<AtomicBool>,
+    queued_at: Instant,
+    started_at: Option<Instant>,
+}
+
+// What the worker is doing right now. Shared with its clients so they can
+// terminate a script whose caller has gone away.
+#[derive(Default)]
+struct WorkerState {
+    id: usize,
+    handle: Option<v8::IsolateHandle>,
+    gc_log: Option<Arc<GcLog>>,
+    running: u64,
+    control: Option<ControlTx>,
+    // The worker's end of its command queue, only used to see how long it is
+    queue: Option<ServerRx>,
+    // When each command in the queue was sent, oldest first
+    queued: VecDeque<Instant>,
+    // Commands run since the worker started
+    requests: u64,
+    // Those of them that failed
+    errors: u64,
+    // CPU time the worker thread spent running them
+    cpu: Duration,
+    // Times the isolate was replaced, after a panic or when asked to
+    recycles: u64,
+    // Bumped whenever the isolate is replaced or a command that changes
+    // its state runs, so cached results from before can be told apart
+    generation: u64,
+    last_error: Option<String>,
+    // The worker thread's id as top -H and perf show it
+    os_thread_id: Option<i64>,
+    // When the worker started or last finished a command
+    last_active: Option<Instant>,
+    // Isolates still to fail to start, see test_support::fail_isolates
+    failing_isolates: u32,
+}
+
+type SharedWorkerState = Arc<Mutex<WorkerState>>;
+
+impl WorkerState {
+    fn queue_age(&self) -> Duration {
+        self.queued
+            .front()
+            .map_or_else(Duration::default, |at| at.elapsed())
+    }
+
+    fn terminate(state: &SharedWorkerState, job_id: u64) {
+        let state = state.lock().unwrap();
+        if state.running != job_id {
+            return;
+        }
+
+        if let Some(handle) = state.handle.as_ref() {
+            handle.terminate_execution();
+        }
+    }
 }
 
 struct JSServer {
-    send: ServerTx,
     receive: ServerRx,
+    control: ControlRx,
+    state: SharedWorkerState,
     isolate: FortunaIsolate,
+    // Nothing has run on the isolate yet
+    fresh: bool,
+    recycle: bool,
 }
 
 impl JSServer {
-    fn start(js_env: &JSEnv, send: ServerTx, receive: ServerRx) {
+    // Supervises the worker: if processing a command panics, the command
+    // that caused it is failed and a fresh isolate is created from the
+    // snapshot to run what's queued behind it. If creating the isolate is
+    // what panics, it's retried with backoff until MAX_SPAWN_FAILURES in a
+    // row, when the worker gives up and fails everything queued for it.
+    fn start(
+        js_env: &JSEnv,
+        caps: Capabilities,
+        receive: ServerRx,
+        control: ControlRx,
+        state: SharedWorkerState,
+    ) {
         let data = js_env.startup_data.clone();
-        thread::spawn(move || {
-            let mut server = JSServer {
-                receive,
-                send,
-                isolate: FortunaIsolate::new_from_snapshot(data.as_slice()),
-            };
+        let id = state.lock().unwrap().id;
+        let core = {
+            let cores = WORKER_CORES.lock().unwrap();
+            cores.get(id % cores.len().max(1)).copied()
+        };
+        let spawned = threads::spawn(thread_name(id), move || {
+            state.lock().unwrap().os_thread_id = Some(threads::os_thread_id());
+            if let Some(core) = core {
+                if let Err(err) = affinity::pin_current_thread(&[core]) {
+                    log::warn!("can't pin worker {} to core {}: {}", id, core, err);
+                }
+            }
 
+            let mut spawn_failures = 0;
             loop {
-                select! {
-                    recv(server.receive) -> sent => {
-                        match sent {
-                            Ok(cmd) => {
-                                if !server.process(cmd) {
-                                    println!("exiting");
-                                    break;
-                                }
-                            },
-                            Err(RecvError) => {
-                                println!("exiting RecvError");
-                                break;
-                            }
+                let generation = state.lock().unwrap().generation;
+                let run = panic::catch_unwind(AssertUnwindSafe(|| {
+                    JSServer::run(
+                        data.as_slice(),
+                        &caps,
+                        receive.clone(),
+                        control.clone(),
+                        state.clone(),
+                    )
+                }));
+
+                match run {
+                    Ok(true) => {
+                        state.lock().unwrap().recycles += 1;
+                        continue;
+                    }
+                    Ok(false) => break,
+                    Err(_) => (),
+                }
+
+                {
+                    let mut state = state.lock().unwrap();
+                    state.recycles += 1;
+                    state.last_error = Some(JSError::WorkerCrashed.to_string());
+                }
+
+                // run bumps the generation once it has an isolate, so if it
+                // hasn't moved the panic came from creating one
+                if state.lock().unwrap().generation != generation {
+                    log::warn!("worker {} panicked, restarting", id);
+                    spawn_failures = 0;
+                    continue;
+                }
+
+                spawn_failures += 1;
+                if spawn_failures >= MAX_SPAWN_FAILURES {
+                    log::error!(
+                        "worker {} can't create an isolate, giving up after {} tries",
+                        id,
+                        spawn_failures
+                    );
+                    break;
+                }
+                let backoff = SPAWN_BACKOFF * 2u32.pow(spawn_failures - 1);
+                log::warn!(
+                    "worker {} can't create an isolate, retrying in {:?}",
+                    id,
+                    backoff
+                );
+                std::thread::sleep(backoff);
+            }
+
+            // Nothing will run what's left, so fail it rather than leave
+            // its callers waiting. Without queue clients fail whatever they
+            // send from now on too, see JSClient::send.
+            {
+                let mut state = state.lock().unwrap();
+                state.queue = None;
+                state.control = None;
+                for job in receive.try_iter() {
+                    state.queued.pop_front();
+                    let _ = job.reply.send(JSError::WorkerCrashed.into());
+                }
+            }
+            WORKERS.lock().unwrap().remove(&id);
+        });
+        if let Err(err) = spawned {
+            panic!("can't start worker {}: {}", id, err);
+        }
+    }
+
+    // Returns true if the worker should carry on with a fresh isolate
+    fn run(
+        data: &[u8],
+        caps: &Capabilities,
+        receive: ServerRx,
+        control: ControlRx,
+        state: SharedWorkerState,
+    ) -> bool {
+        {
+            let mut state = state.lock().unwrap();
+            if state.failing_isolates > 0 {
+                state.failing_isolates -= 1;
+                panic!("failing to create an isolate on purpose");
+            }
+        }
+        let mut isolate = FortunaIsolate::new_from_snapshot_with_capabilities(data, caps);
+        let id = {
+            let mut state = state.lock().unwrap();
+            state.handle = Some(isolate.thread_safe_handle());
+            state.gc_log = Some(isolate.gc_log());
+            state.running = 0;
+            state.generation += 1;
+            state.id
+        };
+        // DevTools messages, if this is the worker being inspected
+        let inspect = match inspector::claim(id) {
+            Some(inbound) => {
+                isolate.attach_inspector(inbound.clone());
+                inbound
+            }
+            None => never(),
+        };
+
+        let mut server = JSServer {
+            receive,
+            control,
+            state,
+            isolate,
+            fresh: true,
+            recycle: false,
+        };
+
+        loop {
+            select! {
+                recv(server.receive) -> sent => {
+                    match sent {
+                        Ok(job) => server.process(job),
+                        Err(RecvError) => {
+                            log::debug!("worker {} exiting, its clients are gone", id);
+                            break;
                         }
                     }
                 }
+                recv(server.control) -> sent => {
+                    if let Ok(control) = sent {
+                        server.handle_control(control);
+                    }
+                }
+                recv(inspect) -> sent => {
+                    if let Ok(inbound) = sent {
+                        server.isolate.inspect(inbound);
+                    }
+                }
+            }
+            if server.recycle {
+                log::info!("recycling worker {}", id);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn process(&mut self, job: Job) {
+        let Job {
+            id,
+            cmd,
+            reply,
+            cancelled,
+            killed,
+            queued_at,
+            deadline,
+            request_id,
+        } = job;
+
+        // Set before looking at cancelled and killed so that the caller
+        // giving up, or a kill, either sees the job running and terminates
+        // it or is seen here
+        {
+            let mut state = self.state.lock().unwrap();
+            state.running = id;
+            state.queued.pop_front();
+        }
+
+        // Nobody is waiting for this one anymore
+        if cancelled.load(Ordering::SeqCst) {
+            self.stop_running();
+            return;
+        }
+        if deadline.map_or(false, |deadline| deadline <= Instant::now()) {
+            let _ = reply.send(JSError::DeadlineExceeded.into());
+            self.stop_running();
+            return;
+        }
+        host_functions::take_logs();
+        let _ = host_functions::take_emitted();
+        host_functions::start_command();
+        host_functions::set_request_id(request_id.clone());
+        let started = Instant::now();
+        if let Some(job) = JOBS.lock().unwrap().get_mut(&id) {
+            job.started_at = Some(started);
+        }
+        if CRASHES_ARMED.load(Ordering::Relaxed) {
+            crash_if_asked(&cmd.payload);
+        }
+        let cpu_started = thread_cpu_time();
+        let result = match cmd.operation {
+            _ if killed.load(Ordering::SeqCst) => Err(JSError::Killed),
+            Ops::RESET => self.isolate.reset(),
+            Ops::EVAL => self.isolate.eval(cmd.payload.as_str(), &[]),
+            Ops::GLOBALS => self.isolate.set_globals(cmd.payload.as_str()),
+            Ops::REDUCE | Ops::REREDUCE => {
+                let input = cmd.args.first().map_or("[]", String::as_str);
+                let rereduce = matches!(cmd.operation, Ops::REREDUCE);
+                self.isolate.reduce(cmd.payload.as_str(), input, rereduce)
+            }
+            Ops::CALL | Ops::REWRITE => {
+                self.isolate.call(cmd.payload.as_str(), cmd.args.as_slice())
+            }
+            Ops::CALL_JSON => self
+                .isolate
+                .call_json(cmd.payload.as_str(), cmd.args.as_slice()),
+            Ops::REGISTER_DDOC => {
+                let functions = cmd.args.first().map_or("{}", String::as_str);
+                self.isolate.register_ddoc(cmd.payload.as_str(), functions)
             }
+            Ops::EVICT_DDOC => self.isolate.evict_ddoc(cmd.payload.as_str()),
+            Ops::MAP_DOC => {
+                let doc = cmd.args.first().map_or("{}", String::as_str);
+                self.isolate.map_doc(cmd.payload.as_str(), doc)
+            }
+            Ops::MAP_DOCS if !cmd.docs.is_empty() => {
+                let docs: Vec<Doc> = cmd.docs.iter().map(Doc::Value).collect();
+                self.isolate.map_parsed_docs(cmd.payload.as_str(), &docs)
+            }
+            Ops::MAP_DOCS => self
+                .isolate
+                .map_docs(cmd.payload.as_str(), cmd.args.as_slice()),
+            Ops::WASM_CALL => self
+                .isolate
+                .wasm_call(cmd.payload.as_str(), cmd.args.as_slice()),
+            Ops::FILTER => {
+                let arg = |i: usize, default| cmd.args.get(i).map_or(default, String::as_str);
+                self.isolate.filter_docs(
+                    cmd.payload.as_str(),
+                    arg(0, ""),
+                    arg(1, "[]"),
+                    arg(2, "{}"),
+                )
+            }
+        };
+        // Terminated scripts fail with a timeout otherwise
+        let result = if killed.load(Ordering::SeqCst) {
+            Err(JSError::Killed)
+        } else {
+            result
+        };
+        let stats = ExecStats {
+            queue_wait: started.duration_since(queued_at),
+            execution: started.elapsed(),
+            cpu: thread_cpu_time()
+                .checked_sub(cpu_started)
+                .unwrap_or_default(),
+            heap_used: self.isolate.heap_used(),
+        };
+        let worker_id = {
+            let mut state = self.state.lock().unwrap();
+            state.requests += 1;
+            state.cpu += stats.cpu;
+            if changes_state(&cmd.operation) {
+                state.generation += 1;
+            }
+            if let Err(err) = &result {
+                state.errors += 1;
+                state.last_error = Some(err.to_string());
+            }
+            state.id
+        };
+        stats.record(&cmd.operation, request_id.as_deref());
+        if self.fresh {
+            let kind = format!("{:?}", cmd.operation).to_lowercase();
+            metrics::FIRST_COMMAND_SECONDS.observe(&kind, stats.execution);
+            self.fresh = false;
+        }
+
+        let logs = host_functions::take_logs();
+        let (result, emitted) = match host_functions::take_emitted() {
+            Ok(emitted) => (result, emitted),
+            Err(reason) => (Err(JSError::EmitLimitExceeded(reason)), Vec::new()),
+        };
+        for line in logs.iter() {
+            log::log!(
+                target: "fortuna::js",
+                line.level,
+                "worker={} job={} request={} {}",
+                worker_id,
+                id,
+                request_id.as_deref().unwrap_or("-"),
+                line.message
+            );
+        }
+
+        // The client may have stopped waiting for the reply so a failed
+        // send isn't an error.
+        let _ = reply.send(Reply {
+            result,
+            logs,
+            emitted,
+            stats,
+            crashed: false,
         });
+
+        self.stop_running();
+    }
+
+    // A terminate that arrived after the script finished, or before it
+    // started, must not leak into the next command. V8 only reports it as
+    // terminating while a script is being torn down, one that's merely
+    // been asked for isn't, so it's cancelled either way.
+    fn stop_running(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.running = 0;
+        state.last_active = Some(Instant::now());
+        if let Some(handle) = state.handle.as_ref() {
+            handle.cancel_terminate_execution();
+        }
     }
 
-    fn process(&mut self, cmd: Command) -> bool {
-        match cmd.operation {
-            Ops::EXIT => false,
-            Ops::EVAL => {
-                self.eval(cmd.payload);
-                true
+    fn handle_control(&mut self, control: Control) {
+        match control {
+            Control::Stats(reply) => {
+                let _ = reply.send(self.isolate.heap_stats());
+            }
+            Control::Recycle => self.recycle = true,
+            Control::StartProfile(interval) => {
+                if let Err(err) = self.isolate.start_profiling(interval) {
+                    let id = self.state.lock().unwrap().id;
+                    log::warn!("can't start profiling worker {}: {}", id, err);
+                }
             }
-            Ops::CALL => {
-                self.call(cmd.payload, cmd.args.as_slice());
-                true
+            Control::StopProfile(reply) => {
+                let _ = reply.send(self.isolate.stop_profiling());
             }
-            Ops::REWRITE => {
-                self.call(cmd.payload, cmd.args.as_slice());
-                true
+            Control::HeapSnapshot(chunks) => {
+                // Whoever asked gave up waiting
+                if chunks.is_closed() {
+                    return;
+                }
+                let taken = self.isolate.heap_snapshot(|chunk| {
+                    let _ = chunks.unbounded_send(Ok(chunk));
+                });
+                if let Err(reason) = taken {
+                    let _ = chunks.unbounded_send(Err(reason));
+                }
             }
         }
     }
+}
+
+// Commands that change what later commands see. CALLs can too but results
+// are only cached for callers that know theirs don't.
+fn changes_state(op: &Ops) -> bool {
+    matches!(
+        op,
+        Ops::EVAL
+            | Ops::GLOBALS
+            | Ops::REGISTER_DDOC
+            | Ops::EVICT_DDOC
+            | Ops::WASM_CALL
+            | Ops::RESET
+    )
+}
+
+impl ExecStats {
+    // request_id, if there is one, becomes the exemplar of the buckets the
+    // durations fall in
+    fn record(&self, op: &Ops, request_id: Option<&str>) {
+        let kind = format!("{:?}", op).to_lowercase();
+        metrics::COMMAND_QUEUE_WAIT_SECONDS.observe_with_exemplar(
+            &kind,
+            self.queue_wait,
+            request_id,
+        );
+        metrics::COMMAND_EXECUTION_SECONDS.observe_with_exemplar(&kind, self.execution, request_id);
+        metrics::COMMAND_CPU_SECONDS.observe_with_exemplar(&kind, self.cpu, request_id);
+        metrics::COMMAND_HEAP_USED_BYTES.observe_value(&kind, self.heap_used as f64);
+    }
+}
+
+// What worker id's thread is named, e.g. fortuna-js-1
+pub fn thread_name(id: usize) -> String {
+    format!("fortuna-js-{}", id)
+}
 
-    fn eval(&mut self, script: String) {
-        let resp = self.isolate.eval(script.as_str(), &[]);
-        self.send.send(resp).unwrap();
+// CPU time used by the calling thread so far
+fn thread_cpu_time() -> Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) } != 0 {
+        return Duration::default();
     }
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+// Cancels its job if dropped before the reply arrived, which is what happens
+// when hyper drops the request future because the client disconnected.
+struct CancelOnDrop {
+    job_id: u64,
+    cancelled: Arc<AtomicBool>,
+    state: SharedWorkerState,
+    done: bool,
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        JOBS.lock().unwrap().remove(&self.job_id);
+        if self.done {
+            return;
+        }
 
-    fn call(&mut self, fun_name: String, args: &[String]) {
-        let resp = self.isolate.call(fun_name.as_str(), args);
-        self.send.send(resp).unwrap();
+        self.cancelled.store(true, Ordering::SeqCst);
+        WorkerState::terminate(&self.state, self.job_id);
     }
 }
 
 #[derive(Clone)]
 pub struct JSClient {
-    pub tx: ClientTx,
-    pub rx: ClientRx,
+    tx: ClientTx,
+    state: SharedWorkerState,
+    rewrite_cache: Arc<RewriteCache>,
+    // Given to the commands sent through this client
+    request_id: Option<String>,
 }
 
 impl JSClient {
-    pub fn run(&self, cmd: Command) -> String {
-        self.tx.send(cmd).unwrap();
-        self.rx.recv().unwrap()
+    // The same worker, with request_id going along with each command for
+    // its logs, metrics and requestId() in JS
+    pub fn for_request(&self, request_id: &str) -> JSClient {
+        JSClient {
+            request_id: Some(request_id.to_string()),
+            ..self.clone()
+        }
+    }
+
+    pub async fn run(&self, cmd: Command) -> JSResult {
+        self.execute(cmd).await.result
+    }
+
+    // Like run but gives up if the worker hasn't answered within timeout.
+    pub async fn run_timeout(&self, cmd: Command, timeout: Duration) -> JSResult {
+        self.execute_timeout(cmd, timeout).await.result
+    }
+
+    // REWRITEs already done by a worker of the same JSEnv are answered from
+    // its RewriteCache without going to the worker
+    pub async fn execute(&self, cmd: Command) -> Reply {
+        self.execute_until(cmd, None).await
+    }
+
+    async fn execute_until(&self, cmd: Command, deadline: Option<Instant>) -> Reply {
+        let cache_key = self.rewrite_cache.key(&cmd);
+        if let Some(result) = cache_key
+            .as_ref()
+            .and_then(|key| self.rewrite_cache.get(key))
+        {
+            return Reply {
+                result: Ok(result),
+                logs: Vec::new(),
+                emitted: Vec::new(),
+                stats: ExecStats::default(),
+                crashed: false,
+            };
+        }
+        let reply = self.send(cmd, deadline).await;
+        if let (Some(key), Ok(result)) = (cache_key, &reply.result) {
+            self.rewrite_cache.put(key, result.clone());
+        }
+        reply
+    }
+
+    // A reply channel that closes without an answer means the worker
+    // panicked while running the command.
+    async fn send(&self, cmd: Command, deadline: Option<Instant>) -> Reply {
+        let id = NEXT_JOB_ID.fetch_add(1, Ordering::SeqCst);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let killed = Arc::new(AtomicBool::new(false));
+        let (reply, rx) = oneshot::channel::<Reply>();
+
+        let queued_at = Instant::now();
+        let job = InFlight {
+            op: format!("{:?}", cmd.operation).to_lowercase(),
+            request_id: self.request_id.clone(),
+            worker: self.state.clone(),
+            worker_id: self.id(),
+            killed: killed.clone(),
+            queued_at,
+            started_at: None,
+        };
+        JOBS.lock().unwrap().insert(id, job);
+        let job = Job {
+            id,
+            cmd,
+            reply,
+            cancelled: cancelled.clone(),
+            killed,
+            queued_at,
+            deadline,
+            request_id: self.request_id.clone(),
+        };
+        // From here on the guard takes the job out of JOBS
+        let mut guard = CancelOnDrop {
+            job_id: id,
+            cancelled,
+            state: self.state.clone(),
+            done: false,
+        };
+        {
+            // Sent under the lock so the job can't land in the queue after
+            // a worker that gave up has emptied it
+            let mut state = self.state.lock().unwrap();
+            state.queued.push_back(queued_at);
+            if state.queue.is_none() || self.tx.send(job).is_err() {
+                state.queued.pop_back();
+                guard.done = true;
+                return JSError::WorkerCrashed.into();
+            }
+        }
+
+        let resp = rx.await.unwrap_or_else(|_| Reply {
+            crashed: true,
+            ..Reply::from(JSError::WorkerCrashed)
+        });
+        guard.done = true;
+        resp
+    }
+
+    pub async fn execute_timeout(&self, cmd: Command, timeout: Duration) -> Reply {
+        match tokio::time::timeout(timeout, self.execute(cmd)).await {
+            Ok(resp) => resp,
+            Err(_) => JSError::Timeout.into(),
+        }
+    }
+
+    // Like execute_timeout but with the time the caller stops waiting. A
+    // command still queued then is never run, one that's running is
+    // terminated.
+    pub async fn execute_deadline(&self, cmd: Command, deadline: Instant) -> Reply {
+        let execute = self.execute_until(cmd, Some(deadline));
+        match tokio::time::timeout_at(deadline.into(), execute).await {
+            Ok(resp) => resp,
+            Err(_) => JSError::DeadlineExceeded.into(),
+        }
+    }
+
+    // The worker's id in the admin API
+    pub fn id(&self) -> usize {
+        self.state.lock().unwrap().id
+    }
+
+    // Changes whenever the worker's state might have, see WorkerState
+    pub fn generation(&self) -> u64 {
+        self.state.lock().unwrap().generation
+    }
+
+    // Number of commands waiting for the worker
+    pub fn queue_depth(&self) -> usize {
+        self.tx.len()
+    }
+
+    // How long the oldest command waiting for the worker has been waiting,
+    // zero if none are
+    pub fn queue_age(&self) -> Duration {
+        self.state.lock().unwrap().queue_age()
+    }
+
+    // Whether the worker stopped trying to create an isolate, after which
+    // everything sent to it fails with WorkerCrashed
+    pub fn gave_up(&self) -> bool {
+        self.state.lock().unwrap().queue.is_none()
+    }
+
+    // The next times isolates the worker starts panic instead
+    pub(crate) fn fail_isolates(&self, times: u32) {
+        self.state.lock().unwrap().failing_isolates = times;
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.state.lock().unwrap().running != 0 || !self.tx.is_empty()
+    }
+
+    // How long the worker has had nothing to do, zero while it's busy
+    pub fn idle_for(&self) -> Duration {
+        let state = self.state.lock().unwrap();
+        if state.running != 0 || !self.tx.is_empty() {
+            return Duration::default();
+        }
+        state
+            .last_active
+            .map_or_else(Duration::default, |at| at.elapsed())
+    }
+}
+
+#[derive(Debug)]
+pub struct WorkerInfo {
+    pub id: usize,
+    pub busy: bool,
+    pub os_thread_id: Option<i64>,
+    pub recent_gc: Vec<GcEvent>,
+}
+
+// A worker's counters along with its heap, which is missing if the worker
+// didn't answer in time, e.g. because it's running a long script
+#[derive(Debug)]
+pub struct WorkerStats {
+    pub id: usize,
+    // The worker's thread, see threads::list
+    pub thread: String,
+    pub os_thread_id: Option<i64>,
+    pub requests: u64,
+    pub errors: u64,
+    // CPU time spent running commands
+    pub cpu: Duration,
+    pub recycles: u64,
+    pub last_error: Option<String>,
+    pub queue_depth: usize,
+    pub queue_age: Duration,
+    pub heap: Option<HeapStats>,
+}
+
+// Asks every worker for its heap statistics at once, waiting at most timeout
+// for them to answer
+pub async fn worker_stats(timeout: Duration) -> Vec<WorkerStats> {
+    let states: Vec<SharedWorkerState> = WORKERS.lock().unwrap().values().cloned().collect();
+
+    let requests = states.into_iter().map(|state| async move {
+        let (mut worker, control) = {
+            let state = state.lock().unwrap();
+            let worker = WorkerStats {
+                id: state.id,
+                thread: thread_name(state.id),
+                os_thread_id: state.os_thread_id,
+                requests: state.requests,
+                errors: state.errors,
+                cpu: state.cpu,
+                recycles: state.recycles,
+                last_error: state.last_error.clone(),
+                queue_depth: state.queue.as_ref().map_or(0, |queue| queue.len()),
+                queue_age: state.queue_age(),
+                heap: None,
+            };
+            (worker, state.control.clone())
+        };
+
+        if let Some(control) = control {
+            let (reply, rx) = oneshot::channel();
+            if control.send(Control::Stats(reply)).is_ok() {
+                worker.heap = tokio::time::timeout(timeout, rx)
+                    .await
+                    .ok()
+                    .and_then(Result::ok);
+            }
+        }
+        worker
+    });
+    future::join_all(requests).await
+}
+
+// Runs V8's CPU profiler on every worker, or only on worker only, for
+// duration and returns each worker's .cpuprofile JSON. A worker still busy
+// with a script grace after that is left out.
+pub async fn profile_workers(
+    duration: Duration,
+    interval: Duration,
+    grace: Duration,
+    only: Option<usize>,
+) -> Vec<(usize, Result<String, String>)> {
+    let controls: Vec<(usize, ControlTx)> = WORKERS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(id, _)| only.map_or(true, |only| only == **id))
+        .filter_map(|(id, state)| Some((*id, state.lock().unwrap().control.clone()?)))
+        .collect();
+    for (_, control) in controls.iter() {
+        let _ = control.send(Control::StartProfile(interval));
+    }
+    tokio::time::delay_for(duration).await;
+
+    let profiles = controls.into_iter().map(|(id, control)| async move {
+        let (reply, rx) = oneshot::channel();
+        let profile = if control.send(Control::StopProfile(reply)).is_err() {
+            Err("the worker exited".to_string())
+        } else {
+            match tokio::time::timeout(grace, rx).await {
+                Ok(Ok(profile)) => profile,
+                Ok(Err(_)) => Err("the worker exited".to_string()),
+                Err(_) => Err("the worker is busy".to_string()),
+            }
+        };
+        (id, profile)
+    });
+    future::join_all(profiles).await
+}
+
+// Asks worker id for a heap snapshot, which it takes once it's done with
+// what it's running. None if there's no such worker.
+pub fn heap_snapshot(id: usize) -> Option<mpsc::UnboundedReceiver<Result<String, String>>> {
+    let control = WORKERS
+        .lock()
+        .unwrap()
+        .get(&id)
+        .and_then(|state| state.lock().unwrap().control.clone())?;
+    let (chunks, receiver) = mpsc::unbounded();
+    control.send(Control::HeapSnapshot(chunks)).ok()?;
+    Some(receiver)
+}
+
+// The longest any command has been waiting for a worker
+pub fn max_queue_age() -> Duration {
+    WORKERS
+        .lock()
+        .unwrap()
+        .values()
+        .map(|state| state.lock().unwrap().queue_age())
+        .max()
+        .unwrap_or_default()
+}
+
+pub fn workers() -> Vec<WorkerInfo> {
+    WORKERS
+        .lock()
+        .unwrap()
+        .values()
+        .map(|state| {
+            let state = state.lock().unwrap();
+            WorkerInfo {
+                id: state.id,
+                busy: state.running != 0,
+                os_thread_id: state.os_thread_id,
+                recent_gc: state
+                    .gc_log
+                    .as_ref()
+                    .map(|log| log.recent())
+                    .unwrap_or_default(),
+            }
+        })
+        .collect()
+}
+
+// A command queued for or running on a worker
+#[derive(Clone, Debug, PartialEq)]
+pub struct JobInfo {
+    pub id: u64,
+    pub op: String,
+    pub request_id: Option<String>,
+    pub worker: usize,
+    // Since it was queued
+    pub age: Duration,
+    pub running: bool,
+}
+
+// Every command queued or running, oldest first
+pub fn jobs() -> Vec<JobInfo> {
+    let now = Instant::now();
+    JOBS.lock()
+        .unwrap()
+        .iter()
+        .map(|(id, job)| JobInfo {
+            id: *id,
+            op: job.op.clone(),
+            request_id: job.request_id.clone(),
+            worker: job.worker_id,
+            age: now.duration_since(job.queued_at),
+            running: job.started_at.is_some(),
+        })
+        .collect()
+}
+
+// Fails job id with JSError::Killed, terminating its script if it's
+// running. Returns false if there's no such job, e.g. because it finished.
+pub fn kill_job(id: u64) -> bool {
+    let (killed, worker) = match JOBS.lock().unwrap().get(&id) {
+        Some(job) => (job.killed.clone(), job.worker.clone()),
+        None => return false,
+    };
+    killed.store(true, Ordering::SeqCst);
+    WorkerState::terminate(&worker, id);
+    true
+}
+
+// Asks worker id to replace its isolate with a fresh one from the snapshot
+// once it's done with what it's running. Commands queued for it are run on
+// the new isolate. Returns false if there's no such worker.
+pub fn recycle_worker(id: usize) -> bool {
+    let control = match WORKERS.lock().unwrap().get(&id) {
+        Some(state) => state.lock().unwrap().control.clone(),
+        None => return false,
+    };
+    match control {
+        Some(control) => control.send(Control::Recycle).is_ok(),
+        None => false,
+    }
+}
+
+// Commands with payload panic the worker running them, the next times of
+// them only
+pub(crate) fn crash_on(payload: &str, times: usize) {
+    CRASH_ON.lock().unwrap().insert(payload.to_string(), times);
+    CRASHES_ARMED.store(true, Ordering::Relaxed);
+}
+
+fn crash_if_asked(payload: &str) {
+    let crash = match CRASH_ON.lock().unwrap().get_mut(payload) {
+        Some(times) if *times > 0 => {
+            *times -= 1;
+            true
+        }
+        _ => false,
+    };
+    if crash {
+        panic!("crashing on purpose");
     }
 }
 
-pub fn create_js_env(js_env: &JSEnv) -> JSClient {
-    let (tx1, rx1) = cross_unbounded::<Command>();
-    let (tx2, rx2) = cross_unbounded::<String>();
+// Workers started from now on are pinned to one of cores. An empty list
+// leaves them unpinned.
+pub fn set_worker_cores(cores: Vec<usize>) {
+    *WORKER_CORES.lock().unwrap() = cores;
+}
+
+// The worker's context only gets the host functions granted by caps
+pub fn create_js_env(js_env: &JSEnv, caps: &Capabilities) -> JSClient {
+    let (tx, rx) = cross_unbounded::<Job>();
+    let (control_tx, control_rx) = cross_unbounded::<Control>();
+    let id = NEXT_WORKER_ID.fetch_add(1, Ordering::SeqCst);
+    let state = SharedWorkerState::new(Mutex::new(WorkerState {
+        id,
+        control: Some(control_tx),
+        queue: Some(rx.clone()),
+        last_active: Some(Instant::now()),
+        ..WorkerState::default()
+    }));
+    WORKERS.lock().unwrap().insert(id, state.clone());
 
-    let client = JSClient { tx: tx1, rx: rx2 };
+    let client = JSClient {
+        tx,
+        state: state.clone(),
+        rewrite_cache: js_env.rewrite_cache.clone(),
+        request_id: None,
+    };
 
-    JSServer::start(js_env, tx2, rx1);
+    JSServer::start(js_env, caps.clone(), rx, control_rx, state);
 
     client
 }
@@ -1,9 +1,73 @@
+pub mod access_log;
+pub mod admin;
+pub mod affinity;
+pub mod audit;
+pub mod auth;
+pub mod batching;
+pub mod builtins;
+pub mod checkpoint;
+pub mod client;
+pub mod collate;
+pub mod compression;
+pub mod config;
+pub mod deadline;
+pub mod design_docs;
+pub mod determinism;
+pub mod embed;
+pub mod error;
+#[cfg(feature = "etf")]
+pub mod etf;
+mod fetch;
+pub mod framing;
+pub mod gc;
+pub mod harness;
+pub mod health;
+pub mod host_functions;
 pub mod http_service;
+pub mod inspector;
 pub mod js_engine;
 pub mod js_server;
+mod line_writer;
+pub mod listen;
+pub mod metrics;
+pub mod mirror;
+mod modules;
+pub mod msgpack;
+pub mod pool;
+pub mod profile;
+pub mod proto;
+pub mod quarantine;
+pub mod rate_limit;
+pub mod repl;
+pub mod request;
+pub mod request_id;
+pub mod result_cache;
+pub mod rewrite_cache;
+pub mod self_check;
+mod semaphore;
+pub mod sessions;
+pub mod shadow;
+pub mod slow_requests;
+pub mod test_support;
+pub mod threads;
+pub mod throttle;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod trace;
+pub mod transcode;
+pub mod v8_config;
+pub mod websocket;
 
+pub use embed::{Fortuna, FortunaConfig};
+pub use error::{ErrorInfo, JSError, JSResult};
+pub use health::create_health_server;
+pub use host_functions::{Capabilities, Capability, Clock};
 pub use http_service::*;
 pub use js_engine::init as init_v8;
+pub use js_engine::init_with as init_v8_with;
 pub use js_engine::*;
+pub use pool::{Affinity, PoolConfig, WorkerPool};
+pub use proto::ateles;
+pub use request::{JsRequestBuilder, JsResponseBuilder};
 
 pub use js_server::create_js_env;
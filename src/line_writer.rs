@@ -0,0 +1,93 @@
+use crossbeam::crossbeam_channel::{bounded, Sender};
+use std::io::{self, BufWriter, Write};
+use std::thread::JoinHandle;
+
+use crate::metrics::Counter;
+use crate::threads;
+
+// Lines waiting to be written. Past this they're dropped rather than
+// slowing down requests.
+const MAX_PENDING: usize = 10_000;
+
+// Where a LineWriter's lines end up
+pub trait Sink: Send + 'static {
+    fn write_line(&mut self, line: &str) -> io::Result<()>;
+
+    // Called whenever the queue runs dry so the output is never far behind
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<W: Write + Send + 'static> Sink for BufWriter<W> {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        writeln!(self, "{}", line)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Write::flush(self)
+    }
+}
+
+// Writes lines to a sink from a thread of its own so the requests they're
+// about never wait on the disk. Shared by the access log, audit log and
+// mirror.
+pub struct LineWriter {
+    sender: Option<Sender<String>>,
+    writer: Option<JoinHandle<()>>,
+    // Counts lines dropped because the writer fell behind
+    dropped: &'static Counter,
+}
+
+impl LineWriter {
+    // what names the output in the warning logged if the sink fails, after
+    // which nothing more is written
+    pub fn start<S: Sink>(
+        thread_name: String,
+        what: String,
+        dropped: &'static Counter,
+        mut sink: S,
+    ) -> io::Result<LineWriter> {
+        let (sender, receiver) = bounded::<String>(MAX_PENDING);
+        let writer = threads::spawn(thread_name, move || {
+            for line in receiver.iter() {
+                let written = sink.write_line(&line).and_then(|_| {
+                    if receiver.is_empty() {
+                        sink.flush()
+                    } else {
+                        Ok(())
+                    }
+                });
+                if let Err(err) = written {
+                    log::warn!("stopped writing {}: {}", what, err);
+                    return;
+                }
+            }
+        })?;
+        Ok(LineWriter {
+            sender: Some(sender),
+            writer: Some(writer),
+            dropped,
+        })
+    }
+
+    // Queues line, or drops it if too many are waiting. Returns whether it
+    // was queued.
+    pub fn send(&self, line: String) -> bool {
+        let queued = self.sender.as_ref().unwrap().try_send(line).is_ok();
+        if !queued {
+            self.dropped.inc();
+        }
+        queued
+    }
+}
+
+// Everything queued is written before this returns
+impl Drop for LineWriter {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
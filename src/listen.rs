@@ -0,0 +1,103 @@
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+const BACKLOG: i32 = 1024;
+
+// Somewhere to accept connections, either an address like [::1]:8444 or a
+// Unix socket written as unix:/path/to/socket
+#[derive(Clone, Debug, PartialEq)]
+pub enum Bind {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Bind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(format!("missing socket path in {}", s));
+            }
+            return Ok(Bind::Unix(PathBuf::from(path)));
+        }
+        s.parse()
+            .map(Bind::Tcp)
+            .map_err(|_| format!("invalid listen address {}", s))
+    }
+}
+
+impl fmt::Display for Bind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Bind::Tcp(addr) => write!(f, "http://{}", addr),
+            Bind::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+// Binds a listening socket for addr. An IPv6 socket is dual-stack, i.e. [::]
+// accepts IPv4 connections as well, unless v6_only is set.
+pub fn bind(addr: &SocketAddr, v6_only: bool) -> io::Result<TcpListener> {
+    bind_with(addr, v6_only, false)
+}
+
+// Binds count listening sockets for addr with SO_REUSEPORT, so each can have
+// an acceptor of its own and the kernel spreads new connections over them.
+// With port 0 they all get the port the first one was given.
+pub fn bind_reuse_port(
+    addr: &SocketAddr,
+    v6_only: bool,
+    count: usize,
+) -> io::Result<Vec<TcpListener>> {
+    let first = bind_with(addr, v6_only, true)?;
+    let addr = first.local_addr()?;
+    let mut listeners = vec![first];
+    for _ in 1..count {
+        listeners.push(bind_with(&addr, v6_only, true)?);
+    }
+    Ok(listeners)
+}
+
+fn bind_with(addr: &SocketAddr, v6_only: bool, reuse_port: bool) -> io::Result<TcpListener> {
+    let domain = match addr {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    socket.bind(&SockAddr::from(*addr))?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into_tcp_listener())
+}
+
+// IPv4 clients of a dual-stack listener show up as ::ffff:a.b.c.d. Report
+// them as the IPv4 address they really are.
+pub fn peer_addr(addr: SocketAddr) -> SocketAddr {
+    if let SocketAddr::V6(v6) = addr {
+        let segments = v6.ip().segments();
+        if segments[..6] == [0, 0, 0, 0, 0, 0xffff] {
+            if let Some(v4) = v6.ip().to_ipv4() {
+                return SocketAddr::new(IpAddr::V4(v4), v6.port());
+            }
+        }
+    }
+    addr
+}
+
+pub fn family(addr: &SocketAddr) -> &'static str {
+    match addr {
+        SocketAddr::V4(_) => "ipv4",
+        SocketAddr::V6(_) => "ipv6",
+    }
+}
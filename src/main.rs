@@ -1,14 +1,670 @@
-use fortuna::{create_server, init_v8};
+use fortuna::access_log::{AccessLog, AccessLogFormat};
+use fortuna::admin::AdminConfig;
+use fortuna::affinity::parse_cores;
+use fortuna::audit::{AuditConfig, AuditDest, AuditLog};
+use fortuna::auth;
+use fortuna::checkpoint::CheckpointConfig;
+use fortuna::config::{self, Config};
+use fortuna::determinism::Determinism;
+use fortuna::harness::HarnessSpec;
+use fortuna::host_functions::EmitLimits;
+use fortuna::inspector::{self, InspectConfig};
+use fortuna::listen::Bind;
+use fortuna::mirror::Mirror;
+use fortuna::quarantine;
+use fortuna::rate_limit::RateLimitConfig;
+use fortuna::repl::{Outcome, Repl};
+use fortuna::result_cache::ResultCacheConfig;
+use fortuna::self_check;
+use fortuna::shadow::Shadow;
+use fortuna::slow_requests;
+use fortuna::throttle::{self, ThrottleConfig};
+use fortuna::trace::Tracer;
+use fortuna::v8_config::{self, SecurityMode};
+use fortuna::{
+    check_bundle, create_health_server, init_v8_with, run_server, snapshot_prebuilt, Affinity,
+    Capabilities, Clock, Http2Config, JSEnv, MakeService, PlatformConfig, PoolConfig,
+};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "fortuna", about = "Run JS for CouchDB in V8")]
+struct Opt {
+    /// TOML file of settings that take the place of these options, see
+    /// Config. Some are applied again whenever it changes or on SIGHUP.
+    #[structopt(long)]
+    config: Option<PathBuf>,
+
+    /// Address to serve requests on, IPv4 or IPv6 e.g. [::]:8444
+    #[structopt(long, default_value = "127.0.0.1:8444")]
+    listen: SocketAddr,
+
+    /// Serve requests on each of these instead of --listen, e.g.
+    /// --bind 127.0.0.1:8444 --bind [::1]:8444 --bind unix:/tmp/fortuna.sock
+    #[structopt(long)]
+    bind: Vec<Bind>,
+
+    /// Serve /stats and /admin/ only on this address or unix: socket rather
+    /// than with Execute, needs --admin-token-file
+    #[structopt(long)]
+    admin_listen: Option<Bind>,
+
+    /// File whose first line is the bearer token --admin-listen requires
+    #[structopt(long)]
+    admin_token_file: Option<PathBuf>,
+
+    /// File whose first line is the bearer token /Ateles/ and /ws requests
+    /// need, the same one CouchDB is configured with
+    #[structopt(long)]
+    execute_token_file: Option<PathBuf>,
+
+    /// Address to serve gRPC health checks on
+    #[structopt(long, default_value = "127.0.0.1:8445")]
+    health_listen: SocketAddr,
+
+    /// Don't accept IPv4 connections on an IPv6 listen address
+    #[structopt(long)]
+    ipv6_only: bool,
+
+    /// Bind each TCP address once per runtime thread with SO_REUSEPORT and
+    /// accept on all of them, for high connection rates
+    #[structopt(long)]
+    reuse_port: bool,
+
+    /// Delay background commands while this process uses more than this
+    /// fraction of all cores
+    #[structopt(long, default_value = "0.9")]
+    throttle_max_cpu: f64,
+
+    /// Delay background commands while the load average per core is above
+    /// this
+    #[structopt(long, default_value = "1.5")]
+    throttle_max_load: f64,
+
+    /// Run V8 without background threads
+    #[structopt(long)]
+    v8_single_threaded: bool,
+
+    /// Cores V8's background threads may use, e.g. 0-3
+    #[structopt(long)]
+    v8_cores: Option<String>,
+
+    /// Cores to pin the JS worker threads to, e.g. 4-15
+    #[structopt(long)]
+    worker_cores: Option<String>,
+
+    /// Most heap each isolate may use in MiB, leave unset for V8's default.
+    /// A warning is printed if the V8 build can't enforce this much.
+    #[structopt(long)]
+    max_heap_mb: Option<usize>,
+
+    /// Flags passed on to V8, e.g. "--max_old_space_size=512 --jitless"
+    #[structopt(long, default_value = "")]
+    v8_flags: String,
+
+    /// hardened runs V8 without a JIT and with its mitigations against
+    /// side channels on, trading speed for isolation
+    #[structopt(long, default_value = "default")]
+    security_mode: SecurityMode,
+
+    /// Seconds an idle session is kept for unless it asks for another TTL
+    #[structopt(long, default_value = "300")]
+    session_ttl: u64,
+
+    /// Directory of runtime JS to run shadowed connections against, in
+    /// place of the bundle in js/
+    #[structopt(long)]
+    shadow_bundle: Option<PathBuf>,
+
+    /// Fraction of connections to shadow when --shadow-bundle is set
+    #[structopt(long, default_value = "0.01")]
+    shadow_sample: f64,
+
+    /// Another bundle of runtime JS that requests can ask for by name with
+    /// their harness field, e.g. --harness experimental=/opt/harness.
+    /// Can be given more than once.
+    #[structopt(long)]
+    harness: Vec<HarnessSpec>,
+
+    /// Workers started for each --harness
+    #[structopt(long, default_value = "2")]
+    harness_workers: usize,
+
+    /// Directory to write a sample of requests to, as a corpus for
+    /// replaying later
+    #[structopt(long)]
+    mirror_dir: Option<PathBuf>,
+
+    /// Fraction of requests to mirror when --mirror-dir is set
+    #[structopt(long, default_value = "0.01")]
+    mirror_sample: f64,
+
+    /// Mirror whole requests rather than just their hashes and sizes. The
+    /// corpus then holds the docs that were sent so only use it to debug.
+    #[structopt(long)]
+    mirror_payloads: bool,
+
+    /// OTLP/HTTP endpoint to export request spans to, e.g.
+    /// http://localhost:4318/v1/traces
+    #[structopt(long)]
+    otlp_endpoint: Option<String>,
+
+    /// Fraction of requests without a traceparent header to trace when
+    /// --otlp-endpoint is set
+    #[structopt(long, default_value = "0")]
+    trace_sample: f64,
+
+    /// service.name the spans are exported under
+    #[structopt(long, default_value = "fortuna")]
+    trace_service_name: String,
+
+    /// JSON file of rate limits by client IP or design doc, see
+    /// RateLimitConfig
+    #[structopt(long)]
+    rate_limits: Option<PathBuf>,
+
+    /// File to append a line per request to, - for stdout
+    #[structopt(long)]
+    access_log: Option<PathBuf>,
+
+    /// Format of --access-log lines, common or json
+    #[structopt(long, default_value = "common")]
+    access_log_format: AccessLogFormat,
+
+    /// Most REWRITE results kept to answer repeated rewrites of the same
+    /// function without a worker, 0 to not keep any
+    #[structopt(long, default_value = "4096")]
+    rewrite_cache_entries: usize,
+
+    /// File to record every request that runs code of its own to, or
+    /// syslog
+    #[structopt(long)]
+    audit_log: Option<AuditDest>,
+
+    /// Rotate the --audit-log file once it's over this many MiB, 0 to never
+    #[structopt(long, default_value = "100")]
+    audit_log_max_mb: u64,
+
+    /// Rotated --audit-log files to keep
+    #[structopt(long, default_value = "5")]
+    audit_log_keep: usize,
+
+    /// Record the scripts themselves in the audit log, not just their hashes
+    #[structopt(long)]
+    audit_source: bool,
+
+    /// Also record a hash of each script together with its arguments
+    #[structopt(long)]
+    audit_hash_args: bool,
+
+    /// Cache successful results of these operations for this many
+    /// milliseconds, e.g. call=500,filter=250. Only for functions without
+    /// side effects.
+    #[structopt(long)]
+    result_cache: Option<String>,
+
+    /// Most results kept by --result-cache
+    #[structopt(long, default_value = "10000")]
+    result_cache_size: usize,
+
+    /// Compress responses of at least this many bytes for clients that
+    /// accept gzip or deflate
+    #[structopt(long)]
+    compress_min_size: Option<usize>,
+
+    /// Largest request body accepted, before and after decompression
+    #[structopt(long, default_value = "67108864")]
+    max_body_size: usize,
+
+    /// Crashes after which a script is rejected until cleared through
+    /// /admin/quarantine, 0 to never quarantine
+    #[structopt(long, default_value = "3")]
+    quarantine_after: u64,
+
+    /// Requests taking at least this many milliseconds are listed in
+    /// /admin/slow and the admin UI, 0 to not keep track
+    #[structopt(long, default_value = "1000")]
+    slow_request_ms: u64,
+
+    /// Only accept HTTP/2 connections, started with prior knowledge
+    #[structopt(long)]
+    http2_only: bool,
+
+    /// HTTP/2 flow control window of each stream in bytes
+    #[structopt(long)]
+    http2_stream_window: Option<u32>,
+
+    /// HTTP/2 flow control window of each connection in bytes
+    #[structopt(long)]
+    http2_connection_window: Option<u32>,
+
+    /// Most requests at once on an HTTP/2 connection
+    #[structopt(long)]
+    http2_max_streams: Option<u32>,
+
+    /// Seconds between keepalive pings on idle HTTP/2 connections, unset to
+    /// not ping
+    #[structopt(long)]
+    http2_keepalive: Option<u64>,
+
+    /// Seconds to wait for a keepalive ping to be answered before closing
+    /// the connection
+    #[structopt(long, default_value = "20")]
+    http2_keepalive_timeout: u64,
+
+    /// Build the snapshot, run smoke tests on an isolate, print how they
+    /// went and exit, nonzero if one failed
+    #[structopt(long)]
+    check: bool,
+
+    /// Share a pool of at most this many workers between connections
+    /// instead of starting one per connection
+    #[structopt(long)]
+    pool_max_size: Option<usize>,
+
+    /// Pool workers kept running however idle they are
+    #[structopt(long, default_value = "1")]
+    pool_min_size: usize,
+
+    /// Seconds a pool worker beyond --pool-min-size can be idle before it
+    /// is torn down
+    #[structopt(long, default_value = "300")]
+    pool_idle_ttl: u64,
+
+    /// How pool workers are picked: connection pins each connection to
+    /// one, request spreads requests over them and stream keeps requests
+    /// with the same stream_key on one worker, in order
+    #[structopt(long, default_value = "connection")]
+    pool_affinity: Affinity,
+
+    /// What Date.now() and new Date() give: real, command for the time the
+    /// running command started, or a fixed time in milliseconds since the
+    /// epoch or RFC 3339
+    #[structopt(long, default_value = "real")]
+    clock: Clock,
+
+    /// Timezone Date works in, e.g. UTC. Sets TZ for the process.
+    #[structopt(long)]
+    timezone: Option<String>,
+
+    /// Default locale of Intl and toLocaleString, e.g. en-US
+    #[structopt(long)]
+    locale: Option<String>,
+
+    /// What's done about map and reduce functions that use Math.random,
+    /// Date.now and the like: allow, warn to log them or strict to fail
+    /// registering them. Date is allowed with a fixed --clock.
+    #[structopt(long, default_value = "allow")]
+    determinism: Determinism,
+
+    /// Host functions the workers' contexts get, e.g.
+    /// digest,tokenize,collate,datetime,log,emit,wasm,fetch. Every one but
+    /// fetch by default.
+    #[structopt(long)]
+    capabilities: Option<Capabilities>,
+
+    /// Most rows a single command, e.g. mapping one doc, can emit before
+    /// it fails with emit_limit_exceeded
+    #[structopt(long, default_value = "100000")]
+    max_emit_rows: usize,
+
+    /// Largest [key, value] JSON of a single emitted row
+    #[structopt(long, default_value = "1048576")]
+    max_emit_row_bytes: usize,
+
+    /// Most bytes of rows a single command can emit
+    #[structopt(long, default_value = "67108864")]
+    max_emit_bytes: usize,
+
+    /// Save the most used design docs' functions to this file and register
+    /// those saved by the last run on every pool worker before taking
+    /// traffic
+    #[structopt(long)]
+    checkpoint: Option<PathBuf>,
+
+    /// Most design docs kept in --checkpoint
+    #[structopt(long, default_value = "100")]
+    checkpoint_ddocs: usize,
+
+    /// Seconds between saves of --checkpoint
+    #[structopt(long, default_value = "60")]
+    checkpoint_interval: u64,
+
+    /// Serve the Chrome DevTools protocol on this address for the worker
+    /// picked by --inspect-worker, e.g. 127.0.0.1:9229. Anyone who can
+    /// reach it can run code on that worker.
+    #[structopt(long)]
+    inspect: Option<SocketAddr>,
+
+    /// Id of the worker to inspect, workers are numbered from 1 as they
+    /// start
+    #[structopt(long, default_value = "1")]
+    inspect_worker: usize,
+
+    #[structopt(subcommand)]
+    cmd: Option<Cmd>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Cmd {
+    /// Evaluate JS line by line on a local isolate started from the
+    /// snapshot, see .help once it's running
+    Repl {
+        /// Files to evaluate before the first prompt
+        files: Vec<PathBuf>,
+    },
+}
+
+// The runtime's threads, as given to tokio::main below
+const CORE_THREADS: usize = 6;
 
 #[tokio::main(core_threads = 6)]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    init_v8();
-    let addr = "127.0.0.1:8444".parse().unwrap();
-    let server = create_server(&addr);
+    let mut opt = Opt::from_args();
+    let config = match &opt.config {
+        Some(path) => Config::from_file(path)?,
+        None => Config::default(),
+    };
+    merge(&mut opt, &config)?;
+
+    // Everything RUST_LOG lets through is logged, up to the config's
+    // log_level, which can be changed without a restart
+    let log_env = std::env::var_os("RUST_LOG").is_some();
+    env_logger::Builder::new()
+        .filter_level(log::LevelFilter::Trace)
+        .parse_default_env()
+        .init();
+    if !log_env {
+        // env_logger's own default
+        log::set_max_level(log::LevelFilter::Error);
+    }
+    if let Err(err) = check_bundle() {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+    let platform = PlatformConfig {
+        single_threaded: opt.v8_single_threaded,
+        platform_cores: parse_cores(opt.v8_cores.as_deref().unwrap_or(""))?,
+        worker_cores: parse_cores(opt.worker_cores.as_deref().unwrap_or(""))?,
+        max_heap: opt.max_heap_mb.map(|mb| mb * 1024 * 1024),
+        v8_flags: opt.v8_flags.clone(),
+        security_mode: opt.security_mode,
+        timezone: opt.timezone.clone(),
+        locale: opt.locale.clone(),
+    };
+    init_v8_with(&platform)?;
+    if opt.security_mode == SecurityMode::Hardened {
+        println!("V8 is hardened: no JIT and side channel mitigations on");
+    }
+    if opt.check {
+        let checks = match JSEnv::try_new() {
+            Ok(js_env) => self_check::run(&js_env),
+            Err(err) => {
+                eprintln!("FAIL snapshot: {}", err);
+                std::process::exit(1);
+            }
+        };
+        print!("{}", self_check::report(&checks));
+        std::process::exit(if self_check::passed(&checks) { 0 } else { 1 });
+    }
+    if let Some(Cmd::Repl { files }) = &opt.cmd {
+        let js_env = JSEnv::try_new()?;
+        let mut repl = Repl::new(&js_env, &Capabilities::all().with_clock(opt.clock));
+        for file in files {
+            if let Outcome::Print(text) = repl.handle(&format!(".load {}", file.display())) {
+                println!("{}", text);
+            }
+        }
+        let stdin = std::io::stdin();
+        repl.run(stdin.lock(), std::io::stdout())?;
+        return Ok(());
+    }
+    if let Some(heap) = v8_config::current() {
+        println!(
+            "V8 {}, pointer compression {}, isolate heaps can be held to at most {} bytes",
+            heap.version,
+            if heap.pointer_compression {
+                "on"
+            } else {
+                "off"
+            },
+            heap.max_heap_limit
+        );
+    }
+    throttle::start(ThrottleConfig {
+        max_cpu: opt.throttle_max_cpu,
+        max_load: opt.throttle_max_load,
+        ..ThrottleConfig::default()
+    });
+
+    if let Some(addr) = opt.inspect {
+        let addr = inspector::start(&InspectConfig {
+            addr,
+            worker: opt.inspect_worker,
+        })?;
+        println!(
+            "Inspecting worker {}, open devtools://devtools/bundled/js_app.html?v8only=true&ws={}/ws",
+            opt.inspect_worker, addr
+        );
+    }
+
+    quarantine::set_threshold(opt.quarantine_after);
+    slow_requests::set_threshold(Duration::from_millis(opt.slow_request_ms));
+    config.apply();
+
+    let capabilities = opt
+        .capabilities
+        .clone()
+        .unwrap_or_else(Capabilities::local)
+        .with_clock(opt.clock)
+        .with_determinism(opt.determinism)
+        .with_emit_limits(EmitLimits {
+            max_rows: opt.max_emit_rows,
+            max_row_bytes: opt.max_emit_row_bytes,
+            max_bytes: opt.max_emit_bytes,
+        });
+    let mut make_service = MakeService::with_capabilities(capabilities.clone())
+        .with_session_ttl(Duration::from_secs(opt.session_ttl))
+        .with_rewrite_cache_size(opt.rewrite_cache_entries);
+    if let Some(dir) = &opt.shadow_bundle {
+        let shadow_env = JSEnv::from_dir(dir)?;
+        make_service = make_service.with_shadow(Shadow::new(
+            shadow_env,
+            capabilities.clone(),
+            opt.shadow_sample,
+        ));
+        println!(
+            "Shadowing {} of connections on {}",
+            opt.shadow_sample,
+            dir.display()
+        );
+    }
+
+    for spec in &opt.harness {
+        let harness_env = JSEnv::from_dir(&spec.dir)?;
+        make_service = make_service.with_harness(&spec.name, harness_env, opt.harness_workers);
+        println!(
+            "Loaded harness {} from {} on {} workers",
+            spec.name,
+            spec.dir.display(),
+            opt.harness_workers
+        );
+    }
+
+    if let Some(dir) = &opt.mirror_dir {
+        let mirror = Mirror::start(dir, opt.mirror_sample, opt.mirror_payloads)?;
+        println!(
+            "Mirroring {} of requests to {}",
+            opt.mirror_sample,
+            mirror.path().display()
+        );
+        make_service = make_service.with_mirror(mirror);
+    }
+
+    if let Some(endpoint) = &opt.otlp_endpoint {
+        let tracer = Tracer::start(endpoint, &opt.trace_service_name, opt.trace_sample);
+        println!("Exporting request spans to {}", endpoint);
+        make_service = make_service.with_tracer(tracer);
+    }
+
+    if let Some(path) = &opt.access_log {
+        let access_log = AccessLog::open(path, opt.access_log_format)?;
+        make_service = make_service.with_access_log(access_log);
+    }
+
+    if let Some(dest) = &opt.audit_log {
+        let config = AuditConfig {
+            max_bytes: opt.audit_log_max_mb * 1024 * 1024,
+            keep: opt.audit_log_keep,
+            source: opt.audit_source,
+            hash_args: opt.audit_hash_args,
+            ..AuditConfig::new(dest.clone())
+        };
+        make_service = make_service.with_audit_log(AuditLog::open(&config)?);
+    }
+
+    match (&opt.admin_listen, &opt.admin_token_file) {
+        (Some(bind), Some(token_file)) => {
+            let config = AdminConfig::new(bind.clone(), token_file)?;
+            make_service = make_service.with_admin(config);
+        }
+        (Some(_), None) => return Err("--admin-listen needs --admin-token-file".into()),
+        (None, _) => (),
+    }
 
-    println!("Listening on http://{}", addr);
+    if let Some(path) = &opt.execute_token_file {
+        let token = auth::read_token(path, "execute")?;
+        make_service = make_service.with_execute_token(token);
+    }
 
-    server.await?;
+    if let Some(path) = &opt.rate_limits {
+        let config = RateLimitConfig::from_file(path)?;
+        println!(
+            "Rate limiting with {} rules from {}",
+            config.rules.len(),
+            path.display()
+        );
+        make_service = make_service.with_rate_limits(config);
+    }
+
+    if let Some(ttls) = &opt.result_cache {
+        let config = ResultCacheConfig::parse(ttls, opt.result_cache_size)?;
+        println!(
+            "Caching results of {} for up to {} requests",
+            ttls, config.max_entries
+        );
+        make_service = make_service.with_result_cache(config);
+    }
+
+    if let Some(max_size) = opt.pool_max_size {
+        let config = PoolConfig {
+            min_size: opt.pool_min_size.min(max_size),
+            max_size,
+            idle_ttl: Duration::from_secs(opt.pool_idle_ttl),
+        };
+        make_service = make_service.with_pool_config(config, opt.pool_affinity);
+    }
+    if let Some(path) = &opt.checkpoint {
+        println!(
+            "Checkpointing the {} most used design docs to {}",
+            opt.checkpoint_ddocs,
+            path.display()
+        );
+        make_service = make_service.with_checkpoint(CheckpointConfig {
+            path: path.clone(),
+            max_design_docs: opt.checkpoint_ddocs,
+            interval: Duration::from_secs(opt.checkpoint_interval.max(1)),
+        });
+    }
+    make_service = make_service
+        .with_max_body_size(opt.max_body_size)
+        .with_http2(Http2Config {
+            only: opt.http2_only,
+            initial_stream_window: opt.http2_stream_window,
+            initial_connection_window: opt.http2_connection_window,
+            max_concurrent_streams: opt.http2_max_streams,
+            keep_alive_interval: opt.http2_keepalive.map(Duration::from_secs),
+            keep_alive_timeout: Duration::from_secs(opt.http2_keepalive_timeout),
+        });
+    if opt.reuse_port {
+        make_service = make_service.with_acceptors(CORE_THREADS);
+    }
+    if let Some(min_size) = opt.compress_min_size {
+        make_service = make_service.with_compression(min_size);
+    }
+
+    let startup = make_service.js_env().measure_startup();
+    println!(
+        "Snapshot is {} bytes{}, isolates start in {:?} and take {:?} for a first eval",
+        startup.snapshot_bytes,
+        if snapshot_prebuilt() {
+            " (prebuilt)"
+        } else {
+            ""
+        },
+        startup.create,
+        startup.first_eval
+    );
+
+    let binds = if opt.bind.is_empty() {
+        vec![Bind::Tcp(opt.listen)]
+    } else {
+        opt.bind.clone()
+    };
+
+    let health_addr = opt.health_listen;
+    let health = make_service.health_service();
+    tokio::spawn(async move {
+        // Load balancers would take the node for dead, so rather than keep
+        // serving without health checks give up
+        if let Err(err) = create_health_server(health_addr, health).await {
+            log::error!("gRPC health server failed: {}", err);
+            std::process::exit(1);
+        }
+    });
+
+    println!("gRPC health checks on http://{}", health_addr);
+
+    if let Some(path) = opt.config.clone() {
+        tokio::spawn(config::watch(path, config));
+    }
+
+    run_server(&binds, opt.ipv6_only, make_service).await?;
+
+    Ok(())
+}
 
+// Settings from the config file take the place of the options'
+fn merge(opt: &mut Opt, config: &Config) -> Result<(), String> {
+    let binds = config.binds();
+    if !binds.is_empty() {
+        opt.bind = binds;
+    }
+    opt.pool_max_size = config.pool_max_size.or(opt.pool_max_size);
+    opt.pool_min_size = config.pool_min_size.unwrap_or(opt.pool_min_size);
+    opt.pool_idle_ttl = config.pool_idle_ttl_secs.unwrap_or(opt.pool_idle_ttl);
+    opt.session_ttl = config.session_ttl_secs.unwrap_or(opt.session_ttl);
+    opt.max_body_size = config.max_body_size.unwrap_or(opt.max_body_size);
+    opt.access_log = config.access_log.clone().or_else(|| opt.access_log.take());
+    if let Some(format) = &config.access_log_format {
+        opt.access_log_format = format.parse()?;
+    }
+    opt.slow_request_ms = config.slow_request_ms.unwrap_or(opt.slow_request_ms);
+    opt.quarantine_after = config.quarantine_after.unwrap_or(opt.quarantine_after);
+    if let Some(flags) = &config.v8_flags {
+        opt.v8_flags = flags.clone();
+    }
+    if let Some(mode) = &config.security_mode {
+        opt.security_mode = mode.parse()?;
+    }
+    if let Some(caps) = &config.capabilities {
+        opt.capabilities = Some(caps.parse()?);
+    }
+    opt.max_emit_rows = config.max_emit_rows.unwrap_or(opt.max_emit_rows);
+    opt.max_emit_row_bytes = config.max_emit_row_bytes.unwrap_or(opt.max_emit_row_bytes);
+    opt.max_emit_bytes = config.max_emit_bytes.unwrap_or(opt.max_emit_bytes);
     Ok(())
 }
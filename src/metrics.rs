@@ -0,0 +1,427 @@
+use lazy_static::lazy_static;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Bucket upper bounds in seconds used for pause and latency histograms
+const DURATION_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+// Bucket upper bounds for the number of requests served on one connection
+const COUNT_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 50.0, 100.0, 500.0, 1000.0, 10000.0];
+
+// Bucket upper bounds in bytes for heap sizes
+const BYTES_BUCKETS: &[f64] = &[
+    1048576.0,
+    4194304.0,
+    16777216.0,
+    67108864.0,
+    268435456.0,
+    1073741824.0,
+];
+
+// Bucket upper bounds in seconds for how long connections stay open
+const LIFETIME_BUCKETS: &[f64] = &[0.1, 1.0, 10.0, 60.0, 300.0, 900.0, 3600.0];
+
+lazy_static! {
+    pub static ref GC_PAUSE_SECONDS: Histogram = Histogram::new(
+        "fortuna_gc_pause_seconds",
+        "V8 garbage collection pauses",
+        DURATION_BUCKETS
+    );
+    pub static ref COMMAND_QUEUE_WAIT_SECONDS: Histogram = Histogram::new(
+        "fortuna_command_queue_wait_seconds",
+        "Time commands spent queued for a worker",
+        DURATION_BUCKETS
+    );
+    pub static ref COMMAND_EXECUTION_SECONDS: Histogram = Histogram::new(
+        "fortuna_command_execution_seconds",
+        "Wall time spent running commands",
+        DURATION_BUCKETS
+    );
+    pub static ref COMMAND_CPU_SECONDS: Histogram = Histogram::new(
+        "fortuna_command_cpu_seconds",
+        "CPU time spent running commands",
+        DURATION_BUCKETS
+    );
+    pub static ref COMMAND_HEAP_USED_BYTES: Histogram = Histogram::new(
+        "fortuna_command_heap_used_bytes",
+        "V8 heap in use after running commands",
+        BYTES_BUCKETS
+    );
+    pub static ref SNAPSHOT_BYTES: Gauge = Gauge::new(
+        "fortuna_snapshot_bytes",
+        "Size of the startup snapshot isolates are created from"
+    );
+    pub static ref ISOLATE_CREATE_SECONDS: Histogram = Histogram::new(
+        "fortuna_isolate_create_seconds",
+        "Time taken to create an isolate from the snapshot",
+        DURATION_BUCKETS
+    );
+    pub static ref FIRST_COMMAND_SECONDS: Histogram = Histogram::new(
+        "fortuna_first_command_seconds",
+        "Wall time of the first command run on a fresh isolate",
+        DURATION_BUCKETS
+    );
+    pub static ref QUEUE_AGE_SECONDS: Gauge = Gauge::new(
+        "fortuna_queue_age_seconds",
+        "How long the oldest command waiting for a worker has been waiting"
+    );
+    pub static ref SHED_COMMANDS_TOTAL: Counter = Counter::new(
+        "fortuna_shed_commands_total",
+        "Background commands turned away because a worker's queue was too old"
+    );
+    pub static ref RETRIED_COMMANDS_TOTAL: Counter = Counter::new(
+        "fortuna_retried_commands_total",
+        "Commands sent again after a worker crashed while they were on it"
+    );
+    pub static ref DESIGN_DOC_CONTEXTS: Gauge = Gauge::new(
+        "fortuna_design_doc_contexts",
+        "Design doc contexts kept over every isolate"
+    );
+    pub static ref EVICTED_CONTEXTS_TOTAL: Counter = Counter::new(
+        "fortuna_evicted_contexts_total",
+        "Least recently used design doc contexts evicted to make room for another"
+    );
+    pub static ref THROTTLE_ACTIVE: Gauge = Gauge::new(
+        "fortuna_throttle_active",
+        "1 while background commands are being delayed because the host is busy"
+    );
+    pub static ref THROTTLED_COMMANDS_TOTAL: Counter = Counter::new(
+        "fortuna_throttled_commands_total",
+        "Background commands that were delayed by the throttle"
+    );
+    pub static ref PROCESS_CPU_RATIO: Gauge = Gauge::new(
+        "fortuna_process_cpu_ratio",
+        "CPU used by this process as a fraction of all cores"
+    );
+    pub static ref LOAD_PER_CORE: Gauge = Gauge::new(
+        "fortuna_load_per_core",
+        "One minute load average divided by the number of cores"
+    );
+    pub static ref CONNECTIONS_TOTAL: Counter =
+        Counter::new("fortuna_connections_total", "Connections accepted");
+    pub static ref CONNECTIONS_OPEN: Gauge =
+        Gauge::new("fortuna_connections_open", "Connections currently open");
+    pub static ref CONNECTION_REQUESTS: Histogram = Histogram::new(
+        "fortuna_connection_requests",
+        "Requests served per connection before it closed",
+        COUNT_BUCKETS
+    );
+    pub static ref CONNECTION_SECONDS: Histogram = Histogram::new(
+        "fortuna_connection_seconds",
+        "How long connections stayed open",
+        LIFETIME_BUCKETS
+    );
+    pub static ref SESSIONS_OPEN: Gauge =
+        Gauge::new("fortuna_sessions_open", "Sessions that haven't expired yet");
+    pub static ref POOL_WORKERS: Gauge = Gauge::new(
+        "fortuna_pool_workers",
+        "Workers in the shared pool, busy or warm"
+    );
+    pub static ref QUARANTINED_SCRIPTS: Gauge = Gauge::new(
+        "fortuna_quarantined_scripts",
+        "Scripts rejected for crashing workers"
+    );
+    pub static ref MIRRORED_REQUESTS_TOTAL: Counter = Counter::new(
+        "fortuna_mirrored_requests_total",
+        "Requests written to the mirror corpus"
+    );
+    pub static ref MIRROR_DROPPED_TOTAL: Counter = Counter::new(
+        "fortuna_mirror_dropped_total",
+        "Sampled requests not mirrored because the writer fell behind"
+    );
+    pub static ref RATE_LIMITED_TOTAL: Counter = Counter::new(
+        "fortuna_rate_limited_total",
+        "Requests rejected for being over a rate limit"
+    );
+    pub static ref RESULT_CACHE_HITS_TOTAL: Counter = Counter::new(
+        "fortuna_result_cache_hits_total",
+        "Requests answered from the result cache"
+    );
+    pub static ref RESULT_CACHE_MISSES_TOTAL: Counter = Counter::new(
+        "fortuna_result_cache_misses_total",
+        "Cacheable requests that had to be run"
+    );
+    pub static ref REWRITE_CACHE_HITS_TOTAL: Counter = Counter::new(
+        "fortuna_rewrite_cache_hits_total",
+        "REWRITEs answered from the rewrite cache"
+    );
+    pub static ref REWRITE_CACHE_MISSES_TOTAL: Counter = Counter::new(
+        "fortuna_rewrite_cache_misses_total",
+        "Cacheable REWRITEs that had to be run"
+    );
+    pub static ref ACCESS_LOG_DROPPED_TOTAL: Counter = Counter::new(
+        "fortuna_access_log_dropped_total",
+        "Access log lines not written because the writer fell behind"
+    );
+    pub static ref AUDIT_LOG_DROPPED_TOTAL: Counter = Counter::new(
+        "fortuna_audit_log_dropped_total",
+        "Audit log entries not written because the writer fell behind or syslog was unreachable"
+    );
+    pub static ref SHADOW_COMMANDS_TOTAL: Counter = Counter::new(
+        "fortuna_shadow_commands_total",
+        "Commands run a second time on a shadow worker"
+    );
+    pub static ref SHADOW_MISMATCHES_TOTAL: Counter = Counter::new(
+        "fortuna_shadow_mismatches_total",
+        "Shadowed commands whose result differed from the primary worker's"
+    );
+    pub static ref TRACE_SPANS_EXPORTED_TOTAL: Counter = Counter::new(
+        "fortuna_trace_spans_exported_total",
+        "Spans sent to the trace collector"
+    );
+    pub static ref TRACE_SPANS_DROPPED_TOTAL: Counter = Counter::new(
+        "fortuna_trace_spans_dropped_total",
+        "Spans not exported because the collector failed or fell behind"
+    );
+    pub static ref SHADOW_EXECUTION_SECONDS: Histogram = Histogram::new(
+        "fortuna_shadow_execution_seconds",
+        "Time shadowed commands took on the primary and on the shadow worker",
+        DURATION_BUCKETS
+    );
+}
+
+// Renders every metric in the Prometheus text format
+pub fn render() -> String {
+    render_as(false)
+}
+
+// OpenMetrics, which also has the request ids of exemplars
+pub fn render_openmetrics() -> String {
+    let mut out = render_as(true);
+    out.push_str("# EOF\n");
+    out
+}
+
+fn render_as(openmetrics: bool) -> String {
+    let mut out = String::new();
+    GC_PAUSE_SECONDS.render(&mut out, openmetrics);
+    COMMAND_QUEUE_WAIT_SECONDS.render(&mut out, openmetrics);
+    COMMAND_EXECUTION_SECONDS.render(&mut out, openmetrics);
+    COMMAND_CPU_SECONDS.render(&mut out, openmetrics);
+    COMMAND_HEAP_USED_BYTES.render(&mut out, openmetrics);
+    SNAPSHOT_BYTES.render(&mut out, openmetrics);
+    ISOLATE_CREATE_SECONDS.render(&mut out, openmetrics);
+    FIRST_COMMAND_SECONDS.render(&mut out, openmetrics);
+    QUEUE_AGE_SECONDS.render(&mut out, openmetrics);
+    SHED_COMMANDS_TOTAL.render(&mut out, openmetrics);
+    RETRIED_COMMANDS_TOTAL.render(&mut out, openmetrics);
+    DESIGN_DOC_CONTEXTS.render(&mut out, openmetrics);
+    EVICTED_CONTEXTS_TOTAL.render(&mut out, openmetrics);
+    THROTTLE_ACTIVE.render(&mut out, openmetrics);
+    THROTTLED_COMMANDS_TOTAL.render(&mut out, openmetrics);
+    PROCESS_CPU_RATIO.render(&mut out, openmetrics);
+    LOAD_PER_CORE.render(&mut out, openmetrics);
+    CONNECTIONS_TOTAL.render(&mut out, openmetrics);
+    CONNECTIONS_OPEN.render(&mut out, openmetrics);
+    CONNECTION_REQUESTS.render(&mut out, openmetrics);
+    CONNECTION_SECONDS.render(&mut out, openmetrics);
+    SESSIONS_OPEN.render(&mut out, openmetrics);
+    POOL_WORKERS.render(&mut out, openmetrics);
+    QUARANTINED_SCRIPTS.render(&mut out, openmetrics);
+    MIRRORED_REQUESTS_TOTAL.render(&mut out, openmetrics);
+    MIRROR_DROPPED_TOTAL.render(&mut out, openmetrics);
+    SHADOW_COMMANDS_TOTAL.render(&mut out, openmetrics);
+    SHADOW_MISMATCHES_TOTAL.render(&mut out, openmetrics);
+    SHADOW_EXECUTION_SECONDS.render(&mut out, openmetrics);
+    TRACE_SPANS_EXPORTED_TOTAL.render(&mut out, openmetrics);
+    TRACE_SPANS_DROPPED_TOTAL.render(&mut out, openmetrics);
+    AUDIT_LOG_DROPPED_TOTAL.render(&mut out, openmetrics);
+    REWRITE_CACHE_HITS_TOTAL.render(&mut out, openmetrics);
+    REWRITE_CACHE_MISSES_TOTAL.render(&mut out, openmetrics);
+    out
+}
+
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub fn new(name: &'static str, help: &'static str) -> Counter {
+        Counter {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, out: &mut String, openmetrics: bool) {
+        // OpenMetrics names the family without the _total of its sample
+        let family = if openmetrics {
+            self.name.trim_end_matches("_total")
+        } else {
+            self.name
+        };
+        writeln!(out, "# HELP {} {}", family, self.help).unwrap();
+        writeln!(out, "# TYPE {} counter", family).unwrap();
+        writeln!(out, "{} {}", self.name, self.get()).unwrap();
+    }
+}
+
+pub struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    value: Mutex<f64>,
+}
+
+impl Gauge {
+    pub fn new(name: &'static str, help: &'static str) -> Gauge {
+        Gauge {
+            name,
+            help,
+            value: Mutex::new(0.0),
+        }
+    }
+
+    pub fn inc(&self) {
+        *self.value.lock().unwrap() += 1.0;
+    }
+
+    pub fn dec(&self) {
+        *self.value.lock().unwrap() -= 1.0;
+    }
+
+    pub fn set(&self, value: f64) {
+        *self.value.lock().unwrap() = value;
+    }
+
+    pub fn get(&self) -> f64 {
+        *self.value.lock().unwrap()
+    }
+
+    fn render(&self, out: &mut String, _openmetrics: bool) {
+        writeln!(out, "# HELP {} {}", self.name, self.help).unwrap();
+        writeln!(out, "# TYPE {} gauge", self.name).unwrap();
+        writeln!(out, "{} {}", self.name, self.get()).unwrap();
+    }
+}
+
+#[derive(Default)]
+struct HistogramSeries {
+    counts: Vec<u64>,
+    count: u64,
+    sum: f64,
+    // The request id and value last observed in each bucket, +Inf last
+    exemplars: Vec<Option<(String, f64)>>,
+}
+
+// A histogram with a single `kind` label. Series are created the first time a
+// kind is observed.
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    buckets: &'static [f64],
+    series: Mutex<Vec<(String, HistogramSeries)>>,
+}
+
+impl Histogram {
+    pub fn new(name: &'static str, help: &'static str, buckets: &'static [f64]) -> Histogram {
+        Histogram {
+            name,
+            help,
+            buckets,
+            series: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn observe(&self, kind: &str, value: Duration) {
+        self.observe_value(kind, value.as_secs_f64());
+    }
+
+    pub fn observe_value(&self, kind: &str, value: f64) {
+        self.observe_exemplar(kind, value, None);
+    }
+
+    pub fn observe_with_exemplar(&self, kind: &str, value: Duration, request_id: Option<&str>) {
+        self.observe_exemplar(kind, value.as_secs_f64(), request_id);
+    }
+
+    fn observe_exemplar(&self, kind: &str, value: f64, request_id: Option<&str>) {
+        let mut series = self.series.lock().unwrap();
+        let idx = match series.iter().position(|(k, _)| k == kind) {
+            Some(idx) => idx,
+            None => {
+                let counts = vec![0; self.buckets.len()];
+                let new = HistogramSeries {
+                    counts,
+                    exemplars: vec![None; self.buckets.len() + 1],
+                    ..HistogramSeries::default()
+                };
+                series.push((kind.to_string(), new));
+                series.len() - 1
+            }
+        };
+
+        let (_, s) = &mut series[idx];
+        for (i, bound) in self.buckets.iter().enumerate() {
+            if value <= *bound {
+                s.counts[i] += 1;
+            }
+        }
+        s.count += 1;
+        s.sum += value;
+        if let Some(request_id) = request_id {
+            // The bucket the value falls in rather than every one it counts
+            // towards
+            let bucket = self
+                .buckets
+                .iter()
+                .position(|bound| value <= *bound)
+                .unwrap_or_else(|| self.buckets.len());
+            s.exemplars[bucket] = Some((request_id.to_string(), value));
+        }
+    }
+
+    fn render(&self, out: &mut String, openmetrics: bool) {
+        writeln!(out, "# HELP {} {}", self.name, self.help).unwrap();
+        writeln!(out, "# TYPE {} histogram", self.name).unwrap();
+        for (kind, s) in self.series.lock().unwrap().iter() {
+            let exemplar = |bucket: usize| match &s.exemplars[bucket] {
+                Some((request_id, value)) if openmetrics => {
+                    format!(" # {{request_id=\"{}\"}} {}", request_id, value)
+                }
+                _ => String::new(),
+            };
+            for (i, (bound, count)) in self.buckets.iter().zip(s.counts.iter()).enumerate() {
+                writeln!(
+                    out,
+                    "{}_bucket{{kind=\"{}\",le=\"{}\"}} {}{}",
+                    self.name,
+                    kind,
+                    bound,
+                    count,
+                    exemplar(i)
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                "{}_bucket{{kind=\"{}\",le=\"+Inf\"}} {}{}",
+                self.name,
+                kind,
+                s.count,
+                exemplar(self.buckets.len())
+            )
+            .unwrap();
+            writeln!(out, "{}_sum{{kind=\"{}\"}} {}", self.name, kind, s.sum).unwrap();
+            writeln!(out, "{}_count{{kind=\"{}\"}} {}", self.name, kind, s.count).unwrap();
+        }
+    }
+}
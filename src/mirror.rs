@@ -0,0 +1,210 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::http_service::request_hash;
+use crate::line_writer::LineWriter;
+use crate::metrics;
+use crate::proto::ateles::{JsRequest, JsResponse};
+use crate::quarantine::script_hash;
+use crate::shadow::is_sampled;
+
+// One mirrored request and what came of it, a line of JSON in the corpus
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    // Milliseconds since the epoch when it was answered
+    pub ts: u64,
+    pub action: i32,
+    pub script_sha256: String,
+    pub script_bytes: usize,
+    // The script along with its args, the same hash quarantine goes by
+    pub request_sha256: String,
+    pub args: usize,
+    pub args_bytes: usize,
+    pub json_args: bool,
+    // Hash of the session id so a replay can keep a session's requests
+    // together
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_sha256: Option<String>,
+    pub timeout: i32,
+    pub priority: i32,
+    pub emit_format: i32,
+    pub result_encoding: i32,
+    pub batch_size: i32,
+    pub status: i32,
+    pub error_type: i32,
+    pub result_bytes: usize,
+    pub emitted_rows: usize,
+    pub took_us: u64,
+    // Only kept when payloads are mirrored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<Payload>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Payload {
+    pub script: String,
+    pub args: Vec<String>,
+    pub json_args: Vec<String>,
+    pub doc_id: String,
+    pub requires: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub wasm_module: Vec<u8>,
+}
+
+impl Record {
+    pub fn new(
+        request: &JsRequest,
+        response: &JsResponse,
+        took: Duration,
+        payloads: bool,
+    ) -> Record {
+        let args = request.args.iter().chain(request.json_args.iter());
+        let payload = if payloads {
+            Some(Payload {
+                script: request.script.clone(),
+                args: request.args.clone(),
+                json_args: request.json_args.clone(),
+                doc_id: request.doc_id.clone(),
+                requires: request.requires.clone(),
+                wasm_module: request.wasm_module.clone(),
+            })
+        } else {
+            None
+        };
+        Record {
+            ts: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            action: request.action,
+            script_sha256: script_hash(&request.script, None),
+            script_bytes: request.script.len(),
+            request_sha256: request_hash(request),
+            args: request.args.len() + request.json_args.len(),
+            args_bytes: args.map(String::len).sum(),
+            json_args: !request.json_args.is_empty(),
+            session_sha256: if request.session_id.is_empty() {
+                None
+            } else {
+                Some(script_hash(&request.session_id, None))
+            },
+            timeout: request.timeout,
+            priority: request.priority,
+            emit_format: request.emit_format,
+            result_encoding: request.result_encoding,
+            batch_size: request.batch_size,
+            status: response.status,
+            error_type: response.error_type,
+            result_bytes: response.result.len(),
+            emitted_rows: response
+                .emitted
+                .iter()
+                .map(|group| group.rows.len() + group.kv_rows.len())
+                .sum(),
+            took_us: took.as_micros() as u64,
+            payload,
+        }
+    }
+
+    // The request to replay, if the payload was mirrored. Timeouts, priority
+    // and the like come back but not the session id.
+    pub fn request(&self) -> Option<JsRequest> {
+        let payload = self.payload.as_ref()?;
+        Some(JsRequest {
+            action: self.action,
+            script: payload.script.clone(),
+            args: payload.args.clone(),
+            json_args: payload.json_args.clone(),
+            doc_id: payload.doc_id.clone(),
+            requires: payload.requires.clone(),
+            wasm_module: payload.wasm_module.clone(),
+            timeout: self.timeout,
+            priority: self.priority,
+            emit_format: self.emit_format,
+            result_encoding: self.result_encoding,
+            batch_size: self.batch_size,
+            ..JsRequest::default()
+        })
+    }
+}
+
+// Writes a sample of requests to a corpus directory, one file of JSON lines
+// per run, e.g. to replay production-like traffic against a new build. Only
+// hashes and sizes are written unless payloads is set, which is meant for
+// debugging since the corpus then holds whatever docs were sent.
+pub struct Mirror {
+    sample: f64,
+    payloads: bool,
+    requests: AtomicU64,
+    path: PathBuf,
+    writer: LineWriter,
+}
+
+impl Mirror {
+    // sample is the fraction of requests to mirror, from 0.0 to 1.0
+    pub fn start(dir: &Path, sample: f64, payloads: bool) -> io::Result<Mirror> {
+        fs::create_dir_all(dir)?;
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let path = dir.join(format!("requests-{}-{}.jsonl", started, std::process::id()));
+        let file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(&path)?;
+
+        let writer = LineWriter::start(
+            "fortuna-mirror".to_string(),
+            format!("mirrored requests to {}", path.display()),
+            &metrics::MIRROR_DROPPED_TOTAL,
+            BufWriter::new(file),
+        )?;
+        Ok(Mirror {
+            sample: sample.max(0.0).min(1.0),
+            payloads,
+            requests: AtomicU64::new(0),
+            path,
+            writer,
+        })
+    }
+
+    // The file this run's corpus is written to
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Called for every request, says whether to record this one
+    pub fn sampled(&self) -> bool {
+        let n = self.requests.fetch_add(1, Ordering::Relaxed);
+        is_sampled(n, self.sample)
+    }
+
+    // Queues a sampled request to be written. Never waits on the disk.
+    pub fn record(&self, request: &JsRequest, response: &JsResponse, took: Duration) {
+        let record = Record::new(request, response, took, self.payloads);
+        if self.writer.send(serde_json::to_string(&record).unwrap()) {
+            metrics::MIRRORED_REQUESTS_TOTAL.inc();
+        }
+    }
+}
+
+// Reads back a corpus file written by Mirror
+pub fn read(path: &Path) -> io::Result<Vec<Record>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        records.push(record);
+    }
+    Ok(records)
+}
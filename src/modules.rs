@@ -0,0 +1,173 @@
+use rusty_v8 as v8;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use crate::error::{ErrorInfo, JSError};
+use crate::js_engine::error_info;
+
+// Copies a module's exports onto the global object so CALL can find them
+const EXPORT_TO_GLOBAL_JS: &str =
+    "(ns) => { Object.keys(ns).forEach((name) => { globalThis[name] = ns[name]; }); }";
+
+thread_local! {
+    // The modules being loaded on this thread by name. The resolve callback
+    // V8 calls for each import can't carry any state of its own so this is
+    // where it finds them.
+    static COMPILED: RefCell<HashMap<String, v8::Global<v8::Module>>> =
+        RefCell::new(HashMap::new());
+}
+
+// "./util.js" and "util.js" are the same module, there are no directories
+fn normalize(specifier: &str) -> &str {
+    specifier.trim_start_matches("./")
+}
+
+fn script_origin<'sc>(scope: &mut impl v8::ToLocal<'sc>, name: &str) -> v8::ScriptOrigin<'sc> {
+    let resource_name = v8::String::new(scope, name).unwrap();
+    let resource_line_offset = v8::Integer::new(scope, 0);
+    let resource_column_offset = v8::Integer::new(scope, 0);
+    let resource_is_shared_cross_origin = v8::Boolean::new(scope, false);
+    let script_id = v8::Integer::new(scope, 0);
+    let source_map_url = v8::String::new(scope, "").unwrap();
+    let resource_is_opaque = v8::Boolean::new(scope, false);
+    let is_wasm = v8::Boolean::new(scope, false);
+    let is_module = v8::Boolean::new(scope, true);
+    v8::ScriptOrigin::new(
+        resource_name.into(),
+        resource_line_offset,
+        resource_column_offset,
+        resource_is_shared_cross_origin,
+        script_id,
+        source_map_url.into(),
+        resource_is_opaque,
+        is_wasm,
+        is_module,
+    )
+}
+
+fn resolve_callback<'a>(
+    context: v8::Local<'a, v8::Context>,
+    specifier: v8::Local<'a, v8::String>,
+    _referrer: v8::Local<'a, v8::Module>,
+) -> Option<v8::Local<'a, v8::Module>> {
+    let mut cbs = v8::CallbackScope::new_escapable(context);
+    let mut hs = v8::EscapableHandleScope::new(cbs.enter());
+    let scope = hs.enter();
+
+    let specifier = specifier.to_rust_string_lossy(scope);
+    let module = COMPILED.with(|compiled| {
+        compiled
+            .borrow()
+            .get(normalize(specifier.as_str()))
+            .and_then(|module| module.get(scope))
+    });
+
+    match module {
+        Some(module) => Some(scope.escape(module)),
+        None => {
+            let message = format!("cannot find module {}", specifier);
+            let message = v8::String::new(scope, message.as_str()).unwrap();
+            let exception = v8::Exception::error(scope, message);
+            scope.isolate().throw_exception(exception);
+            None
+        }
+    }
+}
+
+// Same as for scripts but says which module it was
+fn module_error<'sc>(
+    scope: &mut impl v8::ToLocal<'sc>,
+    context: v8::Local<v8::Context>,
+    tc: &v8::TryCatch,
+    name: &str,
+) -> ErrorInfo {
+    let mut info = error_info(scope, context, tc);
+    info.message = format!("{} (in module {})", info.message, name);
+    info
+}
+
+// Compiles every module in modules, as (name, source), links their imports
+// against each other by name and evaluates them. Everything the modules
+// export ends up as a global.
+pub fn load<'sc>(
+    scope: &mut impl v8::ToLocal<'sc>,
+    context: v8::Local<v8::Context>,
+    modules: &[(&str, &str)],
+) -> Result<(), JSError> {
+    let mut try_catch = v8::TryCatch::new(scope);
+    let tc = try_catch.enter();
+
+    let result = link_and_evaluate(scope, context, tc, modules);
+
+    COMPILED.with(|compiled| {
+        for (_, mut module) in compiled.borrow_mut().drain() {
+            module.reset(scope);
+        }
+    });
+    result
+}
+
+fn link_and_evaluate<'sc>(
+    scope: &mut impl v8::ToLocal<'sc>,
+    context: v8::Local<v8::Context>,
+    tc: &v8::TryCatch,
+    modules: &[(&str, &str)],
+) -> Result<(), JSError> {
+    let mut compiled = Vec::with_capacity(modules.len());
+    for (name, code) in modules {
+        let origin = script_origin(scope, name);
+        let code = v8::String::new(scope, code).unwrap();
+        let source = v8::script_compiler::Source::new(code, &origin);
+        let module = match v8::script_compiler::compile_module(scope, source) {
+            Some(module) => module,
+            None => {
+                return Err(JSError::CompileError(module_error(
+                    scope, context, tc, name,
+                )))
+            }
+        };
+
+        let mut global = v8::Global::<v8::Module>::new();
+        global.set(scope, module);
+        COMPILED.with(|compiled| {
+            compiled
+                .borrow_mut()
+                .insert(normalize(name).to_string(), global)
+        });
+        compiled.push((name, module));
+    }
+
+    let export_source = v8::String::new(scope, EXPORT_TO_GLOBAL_JS).unwrap();
+    let mut export_script = v8::Script::compile(scope, context, export_source, None).unwrap();
+    let export_value = export_script.run(scope, context).unwrap();
+    let export_to_global = v8::Local::<v8::Function>::try_from(export_value).unwrap();
+
+    for (name, mut module) in compiled {
+        if module.instantiate_module(context, resolve_callback) != Some(true) {
+            return Err(JSError::RuntimeError(module_error(
+                scope, context, tc, name,
+            )));
+        }
+        // Evaluating a module that was already evaluated as an import of an
+        // earlier one does nothing
+        if module.evaluate(scope, context).is_none() {
+            return Err(JSError::RuntimeError(module_error(
+                scope, context, tc, name,
+            )));
+        }
+
+        let namespace = module.get_module_namespace();
+        let receiver = context.global(scope);
+        if export_to_global
+            .call(scope, context, receiver.into(), &[namespace])
+            .is_none()
+        {
+            return Err(JSError::RuntimeError(module_error(
+                scope, context, tc, name,
+            )));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,244 @@
+use serde_json::{Map, Number, Value};
+
+// Encodes JSON as MessagePack, using the smallest representation of each
+// value:
+//
+//   null, true, false -> nil, true, false
+//   numbers           -> ints, or float 64 when they aren't integers
+//   strings           -> str
+//   arrays            -> array
+//   objects           -> map with str keys
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_value(value, &mut out);
+    out
+}
+
+// Decodes MessagePack into JSON. Only what JSON can hold is accepted, i.e.
+// no bin or ext values, maps keyed by strings and finite floats.
+pub fn decode(bytes: &[u8]) -> Result<Value, String> {
+    let mut input = bytes;
+    let value = decode_value(&mut input, 0)?;
+    if !input.is_empty() {
+        return Err(format!("{} bytes left over", input.len()));
+    }
+    Ok(value)
+}
+
+const NIL: u8 = 0xc0;
+const FALSE: u8 = 0xc2;
+const TRUE: u8 = 0xc3;
+const FLOAT32: u8 = 0xca;
+const FLOAT64: u8 = 0xcb;
+const UINT8: u8 = 0xcc;
+const UINT16: u8 = 0xcd;
+const UINT32: u8 = 0xce;
+const UINT64: u8 = 0xcf;
+const INT8: u8 = 0xd0;
+const INT16: u8 = 0xd1;
+const INT32: u8 = 0xd2;
+const INT64: u8 = 0xd3;
+const STR8: u8 = 0xd9;
+const STR16: u8 = 0xda;
+const STR32: u8 = 0xdb;
+const ARRAY16: u8 = 0xdc;
+const ARRAY32: u8 = 0xdd;
+const MAP16: u8 = 0xde;
+const MAP32: u8 = 0xdf;
+
+// Deeper values are refused rather than risking the stack
+const MAX_DEPTH: usize = 512;
+
+fn encode_value(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Null => out.push(NIL),
+        Value::Bool(false) => out.push(FALSE),
+        Value::Bool(true) => out.push(TRUE),
+        Value::Number(n) => encode_number(n, out),
+        Value::String(s) => encode_str(s, out),
+        Value::Array(items) => {
+            encode_len(items.len(), 0x90, ARRAY16, ARRAY32, out);
+            for item in items {
+                encode_value(item, out);
+            }
+        }
+        Value::Object(members) => {
+            encode_len(members.len(), 0x80, MAP16, MAP32, out);
+            for (key, value) in members {
+                encode_str(key, out);
+                encode_value(value, out);
+            }
+        }
+    }
+}
+
+fn encode_number(n: &Number, out: &mut Vec<u8>) {
+    if let Some(u) = n.as_u64() {
+        if u <= 0x7f {
+            out.push(u as u8);
+        } else if u <= u8::MAX as u64 {
+            out.push(UINT8);
+            out.push(u as u8);
+        } else if u <= u16::MAX as u64 {
+            out.push(UINT16);
+            out.extend_from_slice(&(u as u16).to_be_bytes());
+        } else if u <= u32::MAX as u64 {
+            out.push(UINT32);
+            out.extend_from_slice(&(u as u32).to_be_bytes());
+        } else {
+            out.push(UINT64);
+            out.extend_from_slice(&u.to_be_bytes());
+        }
+    } else if let Some(i) = n.as_i64() {
+        // Only negative ones get this far
+        if i >= -32 {
+            out.push(i as i8 as u8);
+        } else if i >= i8::MIN as i64 {
+            out.push(INT8);
+            out.push(i as i8 as u8);
+        } else if i >= i16::MIN as i64 {
+            out.push(INT16);
+            out.extend_from_slice(&(i as i16).to_be_bytes());
+        } else if i >= i32::MIN as i64 {
+            out.push(INT32);
+            out.extend_from_slice(&(i as i32).to_be_bytes());
+        } else {
+            out.push(INT64);
+            out.extend_from_slice(&i.to_be_bytes());
+        }
+    } else {
+        out.push(FLOAT64);
+        out.extend_from_slice(&n.as_f64().unwrap_or(0.0).to_bits().to_be_bytes());
+    }
+}
+
+fn encode_str(s: &str, out: &mut Vec<u8>) {
+    if s.len() < 32 {
+        out.push(0xa0 | s.len() as u8);
+    } else if s.len() <= u8::MAX as usize {
+        out.push(STR8);
+        out.push(s.len() as u8);
+    } else if s.len() <= u16::MAX as usize {
+        out.push(STR16);
+        out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    } else {
+        out.push(STR32);
+        out.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    }
+    out.extend_from_slice(s.as_bytes());
+}
+
+// Lengths below 16 fit in the fix type, for arrays and maps
+fn encode_len(len: usize, fix: u8, marker16: u8, marker32: u8, out: &mut Vec<u8>) {
+    if len < 16 {
+        out.push(fix | len as u8);
+    } else if len <= u16::MAX as usize {
+        out.push(marker16);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        out.push(marker32);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+fn take<'a>(input: &mut &'a [u8], n: usize) -> Result<&'a [u8], String> {
+    if input.len() < n {
+        return Err("truncated value".to_string());
+    }
+    let (taken, rest) = input.split_at(n);
+    *input = rest;
+    Ok(taken)
+}
+
+fn be_u64(input: &mut &[u8], n: usize) -> Result<u64, String> {
+    Ok(take(input, n)?
+        .iter()
+        .fold(0u64, |acc, byte| (acc << 8) | *byte as u64))
+}
+
+fn be_i64(input: &mut &[u8], n: usize) -> Result<i64, String> {
+    // Sign extends from the top bit of the n bytes read
+    let shift = 64 - 8 * n as u32;
+    Ok(((be_u64(input, n)? << shift) as i64) >> shift)
+}
+
+fn decode_value(input: &mut &[u8], depth: usize) -> Result<Value, String> {
+    if depth > MAX_DEPTH {
+        return Err(format!("nested deeper than {}", MAX_DEPTH));
+    }
+    let marker = take(input, 1)?[0];
+    let value = match marker {
+        0x00..=0x7f => Value::from(marker),
+        0xe0..=0xff => Value::from(marker as i8),
+        0xa0..=0xbf => decode_str(input, (marker & 0x1f) as usize)?,
+        0x90..=0x9f => decode_array(input, (marker & 0x0f) as usize, depth)?,
+        0x80..=0x8f => decode_map(input, (marker & 0x0f) as usize, depth)?,
+        NIL => Value::Null,
+        FALSE => Value::Bool(false),
+        TRUE => Value::Bool(true),
+        FLOAT32 => {
+            let bits = be_u64(input, 4)? as u32;
+            float(f32::from_bits(bits) as f64)?
+        }
+        FLOAT64 => float(f64::from_bits(be_u64(input, 8)?))?,
+        UINT8 => Value::from(be_u64(input, 1)?),
+        UINT16 => Value::from(be_u64(input, 2)?),
+        UINT32 => Value::from(be_u64(input, 4)?),
+        UINT64 => Value::from(be_u64(input, 8)?),
+        INT8 => Value::from(be_i64(input, 1)?),
+        INT16 => Value::from(be_i64(input, 2)?),
+        INT32 => Value::from(be_i64(input, 4)?),
+        INT64 => Value::from(be_i64(input, 8)?),
+        STR8 | STR16 | STR32 => {
+            let len = be_u64(input, 1 << (marker - STR8))?;
+            decode_str(input, len as usize)?
+        }
+        ARRAY16 | ARRAY32 => {
+            let len = be_u64(input, 2 << (marker - ARRAY16))?;
+            decode_array(input, len as usize, depth)?
+        }
+        MAP16 | MAP32 => {
+            let len = be_u64(input, 2 << (marker - MAP16))?;
+            decode_map(input, len as usize, depth)?
+        }
+        _ => return Err(format!("can't convert type 0x{:02x} to JSON", marker)),
+    };
+    Ok(value)
+}
+
+fn float(f: f64) -> Result<Value, String> {
+    Number::from_f64(f)
+        .map(Value::Number)
+        .ok_or_else(|| format!("{} isn't a JSON number", f))
+}
+
+fn decode_str(input: &mut &[u8], len: usize) -> Result<Value, String> {
+    let bytes = take(input, len)?;
+    match std::str::from_utf8(bytes) {
+        Ok(s) => Ok(Value::from(s)),
+        Err(err) => Err(format!("invalid str: {}", err)),
+    }
+}
+
+fn decode_array(input: &mut &[u8], len: usize, depth: usize) -> Result<Value, String> {
+    // Every item takes at least a byte, which stops a bogus length from
+    // reserving lots of memory
+    let mut items = Vec::with_capacity(len.min(input.len()));
+    for _ in 0..len {
+        items.push(decode_value(input, depth + 1)?);
+    }
+    Ok(Value::Array(items))
+}
+
+fn decode_map(input: &mut &[u8], len: usize, depth: usize) -> Result<Value, String> {
+    let mut members = Map::new();
+    for _ in 0..len {
+        let key = match decode_value(input, depth + 1)? {
+            Value::String(key) => key,
+            key => return Err(format!("map key {} isn't a string", key)),
+        };
+        let value = decode_value(input, depth + 1)?;
+        members.insert(key, value);
+    }
+    Ok(Value::Object(members))
+}
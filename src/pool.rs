@@ -0,0 +1,246 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use futures_util::future;
+
+use crate::host_functions::Capabilities;
+use crate::js_server::{create_js_env, Command, JSClient, Ops};
+use crate::metrics;
+use crate::threads;
+use crate::JSEnv;
+
+// How requests on a connection are spread over a shared pool
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Affinity {
+    // Every request on a connection goes to the worker it was pinned to when
+    // it was accepted, so state set up with EVAL is there for later CALLs.
+    Connection,
+    // Each request goes to the next worker in the pool. Only safe for
+    // requests that don't rely on state left behind by earlier ones.
+    Request,
+    // Requests with the same stream_key always go to the same worker, whose
+    // queue runs them in order, e.g. so an EVAL is there for the CALLs
+    // after it. Requests without one are spread like with Request.
+    Stream,
+}
+
+impl FromStr for Affinity {
+    type Err = String;
+
+    fn from_str(affinity: &str) -> Result<Self, Self::Err> {
+        match affinity {
+            "connection" => Ok(Affinity::Connection),
+            "request" => Ok(Affinity::Request),
+            "stream" => Ok(Affinity::Stream),
+            _ => Err(format!(
+                "unknown affinity {}, expected connection, request or stream",
+                affinity
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    // Workers kept warm however quiet it gets
+    pub min_size: usize,
+    // Workers are added on demand up to this many
+    pub max_size: usize,
+    // How long a worker beyond min_size can sit idle before it's torn down
+    pub idle_ttl: Duration,
+}
+
+// How often idle workers are looked for
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+// Workers shared by every connection. A pool that's allowed to shrink keeps
+// min_size workers and starts more from the snapshot, up to max_size, when
+// all of them are busy. The extra ones are torn down again once they've been
+// idle for idle_ttl, giving their memory back.
+pub struct WorkerPool {
+    js_env: JSEnv,
+    caps: Capabilities,
+    config: PoolConfig,
+    clients: Mutex<Vec<JSClient>>,
+    next: AtomicUsize,
+}
+
+impl WorkerPool {
+    // A pool of exactly size workers
+    pub fn new(js_env: &JSEnv, caps: &Capabilities, size: usize) -> WorkerPool {
+        WorkerPool::with_config(
+            js_env,
+            caps,
+            PoolConfig {
+                min_size: size,
+                max_size: size,
+                idle_ttl: Duration::default(),
+            },
+        )
+    }
+
+    pub fn with_config(js_env: &JSEnv, caps: &Capabilities, config: PoolConfig) -> WorkerPool {
+        assert!(
+            config.max_size > 0,
+            "a worker pool needs at least one worker"
+        );
+        assert!(
+            config.min_size <= config.max_size,
+            "a worker pool's min_size can't be more than its max_size"
+        );
+        // Always at least one warm worker to hand out
+        let warm = config.min_size.max(1);
+        let clients = (0..warm).map(|_| create_js_env(js_env, caps)).collect();
+        metrics::POOL_WORKERS.set(warm as f64);
+        WorkerPool {
+            js_env: JSEnv {
+                startup_data: js_env.startup_data.clone(),
+                rewrite_cache: js_env.rewrite_cache.clone(),
+            },
+            caps: caps.clone(),
+            config,
+            clients: Mutex::new(clients),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    // Also starts a thread that tears down idle workers for as long as the
+    // returned pool is alive
+    pub fn start(js_env: &JSEnv, caps: &Capabilities, config: PoolConfig) -> Arc<WorkerPool> {
+        let pool = Arc::new(WorkerPool::with_config(js_env, caps, config));
+        if pool.config.min_size < pool.config.max_size {
+            let weak = Arc::downgrade(&pool);
+            threads::spawn("fortuna-pool".to_string(), move || sweep(weak))
+                .expect("can't start the pool sweeper");
+        }
+        pool
+    }
+
+    // Hands out the workers round robin, skipping busy ones. If they're all
+    // busy another worker is started unless the pool is at max_size.
+    pub fn next(&self) -> JSClient {
+        let mut clients = self.clients.lock().unwrap();
+        self.replace_given_up(&mut clients);
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let idle = (0..clients.len())
+            .map(|i| &clients[(start + i) % clients.len()])
+            .find(|client| !client.is_busy());
+        if let Some(client) = idle {
+            return client.clone();
+        }
+
+        if clients.len() < self.config.max_size {
+            let client = create_js_env(&self.js_env, &self.caps);
+            clients.push(client.clone());
+            metrics::POOL_WORKERS.set(clients.len() as f64);
+            return client;
+        }
+        clients[start % clients.len()].clone()
+    }
+
+    // The worker for a stream of requests. Streams are spread over the warm
+    // workers only, which are never torn down, so a stream keeps its worker
+    // however the pool grows and shrinks.
+    pub fn for_key(&self, key: &str) -> JSClient {
+        let mut clients = self.clients.lock().unwrap();
+        self.replace_given_up(&mut clients);
+        let warm = self.config.min_size.max(1).min(clients.len());
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        clients[hasher.finish() as usize % warm].clone()
+    }
+
+    // Workers that gave up on creating an isolate fail everything sent to
+    // them, so they're swapped for new ones in place, keeping the streams
+    // hashed to them on the same slot
+    fn replace_given_up(&self, clients: &mut Vec<JSClient>) {
+        for client in clients.iter_mut().filter(|client| client.gave_up()) {
+            log::warn!("replacing pool worker {}, which gave up", client.id());
+            *client = create_js_env(&self.js_env, &self.caps);
+        }
+    }
+
+    // Tears down the workers beyond min_size that have been idle for
+    // idle_ttl and returns how many there were. A worker still pinned to a
+    // connection keeps running until the connection closes.
+    pub fn hibernate(&self) -> usize {
+        let mut clients = self.clients.lock().unwrap();
+        let before = clients.len();
+        let keep = self.config.min_size.max(1);
+        let mut i = clients.len();
+        while i > keep {
+            i -= 1;
+            if clients[i].idle_for() >= self.config.idle_ttl {
+                clients.remove(i);
+            }
+        }
+        metrics::POOL_WORKERS.set(clients.len() as f64);
+        before - clients.len()
+    }
+
+    // Waits for every worker in the pool to have its isolate up by running a
+    // trivial script on all of them at once. Workers start their isolates in
+    // parallel as soon as they're created, this is only how to know they're
+    // done. Returns how many workers there were.
+    pub async fn warm(&self) -> Result<usize, String> {
+        let clients = self.clients.lock().unwrap().clone();
+        let probes = clients.iter().map(|client| {
+            client.run(Command {
+                operation: Ops::EVAL,
+                payload: "true;".to_string(),
+                args: vec![],
+                docs: Vec::new(),
+            })
+        });
+        for result in future::join_all(probes).await {
+            result.map_err(|err| format!("worker didn't warm up: {}", err))?;
+        }
+        Ok(clients.len())
+    }
+
+    // Registers each of ddocs, signatures and the JSON of their functions,
+    // on every worker in the pool. Returns how many registrations failed,
+    // e.g. for functions that no longer compile.
+    pub async fn register_ddocs(&self, ddocs: &[(String, String)]) -> usize {
+        let clients = self.clients.lock().unwrap().clone();
+        let registrations = clients.iter().flat_map(|client| {
+            ddocs.iter().map(move |(signature, functions)| {
+                client.run(Command {
+                    operation: Ops::REGISTER_DDOC,
+                    payload: signature.clone(),
+                    args: vec![functions.clone()],
+                    docs: Vec::new(),
+                })
+            })
+        });
+        future::join_all(registrations)
+            .await
+            .iter()
+            .filter(|result| result.is_err())
+            .count()
+    }
+
+    // Workers currently in the pool
+    pub fn size(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+}
+
+fn sweep(pool: Weak<WorkerPool>) {
+    loop {
+        thread::sleep(SWEEP_INTERVAL);
+        let pool = match pool.upgrade() {
+            Some(pool) => pool,
+            None => return,
+        };
+        let hibernated = pool.hibernate();
+        if hibernated > 0 {
+            log::debug!("tore down {} idle pool workers", hibernated);
+        }
+    }
+}
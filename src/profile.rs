@@ -0,0 +1,130 @@
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// Longest /admin/profile run allowed
+pub const MAX_DURATION: Duration = Duration::from_secs(300);
+
+// V8's own default
+pub const DEFAULT_INTERVAL: Duration = Duration::from_micros(1000);
+
+// Only one profile at a time, a worker only has the one profiler
+static PROFILING: AtomicBool = AtomicBool::new(false);
+
+// Held while profiling, see try_start
+pub struct Running(());
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        PROFILING.store(false, Ordering::SeqCst);
+    }
+}
+
+// None if a profile is already being taken
+pub fn try_start() -> Option<Running> {
+    if PROFILING.swap(true, Ordering::SeqCst) {
+        None
+    } else {
+        Some(Running(()))
+    }
+}
+
+// Durations like 10s, 500ms, 2m or 1500us. A bare number is seconds.
+pub fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let duration = duration.trim();
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (number, unit) = duration.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration {:?}", duration))?;
+    match unit {
+        "" | "s" => Ok(Duration::from_secs(number)),
+        "ms" => Ok(Duration::from_millis(number)),
+        "us" => Ok(Duration::from_micros(number)),
+        "m" => Ok(Duration::from_secs(number * 60)),
+        _ => Err(format!("invalid duration {:?}", duration)),
+    }
+}
+
+// Combines the .cpuprofile of each worker into one, each worker's calls
+// under a node of its own named after it, e.g. "(worker 3)", with the
+// samples of all of them in time order
+pub fn merge(profiles: Vec<(usize, Value)>) -> Value {
+    let mut nodes = vec![json!({
+        "id": 1,
+        "callFrame": {
+            "functionName": "(root)",
+            "scriptId": "0",
+            "url": "",
+            "lineNumber": -1,
+            "columnNumber": -1,
+        },
+        "hitCount": 0,
+        "children": [],
+    })];
+    let mut next_id = 2;
+    let mut samples: Vec<(i64, i64)> = Vec::new();
+    let mut start_time = i64::max_value();
+    let mut end_time = i64::min_value();
+
+    for (worker, mut profile) in profiles {
+        let offset = next_id - 1;
+        let renumber = |id: &Value| id.as_i64().unwrap_or(0) + offset;
+        let mut worker_nodes = match profile["nodes"].take() {
+            Value::Array(worker_nodes) => worker_nodes,
+            _ => continue,
+        };
+        for (i, node) in worker_nodes.iter_mut().enumerate() {
+            let id = renumber(&node["id"]);
+            next_id = next_id.max(id + 1);
+            node["id"] = json!(id);
+            if let Value::Array(children) = &mut node["children"] {
+                for child in children.iter_mut() {
+                    *child = json!(renumber(&*child));
+                }
+            }
+            // V8 always lists the root first
+            if i == 0 {
+                node["callFrame"]["functionName"] = json!(format!("(worker {})", worker));
+                if let Value::Array(children) = &mut nodes[0]["children"] {
+                    children.push(json!(id));
+                }
+            }
+        }
+        nodes.extend(worker_nodes);
+
+        let mut time = profile["startTime"].as_i64().unwrap_or(0);
+        start_time = start_time.min(time);
+        end_time = end_time.max(profile["endTime"].as_i64().unwrap_or(time));
+        let worker_samples = profile["samples"].as_array().cloned().unwrap_or_default();
+        let deltas = profile["timeDeltas"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        for (sample, delta) in worker_samples.iter().zip(deltas.iter()) {
+            time += delta.as_i64().unwrap_or(0);
+            samples.push((time, renumber(sample)));
+        }
+    }
+
+    if start_time > end_time {
+        start_time = 0;
+        end_time = 0;
+    }
+    samples.sort_by_key(|(time, _)| *time);
+    let mut last = start_time;
+    let mut deltas = Vec::with_capacity(samples.len());
+    for (time, _) in samples.iter() {
+        deltas.push(time - last);
+        last = *time;
+    }
+    json!({
+        "nodes": nodes,
+        "startTime": start_time,
+        "endTime": end_time,
+        "samples": samples.iter().map(|(_, id)| *id).collect::<Vec<_>>(),
+        "timeDeltas": deltas,
+    })
+}
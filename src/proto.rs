@@ -0,0 +1,187 @@
+use sha2::{Digest, Sha256};
+
+use crate::js_server::{Command, Ops};
+use crate::JSError;
+use ateles::js_request::Action;
+use ateles::js_response::ErrorType;
+use ateles::{JsRequest, JsResponse, MapDocsRequest};
+
+// The one copy of the types generated from proto/ateles.proto, used by the
+// server, the client and everything in between
+pub mod ateles {
+    tonic::include_proto!("ateles"); // The string specified here must match the proto package name
+}
+
+// Given in ServerInfo, see ateles.proto for when it changes
+pub const PROTOCOL_VERSION: i32 = 1;
+
+// What the worker is asked to do for js_request
+pub fn operation(js_request: &JsRequest) -> Ops {
+    let json_args = !js_request.json_args.is_empty();
+    match js_request.action {
+        0 | 2 if json_args => Ops::CALL_JSON,
+        0 => Ops::REWRITE,
+        1 => Ops::EVAL,
+        2 => Ops::CALL,
+        3 => Ops::GLOBALS,
+        4 => Ops::REDUCE,
+        5 => Ops::REREDUCE,
+        6 => Ops::REGISTER_DDOC,
+        7 => Ops::EVICT_DDOC,
+        8 => Ops::MAP_DOC,
+        9 => Ops::FILTER,
+        10 => Ops::WASM_CALL,
+        12 => Ops::MAP_DOCS,
+        // RESET, any other action is rejected before it gets this far
+        _ => Ops::RESET,
+    }
+}
+
+impl From<JsRequest> for Command {
+    fn from(js_request: JsRequest) -> Self {
+        if js_request.action == Action::WasmCall as i32 {
+            let mut args = vec![
+                wasm_module_hash(&js_request.wasm_module),
+                js_request
+                    .wasm_module
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect(),
+            ];
+            args.extend(js_request.json_args);
+            return Command {
+                operation: Ops::WASM_CALL,
+                payload: js_request.script,
+                args,
+                docs: Vec::new(),
+            };
+        }
+
+        // Parsed here rather than on the worker, whose isolate builds them
+        // straight into V8 values. If one doesn't parse they all go as JSON
+        // so the isolate gives that doc its error.
+        if js_request.action == Action::MapDocs as i32 {
+            let docs: Result<Vec<serde_json::Value>, _> = js_request
+                .args
+                .iter()
+                .map(|doc| serde_json::from_str(doc))
+                .collect();
+            if let Ok(docs) = docs {
+                return Command {
+                    operation: Ops::MAP_DOCS,
+                    payload: js_request.script,
+                    args: Vec::new(),
+                    docs,
+                };
+            }
+        }
+
+        let json_args = !js_request.json_args.is_empty();
+        Command {
+            operation: operation(&js_request),
+            payload: js_request.script,
+            args: if json_args {
+                js_request.json_args
+            } else {
+                js_request.args
+            },
+            docs: Vec::new(),
+        }
+    }
+}
+
+// A MAP_DOC request for each of a MapDocsRequest's docs
+pub fn map_doc_requests(map_docs: MapDocsRequest) -> Vec<JsRequest> {
+    let mut doc_ids = map_docs.doc_ids.into_iter();
+    map_docs
+        .docs
+        .into_iter()
+        .map(|doc| JsRequest {
+            action: Action::MapDoc as i32,
+            script: map_docs.signature.clone(),
+            args: vec![doc],
+            timeout: map_docs.timeout,
+            priority: map_docs.priority,
+            emit_format: map_docs.emit_format,
+            doc_id: doc_ids.next().unwrap_or_default(),
+            result_encoding: map_docs.result_encoding,
+            session_id: map_docs.session_id.clone(),
+            session_ttl: map_docs.session_ttl,
+            stream_key: map_docs.stream_key.clone(),
+            max_result_bytes: map_docs.max_result_bytes,
+            harness: map_docs.harness.clone(),
+            ..JsRequest::default()
+        })
+        .collect()
+}
+
+// The one MAP_DOCS request that maps all of a batch MapDocsRequest's docs.
+// Rows come back as JSON and are only encoded once split up per doc.
+pub fn map_docs_batch(map_docs: &MapDocsRequest) -> JsRequest {
+    JsRequest {
+        action: Action::MapDocs as i32,
+        script: map_docs.signature.clone(),
+        args: map_docs.docs.clone(),
+        timeout: map_docs.timeout,
+        priority: map_docs.priority,
+        session_id: map_docs.session_id.clone(),
+        session_ttl: map_docs.session_ttl,
+        stream_key: map_docs.stream_key.clone(),
+        max_result_bytes: map_docs.max_result_bytes,
+        harness: map_docs.harness.clone(),
+        ..JsRequest::default()
+    }
+}
+
+// Identifies a WebAssembly module for caching and quarantine
+pub fn wasm_module_hash(module: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(module);
+    hasher
+        .result()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl From<JSError> for JsResponse {
+    fn from(err: JSError) -> Self {
+        let error_type = match err {
+            JSError::CompileError(_) => ErrorType::CompileError,
+            JSError::RuntimeError(_) => ErrorType::RuntimeError,
+            JSError::Timeout => ErrorType::Timeout,
+            JSError::OutOfMemory => ErrorType::Oom,
+            JSError::QueueFull => ErrorType::QueueFull,
+            JSError::EmitLimitExceeded(_) => ErrorType::EmitLimitExceeded,
+            JSError::UnsupportedFeature(_) => ErrorType::UnsupportedFeature,
+            JSError::Quarantined(_) => ErrorType::QuarantinedScript,
+            JSError::InvalidRequest(_) => ErrorType::InvalidRequest,
+            JSError::UnknownDesignDoc(_) => ErrorType::UnknownDesignDoc,
+            JSError::RateLimited(_) => ErrorType::RateLimited,
+            JSError::Killed => ErrorType::Killed,
+            JSError::ResultTooLarge(_) => ErrorType::ResultTooLarge,
+            JSError::DeadlineExceeded => ErrorType::DeadlineExceeded,
+            JSError::Overloaded(_) => ErrorType::Overloaded,
+            JSError::Unauthorized => ErrorType::Unauthorized,
+            JSError::WorkerCrashed | JSError::Internal(_) => ErrorType::Internal,
+        };
+
+        let error = match &err {
+            JSError::CompileError(info) | JSError::RuntimeError(info) => Some(ateles::ErrorInfo {
+                name: info.name.clone(),
+                message: info.message.clone(),
+                stack: info.stack.clone(),
+                line: info.line,
+            }),
+            _ => None,
+        };
+
+        JsResponse {
+            status: 1,
+            result: err.to_string(),
+            error_type: error_type as i32,
+            error,
+            ..JsResponse::default()
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+
+use crate::metrics;
+
+// Crashes after which a script is quarantined, 0 turns quarantining off
+static THRESHOLD: AtomicU64 = AtomicU64::new(3);
+
+lazy_static! {
+    // Crash counts by script hash, for scripts that have crashed a worker
+    static ref CRASHES: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+}
+
+pub fn set_threshold(crashes: u64) {
+    THRESHOLD.store(crashes, Ordering::SeqCst);
+}
+
+// Hashes script along with args, if there are any. Quarantine passes only
+// what identifies the function, the result cache its arguments too.
+pub fn script_hash(script: &str, args: Option<&[String]>) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(script.as_bytes());
+    for arg in args.unwrap_or(&[]) {
+        hasher.input(&[0]);
+        hasher.input(arg.as_bytes());
+    }
+    hasher
+        .result()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub fn is_quarantined(hash: &str) -> bool {
+    let threshold = THRESHOLD.load(Ordering::SeqCst);
+    threshold > 0 && CRASHES.lock().unwrap().get(hash).copied().unwrap_or(0) >= threshold
+}
+
+// Counts a worker panic or OOM against the script that was running. Returns
+// true if that got it quarantined.
+pub fn record_crash(hash: &str) -> bool {
+    let threshold = THRESHOLD.load(Ordering::SeqCst);
+    let mut crashes = CRASHES.lock().unwrap();
+    let count = crashes.entry(hash.to_string()).or_insert(0);
+    *count += 1;
+    let quarantined = threshold > 0 && *count == threshold;
+    if quarantined {
+        log::warn!("quarantined script {} after {} crashes", hash, count);
+        metrics::QUARANTINED_SCRIPTS.set(count_quarantined(&crashes, threshold) as f64);
+    }
+    quarantined
+}
+
+// Quarantined scripts and how many times each crashed a worker
+pub fn quarantined() -> Vec<(String, u64)> {
+    let threshold = THRESHOLD.load(Ordering::SeqCst);
+    CRASHES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, count)| threshold > 0 && **count >= threshold)
+        .map(|(hash, count)| (hash.clone(), *count))
+        .collect()
+}
+
+// Lets the script run again and forgets its crashes. Returns false if it
+// wasn't known.
+pub fn clear(hash: &str) -> bool {
+    let mut crashes = CRASHES.lock().unwrap();
+    let known = crashes.remove(hash).is_some();
+    let threshold = THRESHOLD.load(Ordering::SeqCst);
+    metrics::QUARANTINED_SCRIPTS.set(count_quarantined(&crashes, threshold) as f64);
+    known
+}
+
+pub fn clear_all() {
+    CRASHES.lock().unwrap().clear();
+    metrics::QUARANTINED_SCRIPTS.set(0.0);
+}
+
+fn count_quarantined(crashes: &BTreeMap<String, u64>, threshold: u64) -> usize {
+    if threshold == 0 {
+        return 0;
+    }
+    crashes
+        .values()
+        .filter(|count| **count >= threshold)
+        .count()
+}
@@ -0,0 +1,180 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Past this many buckets the ones that have filled up again are forgotten,
+// a full bucket being no different from a new one
+const MAX_BUCKETS: usize = 10_000;
+
+// What requests are counted by
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitKey {
+    // The address the request came from, without the port
+    ClientIp,
+    // The signature of the design doc the request runs, requests that don't
+    // run one aren't limited by the rule
+    DesignDoc,
+}
+
+impl LimitKey {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LimitKey::ClientIp => "client_ip",
+            LimitKey::DesignDoc => "design_doc",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub key: LimitKey,
+    // Operations the rule applies to by their metrics names, e.g. map_doc or
+    // filter. All of them if empty.
+    #[serde(default)]
+    pub ops: Vec<String>,
+    // Requests per second allowed on average for each key
+    pub rate: f64,
+    // Requests allowed in a burst, rate if not given
+    #[serde(default)]
+    pub burst: Option<f64>,
+}
+
+impl Rule {
+    fn burst(&self) -> f64 {
+        self.burst.unwrap_or(self.rate).max(1.0)
+    }
+}
+
+// Read from the file given with --rate-limits, e.g.
+//
+//   {"rules": [
+//     {"key": "design_doc", "ops": ["map_doc"], "rate": 200, "burst": 400},
+//     {"key": "client_ip", "rate": 1000}
+//   ]}
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub rules: Vec<Rule>,
+}
+
+impl RateLimitConfig {
+    pub fn parse(json: &str) -> Result<RateLimitConfig, String> {
+        let config: RateLimitConfig =
+            serde_json::from_str(json).map_err(|err| format!("invalid rate limits: {}", err))?;
+        let positive = |n: f64| n > 0.0 && n.is_finite();
+        for rule in config.rules.iter() {
+            if !positive(rule.rate) || !rule.burst.into_iter().all(positive) {
+                return Err(format!(
+                    "invalid rate limits: rate and burst must be positive in {:?}",
+                    rule
+                ));
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> io::Result<RateLimitConfig> {
+        let json = fs::read_to_string(path)?;
+        RateLimitConfig::parse(&json).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    // Tokens it has at now, counting what it got back since it was updated
+    fn tokens_at(&self, rule: &Rule, now: Instant) -> f64 {
+        let refill = now.saturating_duration_since(self.updated).as_secs_f64() * rule.rate;
+        (self.tokens + refill).min(rule.burst())
+    }
+}
+
+// Token buckets, one for each rule and key a request has been counted by, so
+// that e.g. one runaway view build can't take every worker away from
+// interactive filter calls.
+pub struct RateLimiter {
+    rules: Vec<Rule>,
+    buckets: Mutex<HashMap<(usize, String), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            rules: config.rules,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn check(&self, client_ip: &str, op: &str, design_doc: Option<&str>) -> Result<(), String> {
+        self.check_at(Instant::now(), client_ip, op, design_doc)
+    }
+
+    // Takes a token from every bucket the request falls in. If one of them
+    // is empty none are taken and the reason is returned.
+    pub fn check_at(
+        &self,
+        now: Instant,
+        client_ip: &str,
+        op: &str,
+        design_doc: Option<&str>,
+    ) -> Result<(), String> {
+        let keys: Vec<(usize, String)> = self
+            .rules
+            .iter()
+            .enumerate()
+            .filter(|(_, rule)| rule.ops.is_empty() || rule.ops.iter().any(|o| o == op))
+            .filter_map(|(i, rule)| match rule.key {
+                LimitKey::ClientIp => Some((i, client_ip.to_string())),
+                LimitKey::DesignDoc => design_doc.map(|ddoc| (i, ddoc.to_string())),
+            })
+            .collect();
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        let mut buckets = self.buckets.lock().unwrap();
+        for key in keys.iter() {
+            let rule = &self.rules[key.0];
+            let tokens = buckets
+                .get(key)
+                .map_or_else(|| rule.burst(), |bucket| bucket.tokens_at(rule, now));
+            if tokens < 1.0 {
+                return Err(format!(
+                    "{} exceeded {} requests per second for {} {}",
+                    op,
+                    rule.rate,
+                    rule.key.as_str(),
+                    key.1
+                ));
+            }
+        }
+        for key in keys.into_iter() {
+            let rule = &self.rules[key.0];
+            let tokens = buckets
+                .get(&key)
+                .map_or_else(|| rule.burst(), |bucket| bucket.tokens_at(rule, now));
+            buckets.insert(
+                key,
+                Bucket {
+                    tokens: tokens - 1.0,
+                    updated: now,
+                },
+            );
+        }
+
+        if buckets.len() > MAX_BUCKETS {
+            let rules = &self.rules;
+            buckets.retain(|(i, _), bucket| bucket.tokens_at(&rules[*i], now) < rules[*i].burst());
+        }
+        Ok(())
+    }
+}
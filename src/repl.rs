@@ -0,0 +1,141 @@
+use serde_json::Value;
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+use crate::host_functions::{self, Capabilities};
+use crate::{FortunaIsolate, JSEnv, JSResult};
+
+const HELP: &str = "\
+Anything that isn't a command is evaluated in the isolate's global context.
+A line ending in \\ continues on the next one.
+
+  .call <name> [args]  call a global function with a JSON array of arguments
+  .load <file>         evaluate a file
+  .rewrite <source>    rewrite an anonymous function like a REWRITE would
+  .reset               start over from the snapshot
+  .help                show this
+  .exit                leave, as does end of input
+";
+
+// What a line of input came to
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    // Lines to print
+    Print(String),
+    Exit,
+}
+
+// A line-oriented REPL on a local isolate, for poking at the runtime JS
+// without a server, e.g.
+//
+//   > function double(x) { return x * 2; }
+//   null
+//   > .call double [21]
+//   42
+//
+// Logs and emitted rows are printed after each result.
+pub struct Repl {
+    isolate: FortunaIsolate,
+}
+
+impl Repl {
+    pub fn new(js_env: &JSEnv, caps: &Capabilities) -> Repl {
+        Repl {
+            isolate: js_env.create_isolate_with_capabilities(caps),
+        }
+    }
+
+    pub fn handle(&mut self, line: &str) -> Outcome {
+        let line = line.trim();
+        let (command, rest) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+        let result = match command {
+            "" => return Outcome::Print(String::new()),
+            ".exit" | ".quit" => return Outcome::Exit,
+            ".help" => return Outcome::Print(HELP.trim_end().to_string()),
+            ".call" => self.call(rest),
+            ".load" => match fs::read_to_string(rest) {
+                Ok(source) => self.isolate.eval(&source, &[]),
+                Err(err) => return Outcome::Print(format!("can't read {}: {}", rest, err)),
+            },
+            ".rewrite" => {
+                let source = Value::String(rest.to_string()).to_string();
+                self.isolate.call("rewriteFun", &[source])
+            }
+            ".reset" => self.isolate.reset(),
+            _ if command.starts_with('.') => {
+                return Outcome::Print(format!("unknown command {}, try .help", command))
+            }
+            _ => self.isolate.eval(line, &[]),
+        };
+        Outcome::Print(self.report(result))
+    }
+
+    // Reads input a line at a time until it ends or .exit, printing a
+    // prompt before each
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, mut out: W) -> io::Result<()> {
+        let mut pending = String::new();
+        write!(out, "> ")?;
+        out.flush()?;
+        for line in input.lines() {
+            let line = line?;
+            if line.ends_with('\\') {
+                pending.push_str(&line[..line.len() - 1]);
+                pending.push('\n');
+                write!(out, ". ")?;
+                out.flush()?;
+                continue;
+            }
+            pending.push_str(&line);
+            match self.handle(&pending) {
+                Outcome::Exit => return Ok(()),
+                Outcome::Print(text) if text.is_empty() => (),
+                Outcome::Print(text) => writeln!(out, "{}", text)?,
+            }
+            pending.clear();
+            write!(out, "> ")?;
+            out.flush()?;
+        }
+        writeln!(out)
+    }
+
+    // .call's arguments are a JSON array, each element is passed as is
+    fn call(&mut self, rest: &str) -> JSResult {
+        let (name, args) = match rest.find(char::is_whitespace) {
+            Some(i) => (&rest[..i], rest[i..].trim()),
+            None => (rest, "[]"),
+        };
+        let args: Vec<String> = match serde_json::from_str(args) {
+            Ok(Value::Array(args)) => args.iter().map(Value::to_string).collect(),
+            _ => {
+                return Ok(format!(
+                    "\"expected a JSON array of arguments, not {}\"",
+                    args
+                ))
+            }
+        };
+        self.isolate.call_json(name, &args)
+    }
+
+    fn report(&mut self, result: JSResult) -> String {
+        let mut lines = Vec::new();
+        for line in host_functions::take_logs() {
+            lines.push(format!("{}: {}", line.level, line.message));
+        }
+        match host_functions::take_emitted() {
+            Ok(groups) => {
+                for row in groups.iter().flatten() {
+                    lines.push(format!("emit: {}", row));
+                }
+            }
+            Err(reason) => lines.push(format!("emit: {}", reason)),
+        }
+        lines.push(match result {
+            Ok(result) => result,
+            Err(err) => format!("error: {}", err),
+        });
+        lines.join("\n")
+    }
+}
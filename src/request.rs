@@ -0,0 +1,358 @@
+use std::time::Duration;
+
+use crate::design_docs;
+use crate::proto::ateles::js_request::{Action, EmitFormat, Priority, ResultEncoding};
+use crate::proto::ateles::{JsRequest, JsResponse, LogMessage};
+use crate::request_id;
+use crate::JSError;
+
+// Longest timeout a request can ask for
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(600);
+
+// Builds a JsRequest, checking on build that the server could make sense of
+// it. For example:
+//
+//   let request = JsRequestBuilder::call("mapDoc")
+//       .json_arg(&doc)
+//       .timeout(Duration::from_secs(5))
+//       .build()?;
+pub struct JsRequestBuilder {
+    request: JsRequest,
+}
+
+impl JsRequestBuilder {
+    pub fn new(action: Action, script: &str) -> JsRequestBuilder {
+        JsRequestBuilder {
+            request: JsRequest {
+                action: action as i32,
+                script: script.to_string(),
+                ..JsRequest::default()
+            },
+        }
+    }
+
+    pub fn eval(script: &str) -> JsRequestBuilder {
+        JsRequestBuilder::new(Action::Eval, script)
+    }
+
+    pub fn call(function: &str) -> JsRequestBuilder {
+        JsRequestBuilder::new(Action::Call, function)
+    }
+
+    pub fn rewrite(function: &str) -> JsRequestBuilder {
+        JsRequestBuilder::new(Action::Rewrite, function)
+    }
+
+    // globals is the JSON of an object
+    pub fn set_globals(globals: &str) -> JsRequestBuilder {
+        JsRequestBuilder::new(Action::SetGlobals, globals)
+    }
+
+    // rows is the JSON of the [key, value] rows
+    pub fn reduce(source: &str, rows: &str) -> JsRequestBuilder {
+        JsRequestBuilder::new(Action::Reduce, source).arg(rows)
+    }
+
+    // values is the JSON of earlier reduce results
+    pub fn rereduce(source: &str, values: &str) -> JsRequestBuilder {
+        JsRequestBuilder::new(Action::Rereduce, source).arg(values)
+    }
+
+    // functions is the JSON of a design_docs::Functions
+    pub fn register_ddoc(signature: &str, functions: &str) -> JsRequestBuilder {
+        JsRequestBuilder::new(Action::RegisterDdoc, signature).arg(functions)
+    }
+
+    pub fn evict_ddoc(signature: &str) -> JsRequestBuilder {
+        JsRequestBuilder::new(Action::EvictDdoc, signature)
+    }
+
+    // doc is the JSON of the doc to map
+    pub fn map_doc(signature: &str, doc: &str) -> JsRequestBuilder {
+        JsRequestBuilder::new(Action::MapDoc, signature).arg(doc)
+    }
+
+    // Each of docs is the JSON of a doc to map
+    pub fn map_docs(signature: &str, docs: &[&str]) -> JsRequestBuilder {
+        JsRequestBuilder::new(Action::MapDocs, signature).args(docs)
+    }
+
+    // docs is the JSON of an array of docs. The JSON of the request can
+    // follow as another arg.
+    pub fn filter(signature: &str, name: &str, docs: &str) -> JsRequestBuilder {
+        JsRequestBuilder::new(Action::Filter, signature)
+            .arg(name)
+            .arg(docs)
+    }
+
+    // Calls function exported by the WebAssembly module, with json_arg for
+    // each argument
+    pub fn wasm_call(module: &[u8], function: &str) -> JsRequestBuilder {
+        let mut builder = JsRequestBuilder::new(Action::WasmCall, function);
+        builder.request.wasm_module = module.to_vec();
+        builder
+    }
+
+    // Starts the worker over from the snapshot
+    pub fn reset() -> JsRequestBuilder {
+        JsRequestBuilder::new(Action::Reset, "")
+    }
+
+    pub fn arg(mut self, arg: &str) -> JsRequestBuilder {
+        self.request.args.push(arg.to_string());
+        self
+    }
+
+    pub fn args<S: AsRef<str>>(mut self, args: &[S]) -> JsRequestBuilder {
+        self.request
+            .args
+            .extend(args.iter().map(|arg| arg.as_ref().to_string()));
+        self
+    }
+
+    // The JSON of an argument the function gets parsed
+    pub fn json_arg(mut self, json: &str) -> JsRequestBuilder {
+        self.request.json_args.push(json.to_string());
+        self
+    }
+
+    // An argument the function gets parsed, as MessagePack
+    pub fn msgpack_arg(mut self, msgpack: &[u8]) -> JsRequestBuilder {
+        self.request.msgpack_args.push(msgpack.to_vec());
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> JsRequestBuilder {
+        // Out of range timeouts are caught by build
+        self.request.timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+        self
+    }
+
+    pub fn priority(mut self, priority: Priority) -> JsRequestBuilder {
+        self.request.priority = priority as i32;
+        self
+    }
+
+    // Emitted rows become ViewRows for doc_id
+    pub fn view_rows(mut self, doc_id: &str) -> JsRequestBuilder {
+        self.request.emit_format = EmitFormat::ViewKv as i32;
+        self.request.doc_id = doc_id.to_string();
+        self
+    }
+
+    pub fn require(mut self, feature: &str) -> JsRequestBuilder {
+        self.request.requires.push(feature.to_string());
+        self
+    }
+
+    pub fn result_encoding(mut self, encoding: ResultEncoding) -> JsRequestBuilder {
+        self.request.result_encoding = encoding as i32;
+        self
+    }
+
+    // A ttl of zero uses the server's default
+    pub fn session(mut self, id: &str, ttl: Duration) -> JsRequestBuilder {
+        self.request.session_id = id.to_string();
+        self.request.session_ttl = ttl.as_secs().min(i32::MAX as u64) as i32;
+        self
+    }
+
+    // Keeps the request in order with the others of its stream, see
+    // Affinity::Stream
+    pub fn stream_key(mut self, key: &str) -> JsRequestBuilder {
+        self.request.stream_key = key.to_string();
+        self
+    }
+
+    // Runs the request on the server's workers for harness rather than
+    // those of its built in bundle
+    pub fn harness(mut self, harness: &str) -> JsRequestBuilder {
+        self.request.harness = harness.to_string();
+        self
+    }
+
+    // Fails the request with RESULT_TOO_LARGE rather than send back more
+    // than this
+    pub fn max_result_bytes(mut self, bytes: usize) -> JsRequestBuilder {
+        self.request.max_result_bytes = bytes.min(i32::MAX as usize) as i32;
+        self
+    }
+
+    pub fn batch_size(mut self, docs: usize) -> JsRequestBuilder {
+        self.request.batch_size = docs.min(i32::MAX as usize) as i32;
+        self
+    }
+
+    // Ties the server's log lines for the request to the caller's own
+    pub fn request_id(mut self, id: &str) -> JsRequestBuilder {
+        self.request.request_id = id.to_string();
+        self
+    }
+
+    pub fn build(self) -> Result<JsRequest, String> {
+        validate(&self.request)?;
+        Ok(self.request)
+    }
+}
+
+// Checks the things about a request that don't depend on the server it's
+// sent to
+pub fn validate(request: &JsRequest) -> Result<(), String> {
+    let action = Action::from_i32(request.action)
+        .ok_or_else(|| format!("unknown action {}", request.action))?;
+    if request.script.is_empty() && action != Action::Reset {
+        return Err(format!("{:?} needs a script", action));
+    }
+    if request.timeout < 0 || request.timeout as u128 > MAX_TIMEOUT.as_millis() {
+        return Err(format!(
+            "timeout {}ms isn't between 0 and {}ms",
+            request.timeout,
+            MAX_TIMEOUT.as_millis()
+        ));
+    }
+    if Priority::from_i32(request.priority).is_none() {
+        return Err(format!("unknown priority {}", request.priority));
+    }
+    if ResultEncoding::from_i32(request.result_encoding).is_none() {
+        return Err(format!(
+            "unknown result encoding {}",
+            request.result_encoding
+        ));
+    }
+    match EmitFormat::from_i32(request.emit_format) {
+        None => return Err(format!("unknown emit format {}", request.emit_format)),
+        Some(EmitFormat::ViewKv) if request.doc_id.is_empty() => {
+            return Err("view rows need a doc_id".to_string())
+        }
+        Some(_) => (),
+    }
+    if request.session_ttl < 0 {
+        return Err(format!("negative session_ttl {}", request.session_ttl));
+    }
+    if request.batch_size < 0 {
+        return Err(format!("negative batch_size {}", request.batch_size));
+    }
+    if !request.request_id.is_empty() && !request_id::is_valid(&request.request_id) {
+        return Err(format!("invalid request_id {:?}", request.request_id));
+    }
+
+    if !request.msgpack_args.is_empty() {
+        if !matches!(action, Action::Call | Action::Rewrite | Action::WasmCall) {
+            return Err(format!("{:?} doesn't take msgpack_args", action));
+        }
+        if !request.args.is_empty() || !request.json_args.is_empty() {
+            return Err(format!(
+                "{:?} takes one of args, json_args or msgpack_args",
+                action
+            ));
+        }
+    }
+
+    match action {
+        Action::Call | Action::Rewrite => {
+            if !request.args.is_empty() && !request.json_args.is_empty() {
+                return Err(format!("{:?} takes args or json_args, not both", action));
+            }
+        }
+        Action::Reduce | Action::Rereduce => {
+            if request.args.len() != 1 || !request.json_args.is_empty() {
+                return Err(format!("{:?} takes exactly one arg", action));
+            }
+        }
+        // EVAL's args are only informational, e.g. the file name
+        Action::Eval => {
+            if !request.json_args.is_empty() {
+                return Err(format!("{:?} doesn't take json_args", action));
+            }
+        }
+        Action::SetGlobals => {
+            if !request.args.is_empty() || !request.json_args.is_empty() {
+                return Err(format!("{:?} doesn't take args", action));
+            }
+            match serde_json::from_str::<serde_json::Value>(&request.script) {
+                Ok(serde_json::Value::Object(_)) => (),
+                _ => return Err(format!("{:?} needs a JSON object", action)),
+            }
+        }
+        Action::RegisterDdoc => {
+            if request.args.len() != 1 || !request.json_args.is_empty() {
+                return Err(format!("{:?} takes exactly one arg", action));
+            }
+            if let Err(err) = design_docs::parse(&request.args[0]) {
+                return Err(err.to_string());
+            }
+        }
+        Action::EvictDdoc => {
+            if !request.args.is_empty() || !request.json_args.is_empty() {
+                return Err(format!("{:?} doesn't take args", action));
+            }
+        }
+        Action::MapDoc => {
+            if request.args.len() != 1 || !request.json_args.is_empty() {
+                return Err(format!("{:?} takes exactly one arg", action));
+            }
+        }
+        Action::MapDocs => {
+            if !request.json_args.is_empty() {
+                return Err(format!("{:?} takes args", action));
+            }
+        }
+        Action::WasmCall => {
+            if request.wasm_module.is_empty() {
+                return Err(format!("{:?} needs a wasm_module", action));
+            }
+            if !request.args.is_empty() {
+                return Err(format!("{:?} takes json_args", action));
+            }
+        }
+        Action::Reset => {
+            if !request.script.is_empty()
+                || !request.args.is_empty()
+                || !request.json_args.is_empty()
+            {
+                return Err(format!("{:?} doesn't take a script or args", action));
+            }
+        }
+        Action::Filter => {
+            if request.args.len() < 2 || request.args.len() > 3 || !request.json_args.is_empty() {
+                return Err(format!("{:?} takes two or three args", action));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Builds the JsResponse the server would send, e.g. for tests
+pub struct JsResponseBuilder {
+    response: JsResponse,
+}
+
+impl JsResponseBuilder {
+    pub fn ok(result: &str) -> JsResponseBuilder {
+        JsResponseBuilder {
+            response: JsResponse {
+                status: 0,
+                result: result.to_string(),
+                ..JsResponse::default()
+            },
+        }
+    }
+
+    pub fn error(err: JSError) -> JsResponseBuilder {
+        JsResponseBuilder {
+            response: err.into(),
+        }
+    }
+
+    pub fn log(mut self, level: log::Level, message: &str) -> JsResponseBuilder {
+        self.response.logs.push(LogMessage {
+            level: level.to_string().to_lowercase(),
+            message: message.to_string(),
+        });
+        self
+    }
+
+    pub fn build(self) -> JsResponse {
+        self.response
+    }
+}
@@ -0,0 +1,33 @@
+use crate::trace;
+
+// Set by callers that already have an id for the request, e.g. from their
+// own logs
+pub const REQUEST_ID: &str = "x-request-id";
+
+// Longest id taken from a caller. Keeps ids short enough to be exemplar
+// labels in /metrics.
+const MAX_LEN: usize = 64;
+
+// id if it's one that can go into log lines and metrics as is, or a new
+// one otherwise
+pub fn or_generate(id: &str) -> String {
+    if is_valid(id) {
+        id.to_string()
+    } else {
+        generate()
+    }
+}
+
+// 32 hex digits, like a trace id
+pub fn generate() -> String {
+    format!("{:016x}{:016x}", trace::random_id(), trace::random_id())
+}
+
+// Printable ASCII without quotes or backslashes, which would need escaping
+pub fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && b != b'"' && b != b'\\')
+}
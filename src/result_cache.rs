@@ -0,0 +1,178 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::http_service::request_hash;
+use crate::metrics;
+use crate::proto::ateles::{JsRequest, JsResponse};
+
+// Operations whose results can be cached, by their metrics names. They're
+// the ones identified by their function along with its arguments.
+const CACHEABLE_OPS: &[&str] = &["call", "call_json", "rewrite", "map_doc", "filter"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResultCacheConfig {
+    // How long results are kept for each operation that's cached
+    pub ttls: BTreeMap<String, Duration>,
+    pub max_entries: usize,
+}
+
+impl ResultCacheConfig {
+    // ttls is a list like "call=500,filter=250" of operations and how many
+    // milliseconds their results are kept for
+    pub fn parse(ttls: &str, max_entries: usize) -> Result<ResultCacheConfig, String> {
+        let mut config = ResultCacheConfig {
+            ttls: BTreeMap::new(),
+            max_entries,
+        };
+        for entry in ttls.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let mut parts = entry.splitn(2, '=');
+            let op = parts.next().unwrap_or("");
+            if !CACHEABLE_OPS.contains(&op) {
+                return Err(format!(
+                    "results of {:?} can't be cached, only of {}",
+                    op,
+                    CACHEABLE_OPS.join(", ")
+                ));
+            }
+            let ms: u64 = parts
+                .next()
+                .and_then(|ms| ms.parse().ok())
+                .ok_or_else(|| format!("expected op=milliseconds, not {:?}", entry))?;
+            config
+                .ttls
+                .insert(op.to_string(), Duration::from_millis(ms));
+        }
+        Ok(config)
+    }
+}
+
+// A result is only good for the worker that produced it and for as long as
+// nothing has changed the worker's state
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct CacheKey {
+    worker: usize,
+    generation: u64,
+    action: i32,
+    // The function and its arguments
+    request: String,
+    emit_format: i32,
+    result_encoding: i32,
+    doc_id: String,
+    // A result that fit one request's limit may not fit another's
+    max_result_bytes: i32,
+}
+
+struct Entry {
+    response: JsResponse,
+    expires: Instant,
+    // Tells this entry apart from one inserted under the same key later
+    seq: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<CacheKey, Entry>,
+    // Oldest first, for evicting once max_entries is reached
+    order: VecDeque<(u64, CacheKey)>,
+    seq: u64,
+}
+
+// Successful results of identical requests, e.g. the same validate function
+// called with the same doc over and over during a replication storm. Only
+// for functions without side effects, which is why it's opt in.
+pub struct ResultCache {
+    config: ResultCacheConfig,
+    entries: Mutex<Entries>,
+}
+
+impl ResultCache {
+    pub fn new(config: ResultCacheConfig) -> ResultCache {
+        ResultCache {
+            config,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    // None if the request's results aren't cached
+    pub fn key(
+        &self,
+        worker: usize,
+        generation: u64,
+        op: &str,
+        js_request: &JsRequest,
+    ) -> Option<CacheKey> {
+        if !self.config.ttls.contains_key(op) || self.config.max_entries == 0 {
+            return None;
+        }
+        Some(CacheKey {
+            worker,
+            generation,
+            action: js_request.action,
+            request: format!("{}:{}", op, request_hash(js_request)),
+            emit_format: js_request.emit_format,
+            result_encoding: js_request.result_encoding,
+            doc_id: js_request.doc_id.clone(),
+            max_result_bytes: js_request.max_result_bytes,
+        })
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<JsResponse> {
+        let mut entries = self.entries.lock().unwrap();
+        let hit = match entries.map.get(key) {
+            Some(entry) if entry.expires > Instant::now() => Some(entry.response.clone()),
+            Some(_) => {
+                entries.map.remove(key);
+                None
+            }
+            None => None,
+        };
+        match hit {
+            Some(_) => metrics::RESULT_CACHE_HITS_TOTAL.inc(),
+            None => metrics::RESULT_CACHE_MISSES_TOTAL.inc(),
+        }
+        hit
+    }
+
+    // Only successful responses are kept
+    pub fn put(&self, key: CacheKey, op: &str, response: &JsResponse) {
+        let ttl = match self.config.ttls.get(op) {
+            Some(ttl) if response.status == 0 => *ttl,
+            _ => return,
+        };
+        let mut entries = self.entries.lock().unwrap();
+        entries.seq += 1;
+        let seq = entries.seq;
+        entries.order.push_back((seq, key.clone()));
+        entries.map.insert(
+            key,
+            Entry {
+                response: response.clone(),
+                expires: Instant::now() + ttl,
+                seq,
+            },
+        );
+        while entries.map.len() > self.config.max_entries {
+            let (seq, key) = match entries.order.pop_front() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            if entries.map.get(&key).map(|entry| entry.seq) == Some(seq) {
+                entries.map.remove(&key);
+            }
+        }
+        // Keys replaced or expired since leave stale entries behind
+        if entries.order.len() > self.config.max_entries * 2 {
+            let Entries { map, order, .. } = &mut *entries;
+            order.retain(|(seq, key)| map.get(key).map(|entry| entry.seq) == Some(*seq));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::js_server::{Command, Ops};
+use crate::metrics;
+use crate::quarantine::script_hash;
+
+// The runtime's rewrite functions. harden.js locks them so nothing can
+// replace them, which makes the same source always rewrite the same way on
+// any worker started from the same snapshot.
+pub const REWRITE_FUNCTIONS: &[&str] = &["rewriteFun", "rewriteFuns", "rewriteFunInt"];
+
+pub const DEFAULT_MAX_ENTRIES: usize = 4096;
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct RewriteKey {
+    op: String,
+    script_hash: String,
+    args_hash: String,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<RewriteKey, (String, u64)>,
+    // Keys by when they were last used, least recently first
+    used: BTreeMap<u64, RewriteKey>,
+    tick: u64,
+}
+
+impl Entries {
+    fn touch(&mut self, key: &RewriteKey) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some((_, used)) = self.map.get_mut(key) {
+            self.used.remove(used);
+            *used = tick;
+            self.used.insert(tick, key.clone());
+        }
+    }
+}
+
+// Results of REWRITE commands, shared by every worker of a JSEnv. View
+// builds rewrite the same map functions over and over, this answers them
+// without going to a worker at all. Least recently used results are evicted
+// once it's full.
+pub struct RewriteCache {
+    max_entries: AtomicUsize,
+    entries: Mutex<Entries>,
+}
+
+impl RewriteCache {
+    pub fn new(max_entries: usize) -> RewriteCache {
+        RewriteCache {
+            max_entries: AtomicUsize::new(max_entries),
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    // 0 turns the cache off
+    pub fn set_max_entries(&self, max_entries: usize) {
+        self.max_entries.store(max_entries, Ordering::SeqCst);
+        let mut entries = self.entries.lock().unwrap();
+        evict(&mut entries, max_entries);
+    }
+
+    // None for commands whose results aren't cached: anything but a REWRITE
+    // by one of the runtime's rewrite functions
+    pub fn key(&self, cmd: &Command) -> Option<RewriteKey> {
+        if !matches!(cmd.operation, Ops::REWRITE)
+            || !REWRITE_FUNCTIONS.contains(&cmd.payload.as_str())
+            || self.max_entries.load(Ordering::Relaxed) == 0
+        {
+            return None;
+        }
+        Some(RewriteKey {
+            op: format!("{:?}", cmd.operation).to_lowercase(),
+            script_hash: script_hash(&cmd.payload, None),
+            args_hash: script_hash("", Some(cmd.args.as_slice())),
+        })
+    }
+
+    pub fn get(&self, key: &RewriteKey) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap();
+        let hit = entries.map.get(key).map(|(result, _)| result.clone());
+        match hit {
+            Some(_) => {
+                entries.touch(key);
+                metrics::REWRITE_CACHE_HITS_TOTAL.inc();
+            }
+            None => metrics::REWRITE_CACHE_MISSES_TOTAL.inc(),
+        }
+        hit
+    }
+
+    pub fn put(&self, key: RewriteKey, result: String) {
+        let max_entries = self.max_entries.load(Ordering::Relaxed);
+        let mut entries = self.entries.lock().unwrap();
+        if let Some((_, used)) = entries.map.remove(&key) {
+            entries.used.remove(&used);
+        }
+        entries.tick += 1;
+        let tick = entries.tick;
+        entries.used.insert(tick, key.clone());
+        entries.map.insert(key, (result, tick));
+        evict(&mut entries, max_entries);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for RewriteCache {
+    fn default() -> RewriteCache {
+        RewriteCache::new(DEFAULT_MAX_ENTRIES)
+    }
+}
+
+fn evict(entries: &mut Entries, max_entries: usize) {
+    while entries.map.len() > max_entries {
+        let oldest = match entries.used.keys().next() {
+            Some(tick) => *tick,
+            None => break,
+        };
+        if let Some(key) = entries.used.remove(&oldest) {
+            entries.map.remove(&key);
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::time::{Duration, Instant};
+
+use crate::host_functions::{self, Capabilities};
+use crate::{FortunaIsolate, JSEnv, JSError};
+
+// A design doc and a doc to map with it
+const FUNCTIONS: &str = r#"{
+    "map": ["function(doc) { emit(doc._id, doc.value); }"],
+    "filters": {"big": "function(doc, req) { return doc.value > 1; }"}
+}"#;
+const DOC: &str = r#"{"_id": "sample", "value": 2}"#;
+
+// How one of the smoke tests went
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub took: Duration,
+    pub outcome: Result<(), String>,
+}
+
+type CheckFn = fn(&mut FortunaIsolate) -> Result<(), String>;
+
+const CHECKS: &[(&str, CheckFn)] = &[
+    ("eval", check_eval),
+    ("rewrite", check_rewrite),
+    ("map_doc", check_map_doc),
+    ("filter", check_filter),
+    ("runtime_error", check_runtime_error),
+    ("compile_error", check_compile_error),
+    ("reset", check_reset),
+];
+
+// Runs the smoke tests on one isolate from js_env's snapshot, in the order
+// a worker would see such commands. Stops at the first failure as later
+// checks would only fail along with it.
+pub fn run(js_env: &JSEnv) -> Vec<Check> {
+    let started = Instant::now();
+    let mut isolate = js_env.create_isolate_with_capabilities(&Capabilities::all());
+    let mut checks = vec![Check {
+        name: "create_isolate",
+        took: started.elapsed(),
+        outcome: Ok(()),
+    }];
+
+    for &(name, check) in CHECKS {
+        let started = Instant::now();
+        let _ = host_functions::take_emitted();
+        let outcome = check(&mut isolate);
+        let failed = outcome.is_err();
+        checks.push(Check {
+            name,
+            took: started.elapsed(),
+            outcome,
+        });
+        if failed {
+            break;
+        }
+    }
+    checks
+}
+
+pub fn passed(checks: &[Check]) -> bool {
+    checks.len() == CHECKS.len() + 1 && checks.iter().all(|check| check.outcome.is_ok())
+}
+
+// A line per check, e.g. "ok   map_doc (1.2ms)"
+pub fn report(checks: &[Check]) -> String {
+    checks
+        .iter()
+        .map(|check| match &check.outcome {
+            Ok(()) => format!("ok   {} ({:?})\n", check.name, check.took),
+            Err(reason) => format!("FAIL {} ({:?}): {}\n", check.name, check.took, reason),
+        })
+        .collect()
+}
+
+fn expect(what: &str, result: Result<String, JSError>, expected: &str) -> Result<(), String> {
+    match result {
+        Ok(result) if result == expected => Ok(()),
+        Ok(result) => Err(format!("{} gave {}, expected {}", what, result, expected)),
+        Err(err) => Err(format!("{} failed: {}", what, err)),
+    }
+}
+
+fn check_eval(isolate: &mut FortunaIsolate) -> Result<(), String> {
+    expect("1 + 1", isolate.eval("1 + 1;", &[]), "2")
+}
+
+fn check_rewrite(isolate: &mut FortunaIsolate) -> Result<(), String> {
+    // rewriteFun takes the JSON of an anonymous function's source
+    let fun = "\"function(doc) { return doc; }\"".to_string();
+    match isolate.call("rewriteFun", &[fun]) {
+        Ok(source) if source.contains("function") => Ok(()),
+        Ok(source) => Err(format!("rewriteFun gave {}", source)),
+        Err(err) => Err(format!("rewriteFun failed: {}", err)),
+    }
+}
+
+fn check_map_doc(isolate: &mut FortunaIsolate) -> Result<(), String> {
+    expect(
+        "registering a design doc",
+        isolate.register_ddoc("self_check", FUNCTIONS),
+        "true",
+    )?;
+    expect("mapping a doc", isolate.map_doc("self_check", DOC), "true")?;
+    let emitted = host_functions::take_emitted()?;
+    if emitted != vec![vec!["[\"sample\",2]".to_string()]] {
+        return Err(format!("mapping a doc emitted {:?}", emitted));
+    }
+    Ok(())
+}
+
+fn check_filter(isolate: &mut FortunaIsolate) -> Result<(), String> {
+    let docs = format!("[{}]", DOC);
+    expect(
+        "filtering a doc",
+        isolate.filter_docs("self_check", "big", &docs, "{}"),
+        "[true]",
+    )
+}
+
+fn check_runtime_error(isolate: &mut FortunaIsolate) -> Result<(), String> {
+    match isolate.eval("throw new TypeError('self check');", &[]) {
+        Err(JSError::RuntimeError(info)) if info.name == "TypeError" => Ok(()),
+        other => Err(format!("a throw gave {:?}", other)),
+    }
+}
+
+fn check_compile_error(isolate: &mut FortunaIsolate) -> Result<(), String> {
+    match isolate.eval("function (", &[]) {
+        Err(JSError::CompileError(_)) => Ok(()),
+        other => Err(format!("a syntax error gave {:?}", other)),
+    }
+}
+
+fn check_reset(isolate: &mut FortunaIsolate) -> Result<(), String> {
+    expect("resetting", isolate.reset(), "true")?;
+    if isolate.design_doc_count() != 0 {
+        return Err("design docs survived a reset".to_string());
+    }
+    expect("1 + 1 after a reset", isolate.eval("1 + 1;", &[]), "2")
+}
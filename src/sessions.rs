@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::harness::Harness;
+use crate::host_functions::Capabilities;
+use crate::js_server::{create_js_env, JSClient};
+use crate::metrics;
+use crate::threads;
+use crate::JSEnv;
+
+// How often idle sessions are looked for
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+struct Session {
+    js_client: JSClient,
+    ttl: Duration,
+    last_used: Instant,
+    // Empty for the built in one
+    harness: String,
+}
+
+// Workers that belong to a session id chosen by the caller rather than to a
+// connection. A caller that reconnects, e.g. after a network blip in the
+// middle of building an index, carries on with the same worker and whatever
+// it set up there. Sessions are dropped once they've been idle for their TTL.
+pub struct Sessions {
+    js_env: Arc<JSEnv>,
+    capabilities: Capabilities,
+    default_ttl: Duration,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl Sessions {
+    // Also starts a thread that drops expired sessions for as long as the
+    // returned Sessions is alive
+    pub fn start(
+        js_env: Arc<JSEnv>,
+        capabilities: Capabilities,
+        default_ttl: Duration,
+    ) -> Arc<Sessions> {
+        let sessions = Arc::new(Sessions {
+            js_env,
+            capabilities,
+            default_ttl,
+            sessions: Mutex::new(HashMap::new()),
+        });
+
+        let weak = Arc::downgrade(&sessions);
+        threads::spawn("fortuna-sessions".to_string(), move || sweep(weak))
+            .expect("can't start the session sweeper");
+        sessions
+    }
+
+    // The worker for session id and whether it was just created, in which
+    // case anything the caller set up before is gone. A ttl of zero keeps
+    // the session's current TTL, or the default for a new session.
+    pub fn client(&self, id: &str, ttl: Duration) -> (JSClient, bool) {
+        self.harness_client(id, ttl, None)
+    }
+
+    // Like client but a new session's worker runs harness's bundle. A
+    // session asked for with another harness than it was created with is
+    // started over.
+    pub fn harness_client(
+        &self,
+        id: &str,
+        ttl: Duration,
+        harness: Option<&Harness>,
+    ) -> (JSClient, bool) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = Instant::now();
+        let name = harness.map_or("", Harness::name);
+
+        if let Some(session) = sessions.get_mut(id) {
+            if now.duration_since(session.last_used) < session.ttl && session.harness == name {
+                session.last_used = now;
+                if ttl > Duration::default() {
+                    session.ttl = ttl;
+                }
+                return (session.js_client.clone(), false);
+            }
+        }
+
+        let session = Session {
+            js_client: match harness {
+                Some(harness) => harness.create_client(),
+                None => create_js_env(&self.js_env, &self.capabilities),
+            },
+            ttl: if ttl > Duration::default() {
+                ttl
+            } else {
+                self.default_ttl
+            },
+            last_used: now,
+            harness: name.to_string(),
+        };
+        let js_client = session.js_client.clone();
+        sessions.insert(id.to_string(), session);
+        metrics::SESSIONS_OPEN.set(sessions.len() as f64);
+        (js_client, true)
+    }
+
+    // Drops the sessions that have been idle for longer than their TTL and
+    // returns how many there were. Their workers exit once nothing is using
+    // them any more.
+    pub fn expire(&self) -> usize {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        let now = Instant::now();
+        sessions.retain(|_, session| now.duration_since(session.last_used) < session.ttl);
+        metrics::SESSIONS_OPEN.set(sessions.len() as f64);
+        before - sessions.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn sweep(sessions: Weak<Sessions>) {
+    loop {
+        thread::sleep(SWEEP_INTERVAL);
+        let sessions = match sessions.upgrade() {
+            Some(sessions) => sessions,
+            None => return,
+        };
+        let expired = sessions.expire();
+        if expired > 0 {
+            log::debug!("expired {} idle sessions", expired);
+        }
+    }
+}
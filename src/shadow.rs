@@ -0,0 +1,149 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::http_service::execute_shadow;
+use crate::js_server::{create_js_env, JSClient};
+use crate::metrics;
+use crate::proto::ateles::{JsRequest, JsResponse};
+use crate::{Capabilities, JSEnv};
+
+// A shadowed connection that falls this far behind stops being shadowed
+const MAX_PENDING: usize = 1000;
+
+// Runs a sample of connections a second time on workers built from another
+// bundle and compares the results, e.g. to try out a new version of the
+// runtime JS before switching to it. Whole connections are sampled rather
+// than single requests so the shadow worker sees the same EVALs as the
+// primary one.
+pub struct Shadow {
+    js_env: JSEnv,
+    capabilities: Capabilities,
+    sample: f64,
+    connections: AtomicU64,
+}
+
+impl Shadow {
+    // sample is the fraction of connections to shadow, from 0.0 to 1.0
+    pub fn new(js_env: JSEnv, capabilities: Capabilities, sample: f64) -> Shadow {
+        Shadow {
+            js_env,
+            capabilities,
+            sample: sample.max(0.0).min(1.0),
+            connections: AtomicU64::new(0),
+        }
+    }
+
+    // Called for every new connection. Returns where to send its requests if
+    // it was picked.
+    pub fn connect(&self) -> Option<ShadowConn> {
+        let n = self.connections.fetch_add(1, Ordering::Relaxed);
+        if !is_sampled(n, self.sample) {
+            return None;
+        }
+
+        let (sender, receiver) = unbounded_channel();
+        let conn = ShadowConn {
+            sender,
+            pending: Arc::new(AtomicUsize::new(0)),
+            stopped: Arc::new(AtomicBool::new(false)),
+        };
+        let js_client = create_js_env(&self.js_env, &self.capabilities);
+        tokio::spawn(run(js_client, receiver, conn.pending.clone()));
+        Some(conn)
+    }
+}
+
+// Spreads the sampled connections evenly, e.g. every 4th for 0.25
+pub fn is_sampled(n: u64, sample: f64) -> bool {
+    (n as f64 * sample).floor() != ((n + 1) as f64 * sample).floor()
+}
+
+struct Shadowed {
+    request: JsRequest,
+    primary: JsResponse,
+    primary_time: Duration,
+}
+
+#[derive(Clone)]
+pub struct ShadowConn {
+    sender: UnboundedSender<Shadowed>,
+    pending: Arc<AtomicUsize>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl ShadowConn {
+    // Queues a request that has already been answered by the primary worker.
+    // Never waits for the shadow worker.
+    pub fn send(&self, request: JsRequest, primary: &JsResponse, primary_time: Duration) {
+        if self.stopped.load(Ordering::Relaxed) {
+            return;
+        }
+        // Skipping a request would leave the shadow worker in a different
+        // state so a connection that can't keep up isn't shadowed any more
+        if self.pending.fetch_add(1, Ordering::Relaxed) >= MAX_PENDING {
+            log::warn!("shadow worker fell behind, no longer shadowing the connection");
+            self.stopped.store(true, Ordering::Relaxed);
+            return;
+        }
+        let shadowed = Shadowed {
+            request,
+            primary: primary.clone(),
+            primary_time,
+        };
+        if self.sender.send(shadowed).is_err() {
+            self.stopped.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+async fn run(
+    js_client: JSClient,
+    mut receiver: UnboundedReceiver<Shadowed>,
+    pending: Arc<AtomicUsize>,
+) {
+    while let Some(shadowed) = receiver.recv().await {
+        pending.fetch_sub(1, Ordering::Relaxed);
+
+        let action = shadowed.request.action;
+        let start = Instant::now();
+        let shadow = execute_shadow(&js_client, shadowed.request).await;
+        let shadow_time = start.elapsed();
+
+        metrics::SHADOW_COMMANDS_TOTAL.inc();
+        metrics::SHADOW_EXECUTION_SECONDS.observe("primary", shadowed.primary_time);
+        metrics::SHADOW_EXECUTION_SECONDS.observe("shadow", shadow_time);
+        if let Some(mismatch) = compare(&shadowed.primary, &shadow) {
+            metrics::SHADOW_MISMATCHES_TOTAL.inc();
+            log::warn!(
+                "shadow mismatch for action {}: {} (primary took {:?}, shadow {:?})",
+                action,
+                mismatch,
+                shadowed.primary_time,
+                shadow_time
+            );
+        }
+    }
+}
+
+// Describes the first difference between what the primary and shadow workers
+// answered, if there is one. Logs and stats are expected to differ.
+pub fn compare(primary: &JsResponse, shadow: &JsResponse) -> Option<String> {
+    if primary.status != shadow.status || primary.error_type != shadow.error_type {
+        Some(format!(
+            "status {}/{} vs {}/{}",
+            primary.status, primary.error_type, shadow.status, shadow.error_type
+        ))
+    } else if primary.result != shadow.result {
+        Some(format!(
+            "result {:?} vs {:?}",
+            primary.result, shadow.result
+        ))
+    } else if primary.emitted != shadow.emitted {
+        Some("emitted rows differ".to_string())
+    } else {
+        None
+    }
+}
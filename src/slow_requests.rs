@@ -0,0 +1,64 @@
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// How many slow requests are kept, oldest dropped first
+const MAX_RECENT: usize = 50;
+
+// Requests taking at least this long in microseconds are kept, 0 to keep none
+static THRESHOLD_US: AtomicU64 = AtomicU64::new(1_000_000);
+
+lazy_static! {
+    static ref RECENT: Mutex<VecDeque<SlowRequest>> = Mutex::new(VecDeque::new());
+}
+
+// A request that took longer than the threshold, for the admin UI
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SlowRequest {
+    // Milliseconds since the epoch when it was answered
+    pub ts: u64,
+    pub operation: String,
+    pub peer: String,
+    pub took_us: u64,
+    pub error: Option<String>,
+}
+
+pub fn set_threshold(threshold: Duration) {
+    THRESHOLD_US.store(threshold.as_micros() as u64, Ordering::SeqCst);
+}
+
+pub fn threshold() -> Duration {
+    Duration::from_micros(THRESHOLD_US.load(Ordering::SeqCst))
+}
+
+// Keeps the request if it took at least the threshold and says whether it did
+pub fn record(operation: &str, peer: &str, took: Duration, error: Option<String>) -> bool {
+    let threshold = THRESHOLD_US.load(Ordering::SeqCst);
+    if threshold == 0 || (took.as_micros() as u64) < threshold {
+        return false;
+    }
+    let request = SlowRequest {
+        ts: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64,
+        operation: operation.to_string(),
+        peer: peer.to_string(),
+        took_us: took.as_micros() as u64,
+        error,
+    };
+    let mut recent = RECENT.lock().unwrap();
+    if recent.len() == MAX_RECENT {
+        recent.pop_front();
+    }
+    recent.push_back(request);
+    true
+}
+
+// Newest first
+pub fn recent() -> Vec<SlowRequest> {
+    RECENT.lock().unwrap().iter().rev().cloned().collect()
+}
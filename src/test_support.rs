@@ -0,0 +1,70 @@
+use std::io;
+use std::net::SocketAddr;
+
+use futures::channel::oneshot;
+
+use crate::client::Client;
+use crate::http_service::{create_server_with, MakeService};
+use crate::js_server::{self, JSClient};
+
+// A server on an ephemeral port of 127.0.0.1 for end to end tests. It runs
+// on the current tokio runtime until the TestServer is dropped.
+pub struct TestServer {
+    pub addr: SocketAddr,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl TestServer {
+    pub fn start() -> io::Result<TestServer> {
+        TestServer::start_with(MakeService::new())
+    }
+
+    pub fn start_with(make_service: MakeService) -> io::Result<TestServer> {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let server = create_server_with(&addr, false, make_service)?;
+        let addr = server.local_addr();
+
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        let server = server.with_graceful_shutdown(async {
+            let _ = stopped.await;
+        });
+        tokio::spawn(async move {
+            if let Err(err) = server.await {
+                log::error!("test server failed: {}", err);
+            }
+        });
+
+        Ok(TestServer {
+            addr,
+            shutdown: Some(shutdown),
+        })
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn client(&self) -> Client {
+        Client::new(&self.url())
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+// The next times commands with payload run, they panic the worker running
+// them as a bug in V8 or the engine would
+pub fn crash_on(payload: &str, times: usize) {
+    js_server::crash_on(payload, times);
+}
+
+// The next times the worker starts an isolate, e.g. after a crash, it fails
+// to. Enough of them in a row and it gives up.
+pub fn fail_isolates(client: &JSClient, times: u32) {
+    client.fail_isolates(times);
+}
@@ -0,0 +1,66 @@
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+
+lazy_static! {
+    // Names of the threads started with spawn that are still running, by
+    // their OS thread ids
+    static ref THREADS: Mutex<BTreeMap<i64, String>> = Mutex::new(BTreeMap::new());
+}
+
+// A thread as top -H, perf or /proc/<pid>/task see it
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThreadInfo {
+    pub os_id: i64,
+    pub name: String,
+}
+
+// The id the kernel knows the calling thread by
+pub fn os_thread_id() -> i64 {
+    unsafe { libc::syscall(libc::SYS_gettid) as i64 }
+}
+
+// Starts a thread named name and keeps track of its OS thread id until it
+// exits. Linux only shows the first 15 bytes of the name.
+pub fn spawn<F, T>(name: String, f: F) -> io::Result<JoinHandle<T>>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    thread::Builder::new().name(name.clone()).spawn(move || {
+        let _registered = Registered::new(name);
+        f()
+    })
+}
+
+// Every thread started with spawn that's still running
+pub fn list() -> Vec<ThreadInfo> {
+    THREADS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(os_id, name)| ThreadInfo {
+            os_id: *os_id,
+            name: name.clone(),
+        })
+        .collect()
+}
+
+// Removes the thread from THREADS however it exits, panics included
+struct Registered(i64);
+
+impl Registered {
+    fn new(name: String) -> Registered {
+        let os_id = os_thread_id();
+        THREADS.lock().unwrap().insert(os_id, name);
+        Registered(os_id)
+    }
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        THREADS.lock().unwrap().remove(&self.0);
+    }
+}
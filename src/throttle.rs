@@ -0,0 +1,128 @@
+use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::metrics;
+use crate::threads;
+
+// Set by the monitor thread while the host is over one of its thresholds
+static THROTTLED: AtomicBool = AtomicBool::new(false);
+
+// How long a background command is held back while throttled
+static DELAY_MS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Debug)]
+pub struct ThrottleConfig {
+    // Fraction of all cores this process may use, e.g. 0.9
+    pub max_cpu: f64,
+    // One minute load average per core
+    pub max_load: f64,
+    pub delay: Duration,
+    // How often CPU usage and load are sampled
+    pub interval: Duration,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        ThrottleConfig {
+            max_cpu: 0.9,
+            max_load: 1.5,
+            delay: Duration::from_millis(20),
+            interval: Duration::from_secs(1),
+        }
+    }
+}
+
+// Watches the process CPU usage and the load average so that background
+// commands can be slowed down when the host is saturated, leaving room for a
+// co-located CouchDB.
+pub fn start(config: ThrottleConfig) {
+    DELAY_MS.store(config.delay.as_millis() as u64, Ordering::SeqCst);
+
+    let spawned = threads::spawn("fortuna-throttle".to_string(), move || {
+        let cores = cores();
+        let mut last_cpu = process_cpu_time();
+        let mut last_sample = Instant::now();
+
+        loop {
+            thread::sleep(config.interval);
+
+            let cpu = process_cpu_time();
+            let cpu_ratio = match (last_cpu, cpu) {
+                (Some(last), Some(now)) => {
+                    let used = now.checked_sub(last).unwrap_or_default();
+                    used.as_secs_f64() / last_sample.elapsed().as_secs_f64() / cores
+                }
+                _ => 0.0,
+            };
+            last_cpu = cpu;
+            last_sample = Instant::now();
+
+            let load = load_average().unwrap_or(0.0) / cores;
+            let throttled = cpu_ratio > config.max_cpu || load > config.max_load;
+            if throttled != THROTTLED.swap(throttled, Ordering::SeqCst) {
+                log::info!(
+                    "throttle {}: cpu {:.2} load {:.2}",
+                    if throttled { "on" } else { "off" },
+                    cpu_ratio,
+                    load
+                );
+            }
+
+            metrics::PROCESS_CPU_RATIO.set(cpu_ratio);
+            metrics::LOAD_PER_CORE.set(load);
+            metrics::THROTTLE_ACTIVE.set(if throttled { 1.0 } else { 0.0 });
+        }
+    });
+    if let Err(err) = spawned {
+        log::warn!("can't start the throttle monitor: {}", err);
+    }
+}
+
+pub fn is_throttled() -> bool {
+    THROTTLED.load(Ordering::SeqCst)
+}
+
+// Delays background commands while the host is saturated. Everything else is
+// admitted straight away.
+pub async fn admit(background: bool) {
+    if !background || !is_throttled() {
+        return;
+    }
+
+    metrics::THROTTLED_COMMANDS_TOTAL.inc();
+    let delay = Duration::from_millis(DELAY_MS.load(Ordering::SeqCst));
+    tokio::time::delay_for(delay).await;
+}
+
+fn cores() -> f64 {
+    let cores = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    if cores > 0 {
+        cores as f64
+    } else {
+        1.0
+    }
+}
+
+// User plus system time of this process, from /proc/self/stat
+fn process_cpu_time() -> Option<Duration> {
+    let stat = fs::read_to_string("/proc/self/stat").ok()?;
+    // The command name can contain spaces so start after its closing paren
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks <= 0 {
+        return None;
+    }
+    Some(Duration::from_secs_f64(
+        (utime + stime) as f64 / ticks as f64,
+    ))
+}
+
+fn load_average() -> Option<f64> {
+    let loadavg = fs::read_to_string("/proc/loadavg").ok()?;
+    loadavg.split_whitespace().next()?.parse().ok()
+}
@@ -0,0 +1,116 @@
+use futures::stream::Stream;
+use std::fs;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
+pub use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+// How long accepting waits after failing, e.g. because the process is out
+// of file descriptors, like hyper's own listeners
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+// How long a client gets to finish its handshake before the connection is
+// dropped, so ones that never do don't hold on to a task and an fd
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// What TCP listeners serve HTTPS with, see MakeService::with_tls
+#[derive(Clone)]
+pub struct TlsConfig {
+    server: Arc<ServerConfig>,
+}
+
+impl TlsConfig {
+    // cert is the PEM of the certificate chain, server's first, and key the
+    // PEM of its PKCS #8 or RSA private key. HTTP/2 is offered over ALPN.
+    pub fn from_pem(cert: &[u8], key: &[u8]) -> io::Result<TlsConfig> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+        let certs = pemfile::certs(&mut BufReader::new(cert))
+            .map_err(|_| invalid("can't parse the TLS certificate"))?;
+        if certs.is_empty() {
+            return Err(invalid("no TLS certificate found"));
+        }
+        let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(key))
+            .map_err(|_| invalid("can't parse the TLS private key"))?;
+        if keys.is_empty() {
+            keys = pemfile::rsa_private_keys(&mut BufReader::new(key))
+                .map_err(|_| invalid("can't parse the TLS private key"))?;
+        }
+        let key = match keys.into_iter().next() {
+            Some(key) => key,
+            None => return Err(invalid("no TLS private key found")),
+        };
+
+        let mut server = ServerConfig::new(NoClientAuth::new());
+        server
+            .set_single_cert(certs, key)
+            .map_err(|err| invalid(&err.to_string()))?;
+        server.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+        Ok(TlsConfig::new(server))
+    }
+
+    pub fn from_pem_files(cert: &Path, key: &Path) -> io::Result<TlsConfig> {
+        TlsConfig::from_pem(&fs::read(cert)?, &fs::read(key)?)
+    }
+
+    // For anything from_pem can't set up, e.g. client certificates
+    pub fn new(server: ServerConfig) -> TlsConfig {
+        TlsConfig {
+            server: Arc::new(server),
+        }
+    }
+}
+
+// Accepts connections on listener and does their handshakes, each in a task
+// of its own so a slow client can't hold up the others. Connections whose
+// handshake fails or times out are dropped. Accepting stops once the
+// returned stream is.
+pub(crate) fn accept(
+    listener: std::net::TcpListener,
+    tls: &TlsConfig,
+) -> io::Result<impl Stream<Item = io::Result<TlsStream<TcpStream>>>> {
+    listener.set_nonblocking(true)?;
+    let mut listener = TcpListener::from_std(listener)?;
+    let acceptor = TlsAcceptor::from(tls.server.clone());
+    let (sender, receiver) = mpsc::unbounded_channel();
+    // Set by a handshake that finds nobody left to take its connection
+    let closed = Arc::new(AtomicBool::new(false));
+    tokio::spawn(async move {
+        loop {
+            let accepted = listener.accept().await;
+            if closed.load(Ordering::SeqCst) {
+                break;
+            }
+            let (tcp, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    log::warn!("can't accept a TLS connection: {}", err);
+                    tokio::time::delay_for(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+            let _ = tcp.set_nodelay(true);
+            let acceptor = acceptor.clone();
+            let sender = sender.clone();
+            let closed = closed.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp)).await {
+                    Ok(Ok(stream)) => {
+                        if sender.send(Ok(stream)).is_err() {
+                            closed.store(true, Ordering::SeqCst);
+                        }
+                    }
+                    Ok(Err(err)) => log::debug!("TLS handshake with {} failed: {}", peer, err),
+                    Err(_) => log::debug!("TLS handshake with {} timed out", peer),
+                }
+            });
+        }
+    });
+    Ok(receiver)
+}
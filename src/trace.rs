@@ -0,0 +1,307 @@
+use serde_json::{json, Value};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::metrics;
+use crate::shadow::is_sampled;
+
+// Spans waiting to be exported. Past this they're dropped rather than
+// piling up while the collector is down.
+const MAX_PENDING: usize = 10_000;
+// Most spans sent to the collector at once
+const MAX_BATCH: usize = 512;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// A W3C traceparent header, e.g.
+// 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    pub parent_id: u64,
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    // None for anything that isn't a valid version 00 header, which then
+    // starts a new trace as if there was none
+    pub fn parse(header: &str) -> Option<TraceContext> {
+        let parts: Vec<&str> = header.trim().split('-').collect();
+        if parts.len() != 4 || parts[0] != "00" {
+            return None;
+        }
+        if parts[1].len() != 32 || parts[2].len() != 16 || parts[3].len() != 2 {
+            return None;
+        }
+        let trace_id = u128::from_str_radix(parts[1], 16).ok()?;
+        let parent_id = u64::from_str_radix(parts[2], 16).ok()?;
+        let flags = u8::from_str_radix(parts[3], 16).ok()?;
+        if trace_id == 0 || parent_id == 0 {
+            return None;
+        }
+        Some(TraceContext {
+            trace_id,
+            parent_id,
+            sampled: flags & 1 == 1,
+        })
+    }
+
+    pub fn header(&self) -> String {
+        format!(
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id, self.parent_id, self.sampled as u8
+        )
+    }
+}
+
+// A finished span, in OTLP's terms
+#[derive(Clone, Debug, PartialEq)]
+pub struct Span {
+    pub trace_id: u128,
+    pub span_id: u64,
+    // 0 for the root of a trace
+    pub parent_id: u64,
+    pub name: String,
+    pub server: bool,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(&'static str, String)>,
+    // Why it failed, if it did
+    pub error: Option<String>,
+}
+
+// The spans of one /Ateles/Execute request: the request as a whole and,
+// within it, the time spent waiting for a worker and running the JS
+pub struct RequestTrace {
+    trace_id: u128,
+    parent_id: u64,
+    span_id: u64,
+    start: SystemTime,
+}
+
+impl RequestTrace {
+    // Continues the caller's trace, or starts one
+    pub fn start(context: Option<TraceContext>) -> RequestTrace {
+        let (trace_id, parent_id) = match context {
+            Some(context) => (context.trace_id, context.parent_id),
+            None => (((random_id() as u128) << 64) | random_id() as u128, 0),
+        };
+        RequestTrace {
+            trace_id,
+            parent_id,
+            span_id: random_id(),
+            start: SystemTime::now(),
+        }
+    }
+
+    // The request's span followed by its queue_wait and js_execution spans.
+    // Workers only report how long each took so they're placed back to back
+    // from when the command was sent.
+    pub fn finish(
+        &self,
+        op: &str,
+        sent: SystemTime,
+        queue_wait: Duration,
+        execution: Duration,
+        error: Option<String>,
+    ) -> Vec<Span> {
+        let end = SystemTime::now();
+        let started = sent + queue_wait;
+        let child = |name: &str, start: SystemTime, end: SystemTime| Span {
+            trace_id: self.trace_id,
+            span_id: random_id(),
+            parent_id: self.span_id,
+            name: name.to_string(),
+            server: false,
+            start,
+            end,
+            attributes: vec![("fortuna.op", op.to_string())],
+            error: None,
+        };
+        vec![
+            Span {
+                trace_id: self.trace_id,
+                span_id: self.span_id,
+                parent_id: self.parent_id,
+                name: "fortuna.execute".to_string(),
+                server: true,
+                start: self.start,
+                end,
+                attributes: vec![("fortuna.op", op.to_string())],
+                error,
+            },
+            child("queue_wait", sent, started),
+            child("js_execution", started, started + execution),
+        ]
+    }
+}
+
+// Exports spans to an OpenTelemetry collector over OTLP/HTTP with the JSON
+// encoding, e.g. to http://localhost:4318/v1/traces
+pub struct Tracer {
+    sender: UnboundedSender<Vec<Span>>,
+    pending: Arc<AtomicUsize>,
+    sample: f64,
+    requests: AtomicU64,
+}
+
+impl Tracer {
+    // Requests with a traceparent are traced if the caller sampled them,
+    // sample is the fraction of the others that start a trace of their own.
+    // Needs to be called on a tokio runtime.
+    pub fn start(endpoint: &str, service_name: &str, sample: f64) -> Tracer {
+        let (sender, receiver) = unbounded_channel();
+        let pending = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(export(
+            endpoint.to_string(),
+            service_name.to_string(),
+            receiver,
+            pending.clone(),
+        ));
+        Tracer {
+            sender,
+            pending,
+            sample: sample.max(0.0).min(1.0),
+            requests: AtomicU64::new(0),
+        }
+    }
+
+    // Called for every request with its traceparent header, if it had one.
+    // Returns the trace to record its spans in if it's traced.
+    pub fn trace(&self, traceparent: Option<&str>) -> Option<RequestTrace> {
+        match traceparent.and_then(TraceContext::parse) {
+            Some(context) if context.sampled => Some(RequestTrace::start(Some(context))),
+            Some(_) => None,
+            None => {
+                let n = self.requests.fetch_add(1, Ordering::Relaxed);
+                if is_sampled(n, self.sample) {
+                    Some(RequestTrace::start(None))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    // Queues spans to be exported. Never waits on the collector.
+    pub fn record(&self, spans: Vec<Span>) {
+        let count = spans.len();
+        if self.pending.fetch_add(count, Ordering::Relaxed) >= MAX_PENDING
+            || self.sender.send(spans).is_err()
+        {
+            self.pending.fetch_sub(count, Ordering::Relaxed);
+            metrics::TRACE_SPANS_DROPPED_TOTAL.add(count as u64);
+        }
+    }
+}
+
+async fn export(
+    endpoint: String,
+    service_name: String,
+    mut receiver: UnboundedReceiver<Vec<Span>>,
+    pending: Arc<AtomicUsize>,
+) {
+    let http = reqwest::Client::new();
+    while let Some(mut batch) = receiver.recv().await {
+        // Whatever else is waiting goes along with it
+        while batch.len() < MAX_BATCH {
+            match receiver.try_recv() {
+                Ok(spans) => batch.extend(spans),
+                Err(_) => break,
+            }
+        }
+        pending.fetch_sub(batch.len(), Ordering::Relaxed);
+
+        let body = to_otlp(&service_name, &batch).to_string();
+        let sent = http
+            .post(&endpoint)
+            .header("content-type", "application/json")
+            .body(body)
+            .send()
+            .await;
+        match sent {
+            Ok(resp) if resp.status().is_success() => {
+                metrics::TRACE_SPANS_EXPORTED_TOTAL.add(batch.len() as u64);
+            }
+            Ok(resp) => {
+                log::warn!("trace collector answered {}", resp.status());
+                metrics::TRACE_SPANS_DROPPED_TOTAL.add(batch.len() as u64);
+            }
+            Err(err) => {
+                log::warn!("can't export spans: {}", err);
+                metrics::TRACE_SPANS_DROPPED_TOTAL.add(batch.len() as u64);
+            }
+        }
+    }
+}
+
+// An ExportTraceServiceRequest in OTLP's JSON encoding, where ids are hex
+// and times are strings of nanoseconds since the epoch
+pub fn to_otlp(service_name: &str, spans: &[Span]) -> Value {
+    let spans: Vec<Value> = spans.iter().map(span_to_otlp).collect();
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [attribute("service.name", service_name)],
+            },
+            "scopeSpans": [{
+                "scope": {"name": "fortuna", "version": env!("CARGO_PKG_VERSION")},
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+fn span_to_otlp(span: &Span) -> Value {
+    let mut value = json!({
+        "traceId": format!("{:032x}", span.trace_id),
+        "spanId": format!("{:016x}", span.span_id),
+        "name": span.name,
+        // SPAN_KIND_SERVER or SPAN_KIND_INTERNAL
+        "kind": if span.server { 2 } else { 1 },
+        "startTimeUnixNano": unix_nanos(span.start).to_string(),
+        "endTimeUnixNano": unix_nanos(span.end).to_string(),
+        "attributes": span
+            .attributes
+            .iter()
+            .map(|(key, value)| attribute(key, value))
+            .collect::<Vec<_>>(),
+        "status": match &span.error {
+            // STATUS_CODE_ERROR
+            Some(message) => json!({"code": 2, "message": message}),
+            None => json!({}),
+        },
+    });
+    if span.parent_id != 0 {
+        value["parentSpanId"] = json!(format!("{:016x}", span.parent_id));
+    }
+    value
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({"key": key, "value": {"stringValue": value}})
+}
+
+fn unix_nanos(at: SystemTime) -> u128 {
+    at.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos()
+}
+
+// Trace and span ids only need to be unlikely to collide. RandomState is
+// seeded randomly per process and the counter keeps ids apart within one.
+pub(crate) fn random_id() -> u64 {
+    loop {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+        hasher.write_u128(unix_nanos(SystemTime::now()));
+        let id = hasher.finish();
+        // Zero means no id in both traceparent and OTLP
+        if id != 0 {
+            return id;
+        }
+    }
+}
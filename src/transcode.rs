@@ -0,0 +1,253 @@
+use serde_json::{json, Map, Value};
+use std::convert::TryFrom;
+
+use crate::proto::ateles::js_request::{Action, EmitFormat, Priority, ResultEncoding};
+use crate::proto::ateles::js_response::ErrorType;
+use crate::proto::ateles::{JsRequest, JsResponse};
+
+// Enum values by the names they have in ateles.proto
+const ACTIONS: &[(Action, &str)] = &[
+    (Action::Rewrite, "REWRITE"),
+    (Action::Eval, "EVAL"),
+    (Action::Call, "CALL"),
+    (Action::SetGlobals, "SET_GLOBALS"),
+    (Action::Reduce, "REDUCE"),
+    (Action::Rereduce, "REREDUCE"),
+    (Action::RegisterDdoc, "REGISTER_DDOC"),
+    (Action::EvictDdoc, "EVICT_DDOC"),
+    (Action::MapDoc, "MAP_DOC"),
+    (Action::Filter, "FILTER"),
+    (Action::WasmCall, "WASM_CALL"),
+    (Action::Reset, "RESET"),
+    (Action::MapDocs, "MAP_DOCS"),
+];
+const PRIORITIES: &[(Priority, &str)] = &[
+    (Priority::Normal, "NORMAL"),
+    (Priority::Background, "BACKGROUND"),
+];
+const EMIT_FORMATS: &[(EmitFormat, &str)] =
+    &[(EmitFormat::Json, "JSON"), (EmitFormat::ViewKv, "VIEW_KV")];
+const RESULT_ENCODINGS: &[(ResultEncoding, &str)] = &[
+    (ResultEncoding::Json, "JSON"),
+    (ResultEncoding::Etf, "ETF"),
+    (ResultEncoding::Msgpack, "MSGPACK"),
+];
+const ERROR_TYPES: &[(ErrorType, &str)] = &[
+    (ErrorType::None, "NONE"),
+    (ErrorType::CompileError, "COMPILE_ERROR"),
+    (ErrorType::RuntimeError, "RUNTIME_ERROR"),
+    (ErrorType::Timeout, "TIMEOUT"),
+    (ErrorType::Oom, "OOM"),
+    (ErrorType::QueueFull, "QUEUE_FULL"),
+    (ErrorType::Internal, "INTERNAL"),
+    (ErrorType::EmitLimitExceeded, "EMIT_LIMIT_EXCEEDED"),
+    (ErrorType::UnsupportedFeature, "UNSUPPORTED_FEATURE"),
+    (ErrorType::QuarantinedScript, "QUARANTINED_SCRIPT"),
+    (ErrorType::InvalidRequest, "INVALID_REQUEST"),
+    (ErrorType::UnknownDesignDoc, "UNKNOWN_DESIGN_DOC"),
+    (ErrorType::RateLimited, "RATE_LIMITED"),
+    (ErrorType::Killed, "KILLED"),
+    (ErrorType::ResultTooLarge, "RESULT_TOO_LARGE"),
+    (ErrorType::DeadlineExceeded, "DEADLINE_EXCEEDED"),
+    (ErrorType::Overloaded, "OVERLOADED"),
+    (ErrorType::Unauthorized, "UNAUTHORIZED"),
+];
+
+// Every action the proto has, by name
+pub fn action_names() -> Vec<&'static str> {
+    ACTIONS.iter().map(|(_, name)| *name).collect()
+}
+
+// A JsRequest as JSON for people to read, e.g.
+//
+//   {"action": "EVAL", "script": "1 + 1;", "args": [], "timeout": 0, ...}
+//
+// Every field is there with the name it has in ateles.proto. Enums are
+// given by name unless the value isn't one the proto knows, and
+// wasm_module and msgpack_args are hex.
+pub fn request_to_json(request: &JsRequest) -> Value {
+    json!({
+        "action": enum_name(ACTIONS, request.action),
+        "script": request.script,
+        "args": request.args,
+        "json_args": request.json_args,
+        "timeout": request.timeout,
+        "priority": enum_name(PRIORITIES, request.priority),
+        "emit_format": enum_name(EMIT_FORMATS, request.emit_format),
+        "doc_id": request.doc_id,
+        "requires": request.requires,
+        "result_encoding": enum_name(RESULT_ENCODINGS, request.result_encoding),
+        "session_id": request.session_id,
+        "session_ttl": request.session_ttl,
+        "batch_size": request.batch_size,
+        "wasm_module": hex(&request.wasm_module),
+        "msgpack_args": request.msgpack_args.iter().map(|arg| hex(arg)).collect::<Vec<_>>(),
+        "stream_key": request.stream_key,
+        "max_result_bytes": request.max_result_bytes,
+        "harness": request.harness,
+        "request_id": request.request_id,
+    })
+}
+
+// A JsResponse as JSON, the same way. Bytes, e.g. result_etf and the keys of
+// VIEW_KV rows, are hex.
+pub fn response_to_json(response: &JsResponse) -> Value {
+    let error = response.error.as_ref().map(|error| {
+        json!({
+            "name": error.name,
+            "message": error.message,
+            "stack": error.stack,
+            "line": error.line,
+        })
+    });
+    let logs: Vec<Value> = response
+        .logs
+        .iter()
+        .map(|log| json!({"level": log.level, "message": log.message}))
+        .collect();
+    let emitted: Vec<Value> = response
+        .emitted
+        .iter()
+        .map(|group| {
+            let kv_rows: Vec<Value> = group
+                .kv_rows
+                .iter()
+                .map(|row| json!({"id": row.id, "key": hex(&row.key), "value": row.value}))
+                .collect();
+            json!({
+                "rows": group.rows,
+                "kv_rows": kv_rows,
+                "etf_rows": group.etf_rows.iter().map(|row| hex(row)).collect::<Vec<_>>(),
+                "msgpack_rows": group.msgpack_rows.iter().map(|row| hex(row)).collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+    let stats = response.stats.as_ref().map(|stats| {
+        json!({
+            "execution_us": stats.execution_us,
+            "cpu_us": stats.cpu_us,
+            "queue_wait_us": stats.queue_wait_us,
+            "heap_used": stats.heap_used,
+            "retried": stats.retried,
+        })
+    });
+    json!({
+        "status": response.status,
+        "result": response.result,
+        "error_type": enum_name(ERROR_TYPES, response.error_type),
+        "error": error,
+        "logs": logs,
+        "emitted": emitted,
+        "stats": stats,
+        "features": response.features,
+        "result_etf": hex(&response.result_etf),
+        "session_created": response.session_created,
+        "suggested_batch_size": response.suggested_batch_size,
+        "result_msgpack": hex(&response.result_msgpack),
+        "worker": response.worker,
+        "request_id": response.request_id,
+    })
+}
+
+// The other way around. Fields that are left out get their proto default
+// and enums can be given by name or number.
+pub fn request_from_json(value: &Value) -> Result<JsRequest, String> {
+    let fields: &Map<String, Value> = value
+        .as_object()
+        .ok_or_else(|| "a request must be a JSON object".to_string())?;
+    let mut request = JsRequest::default();
+    for (name, value) in fields {
+        let field = |reason: String| format!("{}: {}", name, reason);
+        match name.as_str() {
+            "action" => request.action = enum_value(ACTIONS, value).map_err(field)?,
+            "script" => request.script = string(value).map_err(field)?,
+            "args" => request.args = strings(value).map_err(field)?,
+            "json_args" => request.json_args = strings(value).map_err(field)?,
+            "timeout" => request.timeout = int(value).map_err(field)?,
+            "priority" => request.priority = enum_value(PRIORITIES, value).map_err(field)?,
+            "emit_format" => {
+                request.emit_format = enum_value(EMIT_FORMATS, value).map_err(field)?
+            }
+            "doc_id" => request.doc_id = string(value).map_err(field)?,
+            "requires" => request.requires = strings(value).map_err(field)?,
+            "result_encoding" => {
+                request.result_encoding = enum_value(RESULT_ENCODINGS, value).map_err(field)?
+            }
+            "session_id" => request.session_id = string(value).map_err(field)?,
+            "session_ttl" => request.session_ttl = int(value).map_err(field)?,
+            "batch_size" => request.batch_size = int(value).map_err(field)?,
+            "wasm_module" => {
+                request.wasm_module = string(value).and_then(|hex| unhex(&hex)).map_err(field)?
+            }
+            "msgpack_args" => {
+                request.msgpack_args = strings(value)
+                    .and_then(|args| args.iter().map(|arg| unhex(arg)).collect())
+                    .map_err(field)?
+            }
+            "stream_key" => request.stream_key = string(value).map_err(field)?,
+            "max_result_bytes" => request.max_result_bytes = int(value).map_err(field)?,
+            "harness" => request.harness = string(value).map_err(field)?,
+            "request_id" => request.request_id = string(value).map_err(field)?,
+            _ => return Err(format!("unknown field {}", name)),
+        }
+    }
+    Ok(request)
+}
+
+fn enum_name<E: Copy + Into<i32>>(names: &[(E, &str)], value: i32) -> Value {
+    match names.iter().find(|(e, _)| (*e).into() == value) {
+        Some((_, name)) => json!(name),
+        None => json!(value),
+    }
+}
+
+fn enum_value<E: Copy + Into<i32>>(names: &[(E, &str)], value: &Value) -> Result<i32, String> {
+    if let Some(name) = value.as_str() {
+        return names
+            .iter()
+            .find(|(_, n)| *n == name)
+            .map(|(e, _)| (*e).into())
+            .ok_or_else(|| format!("unknown value {}", name));
+    }
+    int(value)
+}
+
+fn string(value: &Value) -> Result<String, String> {
+    value
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| format!("expected a string, not {}", value))
+}
+
+fn strings(value: &Value) -> Result<Vec<String>, String> {
+    value
+        .as_array()
+        .ok_or_else(|| format!("expected an array of strings, not {}", value))?
+        .iter()
+        .map(string)
+        .collect()
+}
+
+fn int(value: &Value) -> Result<i32, String> {
+    value
+        .as_i64()
+        .and_then(|n| i32::try_from(n).ok())
+        .ok_or_else(|| format!("expected a 32 bit integer, not {}", value))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(hex: &str) -> Result<Vec<u8>, String> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .filter(|digits| digits.len() == 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| format!("invalid hex {}", String::from_utf8_lossy(pair)))
+        })
+        .collect()
+}
@@ -0,0 +1,203 @@
+use lazy_static::lazy_static;
+use rusty_v8 as v8;
+use serde::Serialize;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+const GIB: usize = 1024 * 1024 * 1024;
+
+// With pointer compression every heap lives in a cage of this size
+const POINTER_COMPRESSION_CAGE: usize = 4 * GIB;
+
+// Asked for when probing so the limit V8 reports back is its own ceiling
+const PROBE_HEAP_LIMIT: usize = 64 * GIB;
+
+// Heap limit in bytes isolates are created with, 0 for V8's default
+static MAX_HEAP: AtomicUsize = AtomicUsize::new(0);
+
+// Whether V8 was started in SecurityMode::Hardened
+static HARDENED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref DETECTED: Mutex<Option<HeapConfig>> = Mutex::new(None);
+    static ref FLAGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+}
+
+// How the V8 build that was linked in handles memory. V8 doesn't say
+// directly so this is worked out by asking an isolate for a huge heap and
+// seeing what limit it ends up with.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HeapConfig {
+    pub version: String,
+    // Every isolate's heap is kept inside a 4GiB cage
+    pub pointer_compression: bool,
+    // V8 before 10.0 has no sandbox. For later versions it can't be told from
+    // here so it's None.
+    pub sandbox: Option<bool>,
+    // The largest heap limit an isolate can actually be held to
+    pub max_heap_limit: usize,
+}
+
+// V8 must have been initialized
+pub fn detect() -> HeapConfig {
+    let params = v8::Isolate::create_params().heap_limits(0, PROBE_HEAP_LIMIT);
+    let mut isolate = v8::Isolate::new(params);
+    let mut stats = v8::HeapStatistics::default();
+    isolate.get_heap_statistics(&mut stats);
+    let max_heap_limit = stats.heap_size_limit();
+
+    let version = v8::V8::get_version().to_string();
+    let major: u32 = version
+        .split('.')
+        .next()
+        .and_then(|major| major.parse().ok())
+        .unwrap_or(0);
+    HeapConfig {
+        pointer_compression: max_heap_limit <= POINTER_COMPRESSION_CAGE,
+        sandbox: if major < 10 { Some(false) } else { None },
+        max_heap_limit,
+        version,
+    }
+}
+
+// A warning if an isolate heap limit of heap_limit bytes is more than config
+// can enforce. V8 would quietly use a smaller limit.
+pub fn check_heap_limit(heap_limit: usize, config: &HeapConfig) -> Option<String> {
+    if heap_limit <= config.max_heap_limit {
+        return None;
+    }
+    let reason = if config.pointer_compression {
+        "pointer compression caps every isolate's heap"
+    } else {
+        "that's as much as V8 allows"
+    };
+    Some(format!(
+        "isolate heap limit of {} bytes can't be enforced by V8 {}, isolates will get at most {} bytes ({})",
+        heap_limit, config.version, config.max_heap_limit, reason
+    ))
+}
+
+// Called once V8 is initialized. Isolates created from now on are limited to
+// max_heap bytes of heap. Returns a warning if V8 can't hold them to that.
+pub fn configure(max_heap: Option<usize>) -> Option<String> {
+    let config = detect();
+    MAX_HEAP.store(max_heap.unwrap_or(0), Ordering::SeqCst);
+    let warning = max_heap.and_then(|limit| check_heap_limit(limit, &config));
+    if let Some(warning) = &warning {
+        log::warn!("{}", warning);
+    }
+    *DETECTED.lock().unwrap() = Some(config);
+    warning
+}
+
+// What configure found, None before V8 is initialized
+pub fn current() -> Option<HeapConfig> {
+    DETECTED.lock().unwrap().clone()
+}
+
+pub fn max_heap() -> Option<usize> {
+    match MAX_HEAP.load(Ordering::SeqCst) {
+        0 => None,
+        limit => Some(limit),
+    }
+}
+
+// How much V8 gives up in speed for isolating the code it runs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SecurityMode {
+    Default,
+    // No JIT, so nothing is compiled to machine code at runtime, and V8's
+    // mitigations against speculative side channels are on. Scripts run
+    // a lot slower and WebAssembly isn't available.
+    Hardened,
+}
+
+impl SecurityMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SecurityMode::Default => "default",
+            SecurityMode::Hardened => "hardened",
+        }
+    }
+
+    // What V8 is started with for the mode
+    pub fn v8_flags(self) -> &'static [&'static str] {
+        match self {
+            SecurityMode::Default => &[],
+            SecurityMode::Hardened => &["--jitless", "--untrusted-code-mitigations"],
+        }
+    }
+}
+
+impl Default for SecurityMode {
+    fn default() -> Self {
+        SecurityMode::Default
+    }
+}
+
+impl FromStr for SecurityMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "default" => Ok(SecurityMode::Default),
+            "hardened" => Ok(SecurityMode::Hardened),
+            _ => Err(format!(
+                "unknown security mode {}, expected default or hardened",
+                mode
+            )),
+        }
+    }
+}
+
+// The mode V8 was started in
+pub fn security_mode() -> SecurityMode {
+    if HARDENED.load(Ordering::SeqCst) {
+        SecurityMode::Hardened
+    } else {
+        SecurityMode::Default
+    }
+}
+
+pub(crate) fn set_security_mode(mode: SecurityMode) {
+    HARDENED.store(mode == SecurityMode::Hardened, Ordering::SeqCst);
+}
+
+// Splits flags for V8, e.g. "--max_old_space_size=512 --jitless", into
+// single flags. V8 ignores flags it doesn't know with no more than a message
+// on stderr, so this at least catches anything that isn't a flag.
+pub fn parse_flags(flags: &str) -> Result<Vec<String>, String> {
+    flags
+        .split_whitespace()
+        .map(|flag| {
+            if flag.starts_with("--") && flag.len() > 2 {
+                Ok(flag.to_string())
+            } else {
+                Err(format!("invalid V8 flag {}", flag))
+            }
+        })
+        .collect()
+}
+
+// Has to be called before V8 is initialized, flags set later have no effect
+pub(crate) fn set_flags(flags: Vec<String>) {
+    if !flags.is_empty() {
+        v8::V8::set_flags_from_string(&flags.join(" "));
+    }
+    *FLAGS.lock().unwrap() = flags;
+}
+
+// The flags given with set_flags
+pub fn flags() -> Vec<String> {
+    FLAGS.lock().unwrap().clone()
+}
+
+// What isolates should be created with
+pub(crate) fn create_params() -> v8::CreateParams {
+    let params = v8::Isolate::create_params();
+    match max_heap() {
+        Some(limit) => params.heap_limits(0, limit),
+        None => params,
+    }
+}
@@ -0,0 +1,121 @@
+use sha1::{Digest, Sha1};
+use std::io;
+
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// Opcodes of the frames understood, RFC 6455 section 5.2
+pub const CONTINUATION: u8 = 0x0;
+pub const TEXT: u8 = 0x1;
+pub const CLOSE: u8 = 0x8;
+pub const PING: u8 = 0x9;
+pub const PONG: u8 = 0xA;
+
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    // Last frame of its message
+    pub fin: bool,
+    pub opcode: u8,
+    // Already unmasked
+    pub payload: Vec<u8>,
+}
+
+// Splits frames out of a connection's bytes as they arrive, like
+// framing::FrameDecoder does for protobuf frames
+pub struct Decoder {
+    buf: Vec<u8>,
+    max_len: u64,
+}
+
+impl Decoder {
+    // Frames with payloads over max_len are an error rather than buffered
+    pub fn new(max_len: u64) -> Decoder {
+        Decoder {
+            buf: Vec::new(),
+            max_len,
+        }
+    }
+
+    pub fn extend(&mut self, chunk: &[u8]) {
+        self.buf.extend_from_slice(chunk);
+    }
+
+    // The next whole frame, or None until more of it has arrived
+    pub fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        if self.buf.len() < 2 {
+            return Ok(None);
+        }
+        let fin = self.buf[0] & 0x80 != 0;
+        let opcode = self.buf[0] & 0x0f;
+        let masked = self.buf[1] & 0x80 != 0;
+        let (len, mut start) = match self.buf[1] & 0x7f {
+            126 if self.buf.len() >= 4 => {
+                let len = u16::from_be_bytes([self.buf[2], self.buf[3]]);
+                (u64::from(len), 4)
+            }
+            127 if self.buf.len() >= 10 => {
+                let mut len = [0u8; 8];
+                len.copy_from_slice(&self.buf[2..10]);
+                (u64::from_be_bytes(len), 10)
+            }
+            126 | 127 => return Ok(None),
+            len => (u64::from(len), 2),
+        };
+        if len > self.max_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "frame too large",
+            ));
+        }
+        let mut mask = [0u8; 4];
+        if masked {
+            if self.buf.len() < start + 4 {
+                return Ok(None);
+            }
+            mask.copy_from_slice(&self.buf[start..start + 4]);
+            start += 4;
+        }
+        let end = start + len as usize;
+        if self.buf.len() < end {
+            return Ok(None);
+        }
+        let mut payload = self.buf[start..end].to_vec();
+        if masked {
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+        }
+        self.buf.drain(..end);
+        Ok(Some(Frame {
+            fin,
+            opcode,
+            payload,
+        }))
+    }
+}
+
+// A whole, unmasked frame. Frames from a server are never masked.
+pub fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= 0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// The Sec-WebSocket-Accept for a Sec-WebSocket-Key, RFC 6455 section 4.2.2
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.input(key.as_bytes());
+    hasher.input(WEBSOCKET_GUID.as_bytes());
+    base64::encode(&hasher.result())
+}
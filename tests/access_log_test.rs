@@ -0,0 +1,96 @@
+use chrono::{TimeZone, Utc};
+use std::time::Duration;
+
+use fortuna::access_log::{AccessEntry, AccessLog, AccessLogFormat, ExecDetails};
+use fortuna::test_support::TestServer;
+use fortuna::MakeService;
+mod common;
+
+fn entry() -> AccessEntry {
+    AccessEntry {
+        ts: Utc.with_ymd_and_hms(2020, 5, 1, 12, 30, 15).unwrap()
+            + chrono::Duration::milliseconds(250),
+        peer: "127.0.0.1:5984".to_string(),
+        method: "POST".to_string(),
+        path: "/Ateles/Execute".to_string(),
+        version: "HTTP/1.1".to_string(),
+        bytes: 0,
+        status: 200,
+        took_us: 1500,
+        op: None,
+        queue_us: None,
+        exec_us: None,
+        request_id: None,
+    }
+}
+
+#[test]
+fn formats() {
+    assert_eq!("json".parse(), Ok(AccessLogFormat::Json));
+    assert!("apache".parse::<AccessLogFormat>().is_err());
+
+    let mut entry = entry();
+    assert_eq!(
+        entry.format(AccessLogFormat::Common),
+        "127.0.0.1:5984 - - [01/May/2020:12:30:15 +0000] \"POST /Ateles/Execute HTTP/1.1\" 200 0"
+    );
+
+    entry.exec(ExecDetails {
+        op: "eval".to_string(),
+        request_id: String::new(),
+        bytes: 42,
+        queue_wait_us: 10,
+        execution_us: 900,
+    });
+    assert_eq!(
+        entry.format(AccessLogFormat::Common),
+        "127.0.0.1:5984 - - [01/May/2020:12:30:15 +0000] \"POST /Ateles/Execute HTTP/1.1\" 200 42 \
+         op=eval queue_us=10 exec_us=900"
+    );
+
+    let json: serde_json::Value =
+        serde_json::from_str(&entry.format(AccessLogFormat::Json)).unwrap();
+    assert_eq!(json["ts"], "2020-05-01T12:30:15.250Z");
+    assert_eq!(json["op"], "eval");
+    assert_eq!(json["bytes"], 42);
+    assert_eq!(json["exec_us"], 900);
+    assert_eq!(json["took_us"], 1500);
+}
+
+#[tokio::test]
+async fn logs_every_request() {
+    common::setup();
+    let path = std::env::temp_dir().join(format!("fortuna-access-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let access_log = AccessLog::open(&path, AccessLogFormat::Json).unwrap();
+    let server = TestServer::start_with(MakeService::new().with_access_log(access_log)).unwrap();
+
+    assert_eq!(server.client().eval("1 + 1;").await.unwrap().status, 0);
+    reqwest::get(&format!("{}/live", server.url()))
+        .await
+        .unwrap();
+
+    // Written in the background
+    let mut lines = Vec::new();
+    for _ in 0..50 {
+        lines = std::fs::read_to_string(&path)
+            .unwrap_or_default()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect();
+        if lines.len() == 2 {
+            break;
+        }
+        tokio::time::delay_for(Duration::from_millis(20)).await;
+    }
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["path"], "/Ateles/Execute");
+    assert_eq!(lines[0]["op"], "eval");
+    assert_eq!(lines[0]["status"], 200);
+    assert!(lines[0]["bytes"].as_u64().unwrap() > 0);
+    assert!(lines[0]["exec_us"].is_u64());
+    assert!(lines[0]["request_id"].is_string());
+    assert_eq!(lines[1]["method"], "GET");
+    assert_eq!(lines[1]["path"], "/live");
+    assert!(lines[1].get("op").is_none());
+}
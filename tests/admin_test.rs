@@ -0,0 +1,76 @@
+use fortuna::admin::{self, AdminConfig};
+use fortuna::test_support::TestServer;
+use fortuna::MakeService;
+mod common;
+
+const TOKEN: &str = "0123456789abcdef-admin";
+
+#[test]
+fn admin_paths_and_tokens() {
+    assert!(admin::is_admin_path("/stats"));
+    assert!(admin::is_admin_path("/admin/heap_snapshot"));
+    assert!(!admin::is_admin_path("/Ateles/Execute"));
+    assert!(!admin::is_admin_path("/metrics"));
+    assert!(!admin::is_admin_path("/administer"));
+
+    let bearer = format!("Bearer {}", TOKEN);
+    assert!(admin::authorized(Some(&bearer), None, TOKEN));
+    assert!(admin::authorized(None, Some(TOKEN), TOKEN));
+    assert!(!admin::authorized(None, None, TOKEN));
+    assert!(!admin::authorized(Some(TOKEN), None, TOKEN));
+    assert!(!admin::authorized(
+        Some("Bearer 0123456789abcdef"),
+        None,
+        TOKEN
+    ));
+    assert!(!admin::authorized(None, Some(""), TOKEN));
+}
+
+#[test]
+fn token_from_file() {
+    let path = std::env::temp_dir().join(format!("fortuna-admin-{}", std::process::id()));
+    let bind = "127.0.0.1:0".parse().unwrap();
+    std::fs::write(&path, format!("{}\nignored\n", TOKEN)).unwrap();
+    let config = AdminConfig::new(bind, &path).unwrap();
+    assert_eq!(config.token, TOKEN);
+
+    std::fs::write(&path, "short\n").unwrap();
+    assert!(AdminConfig::new(config.bind, &path).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn admin_routes_move_to_their_own_listener() {
+    common::setup();
+    let make_service = MakeService::new().with_admin(AdminConfig {
+        bind: "127.0.0.1:0".parse().unwrap(),
+        token: TOKEN.to_string(),
+    });
+    let admin = TestServer::start_with(make_service.admin().unwrap()).unwrap();
+    let server = TestServer::start_with(make_service).unwrap();
+    let http = reqwest::Client::new();
+    let get = |url: String| http.get(&url).send();
+
+    let resp = get(format!("{}/stats", server.url())).await.unwrap();
+    assert_eq!(resp.status(), 404);
+    let resp = get(format!("{}/admin/workers", server.url()))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+    let resp = get(format!("{}/live", server.url())).await.unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(server.client().eval("1 + 1;").await.unwrap().result, "2");
+
+    let resp = get(format!("{}/stats", admin.url())).await.unwrap();
+    assert_eq!(resp.status(), 401);
+    assert_eq!(resp.headers()["www-authenticate"], "Bearer");
+    let resp = http
+        .get(&format!("{}/stats", admin.url()))
+        .bearer_auth(TOKEN)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let url = format!("{}/admin/workers?access_token={}", admin.url(), TOKEN);
+    assert_eq!(get(url).await.unwrap().status(), 200);
+}
@@ -0,0 +1,112 @@
+use futures::executor::block_on;
+use std::time::Duration;
+
+use fortuna::health::{assess, ReadyLevel, ReadySignals};
+use fortuna::js_server::{self, Command, Ops};
+use fortuna::slow_requests;
+use fortuna::test_support::TestServer;
+use fortuna::*;
+mod common;
+
+fn eval(payload: &str) -> Command {
+    Command {
+        operation: Ops::EVAL,
+        payload: payload.to_string(),
+        args: vec![],
+        docs: Vec::new(),
+    }
+}
+
+#[test]
+fn draining_is_not_ready() {
+    let signals = ReadySignals {
+        workers: 4,
+        available: 4,
+        draining: true,
+        ..ReadySignals::default()
+    };
+    let readiness = assess(signals);
+    assert_eq!(readiness.level, ReadyLevel::NotReady);
+    assert_eq!(readiness.reasons, vec!["draining".to_string()]);
+}
+
+#[test]
+fn slow_requests_are_kept() {
+    slow_requests::set_threshold(Duration::from_millis(100));
+    assert!(!slow_requests::record(
+        "eval",
+        "127.0.0.1:1",
+        Duration::from_millis(5),
+        None
+    ));
+    assert!(slow_requests::record(
+        "call",
+        "127.0.0.1:2",
+        Duration::from_millis(150),
+        Some("timeout".to_string())
+    ));
+    let recent = slow_requests::recent();
+    let request = recent.iter().find(|r| r.peer == "127.0.0.1:2").unwrap();
+    assert_eq!(request.operation, "call");
+    assert_eq!(request.took_us, 150_000);
+    assert_eq!(request.error.as_deref(), Some("timeout"));
+    assert!(!recent.iter().any(|r| r.peer == "127.0.0.1:1"));
+}
+
+#[test]
+fn recycled_workers_start_fresh() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let js_client = create_js_env(&js_env, &Capabilities::none());
+    let id = js_client.id();
+
+    block_on(js_client.run(eval("var leftover = 42;"))).unwrap();
+    assert!(js_server::recycle_worker(id));
+    // The recycle happens before the worker picks up the next command
+    std::thread::sleep(Duration::from_millis(100));
+    let result = block_on(js_client.run(eval("typeof leftover;"))).unwrap();
+    assert_eq!(result, "\"undefined\"");
+
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let stats = rt.block_on(js_server::worker_stats(Duration::from_secs(5)));
+    let worker = stats.iter().find(|worker| worker.id == id).unwrap();
+    assert_eq!(worker.recycles, 1);
+
+    assert!(!js_server::recycle_worker(usize::MAX));
+}
+
+#[tokio::test]
+async fn admin_ui_is_served() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+
+    let resp = reqwest::get(&format!("{}/admin/ui", server.url()))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert!(resp.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+    let page = resp.text().await.unwrap();
+    assert!(page.contains("/admin/drain"));
+    assert!(page.contains("/admin/slow"));
+
+    let resp = reqwest::get(&format!("{}/admin/slow", server.url()))
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    assert!(body["requests"].is_array());
+
+    let resp = reqwest::Client::new()
+        .post(&format!(
+            "{}/admin/workers/{}/recycle",
+            server.url(),
+            usize::MAX
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+}
@@ -0,0 +1,12 @@
+use fortuna::affinity::parse_cores;
+
+#[test]
+fn core_lists() {
+    assert_eq!(parse_cores("").unwrap(), Vec::<usize>::new());
+    assert_eq!(
+        parse_cores("0-3,8, 10-11").unwrap(),
+        vec![0, 1, 2, 3, 8, 10, 11]
+    );
+    assert!(parse_cores("3-1").is_err());
+    assert!(parse_cores("a").is_err());
+}
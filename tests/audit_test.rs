@@ -0,0 +1,101 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use fortuna::ateles::js_response::ErrorType;
+use fortuna::ateles::JsResponse;
+use fortuna::audit::{self, AuditConfig, AuditDest, AuditLog};
+use fortuna::test_support::TestServer;
+use fortuna::{JsRequestBuilder, MakeService};
+mod common;
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fortuna-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn entries(path: &PathBuf) -> Vec<serde_json::Value> {
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[test]
+fn parses_destinations() {
+    assert_eq!("syslog".parse(), Ok(AuditDest::Syslog));
+    assert_eq!(
+        "/var/log/fortuna-audit.log".parse(),
+        Ok(AuditDest::File(PathBuf::from("/var/log/fortuna-audit.log")))
+    );
+    assert!("".parse::<AuditDest>().is_err());
+}
+
+#[test]
+fn outcomes() {
+    assert_eq!(audit::outcome(&JsResponse::default()), "ok");
+    let failed = JsResponse {
+        status: 1,
+        error_type: ErrorType::RuntimeError as i32,
+        ..JsResponse::default()
+    };
+    assert_eq!(audit::outcome(&failed), "runtime_error");
+}
+
+#[test]
+fn rotates_files() {
+    let dir = temp_dir("audit-rotate");
+    let path = dir.join("audit.log");
+    for n in 0..3 {
+        fs::write(&path, n.to_string()).unwrap();
+        audit::rotate(&path, 2).unwrap();
+    }
+    assert!(!path.exists());
+    assert_eq!(fs::read_to_string(dir.join("audit.log.1")).unwrap(), "2");
+    assert_eq!(fs::read_to_string(dir.join("audit.log.2")).unwrap(), "1");
+    assert!(!dir.join("audit.log.3").exists());
+}
+
+#[tokio::test]
+async fn records_code_that_ran() {
+    common::setup();
+    let dir = temp_dir("audit-log");
+    let path = dir.join("audit.log");
+    let config = AuditConfig {
+        hash_args: true,
+        ..AuditConfig::new(AuditDest::File(path.clone()))
+    };
+    let audit_log = AuditLog::open(&config).unwrap();
+    let server = TestServer::start_with(MakeService::new().with_audit_log(audit_log)).unwrap();
+    let client = server.client();
+
+    client
+        .eval("function add(a, b) { return a + b; }")
+        .await
+        .unwrap();
+    client.call("add", &["1", "2"]).await.unwrap();
+    client.eval("throw new Error('nope');").await.unwrap();
+    // Not audited, it doesn't bring any code of its own
+    let reset = JsRequestBuilder::reset().build().unwrap();
+    client.execute(&reset).await.unwrap();
+    // Written in the background
+    let mut entries = entries(&path);
+    for _ in 0..100 {
+        if entries.len() >= 3 {
+            break;
+        }
+        tokio::time::delay_for(Duration::from_millis(20)).await;
+        entries = self::entries(&path);
+    }
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0]["op"], "eval");
+    assert_eq!(entries[0]["outcome"], "ok");
+    assert_eq!(entries[0]["script_hash"].as_str().unwrap().len(), 64);
+    assert!(entries[0].get("source").is_none());
+    assert_eq!(entries[1]["op"], "call");
+    assert!(entries[1]["request_hash"].is_string());
+    assert_eq!(entries[2]["outcome"], "runtime_error");
+}
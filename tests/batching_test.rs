@@ -0,0 +1,22 @@
+use std::time::Duration;
+
+use fortuna::batching::suggest;
+
+#[test]
+fn suggested_batch_sizes() {
+    let ms = Duration::from_millis;
+
+    // Not a batch
+    assert_eq!(suggest(0, ms(10), 0, false), 0);
+    // 1ms a doc fits 100 in the target time
+    assert_eq!(suggest(100, ms(100), 0, false), 100);
+    // Fast batches grow, but only by doubling
+    assert_eq!(suggest(100, ms(10), 0, false), 200);
+    // Slow ones shrink straight away
+    assert_eq!(suggest(100, ms(1000), 0, false), 10);
+    // As do batches on a busy worker or host
+    assert_eq!(suggest(100, ms(100), 3, false), 25);
+    assert_eq!(suggest(100, ms(100), 0, true), 50);
+    // Never below one doc
+    assert_eq!(suggest(10, Duration::from_secs(10), 0, false), 1);
+}
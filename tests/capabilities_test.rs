@@ -0,0 +1,220 @@
+use fortuna::*;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread;
+mod common;
+
+#[test]
+fn only_granted_functions_are_installed() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+
+    let mut instance = js_env.create_isolate_with_capabilities(&Capabilities::none());
+    let result = instance
+        .eval("[typeof digest, typeof tokenize, typeof collate];", &[])
+        .unwrap();
+    assert_eq!(result, "[\"undefined\",\"undefined\",\"undefined\"]");
+
+    let caps = Capabilities::parse("digest, collate").unwrap();
+    let mut instance = js_env.create_isolate_with_capabilities(&caps);
+    let result = instance
+        .eval("[typeof digest, typeof tokenize, typeof collate];", &[])
+        .unwrap();
+    assert_eq!(result, "[\"function\",\"undefined\",\"function\"]");
+}
+
+#[test]
+fn unknown_capability() {
+    assert!(Capabilities::parse("digest,network").is_err());
+}
+
+#[test]
+fn fetch_is_only_granted_when_asked_for() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let mut instance = js_env.create_isolate_with_capabilities(&Capabilities::local());
+    let result = instance
+        .eval("[typeof digest, typeof fetch];", &[])
+        .unwrap();
+    assert_eq!(result, "[\"function\",\"undefined\"]");
+    assert!("digest,fetch".parse::<Capabilities>().is_ok());
+}
+
+#[test]
+fn fetch_function() {
+    common::setup();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/thing", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = [0u8; 4096];
+        let read = stream.read(&mut request).unwrap();
+        stream
+            .write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 2\r\nX-Test: yes\r\n\r\nhi")
+            .unwrap();
+        String::from_utf8_lossy(&request[..read]).to_string()
+    });
+
+    let js_env = JSEnv::new();
+    let caps = Capabilities::parse("fetch").unwrap();
+    let mut instance = js_env.create_isolate_with_capabilities(&caps);
+    let script = format!(
+        "var resp = fetch('{}', {{method: 'PUT', headers: {{'x-from': 'js'}}, body: 'yo'}}); \
+         [resp.status, resp.headers['x-test'], resp.body];",
+        url
+    );
+    let result = instance.eval(&script, &[]).unwrap();
+    assert_eq!(result, "[201,\"yes\",\"hi\"]");
+
+    let request = server.join().unwrap();
+    assert!(request.starts_with("PUT /thing HTTP/1.1"));
+    assert!(request.contains("x-from: js"));
+
+    match instance.eval("fetch('file:///etc/passwd');", &[]) {
+        Err(JSError::RuntimeError(info)) => assert_eq!(info.name, "TypeError"),
+        other => panic!("expected a type error, got {:?}", other),
+    }
+}
+
+#[test]
+fn host_functions() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let mut instance = js_env.create_isolate_with_capabilities(&Capabilities::all());
+
+    let result = instance.eval("digest('sha256', 'abc');", &[]).unwrap();
+    assert_eq!(
+        result,
+        "\"ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad\""
+    );
+
+    let result = instance
+        .eval("tokenize('Hello, couch-db World');", &[])
+        .unwrap();
+    assert_eq!(result, "[\"hello\",\"couch\",\"db\",\"world\"]");
+
+    let script = "[collate(null, false), collate('a', 'A'), collate([1, 2], [1, 2]), \
+                  collate({}, [])];";
+    let result = instance.eval(script, &[]).unwrap();
+    assert_eq!(result, "[-1,-1,0,1]");
+}
+
+#[test]
+fn date_functions() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let caps = Capabilities::parse("datetime").unwrap();
+    let mut instance = js_env.create_isolate_with_capabilities(&caps);
+
+    let result = instance
+        .eval("formatDate(1585742400000, '%Y/%m/%d %H:%M');", &[])
+        .unwrap();
+    assert_eq!(result, "\"2020/04/01 12:00\"");
+
+    let result = instance.eval("toISODate(1585742400123);", &[]).unwrap();
+    assert_eq!(result, "\"2020-04-01T12:00:00.123Z\"");
+
+    let result = instance
+        .eval("parseISODate('2020-04-01T14:00:00.123+02:00');", &[])
+        .unwrap();
+    assert_eq!(result, "1585742400123");
+
+    match instance.eval("parseISODate('yesterday');", &[]) {
+        Err(JSError::RuntimeError(info)) => assert_eq!(info.name, "TypeError"),
+        other => panic!("expected a runtime error, got {:?}", other),
+    }
+
+    for script in &["formatDate(1e20, '%Y');", "toISODate(-8.64e15 - 1);"] {
+        match instance.eval(script, &[]) {
+            Err(JSError::RuntimeError(info)) => assert_eq!(info.name, "RangeError"),
+            other => panic!("expected a range error, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn log_functions() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let caps = Capabilities::parse("log").unwrap();
+    let mut instance = js_env.create_isolate_with_capabilities(&caps);
+
+    host_functions::take_logs();
+    let script = "log('mapping'); console.warn('odd doc', {_id: 'foo'}); 1;";
+    instance.eval(script, &[]).unwrap();
+
+    let logs = host_functions::take_logs();
+    assert_eq!(logs.len(), 2);
+    assert_eq!(logs[0].level, log::Level::Info);
+    assert_eq!(logs[0].message, "mapping");
+    assert_eq!(logs[1].level, log::Level::Warn);
+    assert_eq!(logs[1].message, "odd doc {\"_id\":\"foo\"}");
+
+    assert!(host_functions::take_logs().is_empty());
+}
+
+#[test]
+fn emit_functions() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let caps = Capabilities::parse("emit").unwrap();
+    let mut instance = js_env.create_isolate_with_capabilities(&caps);
+
+    let _ = host_functions::take_emitted();
+    let script = "emit('a', 1); emitGroup(); emit(['b', 2], {x: true}); emit('c'); 1;";
+    instance.eval(script, &[]).unwrap();
+
+    let emitted = host_functions::take_emitted().unwrap();
+    assert_eq!(
+        emitted,
+        vec![
+            vec!["[\"a\",1]".to_string()],
+            vec![
+                "[[\"b\",2],{\"x\":true}]".to_string(),
+                "[\"c\",null]".to_string()
+            ],
+        ]
+    );
+
+    assert!(host_functions::take_emitted().unwrap().is_empty());
+}
+
+#[test]
+fn emit_limits() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let limits = host_functions::EmitLimits {
+        max_rows: 2,
+        max_row_bytes: 32,
+        ..host_functions::EmitLimits::default()
+    };
+    let caps = Capabilities::parse("emit")
+        .unwrap()
+        .with_emit_limits(limits);
+    let mut instance = js_env.create_isolate_with_capabilities(&caps);
+
+    let _ = host_functions::take_emitted();
+    instance.eval("emit(1, 1); emit(2, 2); 1;", &[]).unwrap();
+    assert_eq!(host_functions::take_emitted().unwrap().len(), 1);
+
+    // Catching the error doesn't help, the rows are dropped all the same
+    let script = "try { emit(1, 1); emit(2, 2); emit(3, 3); } catch (e) { e.message; }";
+    let result = instance.eval(script, &[]).unwrap();
+    assert_eq!(result, "\"emit_limit_exceeded: more than 2 rows emitted\"");
+    assert!(host_functions::take_emitted().is_err());
+
+    match instance.eval("emit('k', 'x'.repeat(64));", &[]) {
+        Err(JSError::RuntimeError(info)) => assert_eq!(info.name, "RangeError"),
+        other => panic!("expected a runtime error, got {:?}", other),
+    }
+    let reason = host_functions::take_emitted().unwrap_err();
+    assert!(reason.contains("over the limit of 32"), "{}", reason);
+}
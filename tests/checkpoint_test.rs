@@ -0,0 +1,102 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use futures::executor::block_on;
+
+use fortuna::checkpoint::{self, DesignDocs, SavedDesignDoc};
+use fortuna::js_server::{Command, Ops};
+use fortuna::*;
+mod common;
+
+const FUNCTIONS: &str = r#"{"map": ["function(doc) { emit(doc._id, null); }"]}"#;
+
+fn checkpoint_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "fortuna-checkpoint-{}-{}.json",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+fn saved(signature: &str, uses: u64) -> SavedDesignDoc {
+    SavedDesignDoc {
+        signature: signature.to_string(),
+        functions: FUNCTIONS.to_string(),
+        uses,
+    }
+}
+
+#[test]
+fn keeps_the_most_used_design_docs() {
+    let mut docs = DesignDocs::new(2);
+    docs.registered("a", FUNCTIONS);
+    docs.registered("b", FUNCTIONS);
+    docs.registered("c", FUNCTIONS);
+    for _ in 0..3 {
+        docs.used("b");
+    }
+    docs.used("c");
+    // Never registered, so there's nothing to save
+    docs.used("d");
+
+    let signatures: Vec<_> = docs
+        .hottest()
+        .into_iter()
+        .map(|doc| doc.signature)
+        .collect();
+    assert_eq!(signatures, vec!["b", "c"]);
+
+    // Registering again picks up new functions
+    docs.registered("b", r#"{"map": []}"#);
+    assert_eq!(docs.hottest()[0].functions, r#"{"map": []}"#);
+}
+
+#[test]
+fn restored_design_docs_keep_their_uses() {
+    let mut docs = DesignDocs::new(2);
+    docs.restore(&[saved("old", 10)]);
+    docs.registered("new", FUNCTIONS);
+    assert_eq!(docs.hottest(), vec![saved("old", 10), saved("new", 1)]);
+}
+
+#[test]
+fn saves_and_loads_a_checkpoint() {
+    let path = checkpoint_path("save");
+    assert!(checkpoint::load(&path).unwrap().is_empty());
+
+    let docs = vec![saved("a", 2), saved("b", 1)];
+    checkpoint::save(&path, &docs).unwrap();
+    assert_eq!(checkpoint::load(&path).unwrap(), docs);
+
+    fs::write(&path, "not json").unwrap();
+    let err = checkpoint::load(&path).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn registers_design_docs_on_every_pool_worker() {
+    common::setup();
+    let js_env = JSEnv::new();
+    let pool = WorkerPool::new(&js_env, &Capabilities::all(), 2);
+
+    let ddocs = vec![
+        ("good".to_string(), FUNCTIONS.to_string()),
+        ("bad".to_string(), r#"{"map": ["function("]}"#.to_string()),
+    ];
+    // bad fails on both workers
+    assert_eq!(block_on(pool.register_ddocs(&ddocs)), 2);
+
+    for _ in 0..2 {
+        let mapped = block_on(pool.next().run(Command {
+            operation: Ops::MAP_DOC,
+            payload: "good".to_string(),
+            args: vec![r#"{"_id": "foo"}"#.to_string()],
+            docs: Vec::new(),
+        }));
+        assert_eq!(mapped.unwrap(), "true");
+    }
+}
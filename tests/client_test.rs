@@ -0,0 +1,246 @@
+use std::time::{Duration, Instant};
+
+use fortuna::ateles::js_response::ErrorType;
+use fortuna::ateles::MapDocsRequest;
+use fortuna::client::{AtelesClient, ClientConfig};
+use fortuna::rate_limit::RateLimitConfig;
+use fortuna::test_support::TestServer;
+use fortuna::{JsRequestBuilder, MakeService};
+mod common;
+
+const FUNCTIONS: &str = r#"{"map": ["function(doc) { emit(doc._id, doc.n); }"]}"#;
+
+#[tokio::test]
+async fn maps_docs_on_one_worker() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let client = server.client();
+
+    let docs = [r#"{"_id": "a", "n": 1}"#, r#"{"_id": "b", "n": 2}"#];
+    let responses = client
+        .map_docs("client-ddoc", FUNCTIONS, &docs)
+        .await
+        .unwrap();
+    assert_eq!(responses.len(), 2);
+    assert!(responses.iter().all(|resp| resp.status == 0));
+    assert_eq!(responses[1].emitted[0].rows, vec![r#"["b",2]"#]);
+
+    let err = client
+        .map_docs("bad-ddoc", r#"{"map": ["function("]}"#, &docs)
+        .await
+        .unwrap_err();
+    assert!(err.contains("can't register bad-ddoc"), "{}", err);
+}
+
+#[tokio::test]
+async fn streams_mapped_docs() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let client = server.client();
+
+    let register = JsRequestBuilder::register_ddoc("stream-ddoc", FUNCTIONS)
+        .session("map-docs", Duration::from_secs(60))
+        .build()
+        .unwrap();
+    assert_eq!(client.execute(&register).await.unwrap().status, 0);
+
+    let request = MapDocsRequest {
+        signature: "stream-ddoc".to_string(),
+        docs: (0..10)
+            .map(|n| format!(r#"{{"_id": "doc{}", "n": {}}}"#, n, n))
+            .collect(),
+        session_id: "map-docs".to_string(),
+        ..MapDocsRequest::default()
+    };
+    let responses = client.execute_map_docs(&request).await.unwrap();
+    assert_eq!(responses.len(), 10);
+    assert_eq!(responses[7].emitted[0].rows, vec![r#"["doc7",7]"#]);
+
+    // Every doc gets an answer, even without the design doc
+    let unknown = MapDocsRequest {
+        signature: "nope".to_string(),
+        ..request
+    };
+    let responses = client.execute_map_docs(&unknown).await.unwrap();
+    assert_eq!(responses.len(), 10);
+    assert_eq!(responses[0].error_type, ErrorType::UnknownDesignDoc as i32);
+}
+
+#[tokio::test]
+async fn maps_a_batch_with_one_command() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let client = server.client();
+
+    let register = JsRequestBuilder::register_ddoc("batch-ddoc", FUNCTIONS)
+        .session("map-batch", Duration::from_secs(60))
+        .build()
+        .unwrap();
+    assert_eq!(client.execute(&register).await.unwrap().status, 0);
+
+    let mut docs: Vec<String> = (0..5)
+        .map(|n| format!(r#"{{"_id": "doc{}", "n": {}}}"#, n, n))
+        .collect();
+    docs[3] = "{".to_string();
+    let request = MapDocsRequest {
+        signature: "batch-ddoc".to_string(),
+        docs,
+        session_id: "map-batch".to_string(),
+        batch: true,
+        ..MapDocsRequest::default()
+    };
+    let responses = client.execute_map_docs(&request).await.unwrap();
+    assert_eq!(responses.len(), 5);
+    assert_eq!(responses[0].result, "true");
+    assert_eq!(responses[4].emitted[0].rows, vec![r#"["doc4",4]"#]);
+    // Only the doc that didn't parse fails
+    assert_eq!(responses[3].error_type, ErrorType::RuntimeError as i32);
+    assert_eq!(responses[3].error.as_ref().unwrap().name, "SyntaxError");
+    assert!(responses[3].emitted.is_empty());
+
+    let unknown = MapDocsRequest {
+        signature: "nope".to_string(),
+        ..request
+    };
+    let responses = client.execute_map_docs(&unknown).await.unwrap();
+    assert_eq!(responses.len(), 5);
+    assert!(responses
+        .iter()
+        .all(|resp| resp.error_type == ErrorType::UnknownDesignDoc as i32));
+}
+
+#[tokio::test]
+async fn maps_docs_streamed_in_frames() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let client = server.client();
+
+    let register = JsRequestBuilder::register_ddoc("stream-ddoc", FUNCTIONS)
+        .session("map-stream", Duration::from_secs(60))
+        .build()
+        .unwrap();
+    assert_eq!(client.execute(&register).await.unwrap().status, 0);
+
+    let docs: Vec<String> = (0..7)
+        .map(|n| format!(r#"{{"_id": "doc{}", "n": {}}}"#, n, n))
+        .collect();
+    let request = MapDocsRequest {
+        signature: "stream-ddoc".to_string(),
+        docs,
+        session_id: "map-stream".to_string(),
+        ..MapDocsRequest::default()
+    };
+    for batch in &[false, true] {
+        let request = MapDocsRequest {
+            batch: *batch,
+            ..request.clone()
+        };
+        // The last frame only has one doc
+        let responses = client.execute_map_docs_stream(&request, 3).await.unwrap();
+        assert_eq!(responses.len(), 7);
+        for (n, resp) in responses.iter().enumerate() {
+            assert_eq!(resp.status, 0);
+            assert_eq!(resp.emitted[0].rows, vec![format!(r#"["doc{}",{}]"#, n, n)]);
+        }
+    }
+}
+
+#[tokio::test]
+async fn describes_the_server() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let info = server.client().server_info().await.unwrap();
+
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(info.protocol_version, fortuna::proto::PROTOCOL_VERSION);
+    assert!(info.actions.contains(&"MAP_DOCS".to_string()));
+    assert!(info.features.contains(&"deadlines".to_string()));
+    assert!(info.harnesses.is_empty());
+    let limits = info.limits.unwrap();
+    assert!(limits.max_body_bytes > 0);
+    assert_eq!(limits.max_emitted_rows, 100_000);
+}
+
+#[tokio::test]
+async fn pipelines_requests() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let client = server.client();
+
+    let requests = vec![
+        JsRequestBuilder::eval("var total = 40;").build().unwrap(),
+        JsRequestBuilder::eval("total + 2;").build().unwrap(),
+    ];
+    let responses = client.execute_pipelined(&requests).await.unwrap();
+    assert_eq!(responses.len(), 2);
+    assert_eq!(responses[1].result, "42");
+}
+
+#[tokio::test]
+async fn pipelined_frames_keep_to_the_max_body_size() {
+    common::setup();
+    let server = TestServer::start_with(MakeService::new().with_max_body_size(256)).unwrap();
+    let client = server.client();
+
+    let script = format!("var padding = '{}';", "x".repeat(1024));
+    let requests = vec![JsRequestBuilder::eval(&script).build().unwrap()];
+    let responses = client.execute_pipelined(&requests).await.unwrap();
+    assert_ne!(responses[0].status, 0);
+    assert!(
+        responses[0].result.contains("frame too large"),
+        "{}",
+        responses[0].result
+    );
+}
+
+#[tokio::test]
+async fn speaks_http2() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let config = ClientConfig {
+        http2: true,
+        ..ClientConfig::default()
+    };
+    let client = AtelesClient::with_config(&server.url(), config).unwrap();
+    assert_eq!(client.eval("1 + 1;").await.unwrap().result, "2");
+}
+
+#[tokio::test]
+async fn retries_with_backoff() {
+    common::setup();
+    let limits =
+        RateLimitConfig::parse(r#"{"rules": [{"key": "client_ip", "rate": 5, "burst": 1}]}"#)
+            .unwrap();
+    let server = TestServer::start_with(MakeService::new().with_rate_limits(limits)).unwrap();
+
+    let config = ClientConfig {
+        retries: 5,
+        backoff: Duration::from_millis(100),
+        ..ClientConfig::default()
+    };
+    let client = AtelesClient::with_config(&server.url(), config).unwrap();
+    assert_eq!(client.eval("1;").await.unwrap().status, 0);
+    // Over the limit until the bucket refills, which the retries wait out
+    assert_eq!(client.eval("2;").await.unwrap().result, "2");
+
+    // Without retries the limit comes back as is
+    let client = server.client();
+    let resp = client.eval("3;").await.unwrap();
+    assert_eq!(resp.error_type, ErrorType::RateLimited as i32);
+}
+
+#[tokio::test]
+async fn gives_up_on_unreachable_servers() {
+    let config = ClientConfig {
+        retries: 2,
+        backoff: Duration::from_millis(20),
+        ..ClientConfig::default()
+    };
+    // Nothing listens on port 1
+    let client = AtelesClient::with_config("http://127.0.0.1:1", config).unwrap();
+    let start = Instant::now();
+    let err = client.eval("1;").await.unwrap_err();
+    assert!(err.contains("request failed"), "{}", err);
+    // Waited 20ms and then 40ms between the three tries
+    assert!(start.elapsed() >= Duration::from_millis(60));
+}
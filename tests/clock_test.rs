@@ -0,0 +1,68 @@
+use std::thread;
+use std::time::Duration;
+
+use fortuna::host_functions;
+use fortuna::*;
+mod common;
+
+#[test]
+fn parse_clocks() {
+    assert_eq!("real".parse::<Clock>().unwrap(), Clock::Real);
+    assert_eq!("command".parse::<Clock>().unwrap(), Clock::Command);
+    assert_eq!("0".parse::<Clock>().unwrap(), Clock::Fixed(0));
+    assert_eq!(
+        "2020-01-01T00:00:00Z".parse::<Clock>().unwrap(),
+        Clock::Fixed(1_577_836_800_000)
+    );
+    assert!("soon".parse::<Clock>().is_err());
+}
+
+#[test]
+fn fixed_clock() {
+    common::setup();
+    let js_env = JSEnv::new();
+    let caps = Capabilities::all().with_clock(Clock::Fixed(1_577_836_800_000));
+    let mut isolate = js_env.create_isolate_with_capabilities(&caps);
+
+    let result = isolate
+        .eval(
+            "[Date.now(), new Date().getTime(), new Date(5).getTime(), \
+             new Date() instanceof Date, Date().length > 0];",
+            &[],
+        )
+        .unwrap();
+    assert_eq!(result, "[1577836800000,1577836800000,5,true,true]");
+
+    // User code can't put the real one back
+    let result = isolate.eval("Date = null; Date.now();", &[]).unwrap();
+    assert_eq!(result, "1577836800000");
+}
+
+#[test]
+fn command_clock() {
+    common::setup();
+    let js_env = JSEnv::new();
+    let caps = Capabilities::all().with_clock(Clock::Command);
+    let mut isolate = js_env.create_isolate_with_capabilities(&caps);
+
+    host_functions::start_command();
+    let first = isolate.eval("Date.now();", &[]).unwrap();
+    thread::sleep(Duration::from_millis(5));
+    assert_eq!(isolate.eval("new Date().getTime();", &[]).unwrap(), first);
+
+    host_functions::start_command();
+    let next = isolate.eval("Date.now();", &[]).unwrap();
+    assert!(next.parse::<f64>().unwrap() > first.parse::<f64>().unwrap());
+}
+
+#[test]
+fn real_clock_moves() {
+    common::setup();
+    let js_env = JSEnv::new();
+    let mut isolate = js_env.create_isolate();
+
+    let first = isolate.eval("Date.now();", &[]).unwrap();
+    thread::sleep(Duration::from_millis(5));
+    let next = isolate.eval("Date.now();", &[]).unwrap();
+    assert!(next.parse::<f64>().unwrap() > first.parse::<f64>().unwrap());
+}
@@ -0,0 +1,90 @@
+use fortuna::collate::{collate, encode_key};
+use proptest::prelude::*;
+use serde_json::{json, Value};
+
+#[test]
+fn encoded_keys_sort_like_collate() {
+    let values: Vec<Value> = vec![
+        json!(null),
+        json!(false),
+        json!(true),
+        json!(-1.5e300),
+        json!(-2),
+        json!(-0.5),
+        json!(0),
+        json!(0.25),
+        json!(1),
+        json!(100),
+        json!(""),
+        json!("\u{0}"),
+        json!("a"),
+        json!("A"),
+        json!("aa"),
+        json!("aB"),
+        json!("ab"),
+        json!("b"),
+        json!("é"),
+        json!([]),
+        json!([null]),
+        json!([1]),
+        json!([1, "a"]),
+        json!([1, "b"]),
+        json!(["a"]),
+        json!([["a"], 1]),
+        json!({}),
+        json!({"a": 1}),
+        json!({"a": 1, "b": 2}),
+        json!({"a": 2}),
+        json!({"b": 1}),
+        json!({"": 1}),
+        json!({"\u{0}": 1}),
+        json!([{"a": 1}, null]),
+        json!([{"a": 1, "": 2}]),
+        json!([{"a": 1, "\u{0}b": 2}]),
+    ];
+
+    for a in &values {
+        for b in &values {
+            assert_eq!(
+                encode_key(a).cmp(&encode_key(b)),
+                collate(a, b),
+                "{} vs {}",
+                a,
+                b
+            );
+        }
+    }
+}
+
+#[test]
+fn negative_zero_equals_zero() {
+    assert_eq!(encode_key(&json!(-0.0)), encode_key(&json!(0)));
+}
+
+// Short strings from a few characters so that values often share prefixes,
+// differ only by case or contain NULs
+fn json_value() -> impl Strategy<Value = Value> {
+    let leaf = prop_oneof![
+        Just(Value::Null),
+        any::<bool>().prop_map(Value::Bool),
+        (-100i64..100).prop_map(|n| json!(n)),
+        any::<f64>()
+            .prop_filter("JSON numbers are finite", |n| n.is_finite())
+            .prop_map(|n| json!(n)),
+        "[aAbé\\x00]{0,3}".prop_map(Value::String),
+    ];
+    leaf.prop_recursive(3, 32, 4, |inner| {
+        prop_oneof![
+            prop::collection::vec(inner.clone(), 0..4).prop_map(Value::Array),
+            prop::collection::vec(("[aA\\x00]{0,2}", inner), 0..4)
+                .prop_map(|members| Value::Object(members.into_iter().collect())),
+        ]
+    })
+}
+
+proptest! {
+    #[test]
+    fn encoded_keys_always_sort_like_collate(a in json_value(), b in json_value()) {
+        prop_assert_eq!(encode_key(&a).cmp(&encode_key(&b)), collate(&a, &b));
+    }
+}
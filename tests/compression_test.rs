@@ -0,0 +1,46 @@
+use fortuna::compression::{compress, decompress, negotiate, Encoding};
+
+const LIMIT: usize = 1024 * 1024;
+
+#[test]
+fn negotiate_accept_encoding() {
+    assert_eq!(negotiate("gzip"), Some(Encoding::Gzip));
+    assert_eq!(negotiate("deflate, gzip;q=0.5"), Some(Encoding::Gzip));
+    assert_eq!(negotiate("deflate"), Some(Encoding::Deflate));
+    assert_eq!(negotiate("gzip;q=0, deflate"), Some(Encoding::Deflate));
+    assert_eq!(negotiate("*"), Some(Encoding::Gzip));
+    assert_eq!(negotiate("*;q=0, deflate"), Some(Encoding::Deflate));
+    assert_eq!(negotiate("identity"), None);
+    assert_eq!(negotiate("br"), None);
+    assert_eq!(negotiate(""), None);
+}
+
+#[test]
+fn compressed_bodies_round_trip() {
+    let body = "[\"key\", {\"value\": 1}]".repeat(100);
+    for encoding in &[Encoding::Gzip, Encoding::Deflate] {
+        let compressed = compress(*encoding, body.as_bytes());
+        assert!(compressed.len() < body.len());
+        let decompressed = decompress(encoding.as_str(), &compressed, LIMIT).unwrap();
+        assert_eq!(decompressed, body.as_bytes());
+    }
+
+    assert_eq!(decompress("", b"plain", LIMIT).unwrap(), b"plain");
+    assert_eq!(decompress("identity", b"plain", LIMIT).unwrap(), b"plain");
+}
+
+#[test]
+fn bad_request_bodies() {
+    assert!(decompress("gzip", b"not gzip", LIMIT).is_err());
+    assert!(decompress("deflate", b"not deflate", LIMIT).is_err());
+    assert!(decompress("br", b"anything", LIMIT).is_err());
+}
+
+#[test]
+fn decompressed_size_is_limited() {
+    let body = vec![b'x'; 10000];
+    let compressed = compress(Encoding::Gzip, &body);
+    assert!(compressed.len() < 1000);
+    assert!(decompress("gzip", &compressed, 10000).is_ok());
+    assert!(decompress("gzip", &compressed, 9999).is_err());
+}
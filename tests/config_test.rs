@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+use fortuna::config::{self, Config};
+mod common;
+
+#[test]
+fn parses_settings() {
+    let config = Config::parse(
+        r#"
+        listen = "127.0.0.1:8444"
+        pool_max_size = 4
+        log_level = "info"
+        max_queue_depth = 10
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.pool_max_size, Some(4));
+    assert_eq!(config.log_level, Some("info".to_string()));
+    assert_eq!(config.binds().len(), 1);
+    assert_eq!(Config::parse("").unwrap(), Config::default());
+
+    assert!(Config::parse("pool_size = 4").is_err());
+    assert!(Config::parse(r#"log_level = "loud""#).is_err());
+    assert!(Config::parse(r#"bind = ["nowhere"]"#).is_err());
+    assert!(Config::parse("[").is_err());
+    assert!(Config::parse(r#"{"pool_max_size": 4}"#).is_err());
+    assert!(Config::parse(r#"v8_flags = "--jitless""#).is_ok());
+    assert!(Config::parse(r#"v8_flags = "jitless""#).is_err());
+    assert!(Config::parse(r#"security_mode = "hardened""#).is_ok());
+    assert!(Config::parse(r#"security_mode = "paranoid""#).is_err());
+}
+
+#[test]
+fn tells_reloadable_settings_apart() {
+    let old = Config::parse("slow_request_ms = 100\npool_max_size = 4").unwrap();
+    let new = Config::parse(
+        "slow_request_ms = 200\npool_max_size = 8\ndefault_timeout_ms = 50\n\
+         max_result_bytes = 1024",
+    )
+    .unwrap();
+    let (reloadable, restart) = new.changes(&old);
+    assert_eq!(
+        reloadable,
+        vec!["slow_request_ms", "default_timeout_ms", "max_result_bytes"]
+    );
+    assert_eq!(restart, vec!["pool_max_size"]);
+    assert_eq!(new.changes(&new), (vec![], vec![]));
+}
+
+#[test]
+fn emit_limits_need_a_restart() {
+    let old = Config::default();
+    let new = Config::parse("max_emit_rows = 10\nmax_emit_bytes = 4096").unwrap();
+    assert_eq!(new.max_emit_rows, Some(10));
+    assert_eq!(new.max_emit_row_bytes, None);
+    let (reloadable, restart) = new.changes(&old);
+    assert!(reloadable.is_empty());
+    assert_eq!(restart, vec!["max_emit_rows", "max_emit_bytes"]);
+}
+
+#[test]
+fn reloads_from_file() {
+    common::setup();
+    let path = std::env::temp_dir().join(format!("fortuna-config-{}.toml", std::process::id()));
+    std::fs::write(&path, "max_queue_depth = 3").unwrap();
+    let mut current = Config::from_file(&path).unwrap();
+    current.apply();
+    assert_eq!(config::max_queue_depth(), 3);
+
+    std::fs::write(&path, "max_queue_depth = 5\ndefault_timeout_ms = 250").unwrap();
+    config::reload(&path, &mut current);
+    assert_eq!(config::max_queue_depth(), 5);
+    assert_eq!(config::default_timeout(), Some(Duration::from_millis(250)));
+    assert_eq!(current.max_queue_depth, Some(5));
+
+    // A broken file leaves the settings as they were
+    std::fs::write(&path, "max_queue_depth =").unwrap();
+    config::reload(&path, &mut current);
+    assert_eq!(config::max_queue_depth(), 5);
+
+    std::fs::write(&path, "max_queue_depth = 0\ndefault_timeout_ms = 0").unwrap();
+    config::reload(&path, &mut current);
+    assert_eq!(config::max_queue_depth(), 0);
+    assert_eq!(config::default_timeout(), None);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,118 @@
+use std::sync::mpsc;
+use std::sync::{Arc, Barrier};
+use std::thread;
+
+use fortuna::config::{self, Config};
+use fortuna::error::JSError;
+use fortuna::js_engine::{self, FortunaIsolate, JSEnv};
+use fortuna::metrics;
+mod common;
+
+const FUNCTIONS: &str = r#"{"map": ["function(doc) { emit(doc._id, null); }"]}"#;
+
+// Runs steps on an isolate of its own thread, like a worker's, and keeps
+// the isolate until the returned sender is dropped
+fn hold<F>(js_env: Arc<JSEnv>, steps: F) -> (mpsc::SyncSender<()>, thread::JoinHandle<()>)
+where
+    F: FnOnce(&mut FortunaIsolate) + Send + 'static,
+{
+    let (ready_tx, ready_rx) = mpsc::sync_channel(0);
+    let (done_tx, done_rx) = mpsc::sync_channel::<()>(0);
+    let handle = thread::spawn(move || {
+        let mut isolate = js_env.create_isolate();
+        steps(&mut isolate);
+        ready_tx.send(()).unwrap();
+        let _ = done_rx.recv();
+    });
+    ready_rx.recv().expect("steps failed");
+    (done_tx, handle)
+}
+
+// One test so the process wide limits aren't changed under another
+#[test]
+fn least_recently_used_design_docs_are_evicted() {
+    common::setup();
+    let config = Config::parse("max_contexts_per_isolate = 2\nmax_contexts = 3").unwrap();
+    config.apply();
+    assert_eq!(config::max_contexts_per_isolate(), 2);
+    assert_eq!(config::max_contexts(), 3);
+    let (reloadable, _) = config.changes(&Config::default());
+    assert_eq!(reloadable, vec!["max_contexts_per_isolate", "max_contexts"]);
+
+    let js_env = Arc::new(JSEnv::new());
+    let first = hold(js_env.clone(), |isolate| {
+        isolate.register_ddoc("a", FUNCTIONS).unwrap();
+        isolate.register_ddoc("b", FUNCTIONS).unwrap();
+        // Running a makes b the least recently used
+        isolate.map_doc("a", r#"{"_id": "foo"}"#).unwrap();
+        isolate.register_ddoc("c", FUNCTIONS).unwrap();
+        assert_eq!(isolate.design_doc_count(), 2);
+        assert!(isolate.map_doc("a", r#"{"_id": "foo"}"#).is_ok());
+        match isolate.map_doc("b", r#"{"_id": "foo"}"#) {
+            Err(JSError::UnknownDesignDoc(signature)) => assert_eq!(signature, "b"),
+            other => panic!("expected b to be evicted, got {:?}", other),
+        }
+        // Registering a signature again replaces it without evicting another
+        isolate.register_ddoc("c", FUNCTIONS).unwrap();
+        assert_eq!(isolate.design_doc_count(), 2);
+        // One that doesn't compile doesn't evict anything either
+        let broken = r#"{"map": ["function(doc) {"]}"#;
+        assert!(isolate.register_ddoc("z", broken).is_err());
+        assert!(isolate.map_doc("a", r#"{"_id": "foo"}"#).is_ok());
+        assert!(isolate.map_doc("c", r#"{"_id": "foo"}"#).is_ok());
+    });
+    assert_eq!(js_engine::design_doc_contexts(), 2);
+
+    // The third context is the last there can be over every isolate, after
+    // which an isolate evicts its own to make room
+    let second = hold(js_env.clone(), |isolate| {
+        isolate.register_ddoc("d", FUNCTIONS).unwrap();
+        assert_eq!(js_engine::design_doc_contexts(), 3);
+        isolate.register_ddoc("e", FUNCTIONS).unwrap();
+        assert_eq!(isolate.design_doc_count(), 1);
+    });
+    assert_eq!(js_engine::design_doc_contexts(), 3);
+    assert!(metrics::render().contains("fortuna_evicted_contexts_total 2"));
+    assert!(metrics::render().contains("fortuna_design_doc_contexts 3"));
+
+    // or is turned away if it has none
+    let mut isolate = js_env.create_isolate();
+    match isolate.register_ddoc("f", FUNCTIONS) {
+        Err(JSError::Overloaded(_)) => (),
+        other => panic!("expected OVERLOADED, got {:?}", other),
+    }
+    assert_eq!(isolate.design_doc_count(), 0);
+
+    for (done, handle) in vec![first, second] {
+        drop(done);
+        handle.join().unwrap();
+    }
+    assert_eq!(js_engine::design_doc_contexts(), 0);
+    isolate.register_ddoc("f", FUNCTIONS).unwrap();
+    assert_eq!(js_engine::design_doc_contexts(), 1);
+
+    // Isolates registering at the same time share the last two between them
+    let started = Arc::new(Barrier::new(6));
+    let registered = Arc::new(Barrier::new(6));
+    let racing: Vec<_> = (0..6)
+        .map(|i| {
+            let (js_env, started, registered) =
+                (js_env.clone(), started.clone(), registered.clone());
+            thread::spawn(move || {
+                let mut isolate = js_env.create_isolate();
+                started.wait();
+                let ok = isolate.register_ddoc(&format!("g{}", i), FUNCTIONS).is_ok();
+                // Kept until they've all tried
+                registered.wait();
+                ok
+            })
+        })
+        .collect();
+    let won = racing
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .filter(|ok| *ok)
+        .count();
+    assert_eq!(won, 2);
+    assert_eq!(js_engine::design_doc_contexts(), 1);
+}
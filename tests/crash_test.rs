@@ -0,0 +1,89 @@
+use futures::executor::block_on;
+use futures::future;
+
+use fortuna::js_server::{Command, Ops};
+use fortuna::test_support;
+use fortuna::*;
+mod common;
+
+fn eval(script: &str) -> Command {
+    Command {
+        operation: Ops::EVAL,
+        payload: script.to_string(),
+        args: vec![],
+        docs: Vec::new(),
+    }
+}
+
+#[test]
+fn crashed_workers_get_a_fresh_isolate() {
+    common::setup();
+
+    let client = create_js_env(&JSEnv::new(), &Capabilities::none());
+    assert_eq!(
+        block_on(client.run(eval("var kept = 1; kept;"))).unwrap(),
+        "1"
+    );
+
+    let script = "1; // crashed_workers_get_a_fresh_isolate";
+    test_support::crash_on(script, 1);
+    let reply = block_on(client.execute(eval(script)));
+    assert!(reply.crashed);
+    match reply.result {
+        Err(err @ JSError::WorkerCrashed) => assert_eq!(err.to_string(), "worker_crashed"),
+        other => panic!("expected worker_crashed, got {:?}", other),
+    }
+
+    // The new isolate runs the next command, without what the old one had
+    assert_eq!(block_on(client.run(eval("1 + 1;"))).unwrap(), "2");
+    assert!(block_on(client.run(eval("kept;"))).is_err());
+    assert!(!client.gave_up());
+}
+
+#[test]
+fn workers_that_give_up_fail_what_is_sent_to_them() {
+    common::setup();
+
+    let client = create_js_env(&JSEnv::new(), &Capabilities::none());
+    // Its first isolate is up before the others start failing
+    assert_eq!(block_on(client.run(eval("0;"))).unwrap(), "0");
+    let script = "1; // workers_that_give_up_fail_what_is_sent_to_them";
+    test_support::crash_on(script, 1);
+    test_support::fail_isolates(&client, u32::MAX);
+
+    // The second command is still queued when the worker gives up
+    let (crashed, pending) = block_on(future::join(
+        client.run(eval(script)),
+        client.run(eval("2;")),
+    ));
+    assert!(matches!(crashed, Err(JSError::WorkerCrashed)));
+    assert!(matches!(pending, Err(JSError::WorkerCrashed)));
+
+    assert!(client.gave_up());
+    assert!(matches!(
+        block_on(client.run(eval("3;"))),
+        Err(JSError::WorkerCrashed)
+    ));
+}
+
+#[test]
+fn pools_replace_workers_that_give_up() {
+    common::setup();
+
+    let pool = WorkerPool::new(&JSEnv::new(), &Capabilities::none(), 1);
+    let client = pool.next();
+    assert_eq!(block_on(client.run(eval("0;"))).unwrap(), "0");
+    let script = "1; // pools_replace_workers_that_give_up";
+    test_support::crash_on(script, 1);
+    test_support::fail_isolates(&client, u32::MAX);
+    assert!(block_on(client.run(eval(script))).is_err());
+    while !client.gave_up() {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+
+    let replacement = pool.next();
+    assert_ne!(replacement.id(), client.id());
+    assert_eq!(block_on(replacement.run(eval("1 + 1;"))).unwrap(), "2");
+    assert_eq!(pool.for_key("stream").id(), replacement.id());
+    assert_eq!(pool.size(), 1);
+}
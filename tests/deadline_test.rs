@@ -0,0 +1,104 @@
+use prost::Message;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use fortuna::ateles::js_response::ErrorType;
+use fortuna::ateles::JsResponse;
+use fortuna::deadline::{parse_deadline, parse_grpc_timeout};
+use fortuna::js_server::{Command, Ops};
+use fortuna::test_support::TestServer;
+use fortuna::*;
+mod common;
+
+#[test]
+fn grpc_timeouts() {
+    assert_eq!(parse_grpc_timeout("250m"), Ok(Duration::from_millis(250)));
+    assert_eq!(parse_grpc_timeout("5S"), Ok(Duration::from_secs(5)));
+    assert_eq!(parse_grpc_timeout("2M"), Ok(Duration::from_secs(120)));
+    assert_eq!(parse_grpc_timeout("1H"), Ok(Duration::from_secs(3600)));
+    assert_eq!(parse_grpc_timeout("10u"), Ok(Duration::from_micros(10)));
+    assert_eq!(
+        parse_grpc_timeout("99999999n"),
+        Ok(Duration::from_nanos(99_999_999))
+    );
+    assert!(parse_grpc_timeout("123456789S").is_err());
+    assert!(parse_grpc_timeout("5s").is_err());
+    assert!(parse_grpc_timeout("S").is_err());
+    assert!(parse_grpc_timeout("-5S").is_err());
+}
+
+#[test]
+fn request_deadlines() {
+    let at = UNIX_EPOCH + Duration::from_millis(1_588_334_400_250);
+    assert_eq!(parse_deadline("1588334400250"), Ok(at));
+    assert_eq!(parse_deadline("2020-05-01T12:00:00.250Z"), Ok(at));
+    assert_eq!(parse_deadline("2020-05-01T14:00:00.250+02:00"), Ok(at));
+    assert!(parse_deadline("").is_err());
+    assert!(parse_deadline("tomorrow").is_err());
+}
+
+#[tokio::test]
+async fn expired_commands_never_run() {
+    common::setup();
+    let js_env = JSEnv::new();
+    let js_client = create_js_env(&js_env, &Capabilities::none());
+    let eval = |script: &str| Command {
+        operation: Ops::EVAL,
+        payload: script.to_string(),
+        args: vec![],
+        docs: Vec::new(),
+    };
+
+    let busy = js_client.execute_timeout(eval("while (true) {}"), Duration::from_millis(300));
+    let late = js_client.execute_deadline(
+        eval("globalThis.ran = true;"),
+        Instant::now() + Duration::from_millis(50),
+    );
+    let (busy, late) = futures::join!(busy, late);
+    assert!(matches!(busy.result, Err(JSError::Timeout)));
+    assert!(matches!(late.result, Err(JSError::DeadlineExceeded)));
+
+    let ran = js_client.run(eval("typeof ran;")).await.unwrap();
+    assert_eq!(ran, "\"undefined\"");
+}
+
+async fn post(server: &TestServer, script: &str, header: (&str, String)) -> (u16, JsResponse) {
+    let request = JsRequestBuilder::eval(script).build().unwrap();
+    let mut body = Vec::new();
+    request.encode(&mut body).unwrap();
+    let resp = reqwest::Client::new()
+        .post(&format!("{}/Ateles/Execute", server.url()))
+        .header(header.0, header.1)
+        .body(body)
+        .send()
+        .await
+        .unwrap();
+    let status = resp.status().as_u16();
+    let js_resp = JsResponse::decode(resp.bytes().await.unwrap()).unwrap();
+    (status, js_resp)
+}
+
+#[tokio::test]
+async fn deadlines_over_http() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+
+    let (_, resp) = post(&server, "1 + 1;", ("grpc-timeout", "5S".to_string())).await;
+    assert_eq!(resp.result, "2");
+
+    let (_, resp) = post(
+        &server,
+        "while (true) {}",
+        ("grpc-timeout", "100m".to_string()),
+    )
+    .await;
+    assert_eq!(resp.error_type, ErrorType::DeadlineExceeded as i32);
+
+    let past = SystemTime::now() - Duration::from_secs(1);
+    let ms = past.duration_since(UNIX_EPOCH).unwrap().as_millis();
+    let (_, resp) = post(&server, "1 + 1;", ("x-request-deadline", ms.to_string())).await;
+    assert_eq!(resp.error_type, ErrorType::DeadlineExceeded as i32);
+
+    let (status, resp) = post(&server, "1 + 1;", ("grpc-timeout", "soon".to_string())).await;
+    assert_eq!(status, 400);
+    assert_eq!(resp.error_type, ErrorType::InvalidRequest as i32);
+}
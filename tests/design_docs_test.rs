@@ -0,0 +1,381 @@
+use fortuna::host_functions;
+use fortuna::*;
+mod common;
+
+const FUNCTIONS: &str = r#"{
+    "map": [
+        "function(doc) { emit(doc._id, doc.value); }",
+        "function(doc) { if (doc.value > 1) { emit(doc.value, null); } }"
+    ],
+    "filters": {
+        "big": "function(doc, req) { return doc.value > (req.min || 1); }"
+    }
+}"#;
+
+#[test]
+fn map_with_a_registered_design_doc() {
+    common::setup();
+    let js_env = JSEnv::new();
+    let mut isolate = js_env.create_isolate();
+
+    assert_eq!(isolate.register_ddoc("sig1", FUNCTIONS).unwrap(), "true");
+    assert_eq!(isolate.design_doc_count(), 1);
+
+    let _ = host_functions::take_emitted();
+    let result = isolate.map_doc("sig1", r#"{"_id": "foo", "value": 1}"#);
+    assert_eq!(result.unwrap(), "true");
+    // A group per map function, even if it emitted nothing
+    let emitted = host_functions::take_emitted().unwrap();
+    assert_eq!(emitted, vec![vec!["[\"foo\",1]".to_string()], vec![]]);
+
+    isolate
+        .map_doc("sig1", r#"{"_id": "bar", "value": 2}"#)
+        .unwrap();
+    let emitted = host_functions::take_emitted().unwrap();
+    assert_eq!(
+        emitted,
+        vec![
+            vec!["[\"bar\",2]".to_string()],
+            vec!["[2,null]".to_string()]
+        ]
+    );
+}
+
+#[test]
+fn map_a_batch_of_docs() {
+    common::setup();
+    let js_env = JSEnv::new();
+    let mut isolate = js_env.create_isolate();
+    isolate.register_ddoc("sig1", FUNCTIONS).unwrap();
+
+    let _ = host_functions::take_emitted();
+    let docs = vec![
+        r#"{"_id": "foo", "value": 1}"#.to_string(),
+        "not json".to_string(),
+        r#"{"_id": "bar", "value": 2}"#.to_string(),
+    ];
+    let result = isolate.map_docs("sig1", &docs).unwrap();
+    let result: serde_json::Value = serde_json::from_str(&result).unwrap();
+    assert!(result[0].is_null());
+    assert_eq!(result[1]["name"], "SyntaxError");
+    assert!(result[2].is_null());
+
+    // The doc that failed still has its groups, just empty ones
+    let emitted = host_functions::take_emitted().unwrap();
+    assert_eq!(
+        emitted,
+        vec![
+            vec!["[\"foo\",1]".to_string()],
+            vec![],
+            vec![],
+            vec![],
+            vec!["[\"bar\",2]".to_string()],
+            vec!["[2,null]".to_string()]
+        ]
+    );
+}
+
+#[test]
+fn map_docs_parsed_in_rust() {
+    common::setup();
+    let js_env = JSEnv::new();
+    let mut isolate = js_env.create_isolate();
+    isolate.register_ddoc("sig1", FUNCTIONS).unwrap();
+    isolate.register_ddoc("sig2", FUNCTIONS).unwrap();
+
+    // Parsed once and mapped by both design docs, with the same rows as
+    // when given as JSON
+    let json = r#"{"_id": "foo", "value": 2, "tags": ["a", {"b": null}]}"#;
+    let doc: serde_json::Value = serde_json::from_str(json).unwrap();
+    let _ = host_functions::take_emitted();
+    isolate.map_doc("sig1", json).unwrap();
+    let expected = host_functions::take_emitted().unwrap();
+    for signature in &["sig1", "sig2"] {
+        isolate.map_parsed_doc(signature, Doc::Value(&doc)).unwrap();
+        assert_eq!(host_functions::take_emitted().unwrap(), expected);
+    }
+
+    // Parsed docs aren't frozen, map functions can change them as usual
+    let mutating = r#"{"map": ["function(doc) { doc.value++; emit(doc.value, null); }"]}"#;
+    isolate.register_ddoc("sig3", mutating).unwrap();
+    let docs = [Doc::Value(&doc), Doc::Json("not json")];
+    let result = isolate.map_parsed_docs("sig3", &docs).unwrap();
+    let result: serde_json::Value = serde_json::from_str(&result).unwrap();
+    assert!(result[0].is_null());
+    assert_eq!(result[1]["name"], "SyntaxError");
+    assert_eq!(
+        host_functions::take_emitted().unwrap(),
+        vec![vec!["[3,null]".to_string()], vec![]]
+    );
+
+    // "__proto__" is an own key, not the doc's prototype
+    let keys = r#"{"map": ["function(doc) { emit(Object.keys(doc), doc.polluted); }"]}"#;
+    isolate.register_ddoc("sig4", keys).unwrap();
+    let json = r#"{"__proto__": {"polluted": true}}"#;
+    let doc: serde_json::Value = serde_json::from_str(json).unwrap();
+    isolate.map_doc("sig4", json).unwrap();
+    let expected = host_functions::take_emitted().unwrap();
+    assert_eq!(expected, vec![vec!["[[\"__proto__\"],null]".to_string()]]);
+    isolate.map_parsed_doc("sig4", Doc::Value(&doc)).unwrap();
+    assert_eq!(host_functions::take_emitted().unwrap(), expected);
+}
+
+#[test]
+fn rows_of_a_doc_that_throws_are_dropped() {
+    common::setup();
+    let js_env = JSEnv::new();
+    let mut isolate = js_env.create_isolate();
+    let functions = r#"{
+        "map": [
+            "function(doc) { emit(doc._id, 1); }",
+            "function(doc) { if (doc.bad) { throw new Error('bad doc'); } emit(doc._id, 2); }"
+        ]
+    }"#;
+    isolate.register_ddoc("sig2", functions).unwrap();
+
+    let _ = host_functions::take_emitted();
+    let docs = vec![
+        r#"{"_id": "a", "bad": true}"#.to_string(),
+        r#"{"_id": "b"}"#.to_string(),
+    ];
+    let result = isolate.map_docs("sig2", &docs).unwrap();
+    let result: serde_json::Value = serde_json::from_str(&result).unwrap();
+    assert_eq!(result[0]["message"], "bad doc");
+    assert!(result[1].is_null());
+
+    let emitted = host_functions::take_emitted().unwrap();
+    assert_eq!(
+        emitted,
+        vec![
+            vec![],
+            vec![],
+            vec!["[\"b\",1]".to_string()],
+            vec!["[\"b\",2]".to_string()]
+        ]
+    );
+
+    match isolate.map_docs("nope", &docs) {
+        Err(JSError::UnknownDesignDoc(signature)) => assert_eq!(signature, "nope"),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn emit_limits_apply_to_each_doc_of_a_batch() {
+    common::setup();
+    let js_env = JSEnv::new();
+    let limits = host_functions::EmitLimits {
+        max_rows: 2,
+        ..host_functions::EmitLimits::default()
+    };
+    let caps = Capabilities::all().with_emit_limits(limits);
+    let mut isolate = js_env.create_isolate_with_capabilities(&caps);
+    let functions = r#"{
+        "map": ["function(doc) { for (var i = 0; i < doc.rows; i++) { emit(doc._id, i); } }"]
+    }"#;
+    isolate.register_ddoc("sig3", functions).unwrap();
+
+    let _ = host_functions::take_emitted();
+    let docs = vec![
+        r#"{"_id": "a", "rows": 2}"#.to_string(),
+        r#"{"_id": "b", "rows": 3}"#.to_string(),
+        r#"{"_id": "c", "rows": 2}"#.to_string(),
+    ];
+    let result = isolate.map_docs("sig3", &docs).unwrap();
+    let result: serde_json::Value = serde_json::from_str(&result).unwrap();
+    assert!(result[0].is_null());
+    assert_eq!(result[1]["name"], "RangeError");
+    assert!(result[1]["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("emit_limit_exceeded"));
+    assert!(result[2].is_null());
+
+    // Four rows in all, over the limit for a single doc but not for any one
+    let emitted = host_functions::take_emitted().unwrap();
+    assert_eq!(
+        emitted,
+        vec![
+            vec!["[\"a\",0]".to_string(), "[\"a\",1]".to_string()],
+            vec![],
+            vec!["[\"c\",0]".to_string(), "[\"c\",1]".to_string()]
+        ]
+    );
+}
+
+#[test]
+fn filter_with_a_registered_design_doc() {
+    common::setup();
+    let js_env = JSEnv::new();
+    let mut isolate = js_env.create_isolate();
+    isolate.register_ddoc("sig1", FUNCTIONS).unwrap();
+
+    let docs = r#"[{"value": 1}, {"value": 2}, {"value": 3}]"#;
+    let result = isolate.filter_docs("sig1", "big", docs, "{}").unwrap();
+    assert_eq!(result, "[false,true,true]");
+    let result = isolate
+        .filter_docs("sig1", "big", docs, r#"{"min": 2}"#)
+        .unwrap();
+    assert_eq!(result, "[false,false,true]");
+
+    match isolate.filter_docs("sig1", "small", docs, "{}") {
+        Err(JSError::RuntimeError(info)) => assert_eq!(info.name, "ReferenceError"),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn design_docs_can_be_evicted() {
+    common::setup();
+    let js_env = JSEnv::new();
+    let mut isolate = js_env.create_isolate();
+    isolate.register_ddoc("sig1", FUNCTIONS).unwrap();
+
+    assert_eq!(isolate.evict_ddoc("sig1").unwrap(), "true");
+    assert_eq!(isolate.evict_ddoc("sig1").unwrap(), "false");
+    assert_eq!(isolate.design_doc_count(), 0);
+    match isolate.map_doc("sig1", "{}") {
+        Err(JSError::UnknownDesignDoc(signature)) => assert_eq!(signature, "sig1"),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn design_docs_have_their_own_globals() {
+    common::setup();
+    let js_env = JSEnv::new();
+    let mut isolate = js_env.create_isolate();
+
+    let leaky = r#"{"map": ["function(doc) { leaked = 1; emit(typeof leaked, null); }"]}"#;
+    let clean = r#"{"map": ["function(doc) { emit(typeof leaked, null); }"]}"#;
+    isolate.register_ddoc("leaky", leaky).unwrap();
+    isolate.register_ddoc("clean", clean).unwrap();
+
+    let _ = host_functions::take_emitted();
+    isolate.map_doc("leaky", "{}").unwrap();
+    isolate.map_doc("clean", "{}").unwrap();
+    let emitted = host_functions::take_emitted().unwrap();
+    assert_eq!(emitted[0], vec!["[\"number\",null]"]);
+    assert_eq!(emitted[1], vec!["[\"undefined\",null]"]);
+    assert_eq!(isolate.eval("typeof leaked", &[]).unwrap(), "\"undefined\"");
+}
+
+#[test]
+fn bad_design_docs_are_rejected() {
+    common::setup();
+    let js_env = JSEnv::new();
+    let mut isolate = js_env.create_isolate();
+
+    assert!(matches!(
+        isolate.register_ddoc("sig1", r#"{"map": ["function(doc) {"]}"#),
+        Err(JSError::CompileError(_))
+    ));
+    assert!(matches!(
+        isolate.register_ddoc("sig1", r#"{"map": ["42"]}"#),
+        Err(JSError::RuntimeError(_))
+    ));
+    assert!(matches!(
+        isolate.register_ddoc("sig1", r#"{"views": {}}"#),
+        Err(JSError::CompileError(_))
+    ));
+    assert_eq!(isolate.design_doc_count(), 0);
+
+    // Dropping an isolate with design docs still registered is fine
+    isolate.register_ddoc("sig1", FUNCTIONS).unwrap();
+    drop(isolate);
+}
+
+#[test]
+fn design_docs_get_the_view_server_globals() {
+    common::setup();
+    let js_env = JSEnv::new();
+    let mut isolate = js_env.create_isolate();
+
+    let functions = r#"{"map": [
+        "function(doc) { emit(sum(doc.values), isArray(doc.values)); }",
+        "function(doc) { emit(toJSON(doc.values), isArray(doc)); }"
+    ]}"#;
+    isolate.register_ddoc("sig1", functions).unwrap();
+
+    let _ = host_functions::take_emitted();
+    isolate.map_doc("sig1", r#"{"values": [1, 2, 3]}"#).unwrap();
+    let emitted = host_functions::take_emitted().unwrap();
+    assert_eq!(emitted[0], vec!["[6,true]"]);
+    assert_eq!(emitted[1], vec!["[\"[1,2,3]\",false]"]);
+
+    // They're in the global context too
+    assert_eq!(isolate.eval("sum([1, 2])", &[]).unwrap(), "3");
+}
+
+#[test]
+fn design_docs_can_require_views_lib() {
+    common::setup();
+    let js_env = JSEnv::new();
+    let mut isolate = js_env.create_isolate();
+
+    let functions = r#"{
+        "map": [
+            "function(doc) { var util = require('views/lib/util'); emit(util.shout(doc.name), util.loads()); }",
+            "function(doc) { emit(require('views/lib/util').loads(), null); }"
+        ],
+        "lib": {
+            "util": "var strings = require('./strings'); var loads = 0; loads += 1; exports.shout = strings.upper; exports.loads = function() { return loads; };",
+            "strings": "module.exports = {upper: function(s) { return s.toUpperCase() + '!'; }};"
+        }
+    }"#;
+    isolate.register_ddoc("sig1", functions).unwrap();
+
+    let _ = host_functions::take_emitted();
+    isolate.map_doc("sig1", r#"{"name": "foo"}"#).unwrap();
+    let emitted = host_functions::take_emitted().unwrap();
+    assert_eq!(emitted[0], vec!["[\"FOO!\",1]"]);
+    // Modules only run once per design doc
+    assert_eq!(emitted[1], vec!["[1,null]"]);
+}
+
+#[test]
+fn missing_modules_are_runtime_errors() {
+    common::setup();
+    let js_env = JSEnv::new();
+    let mut isolate = js_env.create_isolate();
+
+    let functions = r#"{
+        "map": ["function(doc) { require('views/lib/nope'); }"],
+        "lib": {}
+    }"#;
+    isolate.register_ddoc("sig1", functions).unwrap();
+    match isolate.map_doc("sig1", "{}") {
+        Err(JSError::RuntimeError(info)) => assert!(info.message.contains("views/lib/nope")),
+        other => panic!("unexpected {:?}", other),
+    }
+
+    assert!(matches!(
+        isolate.register_ddoc("sig2", r#"{"map": [], "lib": "nope"}"#),
+        Err(JSError::CompileError(_))
+    ));
+}
+
+#[test]
+fn views_lib_modules_are_compiled_at_registration() {
+    common::setup();
+    let js_env = JSEnv::new();
+    let mut isolate = js_env.create_isolate();
+
+    let broken = r#"{"map": [], "lib": {"util": "exports.up = function( {"}}"#;
+    assert!(matches!(
+        isolate.register_ddoc("sig1", broken),
+        Err(JSError::CompileError(_))
+    ));
+    assert_eq!(isolate.design_doc_count(), 0);
+
+    // Nested objects are directories and module variables stay in the module
+    let functions = r#"{
+        "map": ["function(doc) { var m = require('views/lib/deep/mod'); emit(m.name, typeof hidden); }"],
+        "lib": {"deep": {"mod": "var hidden = 1; exports.name = module.id; // done"}}
+    }"#;
+    isolate.register_ddoc("sig1", functions).unwrap();
+    let _ = host_functions::take_emitted();
+    isolate.map_doc("sig1", "{}").unwrap();
+    let emitted = host_functions::take_emitted().unwrap();
+    assert_eq!(emitted[0], vec!["[\"views/lib/deep/mod\",\"undefined\"]"]);
+}
@@ -0,0 +1,120 @@
+use fortuna::determinism::{self, Determinism};
+use fortuna::*;
+mod common;
+
+fn apis(source: &str) -> Vec<(String, i32)> {
+    determinism::scan(source)
+        .into_iter()
+        .map(|violation| (violation.api, violation.line))
+        .collect()
+}
+
+#[test]
+fn parse_determinism() {
+    assert_eq!("allow".parse::<Determinism>().unwrap(), Determinism::Allow);
+    assert_eq!("warn".parse::<Determinism>().unwrap(), Determinism::Warn);
+    assert_eq!(
+        "strict".parse::<Determinism>().unwrap(),
+        Determinism::Strict
+    );
+    assert!("sometimes".parse::<Determinism>().is_err());
+}
+
+#[test]
+fn finds_nondeterministic_apis() {
+    let source = "function(doc) {\n\
+                  emit(Math.random(), Date.now());\n\
+                  emit(new Date(), Date());\n\
+                  emit(crypto.getRandomValues(new Uint8Array(4)), performance.now());\n\
+                  }";
+    assert_eq!(
+        apis(source),
+        vec![
+            ("Math.random".to_string(), 2),
+            ("Date.now".to_string(), 2),
+            ("new Date()".to_string(), 3),
+            ("Date()".to_string(), 3),
+            ("crypto.getRandomValues".to_string(), 4),
+            ("performance.now".to_string(), 4),
+        ]
+    );
+    assert_eq!(
+        apis("function(doc) { emit(new Date, null); }"),
+        vec![("new Date()".to_string(), 1)]
+    );
+}
+
+#[test]
+fn ignores_what_isnt_a_use() {
+    let source = r#"function(doc) {
+        // Math.random()
+        /* Date.now() */
+        var s = "Math.random()" + 'Date.now()' + `new Date()`;
+        var re = /Math.random()/g;
+        var half = doc.n / 2 / Date.UTC(2020, 0, 1);
+        emit(new Date(doc.created), doc.Math.random);
+        emit(`${doc.a}Date.now()`, {t: `${Math.round(doc.t)}`});
+    }"#;
+    assert!(apis(source).is_empty());
+
+    // Code in a template's ${} is still looked at
+    assert_eq!(
+        apis("function(doc) { emit(`${Math.random()}`, null); }"),
+        vec![("Math.random".to_string(), 1)]
+    );
+}
+
+#[test]
+fn strict_rejects_nondeterministic_map_functions() {
+    common::setup();
+    let js_env = JSEnv::new();
+    let caps = Capabilities::all().with_determinism(Determinism::Strict);
+    let mut isolate = js_env.create_isolate_with_capabilities(&caps);
+
+    let functions = r#"{"map": ["function(doc) { emit(doc._id, null); }",
+                                "function(doc) {\n emit(Math.random(), null);\n}"]}"#;
+    match isolate.register_ddoc("random", functions) {
+        Err(JSError::CompileError(info)) => {
+            assert_eq!(info.name, "NondeterminismError");
+            assert_eq!(info.message, "map function 1 uses Math.random on line 2");
+            assert_eq!(info.line, 2);
+        }
+        other => panic!("expected a NondeterminismError, got {:?}", other),
+    }
+
+    let functions = r#"{"map": ["function(doc) { emit(doc._id, null); }"]}"#;
+    assert_eq!(isolate.register_ddoc("fine", functions).unwrap(), "true");
+
+    // Reduce functions are checked when they're run
+    let err = isolate
+        .reduce("function(k, v) { return Date.now(); }", "[]", false)
+        .unwrap_err();
+    assert!(matches!(err, JSError::CompileError(info) if info.name == "NondeterminismError"));
+}
+
+#[test]
+fn fixed_clock_allows_dates() {
+    common::setup();
+    let js_env = JSEnv::new();
+    let caps = Capabilities::all()
+        .with_clock(Clock::Fixed(0))
+        .with_determinism(Determinism::Strict);
+    let mut isolate = js_env.create_isolate_with_capabilities(&caps);
+
+    let functions = r#"{"map": ["function(doc) { emit(Date.now(), null); }"]}"#;
+    assert_eq!(isolate.register_ddoc("dated", functions).unwrap(), "true");
+    let functions = r#"{"map": ["function(doc) { emit(Math.random(), null); }"]}"#;
+    assert!(isolate.register_ddoc("random", functions).is_err());
+}
+
+#[test]
+fn allow_and_warn_register_anyway() {
+    common::setup();
+    let js_env = JSEnv::new();
+    let functions = r#"{"map": ["function(doc) { emit(Math.random(), null); }"]}"#;
+    for determinism in &[Determinism::Allow, Determinism::Warn] {
+        let caps = Capabilities::all().with_determinism(*determinism);
+        let mut isolate = js_env.create_isolate_with_capabilities(&caps);
+        assert_eq!(isolate.register_ddoc("random", functions).unwrap(), "true");
+    }
+}
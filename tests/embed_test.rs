@@ -0,0 +1,63 @@
+use serde_json::json;
+use std::time::Duration;
+
+use fortuna::*;
+mod common;
+
+#[tokio::test]
+async fn evals_and_calls() {
+    common::setup();
+    let fortuna = Fortuna::new(FortunaConfig::default()).unwrap();
+
+    assert_eq!(fortuna.eval("1 + 1;").await.unwrap(), json!(2));
+    assert_eq!(fortuna.eval("undefined;").await.unwrap(), json!(null));
+    fortuna
+        .eval("function merge(a, b) { return Object.assign({}, a, b); }")
+        .await
+        .unwrap();
+    let merged = fortuna
+        .call("merge", &[json!({"a": 1}), json!({"b": [true]})])
+        .await
+        .unwrap();
+    assert_eq!(merged, json!({"a": 1, "b": [true]}));
+}
+
+#[tokio::test]
+async fn errors_come_back() {
+    common::setup();
+    let fortuna = Fortuna::new(FortunaConfig::default()).unwrap();
+
+    match fortuna.eval("throw new TypeError('nope');").await {
+        Err(JSError::RuntimeError(info)) => assert_eq!(info.name, "TypeError"),
+        other => panic!("expected a runtime error, got {:?}", other),
+    }
+    assert!(matches!(
+        fortuna.eval("function (").await,
+        Err(JSError::CompileError(_))
+    ));
+    assert!(fortuna.call("missing", &[]).await.is_err());
+}
+
+#[tokio::test]
+async fn timeouts_apply() {
+    common::setup();
+    let fortuna = Fortuna::new(FortunaConfig {
+        timeout: Some(Duration::from_millis(100)),
+        ..FortunaConfig::default()
+    })
+    .unwrap();
+
+    assert!(matches!(
+        fortuna.eval("while (true) {}").await,
+        Err(JSError::Timeout)
+    ));
+}
+
+#[test]
+fn needs_a_worker() {
+    let config = FortunaConfig {
+        workers: 0,
+        ..FortunaConfig::default()
+    };
+    assert!(Fortuna::new(config).is_err());
+}
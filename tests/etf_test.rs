@@ -0,0 +1,33 @@
+#![cfg(feature = "etf")]
+
+use fortuna::etf::encode;
+use serde_json::json;
+
+#[test]
+fn encodes_like_term_to_binary() {
+    assert_eq!(encode(&json!(null)), b"\x83\x77\x04null");
+    assert_eq!(encode(&json!(true)), b"\x83\x77\x04true");
+    assert_eq!(encode(&json!(1)), [131, 97, 1]);
+    assert_eq!(encode(&json!(-1)), [131, 98, 255, 255, 255, 255]);
+    assert_eq!(
+        encode(&json!(4294967296u64)),
+        [131, 110, 5, 0, 0, 0, 0, 0, 1]
+    );
+    assert_eq!(
+        encode(&json!(-4294967296i64)),
+        [131, 110, 5, 1, 0, 0, 0, 0, 1]
+    );
+    assert_eq!(encode(&json!(1.5)), [131, 70, 63, 248, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(encode(&json!("a")), [131, 109, 0, 0, 0, 1, 97]);
+    assert_eq!(encode(&json!([])), [131, 106]);
+    assert_eq!(encode(&json!([1])), [131, 108, 0, 0, 0, 1, 97, 1, 106]);
+}
+
+#[test]
+fn objects_are_ejson() {
+    assert_eq!(encode(&json!({})), [131, 104, 1, 106]);
+    assert_eq!(
+        encode(&json!({"a": 1})),
+        [131, 104, 1, 108, 0, 0, 0, 1, 104, 2, 109, 0, 0, 0, 1, 97, 97, 1, 106]
+    );
+}
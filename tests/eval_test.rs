@@ -10,15 +10,15 @@ fn simple_evals() {
     let mut instance = js_env.create_isolate();
 
     let script = "var x = 2; x;";
-    let result = instance.eval(script, &[]);
+    let result = instance.eval(script, &[]).unwrap();
     assert_eq!(result, "2");
 
     let script = "var y = 3; y;";
-    let result = instance.eval(script, &[]);
+    let result = instance.eval(script, &[]).unwrap();
     assert_eq!(result, "3");
 
     let script = "let my_fn = () => \"hello\"; my_fn();";
-    let result = instance.eval(script, &[]);
+    let result = instance.eval(script, &[]).unwrap();
     assert_eq!(result, "\"hello\"");
 }
 
@@ -30,9 +30,74 @@ fn eval_and_call() {
     let mut instance = js_env.create_isolate();
 
     let script = "function double(x) {return x * 2;};";
-    let result = instance.eval(script, &[]);
+    let result = instance.eval(script, &[]).unwrap();
     assert_eq!(result, "null");
 
-    let call_result = instance.call("double", &["2".to_string()]);
+    let call_result = instance.call("double", &["2".to_string()]).unwrap();
     assert_eq!(call_result, "4");
 }
+
+#[test]
+fn compile_and_runtime_errors() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let mut instance = js_env.create_isolate();
+
+    match instance.eval("var x = ;", &[]) {
+        Err(JSError::CompileError(info)) => assert_eq!(info.name, "SyntaxError"),
+        other => panic!("expected a compile error, got {:?}", other),
+    }
+
+    match instance.eval("\nthrow new TypeError('bad doc');", &[]) {
+        Err(JSError::RuntimeError(info)) => {
+            assert_eq!(info.name, "TypeError");
+            assert_eq!(info.message, "bad doc");
+            assert_eq!(info.line, 2);
+        }
+        other => panic!("expected a runtime error, got {:?}", other),
+    }
+
+    match instance.call("not_defined", &[]) {
+        Err(JSError::RuntimeError(info)) => assert_eq!(info.name, "TypeError"),
+        other => panic!("expected a runtime error, got {:?}", other),
+    }
+
+    // The isolate is still usable afterwards
+    let result = instance.eval("1 + 1;", &[]).unwrap();
+    assert_eq!(result, "2");
+}
+
+#[test]
+fn call_with_json_args() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let mut instance = js_env.create_isolate();
+
+    let script = "function describe(doc, keys) { return [typeof doc, doc._id, keys.length]; };";
+    instance.eval(script, &[]).unwrap();
+
+    let args = vec![
+        "{\"_id\": \"foo\", \"value\": 1}".to_string(),
+        "[1, 2, 3]".to_string(),
+    ];
+    let result = instance.call_json("describe", &args).unwrap();
+    assert_eq!(result, "[\"object\",\"foo\",3]");
+
+    // Strings arrive as strings without any escaping to undo
+    let script = "function echo(s) { return s; };";
+    instance.eval(script, &[]).unwrap();
+    let result = instance
+        .call_json(
+            "echo",
+            &["\"function(doc) { emit(\\\"a\\\", 1); }\"".to_string()],
+        )
+        .unwrap();
+    assert_eq!(result, "\"function(doc) { emit(\\\"a\\\", 1); }\"");
+
+    match instance.call_json("echo", &["{not json".to_string()]) {
+        Err(JSError::RuntimeError(info)) => assert_eq!(info.name, "SyntaxError"),
+        other => panic!("expected a syntax error, got {:?}", other),
+    }
+}
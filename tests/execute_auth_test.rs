@@ -0,0 +1,76 @@
+use fortuna::ateles::js_response::ErrorType;
+use fortuna::auth;
+use fortuna::client::{AtelesClient, ClientConfig};
+use fortuna::test_support::TestServer;
+use fortuna::MakeService;
+mod common;
+
+const TOKEN: &str = "0123456789abcdef-execute";
+
+#[test]
+fn execute_paths_and_token_files() {
+    assert!(auth::is_execute_path("/Ateles/Execute"));
+    assert!(auth::is_execute_path("/Ateles/MapDocsStream"));
+    assert!(auth::is_execute_path("/ws"));
+    assert!(!auth::is_execute_path("/live"));
+    assert!(!auth::is_execute_path("/metrics"));
+    assert!(!auth::is_execute_path("/admin/workers"));
+
+    let path = std::env::temp_dir().join(format!("fortuna-execute-{}", std::process::id()));
+    std::fs::write(&path, format!("  {}  \n", TOKEN)).unwrap();
+    assert_eq!(auth::read_token(&path, "execute").unwrap(), TOKEN);
+    std::fs::write(&path, "\n").unwrap();
+    let err = auth::read_token(&path, "execute").unwrap_err();
+    assert!(err.to_string().starts_with("execute token in"));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn execute_needs_the_token() {
+    common::setup();
+    let make_service = MakeService::new().with_execute_token(TOKEN.to_string());
+    let server = TestServer::start_with(make_service).unwrap();
+
+    let resp = server.client().eval("1 + 1;").await.unwrap();
+    assert_eq!(resp.error_type, ErrorType::Unauthorized as i32);
+    assert_eq!(resp.result, "unauthorized");
+
+    let config = ClientConfig {
+        token: Some("0123456789abcdef-wrong".to_string()),
+        ..ClientConfig::default()
+    };
+    let client = AtelesClient::with_config(&server.url(), config).unwrap();
+    let resp = client.eval("1 + 1;").await.unwrap();
+    assert_eq!(resp.error_type, ErrorType::Unauthorized as i32);
+
+    let config = ClientConfig {
+        token: Some(TOKEN.to_string()),
+        ..ClientConfig::default()
+    };
+    let client = AtelesClient::with_config(&server.url(), config).unwrap();
+    assert_eq!(client.eval("1 + 1;").await.unwrap().result, "2");
+
+    // Refused before the body is read, whatever it is
+    let http = reqwest::Client::new();
+    let resp = http
+        .post(&format!("{}/Ateles/Execute", server.url()))
+        .body("not a JsRequest")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 401);
+    assert_eq!(resp.headers()["www-authenticate"], "Bearer");
+    let resp = http
+        .get(&format!("{}/ws", server.url()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 401);
+
+    let resp = http
+        .get(&format!("{}/live", server.url()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+}
@@ -0,0 +1,13 @@
+use fortuna::ateles::js_response::ErrorType;
+use fortuna::ateles::JsResponse;
+use fortuna::{JSError, FEATURES};
+
+#[test]
+fn unsupported_feature_response() {
+    assert!(!FEATURES.contains(&"streaming"));
+
+    let resp: JsResponse = JSError::UnsupportedFeature("streaming".to_string()).into();
+    assert_eq!(resp.status, 1);
+    assert_eq!(resp.error_type, ErrorType::UnsupportedFeature as i32);
+    assert_eq!(resp.result, "unsupported_feature: streaming");
+}
@@ -0,0 +1,59 @@
+use fortuna::ateles::JsRequest;
+use fortuna::framing::{encode_frame, FrameDecoder};
+use fortuna::JsRequestBuilder;
+
+fn request(script: &str) -> JsRequest {
+    JsRequestBuilder::eval(script).build().unwrap()
+}
+
+#[test]
+fn decodes_frames_split_across_chunks() {
+    let mut stream = encode_frame(&request("1;"));
+    stream.extend(encode_frame(&request(&"x".repeat(300))));
+    stream.extend(encode_frame(&request("3;")));
+
+    let mut decoder = FrameDecoder::new();
+    let mut decoded = Vec::new();
+    for chunk in stream.chunks(7) {
+        decoder.extend(chunk);
+        while let Some(req) = decoder.next_frame::<JsRequest>().unwrap() {
+            decoded.push(req);
+        }
+    }
+
+    assert!(decoder.is_empty());
+    assert_eq!(decoded.len(), 3);
+    assert_eq!(decoded[0].script, "1;");
+    assert_eq!(decoded[1].script.len(), 300);
+    assert_eq!(decoded[2].script, "3;");
+}
+
+#[test]
+fn incomplete_frame_is_left_buffered() {
+    let frame = encode_frame(&request("1;"));
+
+    let mut decoder = FrameDecoder::new();
+    decoder.extend(&frame[..frame.len() - 1]);
+    assert!(decoder.next_frame::<JsRequest>().unwrap().is_none());
+    assert!(!decoder.is_empty());
+}
+
+#[test]
+fn oversized_frame_is_rejected() {
+    let mut decoder = FrameDecoder::new();
+    // varint for 1GB
+    decoder.extend(&[0x80, 0x80, 0x80, 0x80, 0x04]);
+    assert!(decoder.next_frame::<JsRequest>().is_err());
+}
+
+#[test]
+fn frames_over_the_max_len_are_rejected() {
+    let frame = encode_frame(&request("1 + 1;"));
+    let mut decoder = FrameDecoder::with_max_len(frame.len() - 2);
+    decoder.extend(&frame);
+    assert!(decoder.next_frame::<JsRequest>().is_err());
+
+    let mut decoder = FrameDecoder::with_max_len(frame.len());
+    decoder.extend(&frame);
+    assert!(decoder.next_frame::<JsRequest>().unwrap().is_some());
+}
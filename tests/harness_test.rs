@@ -0,0 +1,87 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use fortuna::ateles::js_response::ErrorType;
+use fortuna::harness::HarnessSpec;
+use fortuna::test_support::TestServer;
+use fortuna::*;
+mod common;
+
+// The built in bundle plus a file that says which harness it is
+fn harness_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fortuna-harness-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let js = Path::new(env!("CARGO_MANIFEST_DIR")).join("js");
+    for entry in fs::read_dir(&js).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_file() {
+            fs::copy(&path, dir.join(path.file_name().unwrap())).unwrap();
+        }
+    }
+    fs::write(dir.join("zz_harness.js"), "var harnessName = 'next';\n").unwrap();
+    dir
+}
+
+#[test]
+fn parses_specs() {
+    let spec: HarnessSpec = "next=/opt/harness".parse().unwrap();
+    assert_eq!(spec.name, "next");
+    assert_eq!(spec.dir, PathBuf::from("/opt/harness"));
+    assert!("next".parse::<HarnessSpec>().is_err());
+    assert!("=/opt/harness".parse::<HarnessSpec>().is_err());
+    assert!("next=".parse::<HarnessSpec>().is_err());
+}
+
+#[tokio::test]
+async fn requests_pick_a_harness() {
+    common::setup();
+    let dir = harness_dir();
+    let js_env = JSEnv::from_dir(&dir).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    let server =
+        TestServer::start_with(MakeService::new().with_harness("next", js_env, 1)).unwrap();
+    let client = server.client();
+    let which = |harness: &str| {
+        JsRequestBuilder::eval("typeof harnessName;")
+            .harness(harness)
+            .build()
+            .unwrap()
+    };
+
+    let resp = client.execute(&which("")).await.unwrap();
+    assert_eq!(resp.result, "\"undefined\"");
+    let resp = client.execute(&which("next")).await.unwrap();
+    assert_eq!(resp.result, "\"string\"");
+
+    let resp = client.execute(&which("nope")).await.unwrap();
+    assert_eq!(resp.error_type, ErrorType::InvalidRequest as i32);
+    assert!(
+        resp.result.contains("unknown harness nope"),
+        "{}",
+        resp.result
+    );
+
+    // A session keeps to the harness it was created with
+    let session = |harness: &str| {
+        JsRequestBuilder::eval("typeof harnessName;")
+            .harness(harness)
+            .session("harness-session", Duration::from_secs(60))
+            .build()
+            .unwrap()
+    };
+    let resp = client.execute(&session("next")).await.unwrap();
+    assert!(resp.session_created);
+    assert_eq!(resp.result, "\"string\"");
+    let resp = client.execute(&session("next")).await.unwrap();
+    assert!(!resp.session_created);
+    let resp = client.execute(&session("")).await.unwrap();
+    assert!(resp.session_created);
+    assert_eq!(resp.result, "\"undefined\"");
+
+    let url = format!("{}/admin/harnesses", server.url());
+    let body = reqwest::get(&url).await.unwrap().text().await.unwrap();
+    let listed: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(listed["harnesses"][0]["name"], "next");
+    assert_eq!(listed["harnesses"][0]["workers"], 1);
+}
@@ -0,0 +1,71 @@
+use serde_json::Value;
+
+use fortuna::js_server::{Command, Ops};
+use fortuna::test_support::TestServer;
+use fortuna::*;
+mod common;
+
+#[test]
+fn snapshots_an_isolate() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let mut instance = js_env.create_isolate();
+    instance
+        .eval(
+            "var leak = []; for (let i = 0; i < 100; i++) { leak.push({ leaked: i }); } 1;",
+            &[],
+        )
+        .unwrap();
+
+    let mut chunks = Vec::new();
+    instance.heap_snapshot(|chunk| chunks.push(chunk)).unwrap();
+    assert!(!chunks.is_empty());
+    let snapshot: Value = serde_json::from_str(&chunks.concat()).unwrap();
+    assert!(snapshot["snapshot"]["meta"].is_object());
+    assert!(snapshot["nodes"].is_array());
+    let strings = snapshot["strings"].as_array().unwrap();
+    assert!(strings.iter().any(|s| s == "leaked"));
+
+    // The isolate carries on as before
+    assert_eq!(instance.eval("leak.length;", &[]).unwrap(), "100");
+}
+
+#[tokio::test]
+async fn heap_snapshot_endpoint() {
+    common::setup();
+
+    let server = TestServer::start().unwrap();
+    let js_env = JSEnv::new();
+    let js_client = create_js_env(&js_env, &Capabilities::none());
+    js_client
+        .run(Command {
+            operation: Ops::EVAL,
+            payload: "true;".to_string(),
+            args: vec![],
+            docs: Vec::new(),
+        })
+        .await
+        .unwrap();
+
+    let url = format!(
+        "{}/admin/heap_snapshot?worker={}",
+        server.url(),
+        js_client.id()
+    );
+    let resp = reqwest::get(&url).await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let disposition = resp.headers()["content-disposition"].to_str().unwrap();
+    assert!(disposition.contains(&format!("worker-{}.heapsnapshot", js_client.id())));
+    let snapshot: Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    assert!(snapshot["nodes"].is_array());
+
+    let resp = reqwest::get(&format!("{}/admin/heap_snapshot", server.url()))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+
+    let url = format!("{}/admin/heap_snapshot?worker={}", server.url(), usize::MAX);
+    let resp = reqwest::get(&url).await.unwrap();
+    assert_eq!(resp.status(), 404);
+}
@@ -0,0 +1,58 @@
+use std::time::Duration;
+
+use fortuna::test_support::TestServer;
+use fortuna::*;
+mod common;
+
+fn h2c() -> reqwest::Client {
+    reqwest::Client::builder()
+        .http2_prior_knowledge()
+        .build()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn serves_http2_with_prior_knowledge() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+
+    let resp = h2c()
+        .get(&format!("{}/ready", server.url()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.version(), reqwest::Version::HTTP_2);
+    assert_eq!(resp.status(), 200);
+
+    // HTTP/1.1 still works alongside
+    let resp = reqwest::get(&format!("{}/ready", server.url()))
+        .await
+        .unwrap();
+    assert_eq!(resp.version(), reqwest::Version::HTTP_11);
+}
+
+#[tokio::test]
+async fn multiplexes_requests_on_one_connection() {
+    common::setup();
+    let config = Http2Config {
+        only: true,
+        initial_stream_window: Some(1024 * 1024),
+        initial_connection_window: Some(4 * 1024 * 1024),
+        max_concurrent_streams: Some(16),
+        keep_alive_interval: Some(Duration::from_secs(5)),
+        keep_alive_timeout: Duration::from_secs(5),
+    };
+    let server = TestServer::start_with(MakeService::new().with_http2(config)).unwrap();
+
+    let client = h2c();
+    let url = format!("{}/ready", server.url());
+    let requests = (0..32).map(|_| client.get(&url).send());
+    for resp in futures::future::join_all(requests).await {
+        let resp = resp.unwrap();
+        assert_eq!(resp.version(), reqwest::Version::HTTP_2);
+        assert_eq!(resp.status(), 200);
+    }
+
+    // Only HTTP/2 is spoken
+    assert!(reqwest::get(&url).await.is_err());
+}
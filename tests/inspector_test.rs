@@ -0,0 +1,145 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+
+use fortuna::inspector::{self, InspectConfig};
+use fortuna::js_server::{Command, Ops};
+use fortuna::*;
+mod common;
+
+fn get(addr: SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, addr).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+fn connect(addr: SocketAddr) -> TcpStream {
+    let mut ws = TcpStream::connect(addr).unwrap();
+    write!(
+        ws,
+        "GET /ws HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        addr
+    )
+    .unwrap();
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        ws.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap();
+    assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+    assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGuzvI5+0oo0Q="));
+    ws
+}
+
+// Clients mask what they send
+fn send(ws: &mut TcpStream, text: &str) {
+    let mask = [1u8, 2, 3, 4];
+    let mut frame = vec![0x81];
+    if text.len() < 126 {
+        frame.push(0x80 | text.len() as u8);
+    } else {
+        frame.push(0x80 | 126);
+        frame.extend_from_slice(&(text.len() as u16).to_be_bytes());
+    }
+    frame.extend_from_slice(&mask);
+    frame.extend(text.bytes().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    ws.write_all(&frame).unwrap();
+}
+
+fn receive(ws: &mut TcpStream) -> String {
+    let mut head = [0u8; 2];
+    ws.read_exact(&mut head).unwrap();
+    let len = match head[1] & 0x7f {
+        126 => {
+            let mut len = [0u8; 2];
+            ws.read_exact(&mut len).unwrap();
+            u16::from_be_bytes(len) as usize
+        }
+        127 => {
+            let mut len = [0u8; 8];
+            ws.read_exact(&mut len).unwrap();
+            u64::from_be_bytes(len) as usize
+        }
+        len => len as usize,
+    };
+    let mut payload = vec![0u8; len];
+    ws.read_exact(&mut payload).unwrap();
+    String::from_utf8(payload).unwrap()
+}
+
+fn receive_until(ws: &mut TcpStream, needle: &str) -> String {
+    loop {
+        let message = receive(ws);
+        if message.contains(needle) {
+            return message;
+        }
+    }
+}
+
+#[test]
+fn accept_key() {
+    assert_eq!(
+        inspector::accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+        "s3pPLMBiTxaQ9kYGuzvI5+0oo0Q="
+    );
+}
+
+// Only this test starts workers so the one it starts is worker 1
+#[tokio::test]
+async fn debugs_a_worker() {
+    common::setup();
+
+    let addr = inspector::start(&InspectConfig {
+        addr: "127.0.0.1:0".parse().unwrap(),
+        worker: 1,
+    })
+    .unwrap();
+    assert_eq!(
+        inspector::websocket_url(),
+        Some(format!("ws://{}/ws", addr))
+    );
+
+    let version = get(addr, "/json/version");
+    assert!(version.starts_with("HTTP/1.1 200"));
+    assert!(version.contains("\"V8-Version\""));
+    let list = get(addr, "/json/list");
+    assert!(list.contains(&format!("\"webSocketDebuggerUrl\":\"ws://{}/ws\"", addr)));
+    assert!(get(addr, "/nope").starts_with("HTTP/1.1 404"));
+
+    let js_env = JSEnv::new();
+    let client = create_js_env(&js_env, &Capabilities::all());
+    assert_eq!(client.id(), 1);
+
+    let mut ws = connect(addr);
+    send(
+        &mut ws,
+        r#"{"id":1,"method":"Runtime.evaluate","params":{"expression":"1 + 1"}}"#,
+    );
+    let reply = receive_until(&mut ws, r#""id":1"#);
+    assert!(reply.contains(r#""value":2"#), "{}", reply);
+    send(&mut ws, r#"{"id":2,"method":"Debugger.enable"}"#);
+    receive_until(&mut ws, r#""id":2"#);
+
+    // The worker stops at the debugger statement until DevTools resumes it
+    let devtools = thread::spawn(move || {
+        let paused = receive_until(&mut ws, "Debugger.paused");
+        send(&mut ws, r#"{"id":3,"method":"Debugger.resume"}"#);
+        receive_until(&mut ws, r#""id":3"#);
+        paused
+    });
+    let result = client
+        .run(Command {
+            operation: Ops::EVAL,
+            payload: "debugger; 42;".to_string(),
+            args: vec![],
+            docs: Vec::new(),
+        })
+        .await;
+    assert_eq!(result.unwrap(), "42");
+    let paused = devtools.join().unwrap();
+    assert!(paused.contains(r#""callFrames""#), "{}", paused);
+}
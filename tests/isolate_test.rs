@@ -0,0 +1,76 @@
+use fortuna::*;
+mod common;
+
+#[test]
+fn create_and_drop_many_isolates() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    for _ in 0..2000 {
+        let instance = js_env.create_isolate();
+        drop(instance);
+    }
+}
+
+#[test]
+fn drop_isolate_after_use() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    for i in 0..200 {
+        let mut instance = js_env.create_isolate();
+        let script = format!("var x = {}; x;", i);
+        let result = instance.eval(script.as_str(), &[]).unwrap();
+        assert_eq!(result, i.to_string());
+    }
+}
+
+#[test]
+fn concurrent_snapshot_creation() {
+    common::setup();
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            std::thread::spawn(|| {
+                let js_env = JSEnv::new();
+                let mut instance = js_env.create_isolate();
+                instance.eval("1 + 1;", &[]).unwrap()
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        assert_eq!(handle.join().unwrap(), "2");
+    }
+}
+
+#[test]
+fn gc_pauses_are_logged() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let mut instance = js_env.create_isolate();
+
+    // Enough short lived garbage to force a few scavenges
+    let script = "let keep = 0; \
+                  for (let i = 0; i < 200000; i++) { keep += [i, {i: i}].length; } \
+                  keep;";
+    instance.eval(script, &[]).unwrap();
+
+    let recent = instance.gc_log().recent();
+    assert!(!recent.is_empty());
+    assert!(recent.len() <= 32);
+}
+
+#[test]
+fn bundle_is_complete() {
+    common::setup();
+
+    assert!(check_bundle().is_ok());
+    let names: Vec<&str> = bundle_manifest().iter().map(|file| file.name).collect();
+    for required in &["escodegen.js", "esprima.js", "rewrite_anon_fun.js"] {
+        assert!(names.contains(required), "{} not in {:?}", required, names);
+    }
+
+    assert!(JSEnv::try_new().is_ok());
+}
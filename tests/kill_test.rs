@@ -0,0 +1,136 @@
+use std::time::{Duration, Instant};
+
+use fortuna::js_server::{self, Command, JobInfo, Ops};
+use fortuna::test_support::TestServer;
+use fortuna::*;
+mod common;
+
+fn eval(payload: &str) -> Command {
+    Command {
+        operation: Ops::EVAL,
+        payload: payload.to_string(),
+        args: vec![],
+        docs: Vec::new(),
+    }
+}
+
+// Waits for worker's jobs to get into the given states, oldest first
+async fn wait_for(worker: usize, running: &[bool]) -> Vec<JobInfo> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let jobs: Vec<_> = js_server::jobs()
+            .into_iter()
+            .filter(|job| job.worker == worker)
+            .collect();
+        if jobs
+            .iter()
+            .map(|job| job.running)
+            .eq(running.iter().cloned())
+        {
+            return jobs;
+        }
+        assert!(Instant::now() < deadline, "jobs are {:?}", jobs);
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn kills_running_and_queued_commands() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let js_client = create_js_env(&js_env, &Capabilities::none());
+    let worker = js_client.id();
+
+    let stuck = tokio::spawn({
+        let js_client = js_client.clone();
+        async move { js_client.run(eval("while (true) {}")).await }
+    });
+    wait_for(worker, &[true]).await;
+    let queued = tokio::spawn({
+        let js_client = js_client.clone();
+        async move { js_client.run(eval("1 + 1;")).await }
+    });
+    let jobs = wait_for(worker, &[true, false]).await;
+    assert_eq!(jobs[0].op, "eval");
+
+    assert!(js_server::kill_job(jobs[1].id));
+    assert!(js_server::kill_job(jobs[0].id));
+    assert!(matches!(stuck.await.unwrap(), Err(JSError::Killed)));
+    assert!(matches!(queued.await.unwrap(), Err(JSError::Killed)));
+
+    // Gone once they're done, and the worker carries on
+    wait_for(worker, &[]).await;
+    assert!(!js_server::kill_job(jobs[0].id));
+    assert_eq!(js_client.run(eval("1 + 1;")).await.unwrap(), "2");
+}
+
+#[tokio::test]
+async fn requests_are_listed_and_killed_over_http() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let url = server.url();
+
+    let stuck = tokio::spawn({
+        let client = server.client();
+        async move { client.eval("while (true) {}").await }
+    });
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let request = loop {
+        let resp = reqwest::get(&format!("{}/admin/requests", url))
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+        let running = body["requests"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|request| request["state"] == "running")
+            .cloned();
+        if let Some(request) = running {
+            break request;
+        }
+        assert!(Instant::now() < deadline);
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    };
+    assert_eq!(request["op"], "eval");
+    assert!(request["worker"].is_u64());
+    assert!(request["age_us"].is_u64());
+
+    let http = reqwest::Client::new();
+    let kill = format!("{}/admin/requests/{}/kill", url, request["id"]);
+    let resp = http.post(&kill).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let resp = stuck.await.unwrap().unwrap();
+    assert_eq!(resp.result, "killed");
+
+    let resp = http.post(&kill).send().await.unwrap();
+    assert_eq!(resp.status(), 404);
+}
+
+#[tokio::test]
+async fn dropped_commands_are_stopped_or_skipped() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let js_client = create_js_env(&js_env, &Capabilities::none());
+    let worker = js_client.id();
+
+    // Both callers give up, one while its command runs and one while it's
+    // still queued behind it
+    let timeout = Duration::from_millis(100);
+    let stuck = js_client.run_timeout(eval("while (true) {}"), timeout);
+    let queued = js_client.run_timeout(eval("var ran = true;"), timeout);
+    let (stuck, queued) = futures::join!(stuck, queued);
+    assert!(matches!(stuck, Err(JSError::Timeout)));
+    assert!(matches!(queued, Err(JSError::Timeout)));
+
+    // The queued one never ran and nothing is left to terminate the next
+    wait_for(worker, &[]).await;
+    for _ in 0..10 {
+        assert_eq!(
+            js_client.run(eval("typeof ran;")).await.unwrap(),
+            "\"undefined\""
+        );
+    }
+}
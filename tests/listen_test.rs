@@ -0,0 +1,54 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use fortuna::listen::{bind, bind_reuse_port, family, peer_addr, Bind};
+
+#[test]
+fn mapped_ipv4_peers_are_reported_as_ipv4() {
+    let mapped: SocketAddr = "[::ffff:10.0.0.1]:5984".parse().unwrap();
+    assert_eq!(peer_addr(mapped), "10.0.0.1:5984".parse().unwrap());
+    assert_eq!(family(&peer_addr(mapped)), "ipv4");
+
+    let v6: SocketAddr = "[::1]:5984".parse().unwrap();
+    assert_eq!(peer_addr(v6), v6);
+    assert_eq!(family(&v6), "ipv6");
+}
+
+#[test]
+fn bind_ipv4_and_ipv6() {
+    let listener = bind(&"127.0.0.1:0".parse().unwrap(), false).unwrap();
+    assert!(listener.local_addr().unwrap().is_ipv4());
+
+    // Not every host has IPv6 configured
+    if let Ok(listener) = bind(&"[::1]:0".parse().unwrap(), true) {
+        assert!(listener.local_addr().unwrap().is_ipv6());
+    }
+}
+
+#[test]
+fn reuse_port_binds_one_port_many_times() {
+    let listeners = bind_reuse_port(&"127.0.0.1:0".parse().unwrap(), false, 4).unwrap();
+    assert_eq!(listeners.len(), 4);
+    let addr = listeners[0].local_addr().unwrap();
+    assert_ne!(addr.port(), 0);
+    assert!(listeners
+        .iter()
+        .all(|listener| listener.local_addr().unwrap() == addr));
+
+    // Without SO_REUSEPORT the port is taken
+    assert!(bind(&addr, false).is_err());
+}
+
+#[test]
+fn parse_binds() {
+    assert_eq!(
+        "[::1]:8444".parse::<Bind>().unwrap(),
+        Bind::Tcp("[::1]:8444".parse().unwrap())
+    );
+    assert_eq!(
+        "unix:/tmp/fortuna.sock".parse::<Bind>().unwrap(),
+        Bind::Unix(PathBuf::from("/tmp/fortuna.sock"))
+    );
+    assert!("unix:".parse::<Bind>().is_err());
+    assert!("localhost".parse::<Bind>().is_err());
+}
@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use fortuna::mirror::{self, Mirror};
+use fortuna::{JSError, JsRequestBuilder, JsResponseBuilder};
+
+fn corpus_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("fortuna-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn mirrors_hashes_and_sizes() {
+    let dir = corpus_dir("mirror-hashes");
+    let mirror = Mirror::start(&dir, 1.0, false).unwrap();
+    let path = mirror.path().to_path_buf();
+
+    let request = JsRequestBuilder::call("mapDoc")
+        .json_arg("{\"_id\": \"secret\"}")
+        .session("indexer", Duration::from_secs(60))
+        .build()
+        .unwrap();
+    let response = JsResponseBuilder::ok("true").build();
+    assert!(mirror.sampled());
+    mirror.record(&request, &response, Duration::from_millis(3));
+    let failed = JsResponseBuilder::error(JSError::Timeout).build();
+    mirror.record(&request, &failed, Duration::from_millis(5));
+    // Waits for everything to be written
+    drop(mirror);
+
+    let records = mirror::read(&path).unwrap();
+    assert_eq!(records.len(), 2);
+    let record = &records[0];
+    assert_eq!(record.action, request.action);
+    assert_eq!(record.script_bytes, "mapDoc".len());
+    assert_eq!(record.args, 1);
+    assert!(record.json_args);
+    assert!(record.session_sha256.is_some());
+    assert_eq!(record.took_us, 3000);
+    assert_eq!(record.status, 0);
+    assert_ne!(records[1].status, 0);
+    // Nothing that was sent is kept
+    assert!(record.request().is_none());
+    let corpus = std::fs::read_to_string(&path).unwrap();
+    assert!(!corpus.contains("secret"));
+    assert!(!corpus.contains("indexer"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn mirrors_payloads_for_replay() {
+    let dir = corpus_dir("mirror-payloads");
+    let mirror = Mirror::start(&dir, 1.0, true).unwrap();
+    let path = mirror.path().to_path_buf();
+
+    let request = JsRequestBuilder::eval("1 + 1;")
+        .timeout(Duration::from_secs(5))
+        .build()
+        .unwrap();
+    mirror.record(
+        &request,
+        &JsResponseBuilder::ok("2").build(),
+        Duration::default(),
+    );
+    drop(mirror);
+
+    let records = mirror::read(&path).unwrap();
+    assert_eq!(records[0].request(), Some(request));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn mirrors_a_sample() {
+    let dir = corpus_dir("mirror-sample");
+    let mirror = Mirror::start(&dir, 0.25, false).unwrap();
+    let sampled = (0..100).filter(|_| mirror.sampled()).count();
+    assert_eq!(sampled, 25);
+    drop(mirror);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
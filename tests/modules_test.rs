@@ -0,0 +1,48 @@
+use fortuna::*;
+mod common;
+
+const UTIL_JS: &str = "export function double(x) { return x * 2; }";
+
+const MAIN_JS: &str = r#"
+import { double } from "./util.js";
+
+export function quadruple(x) {
+    return double(double(x));
+}
+"#;
+
+#[test]
+fn modules_import_each_other_and_export_globals() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let mut instance = js_env.create_isolate();
+
+    instance
+        .load_modules(&[("main.js", MAIN_JS), ("util.js", UTIL_JS)])
+        .unwrap();
+
+    let result = instance.call("quadruple", &["3".to_string()]).unwrap();
+    assert_eq!(result, "12");
+    let result = instance.eval("typeof double;", &[]).unwrap();
+    assert_eq!(result, "\"function\"");
+}
+
+#[test]
+fn module_errors() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let mut instance = js_env.create_isolate();
+
+    match instance.load_modules(&[("bad.js", "export function {")]) {
+        Err(JSError::CompileError(info)) => assert!(info.message.contains("bad.js")),
+        other => panic!("expected a compile error, got {:?}", other),
+    }
+
+    let missing = "import { nope } from \"./missing.js\"; export const x = 1;";
+    match instance.load_modules(&[("main.js", missing)]) {
+        Err(JSError::RuntimeError(info)) => assert!(info.message.contains("missing.js")),
+        other => panic!("expected a runtime error, got {:?}", other),
+    }
+}
@@ -0,0 +1,159 @@
+use prost::Message;
+use serde_json::json;
+use std::time::Duration;
+
+use fortuna::ateles::js_request::ResultEncoding;
+use fortuna::ateles::js_response::ErrorType;
+use fortuna::ateles::JsResponse;
+use fortuna::msgpack::{decode, encode};
+use fortuna::test_support::TestServer;
+use fortuna::{accepted_result_encoding, supports, JsRequestBuilder};
+mod common;
+
+#[test]
+fn encodes_the_smallest_types() {
+    assert_eq!(encode(&json!(null)), [0xc0]);
+    assert_eq!(encode(&json!(false)), [0xc2]);
+    assert_eq!(encode(&json!(true)), [0xc3]);
+    assert_eq!(encode(&json!(1)), [0x01]);
+    assert_eq!(encode(&json!(200)), [0xcc, 200]);
+    assert_eq!(encode(&json!(65536)), [0xce, 0, 1, 0, 0]);
+    assert_eq!(encode(&json!(-1)), [0xff]);
+    assert_eq!(encode(&json!(-33)), [0xd0, 0xdf]);
+    assert_eq!(encode(&json!(-129)), [0xd1, 0xff, 0x7f]);
+    assert_eq!(encode(&json!(1.5)), [0xcb, 63, 248, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(encode(&json!("a")), [0xa1, b'a']);
+    assert_eq!(encode(&json!([])), [0x90]);
+    assert_eq!(encode(&json!([1, "a"])), [0x92, 0x01, 0xa1, b'a']);
+    assert_eq!(encode(&json!({"a": 1})), [0x81, 0xa1, b'a', 0x01]);
+
+    let long = "x".repeat(40);
+    let encoded = encode(&json!(long));
+    assert_eq!(&encoded[..2], [0xd9, 40]);
+    assert_eq!(encoded.len(), 42);
+}
+
+#[test]
+fn decodes_what_it_encodes() {
+    let value = json!({
+        "_id": "doc",
+        "n": [0, 127, 128, 65535, 4294967296u64, -32, -200, -70000, -4294967296i64],
+        "f": 0.25,
+        "nested": {"ok": true, "none": null, "text": "y".repeat(300)},
+        "many": (0..20).collect::<Vec<_>>(),
+    });
+    assert_eq!(decode(&encode(&value)).unwrap(), value);
+
+    // float 32 and str 16 from other encoders
+    assert_eq!(decode(&[0xca, 0x3f, 0xc0, 0, 0]).unwrap(), json!(1.5));
+    assert_eq!(decode(&[0xda, 0, 1, b'a']).unwrap(), json!("a"));
+}
+
+#[test]
+fn rejects_what_json_cant_hold() {
+    // bin, ext and an integer map key
+    assert!(decode(&[0xc4, 1, 0]).is_err());
+    assert!(decode(&[0xd4, 1, 0]).is_err());
+    assert!(decode(&[0x81, 0x01, 0x01]).is_err());
+    // NaN
+    assert!(decode(&[0xcb, 0x7f, 0xf8, 0, 0, 0, 0, 0, 0]).is_err());
+    // Truncated, left over bytes and invalid UTF-8
+    assert!(decode(&[0x92, 0x01]).is_err());
+    assert!(decode(&[0x01, 0x01]).is_err());
+    assert!(decode(&[0xa1, 0xff]).is_err());
+    // Too deep
+    assert!(decode(&vec![0x91; 1000]).is_err());
+}
+
+#[tokio::test]
+async fn args_and_results_as_msgpack() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let client = server.client();
+    let session = |builder: JsRequestBuilder| {
+        builder
+            .session("args_and_results_as_msgpack", Duration::default())
+            .build()
+            .unwrap()
+    };
+
+    let define = JsRequestBuilder::eval(
+        "function total(doc) { emit(doc._id, doc.n.length); return {sum: doc.n[0] + doc.n[1]}; }",
+    );
+    assert_eq!(client.execute(&session(define)).await.unwrap().status, 0);
+
+    let doc = encode(&json!({"_id": "a", "n": [1, 2]}));
+    let call = JsRequestBuilder::call("total")
+        .msgpack_arg(&doc)
+        .result_encoding(ResultEncoding::Msgpack);
+    let resp = client.execute(&session(call)).await.unwrap();
+    assert_eq!(resp.status, 0, "{}", resp.result);
+    assert_eq!(resp.result, "");
+    assert_eq!(decode(&resp.result_msgpack).unwrap(), json!({"sum": 3}));
+    let rows = &resp.emitted[0].msgpack_rows;
+    assert_eq!(decode(&rows[0]).unwrap(), json!(["a", 2]));
+
+    let invalid = JsRequestBuilder::call("total").msgpack_arg(&[0xc1]);
+    let resp = client.execute(&session(invalid)).await.unwrap();
+    assert_eq!(resp.error_type, ErrorType::InvalidRequest as i32);
+}
+
+#[test]
+fn builder_checks_msgpack_args() {
+    let doc = encode(&json!({}));
+    assert!(JsRequestBuilder::call("f")
+        .msgpack_arg(&doc)
+        .build()
+        .is_ok());
+    assert!(JsRequestBuilder::call("f")
+        .json_arg("{}")
+        .msgpack_arg(&doc)
+        .build()
+        .is_err());
+    assert!(JsRequestBuilder::eval("1;")
+        .msgpack_arg(&doc)
+        .build()
+        .is_err());
+}
+
+#[test]
+fn result_encoding_from_accept() {
+    assert_eq!(
+        accepted_result_encoding("application/msgpack"),
+        Some(ResultEncoding::Msgpack)
+    );
+    assert_eq!(
+        accepted_result_encoding("application/json;q=0.5, application/x-msgpack"),
+        Some(ResultEncoding::Msgpack)
+    );
+    assert_eq!(
+        accepted_result_encoding("application/msgpack;q=0.2, application/json"),
+        Some(ResultEncoding::Json)
+    );
+    assert_eq!(accepted_result_encoding("application/msgpack;q=0"), None);
+    assert_eq!(accepted_result_encoding("*/*"), None);
+    // Only offered by servers built with the etf feature
+    assert_eq!(
+        accepted_result_encoding("application/x-erlang-binary").is_some(),
+        supports("etf")
+    );
+}
+
+#[tokio::test]
+async fn results_as_msgpack_by_accept_header() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let eval = JsRequestBuilder::eval("({n: 1});").build().unwrap();
+    let mut body = Vec::new();
+    eval.encode(&mut body).unwrap();
+    let resp = reqwest::Client::new()
+        .post(&format!("{}/Ateles/Execute", server.url()))
+        .header("accept", "application/msgpack")
+        .body(body)
+        .send()
+        .await
+        .unwrap();
+    let js_resp = JsResponse::decode(resp.bytes().await.unwrap()).unwrap();
+    assert_eq!(js_resp.status, 0, "{}", js_resp.result);
+    assert_eq!(decode(&js_resp.result_msgpack).unwrap(), json!({"n": 1}));
+}
@@ -0,0 +1,145 @@
+use std::thread;
+use std::time::Duration;
+
+use futures::executor::block_on;
+
+use fortuna::js_server::{Command, Ops};
+use fortuna::*;
+mod common;
+
+fn eval(script: &str) -> Command {
+    Command {
+        operation: Ops::EVAL,
+        payload: script.to_string(),
+        args: vec![],
+        docs: Vec::new(),
+    }
+}
+
+fn call(fun: &str) -> Command {
+    Command {
+        operation: Ops::CALL,
+        payload: fun.to_string(),
+        args: vec![],
+        docs: Vec::new(),
+    }
+}
+
+#[test]
+fn pinned_worker_keeps_state() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let pool = WorkerPool::new(&js_env, &Capabilities::none(), 2);
+    assert_eq!(pool.size(), 2);
+
+    let pinned = pool.next();
+    let result = block_on(pinned.run(eval("var seen = 42; function get_seen() { return seen; }")));
+    assert!(result.is_ok());
+
+    for _ in 0..4 {
+        assert_eq!(block_on(pinned.run(call("get_seen"))).unwrap(), "42");
+    }
+
+    // The next worker in the pool never saw the EVAL
+    let other = pool.next();
+    assert!(block_on(other.run(call("get_seen"))).is_err());
+}
+
+#[test]
+fn idle_workers_are_torn_down() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let config = PoolConfig {
+        min_size: 1,
+        max_size: 2,
+        idle_ttl: Duration::from_millis(10),
+    };
+    let pool = WorkerPool::with_config(&js_env, &Capabilities::none(), config);
+    assert_eq!(pool.size(), 1);
+
+    // A second worker is only started while the first one is busy
+    let busy = pool.next();
+    assert_eq!(pool.size(), 1);
+    let running = thread::spawn(move || {
+        let script = "let start = Date.now(); while (Date.now() - start < 500) {} 1;";
+        block_on(busy.run(eval(script)))
+    });
+    thread::sleep(Duration::from_millis(100));
+    let extra = pool.next();
+    assert_eq!(pool.size(), 2);
+    assert_eq!(block_on(extra.run(eval("2;"))).unwrap(), "2");
+    assert_eq!(running.join().unwrap().unwrap(), "1");
+
+    thread::sleep(Duration::from_millis(20));
+    assert_eq!(pool.hibernate(), 1);
+    assert_eq!(pool.size(), 1);
+
+    // The warm worker is never torn down
+    thread::sleep(Duration::from_millis(20));
+    assert_eq!(pool.hibernate(), 0);
+    assert_eq!(block_on(pool.next().run(eval("3;"))).unwrap(), "3");
+}
+
+#[test]
+fn streams_stay_on_one_worker() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let pool = WorkerPool::new(&js_env, &Capabilities::none(), 4);
+
+    let worker = pool.for_key("index-1");
+    block_on(worker.run(eval("var seen = 42; function get_seen() { return seen; }"))).unwrap();
+    for _ in 0..8 {
+        let worker = pool.for_key("index-1");
+        assert_eq!(block_on(worker.run(call("get_seen"))).unwrap(), "42");
+    }
+    assert_eq!(pool.for_key("index-1").id(), worker.id());
+}
+
+#[test]
+fn pools_warm_all_their_workers() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let pool = WorkerPool::new(&js_env, &Capabilities::none(), 4);
+    assert_eq!(block_on(pool.warm()), Ok(4));
+    assert_eq!(pool.size(), 4);
+}
+
+#[test]
+fn parses_affinities() {
+    assert_eq!("connection".parse(), Ok(Affinity::Connection));
+    assert_eq!("request".parse(), Ok(Affinity::Request));
+    assert_eq!("stream".parse(), Ok(Affinity::Stream));
+    assert!("worker".parse::<Affinity>().is_err());
+}
+
+#[tokio::test]
+async fn stream_keys_keep_requests_in_order() {
+    common::setup();
+
+    let make_service = MakeService::new().with_pool(4, Affinity::Stream);
+    let server = test_support::TestServer::start_with(make_service).unwrap();
+    let client = server.client();
+
+    let setup = JsRequestBuilder::eval(
+        "var calls = []; function record(n) { calls.push(n); return calls; }",
+    )
+    .stream_key("index-1")
+    .build()
+    .unwrap();
+    assert_eq!(client.execute(&setup).await.unwrap().status, 0);
+
+    for n in 1..=5 {
+        let request = JsRequestBuilder::call("record")
+            .json_arg(&n.to_string())
+            .stream_key("index-1")
+            .build()
+            .unwrap();
+        let js_resp = client.execute(&request).await.unwrap();
+        let expected: Vec<String> = (1..=n).map(|i| i.to_string()).collect();
+        assert_eq!(js_resp.result, format!("[{}]", expected.join(",")));
+    }
+}
@@ -0,0 +1,141 @@
+use serde_json::{json, Value};
+use std::time::Duration;
+
+use fortuna::js_server::{Command, Ops};
+use fortuna::profile::{self, merge, parse_duration};
+use fortuna::test_support::TestServer;
+use fortuna::*;
+mod common;
+
+#[test]
+fn durations() {
+    assert_eq!(parse_duration("10s"), Ok(Duration::from_secs(10)));
+    assert_eq!(parse_duration("10"), Ok(Duration::from_secs(10)));
+    assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+    assert_eq!(parse_duration("250us"), Ok(Duration::from_micros(250)));
+    assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+    assert!(parse_duration("").is_err());
+    assert!(parse_duration("10h").is_err());
+    assert!(parse_duration("s").is_err());
+}
+
+fn node(id: i64, name: &str, children: &[i64]) -> Value {
+    json!({
+        "id": id,
+        "callFrame": { "functionName": name, "scriptId": "1", "url": "", "lineNumber": 0, "columnNumber": 0 },
+        "hitCount": 1,
+        "children": children,
+    })
+}
+
+#[test]
+fn merges_workers() {
+    let first = json!({
+        "nodes": [node(1, "(root)", &[2]), node(2, "map", &[])],
+        "startTime": 100,
+        "endTime": 200,
+        "samples": [2, 1],
+        "timeDeltas": [10, 20],
+    });
+    let second = json!({
+        "nodes": [node(1, "(root)", &[2, 3]), node(2, "reduce", &[]), node(3, "filter", &[])],
+        "startTime": 105,
+        "endTime": 250,
+        "samples": [3],
+        "timeDeltas": [10],
+    });
+    let merged = merge(vec![(1, first), (2, second)]);
+
+    let nodes = merged["nodes"].as_array().unwrap();
+    assert_eq!(nodes.len(), 6);
+    let ids: Vec<_> = nodes
+        .iter()
+        .map(|node| node["id"].as_i64().unwrap())
+        .collect();
+    assert_eq!(ids, vec![1, 2, 3, 4, 5, 6]);
+    assert_eq!(nodes[0]["children"], json!([2, 4]));
+    assert_eq!(nodes[1]["callFrame"]["functionName"], "(worker 1)");
+    assert_eq!(nodes[3]["callFrame"]["functionName"], "(worker 2)");
+    assert_eq!(nodes[3]["children"], json!([5, 6]));
+
+    assert_eq!(merged["startTime"], 100);
+    assert_eq!(merged["endTime"], 250);
+    // At 110 and 130 on the first, 115 on the second
+    assert_eq!(merged["samples"], json!([3, 6, 2]));
+    assert_eq!(merged["timeDeltas"], json!([10, 5, 15]));
+}
+
+#[test]
+fn profiles_an_isolate() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let mut instance = js_env.create_isolate();
+    assert!(instance.stop_profiling().is_err());
+
+    instance
+        .start_profiling(Duration::from_micros(100))
+        .unwrap();
+    assert!(instance
+        .start_profiling(Duration::from_micros(100))
+        .is_err());
+    let script = "function spin() { let x = 0; for (let i = 0; i < 5e6; i++) { x += i % 7; } return x; } spin();";
+    instance.eval(script, &[]).unwrap();
+    let profile: Value = serde_json::from_str(&instance.stop_profiling().unwrap()).unwrap();
+
+    let nodes = profile["nodes"].as_array().unwrap();
+    assert!(nodes
+        .iter()
+        .any(|node| node["callFrame"]["functionName"] == "spin"));
+    assert!(!profile["samples"].as_array().unwrap().is_empty());
+    assert!(instance.stop_profiling().is_err());
+}
+
+#[tokio::test]
+async fn profile_endpoint() {
+    common::setup();
+
+    let server = TestServer::start().unwrap();
+    let js_env = JSEnv::new();
+    let js_client = create_js_env(&js_env, &Capabilities::none());
+    js_client
+        .run(Command {
+            operation: Ops::EVAL,
+            payload: "true;".to_string(),
+            args: vec![],
+            docs: Vec::new(),
+        })
+        .await
+        .unwrap();
+
+    let url = format!(
+        "{}/admin/profile?duration=200ms&worker={}",
+        server.url(),
+        js_client.id()
+    );
+    let resp = reqwest::get(&url).await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let profile: Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    assert!(profile["nodes"].is_array());
+    assert!(profile["startTime"].is_number());
+
+    let url = format!("{}/admin/profile?duration=soon", server.url());
+    let resp = reqwest::get(&url).await.unwrap();
+    assert_eq!(resp.status(), 400);
+
+    let url = format!(
+        "{}/admin/profile?duration=10ms&worker={}",
+        server.url(),
+        usize::MAX
+    );
+    let resp = reqwest::get(&url).await.unwrap();
+    assert_eq!(resp.status(), 404);
+
+    // One profile at a time
+    let running = profile::try_start().unwrap();
+    assert!(profile::try_start().is_none());
+    let resp = reqwest::get(&url).await.unwrap();
+    assert_eq!(resp.status(), 409);
+    drop(running);
+    assert!(profile::try_start().is_some());
+}
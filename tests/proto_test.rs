@@ -0,0 +1,28 @@
+use fortuna::js_server::Command;
+use fortuna::proto::{ateles, operation};
+use fortuna::JSError;
+
+#[test]
+fn converts_requests_to_commands() {
+    let js_request = ateles::JsRequest {
+        action: ateles::js_request::Action::Call as i32,
+        script: "sum".to_string(),
+        json_args: vec!["[1, 2]".to_string()],
+        ..ateles::JsRequest::default()
+    };
+    assert_eq!(format!("{:?}", operation(&js_request)), "CALL_JSON");
+
+    let command = Command::from(js_request);
+    assert_eq!(command.payload, "sum");
+    assert_eq!(command.args, vec!["[1, 2]"]);
+}
+
+#[test]
+fn converts_errors_to_responses() {
+    let js_response = ateles::JsResponse::from(JSError::Timeout);
+    assert_eq!(js_response.status, 1);
+    assert_eq!(
+        js_response.error_type,
+        ateles::js_response::ErrorType::Timeout as i32
+    );
+}
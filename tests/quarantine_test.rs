@@ -0,0 +1,66 @@
+use fortuna::ateles::js_response::ErrorType;
+use fortuna::ateles::JsResponse;
+use fortuna::quarantine;
+use fortuna::{quarantine_hash, JSError, JsRequestBuilder};
+
+#[test]
+fn scripts_are_quarantined_after_repeated_crashes() {
+    quarantine::set_threshold(3);
+    let hash = quarantine::script_hash("function(doc) { crash(); }", None);
+
+    assert!(!quarantine::record_crash(&hash));
+    assert!(!quarantine::record_crash(&hash));
+    assert!(!quarantine::is_quarantined(&hash));
+    assert!(quarantine::record_crash(&hash));
+    assert!(quarantine::is_quarantined(&hash));
+    assert!(quarantine::quarantined().contains(&(hash.clone(), 3)));
+
+    // Until an admin clears it
+    assert!(quarantine::clear(&hash));
+    assert!(!quarantine::is_quarantined(&hash));
+    assert!(!quarantine::clear(&hash));
+
+    let resp: JsResponse = JSError::Quarantined(hash.clone()).into();
+    assert_eq!(resp.error_type, ErrorType::QuarantinedScript as i32);
+    assert_eq!(resp.result, format!("quarantined_script: {}", hash));
+}
+
+#[test]
+fn script_hashes_include_their_arguments() {
+    let args = |doc: &str| vec![doc.to_string()];
+    let a = quarantine::script_hash("mapDoc", Some(args("{\"_id\": \"a\"}").as_slice()));
+    let b = quarantine::script_hash("mapDoc", Some(args("{\"_id\": \"b\"}").as_slice()));
+    assert_ne!(a, b);
+    assert_ne!(a, quarantine::script_hash("mapDoc", None));
+    assert_eq!(a.len(), 64);
+
+    // The separator keeps arguments from running together
+    let joined = quarantine::script_hash("f", Some(&["ab".to_string()][..]));
+    let split = quarantine::script_hash("f", Some(&["a".to_string(), "b".to_string()][..]));
+    assert_ne!(joined, split);
+}
+
+#[test]
+fn calls_are_quarantined_by_function_not_input() {
+    let call = |doc: &str| JsRequestBuilder::call("mapDoc").arg(doc).build().unwrap();
+    let a = quarantine_hash(&call("{\"_id\": \"a\"}"));
+    assert_eq!(a, quarantine_hash(&call("{\"_id\": \"b\"}")));
+    assert_eq!(a, quarantine::script_hash("mapDoc", None));
+
+    let other = JsRequestBuilder::call("reduce").arg("[]").build().unwrap();
+    assert_ne!(a, quarantine_hash(&other));
+}
+
+#[test]
+fn design_docs_are_quarantined_by_signature() {
+    let signature = quarantine::script_hash("crashing-ddoc", None);
+    let requests = vec![
+        JsRequestBuilder::map_doc("crashing-ddoc", "{\"_id\": \"a\"}"),
+        JsRequestBuilder::map_doc("crashing-ddoc", "{\"_id\": \"b\"}"),
+        JsRequestBuilder::map_docs("crashing-ddoc", &["{}", "{\"_id\": \"c\"}"]),
+        JsRequestBuilder::filter("crashing-ddoc", "big", "[{}]"),
+    ];
+    for request in requests {
+        assert_eq!(quarantine_hash(&request.build().unwrap()), signature);
+    }
+}
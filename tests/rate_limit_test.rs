@@ -0,0 +1,101 @@
+use prost::Message;
+use std::time::{Duration, Instant};
+
+use fortuna::ateles::js_response::ErrorType;
+use fortuna::rate_limit::{RateLimitConfig, RateLimiter};
+use fortuna::test_support::TestServer;
+use fortuna::{JsRequestBuilder, MakeService};
+mod common;
+
+const CONFIG: &str = r#"{"rules": [
+    {"key": "design_doc", "ops": ["map_doc"], "rate": 1, "burst": 2},
+    {"key": "client_ip", "rate": 10}
+]}"#;
+
+#[test]
+fn parses_rules() {
+    let config = RateLimitConfig::parse(CONFIG).unwrap();
+    assert_eq!(config.rules.len(), 2);
+    assert_eq!(config.rules[0].ops, vec!["map_doc"]);
+    assert_eq!(config.rules[1].burst, None);
+
+    assert!(RateLimitConfig::parse(r#"{"rules": [{"key": "host", "rate": 1}]}"#).is_err());
+    assert!(RateLimitConfig::parse(r#"{"rules": [{"key": "client_ip", "rate": 0}]}"#).is_err());
+    assert!(RateLimitConfig::parse(r#"{"rules": [], "extra": 1}"#).is_err());
+}
+
+#[test]
+fn design_docs_get_a_bucket_each() {
+    let limiter = RateLimiter::new(RateLimitConfig::parse(CONFIG).unwrap());
+    let now = Instant::now();
+
+    assert!(limiter
+        .check_at(now, "10.0.0.1", "map_doc", Some("a"))
+        .is_ok());
+    assert!(limiter
+        .check_at(now, "10.0.0.1", "map_doc", Some("a"))
+        .is_ok());
+    let err = limiter
+        .check_at(now, "10.0.0.1", "map_doc", Some("a"))
+        .unwrap_err();
+    assert!(err.contains("design_doc a"), "{}", err);
+
+    // Other design docs and operations aren't held back by it
+    assert!(limiter
+        .check_at(now, "10.0.0.1", "map_doc", Some("b"))
+        .is_ok());
+    assert!(limiter
+        .check_at(now, "10.0.0.1", "filter", Some("a"))
+        .is_ok());
+
+    // The bucket fills back up at rate
+    let later = now + Duration::from_secs(1);
+    assert!(limiter
+        .check_at(later, "10.0.0.1", "map_doc", Some("a"))
+        .is_ok());
+    assert!(limiter
+        .check_at(later, "10.0.0.1", "map_doc", Some("a"))
+        .is_err());
+}
+
+#[test]
+fn clients_are_limited_across_operations() {
+    let limiter = RateLimiter::new(RateLimitConfig::parse(CONFIG).unwrap());
+    let now = Instant::now();
+    for _ in 0..10 {
+        assert!(limiter.check_at(now, "10.0.0.2", "eval", None).is_ok());
+    }
+    assert!(limiter.check_at(now, "10.0.0.2", "call", None).is_err());
+    assert!(limiter.check_at(now, "10.0.0.3", "call", None).is_ok());
+}
+
+#[tokio::test]
+async fn over_the_limit_is_a_429() {
+    common::setup();
+    let config = r#"{"rules": [{"key": "client_ip", "ops": ["eval"], "rate": 0.001, "burst": 1}]}"#;
+    let make_service = MakeService::new().with_rate_limits(RateLimitConfig::parse(config).unwrap());
+    let server = TestServer::start_with(make_service).unwrap();
+    let client = server.client();
+
+    assert_eq!(client.eval("1;").await.unwrap().status, 0);
+    let resp = client.eval("1;").await.unwrap();
+    assert_eq!(resp.error_type, ErrorType::RateLimited as i32);
+    assert!(resp.result.starts_with("rate_limited: eval exceeded"));
+
+    // Only evals are limited
+    let globals = JsRequestBuilder::set_globals("{\"LIMIT\": 1}")
+        .build()
+        .unwrap();
+    assert_eq!(client.execute(&globals).await.unwrap().status, 0);
+
+    let mut body = Vec::new();
+    let eval = JsRequestBuilder::eval("1;").build().unwrap();
+    eval.encode(&mut body).unwrap();
+    let resp = reqwest::Client::new()
+        .post(&format!("{}/Ateles/Execute", server.url()))
+        .body(body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 429);
+}
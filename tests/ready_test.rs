@@ -0,0 +1,113 @@
+use std::time::Duration;
+
+use fortuna::health::grpc_health::health_check_response::ServingStatus;
+use fortuna::health::grpc_health::health_server::Health;
+use fortuna::health::grpc_health::HealthCheckRequest;
+use fortuna::health::{assess, ReadyLevel, ReadySignals};
+use fortuna::test_support::TestServer;
+use fortuna::{Affinity, MakeService};
+use tonic::Request;
+mod common;
+
+fn healthy() -> ReadySignals {
+    ReadySignals {
+        queue_depth: 0,
+        queue_wait: Duration::from_millis(1),
+        workers: 4,
+        available: 3,
+        ..ReadySignals::default()
+    }
+}
+
+#[test]
+fn readiness_levels() {
+    let readiness = assess(healthy());
+    assert_eq!(readiness.level, ReadyLevel::Ready);
+    assert!(readiness.reasons.is_empty());
+
+    let slow = ReadySignals {
+        queue_wait: Duration::from_millis(250),
+        ..healthy()
+    };
+    assert_eq!(assess(slow).level, ReadyLevel::Degraded);
+
+    let busy = ReadySignals {
+        workers: 20,
+        available: 1,
+        ..healthy()
+    };
+    assert_eq!(assess(busy).level, ReadyLevel::Degraded);
+
+    let throttled = ReadySignals {
+        throttled: true,
+        queue_depth: 5,
+        ..healthy()
+    };
+    let readiness = assess(throttled);
+    assert_eq!(readiness.level, ReadyLevel::Degraded);
+    assert_eq!(readiness.reasons.len(), 2);
+
+    let backed_up = ReadySignals {
+        queue_depth: 17,
+        ..healthy()
+    };
+    assert_eq!(assess(backed_up).level, ReadyLevel::NotReady);
+
+    // The worst reason wins
+    let failed = ReadySignals {
+        probe_error: Some("timeout".to_string()),
+        throttled: true,
+        ..healthy()
+    };
+    let readiness = assess(failed);
+    assert_eq!(readiness.level, ReadyLevel::NotReady);
+    assert_eq!(readiness.reasons[0], "probe failed: timeout");
+}
+
+#[test]
+fn not_ready_while_warming_up() {
+    let warming = ReadySignals {
+        warming: true,
+        ..healthy()
+    };
+    let readiness = assess(warming);
+    assert_eq!(readiness.level, ReadyLevel::NotReady);
+    assert_eq!(readiness.reasons, vec!["warming up".to_string()]);
+}
+
+#[tokio::test]
+async fn ready_over_http() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+
+    let resp = reqwest::get(&format!("{}/ready", server.url()))
+        .await
+        .unwrap();
+    let status = resp.status();
+    let body: serde_json::Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    // Other tests may be keeping workers busy
+    match body["status"].as_str().unwrap() {
+        "ready" => assert_eq!(status, 200),
+        "degraded" => assert_eq!(status, 429),
+        other => panic!("not ready: {} {}", other, body),
+    }
+    assert!(body["reasons"].is_array());
+}
+
+#[tokio::test]
+async fn grpc_health_checks_the_pool() {
+    common::setup();
+    let make_service = MakeService::new().with_pool(2, Affinity::Request);
+    let health = make_service.health_service();
+
+    let request = Request::new(HealthCheckRequest {
+        service: "ateles.Ateles".to_string(),
+    });
+    let resp = health.check(request).await.unwrap().into_inner();
+    assert_eq!(resp.status, ServingStatus::Serving as i32);
+
+    let request = Request::new(HealthCheckRequest {
+        service: "nope".to_string(),
+    });
+    assert!(health.check(request).await.is_err());
+}
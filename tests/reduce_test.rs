@@ -0,0 +1,75 @@
+use fortuna::builtins::{is_builtin, reduce, rereduce};
+use fortuna::*;
+use serde_json::{json, Value};
+mod common;
+
+fn rows(values: Vec<Value>) -> Vec<Value> {
+    values
+        .into_iter()
+        .enumerate()
+        .map(|(i, value)| json!([i, value]))
+        .collect()
+}
+
+#[test]
+fn builtin_sum_and_count() {
+    assert!(is_builtin(" _sum"));
+    assert!(!is_builtin("function(k, v) { return sum(v); }"));
+
+    let input = rows(vec![json!(1), json!(2), json!(3.5)]);
+    assert_eq!(reduce("_sum", &input).unwrap(), json!(6.5));
+    assert_eq!(reduce("_count", &input).unwrap(), json!(3));
+    assert_eq!(rereduce("_count", &[json!(3), json!(4)]).unwrap(), json!(7));
+
+    let input = rows(vec![json!([1, 2]), json!([3, 4, 5])]);
+    assert_eq!(reduce("_sum", &input).unwrap(), json!([4, 6, 5]));
+    let input = rows(vec![json!({"a": 1}), json!({"a": 2, "b": 3})]);
+    assert_eq!(reduce("_sum", &input).unwrap(), json!({"a": 3, "b": 3}));
+
+    assert!(reduce("_sum", &rows(vec![json!("a")])).is_err());
+    assert!(reduce("_median", &[]).is_err());
+}
+
+#[test]
+fn builtin_stats() {
+    let first = reduce("_stats", &rows(vec![json!(1), json!(3)])).unwrap();
+    assert_eq!(
+        first,
+        json!({"sum": 4, "count": 2, "min": 1, "max": 3, "sumsqr": 10})
+    );
+    let second = reduce("_stats", &rows(vec![json!(-2)])).unwrap();
+    assert_eq!(
+        rereduce("_stats", &[first, second]).unwrap(),
+        json!({"sum": 2, "count": 3, "min": -2, "max": 3, "sumsqr": 14})
+    );
+}
+
+#[test]
+fn builtin_approx_count_distinct() {
+    let keys: Vec<Value> = (0..1000).map(|i| json!([i % 500, null])).collect();
+    let (a, b) = keys.split_at(600);
+    let a = reduce("_approx_count_distinct", a).unwrap();
+    let b = reduce("_approx_count_distinct", b).unwrap();
+    let total = rereduce("_approx_count_distinct", &[a, b]).unwrap();
+    let count = total["count"].as_u64().unwrap();
+    assert!(count > 475 && count < 525, "{}", count);
+}
+
+#[test]
+fn js_reduce() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let mut instance = js_env.create_isolate();
+
+    let fun = "function(keys, values, rereduce) { return [keys ? keys.length : 0, values.reduce((a, b) => a + b, 0), rereduce]; }";
+    let result = instance.reduce(fun, "[[\"a\", 1], [\"b\", 2]]", false);
+    assert_eq!(result.unwrap(), "[2,3,false]");
+    let result = instance.reduce(fun, "[1, 2, 3]", true);
+    assert_eq!(result.unwrap(), "[0,6,true]");
+
+    match instance.reduce("1 +", "[]", false) {
+        Err(JSError::CompileError(_)) => (),
+        other => panic!("expected a compile error, got {:?}", other),
+    }
+}
@@ -0,0 +1,90 @@
+use fortuna::repl::{Outcome, Repl};
+use fortuna::*;
+use std::io::Cursor;
+mod common;
+
+fn print(text: &str) -> Outcome {
+    Outcome::Print(text.to_string())
+}
+
+#[test]
+fn evals_and_calls() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let mut repl = Repl::new(&js_env, &Capabilities::all());
+
+    assert_eq!(repl.handle("var x = 20; x + 1;"), print("21"));
+    repl.handle("function double(x) { return x * 2; }");
+    assert_eq!(repl.handle(".call double [21]"), print("42"));
+    assert_eq!(repl.handle("x * 2;"), print("40"));
+
+    let outcome = repl.handle(".call double {}");
+    assert!(matches!(outcome, Outcome::Print(text) if text.contains("JSON array")));
+
+    let outcome = repl.handle("throw new Error('nope');");
+    assert!(matches!(outcome, Outcome::Print(text) if text.starts_with("error: ")));
+
+    assert_eq!(repl.handle(""), print(""));
+    assert_eq!(repl.handle(".exit"), Outcome::Exit);
+}
+
+#[test]
+fn prints_logs_and_emits() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let mut repl = Repl::new(&js_env, &Capabilities::all());
+
+    assert_eq!(
+        repl.handle("log('hi'); emit('a', 1); 1;"),
+        print("INFO: hi\nemit: [\"a\",1]\n1")
+    );
+    assert_eq!(repl.handle("2;"), print("2"));
+}
+
+#[test]
+fn commands() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let mut repl = Repl::new(&js_env, &Capabilities::all());
+
+    let path = std::env::temp_dir().join(format!("fortuna-repl-{}.js", std::process::id()));
+    std::fs::write(&path, "var loaded = 'yes';").unwrap();
+    repl.handle(&format!(".load {}", path.display()));
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(repl.handle("loaded;"), print("\"yes\""));
+
+    let outcome = repl.handle(".load /no/such/file.js");
+    assert!(matches!(outcome, Outcome::Print(text) if text.starts_with("can't read")));
+
+    let outcome = repl.handle(".rewrite function(doc) { emit(doc._id, null); }");
+    assert!(matches!(outcome, Outcome::Print(text) if !text.starts_with("error")));
+
+    repl.handle(".reset");
+    let outcome = repl.handle("loaded;");
+    assert!(matches!(outcome, Outcome::Print(text) if text.starts_with("error: ")));
+
+    let outcome = repl.handle(".nope");
+    assert!(matches!(outcome, Outcome::Print(text) if text.contains(".help")));
+    let outcome = repl.handle(".help");
+    assert!(matches!(outcome, Outcome::Print(text) if text.contains(".call")));
+}
+
+#[test]
+fn runs_lines() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let mut repl = Repl::new(&js_env, &Capabilities::all());
+
+    let input = Cursor::new("var y = 1 +\\\n  2;\ny;\n.exit\n5;\n");
+    let mut out = Vec::new();
+    repl.run(input, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(out.matches("> ").count(), 3);
+    assert!(out.contains(". "));
+    assert!(out.contains("3\n"));
+    assert!(!out.contains("5\n"));
+}
@@ -0,0 +1,105 @@
+use prost::Message;
+
+use fortuna::ateles::JsResponse;
+use fortuna::request_id;
+use fortuna::test_support::TestServer;
+use fortuna::{metrics, JsRequestBuilder};
+mod common;
+
+#[test]
+fn request_ids_are_checked() {
+    assert!(request_id::is_valid("req-1/2:abc"));
+    assert!(!request_id::is_valid(""));
+    assert!(!request_id::is_valid("has space"));
+    assert!(!request_id::is_valid("quo\"te"));
+    assert!(!request_id::is_valid(&"x".repeat(65)));
+
+    assert_eq!(request_id::or_generate("mine"), "mine");
+    let generated = request_id::or_generate("not mine");
+    assert_eq!(generated.len(), 32);
+    assert!(generated.chars().all(|c| c.is_ascii_hexdigit()));
+    assert_ne!(request_id::generate(), request_id::generate());
+
+    assert!(JsRequestBuilder::eval("1;")
+        .request_id("bad id")
+        .build()
+        .is_err());
+}
+
+#[tokio::test]
+async fn requests_get_an_id() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let client = server.client();
+
+    let resp = client.eval("requestId();").await.unwrap();
+    assert_eq!(resp.status, 0);
+    assert_eq!(resp.request_id.len(), 32);
+    // JS sees the same one, to put in its errors
+    assert_eq!(resp.result, format!("\"{}\"", resp.request_id));
+
+    let other = client.eval("1;").await.unwrap();
+    assert_ne!(other.request_id, resp.request_id);
+
+    let eval = JsRequestBuilder::eval("throw new Error('failed ' + requestId());")
+        .request_id("caller-42")
+        .build()
+        .unwrap();
+    let resp = client.execute(&eval).await.unwrap();
+    assert_eq!(resp.request_id, "caller-42");
+    assert_eq!(resp.error.unwrap().message, "failed caller-42");
+}
+
+#[tokio::test]
+async fn request_id_from_header() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let eval = JsRequestBuilder::eval("requestId();").build().unwrap();
+    let mut body = Vec::new();
+    eval.encode(&mut body).unwrap();
+    let resp = reqwest::Client::new()
+        .post(&format!("{}/Ateles/Execute", server.url()))
+        .header("x-request-id", "from-header")
+        .body(body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.headers()["x-request-id"], "from-header");
+    let js_resp = JsResponse::decode(resp.bytes().await.unwrap()).unwrap();
+    assert_eq!(js_resp.request_id, "from-header");
+    assert_eq!(js_resp.result, "\"from-header\"");
+}
+
+#[tokio::test]
+async fn exemplars_in_openmetrics() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    // Nothing else here sets globals, so no other request takes its place
+    let set_globals = JsRequestBuilder::set_globals(r#"{"A": 1}"#)
+        .request_id("exemplar-1")
+        .build()
+        .unwrap();
+    let resp = server.client().execute(&set_globals).await.unwrap();
+    assert_eq!(resp.status, 0);
+
+    let openmetrics = metrics::render_openmetrics();
+    assert!(openmetrics.lines().any(|line| {
+        line.starts_with("fortuna_command_execution_seconds_bucket{kind=\"globals\"")
+            && line.contains(" # {request_id=\"exemplar-1\"} ")
+    }));
+    assert!(openmetrics.ends_with("# EOF\n"));
+    assert!(openmetrics.contains("# TYPE fortuna_connections counter"));
+    // Prometheus' own format has no exemplars
+    assert!(!metrics::render().contains("request_id="));
+
+    let resp = reqwest::Client::new()
+        .get(&format!("{}/metrics", server.url()))
+        .header("accept", "application/openmetrics-text; version=1.0.0")
+        .send()
+        .await
+        .unwrap();
+    assert!(resp.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("application/openmetrics-text"));
+}
@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use fortuna::ateles::js_request::{Action, EmitFormat};
+use fortuna::ateles::js_response::ErrorType;
+use fortuna::ateles::{JsRequest, JsResponse};
+use fortuna::request::validate;
+use fortuna::{JSError, JsRequestBuilder};
+
+#[test]
+fn builds_requests() {
+    let request = JsRequestBuilder::call("mapDoc")
+        .json_arg("{\"_id\": \"foo\"}")
+        .timeout(Duration::from_secs(5))
+        .view_rows("foo")
+        .session("indexer", Duration::from_secs(60))
+        .batch_size(100)
+        .build()
+        .unwrap();
+    assert_eq!(request.action, Action::Call as i32);
+    assert_eq!(request.script, "mapDoc");
+    assert_eq!(request.json_args, vec!["{\"_id\": \"foo\"}"]);
+    assert_eq!(request.timeout, 5000);
+    assert_eq!(request.emit_format, EmitFormat::ViewKv as i32);
+    assert_eq!(request.doc_id, "foo");
+    assert_eq!(request.session_ttl, 60);
+    assert_eq!(request.batch_size, 100);
+
+    let request = JsRequestBuilder::reduce("_sum", "[[1, 2]]")
+        .build()
+        .unwrap();
+    assert_eq!(request.args, vec!["[[1, 2]]"]);
+
+    assert!(JsRequestBuilder::set_globals("{\"LIMIT\": 10}")
+        .build()
+        .is_ok());
+}
+
+#[test]
+fn rejects_invalid_requests() {
+    // Nothing to run
+    assert!(JsRequestBuilder::eval("").build().is_err());
+    // Except for a reset, which takes nothing
+    assert!(JsRequestBuilder::reset().build().is_ok());
+    assert!(JsRequestBuilder::reset().arg("1").build().is_err());
+    // Timeouts past the maximum
+    assert!(JsRequestBuilder::eval("1;")
+        .timeout(Duration::from_secs(3600))
+        .build()
+        .is_err());
+    // Both kinds of args
+    assert!(JsRequestBuilder::call("f")
+        .arg("1")
+        .json_arg("1")
+        .build()
+        .is_err());
+    // Reduces take exactly their rows
+    assert!(JsRequestBuilder::reduce("_sum", "[]")
+        .arg("[]")
+        .build()
+        .is_err());
+    // Globals have to be an object
+    assert!(JsRequestBuilder::set_globals("[1]").build().is_err());
+    assert!(JsRequestBuilder::set_globals("{}")
+        .arg("extra")
+        .build()
+        .is_err());
+    // Design doc functions are checked before they're sent
+    assert!(JsRequestBuilder::register_ddoc("sig", "{\"map\": []}")
+        .build()
+        .is_ok());
+    assert!(JsRequestBuilder::register_ddoc("sig", "{\"views\": {}}")
+        .build()
+        .is_err());
+    assert!(JsRequestBuilder::filter("sig", "mine", "[]")
+        .arg("{}")
+        .build()
+        .is_ok());
+    assert!(JsRequestBuilder::evict_ddoc("sig")
+        .arg("1")
+        .build()
+        .is_err());
+
+    // Hand built requests with values out of range
+    let request = |action, timeout| JsRequest {
+        action,
+        script: "1;".to_string(),
+        timeout,
+        ..JsRequest::default()
+    };
+    assert!(validate(&request(Action::Eval as i32, 0)).is_ok());
+    assert!(validate(&request(42, 0)).is_err());
+    assert!(validate(&request(Action::Eval as i32, -1)).is_err());
+
+    let view_rows = JsRequest {
+        emit_format: EmitFormat::ViewKv as i32,
+        ..request(Action::Call as i32, 0)
+    };
+    assert!(validate(&view_rows).is_err());
+}
+
+#[test]
+fn invalid_request_response() {
+    let resp: JsResponse = JSError::InvalidRequest("body is over 10 bytes".to_string()).into();
+    assert_eq!(resp.status, 1);
+    assert_eq!(resp.error_type, ErrorType::InvalidRequest as i32);
+    assert_eq!(resp.result, "invalid_request: body is over 10 bytes");
+}
@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use fortuna::ateles::js_response::ErrorType;
+use fortuna::ateles::JsRequest;
+use fortuna::test_support::TestServer;
+use fortuna::*;
+mod common;
+
+#[test]
+fn reset_starts_over_from_the_snapshot() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let mut instance = js_env.create_isolate();
+    instance.eval("var leftover = 1;", &[]).unwrap();
+    instance.set_globals("{\"LIMIT\": 10}").unwrap();
+    let functions = "{\"map\": [\"function(doc) { emit(doc._id, 1); }\"]}";
+    instance.register_ddoc("sig", functions).unwrap();
+
+    assert_eq!(instance.reset().unwrap(), "true");
+    let result = instance
+        .eval("typeof leftover + typeof LIMIT;", &[])
+        .unwrap();
+    assert_eq!(result, "\"undefinedundefined\"");
+    assert_eq!(instance.design_doc_count(), 0);
+
+    // The bundle and host functions are back
+    let result = instance
+        .eval("typeof rewriteFun + typeof emit;", &[])
+        .unwrap();
+    assert_eq!(result, "\"functionfunction\"");
+    // Globals can be set again
+    instance.set_globals("{\"LIMIT\": 20}").unwrap();
+    assert_eq!(instance.eval("LIMIT;", &[]).unwrap(), "20");
+}
+
+#[tokio::test]
+async fn reset_over_http() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let client = server.client();
+    let session = |builder: JsRequestBuilder| {
+        builder
+            .session("reset_over_http", Duration::default())
+            .build()
+            .unwrap()
+    };
+
+    let define = JsRequestBuilder::eval("function hello() { return 'hi'; }");
+    assert_eq!(client.execute(&session(define)).await.unwrap().status, 0);
+    let hello = || JsRequestBuilder::call("hello");
+    assert_eq!(
+        client.execute(&session(hello())).await.unwrap().result,
+        "\"hi\""
+    );
+
+    // Resetting twice is no different from once
+    for _ in 0..2 {
+        let resp = client
+            .execute(&session(JsRequestBuilder::reset()))
+            .await
+            .unwrap();
+        assert_eq!(resp.status, 0);
+        assert_eq!(resp.result, "true");
+    }
+    let resp = client.execute(&session(hello())).await.unwrap();
+    assert_eq!(resp.error_type, ErrorType::RuntimeError as i32);
+}
+
+#[tokio::test]
+async fn reset_is_scoped_to_the_caller() {
+    common::setup();
+    let reset = || JsRequestBuilder::reset().build().unwrap();
+
+    // Each connection has a worker of its own
+    let server = TestServer::start().unwrap();
+    let resp = server.client().execute(&reset()).await.unwrap();
+    assert_eq!(resp.result, "true");
+
+    // Other connections' requests run on the pool's workers too
+    let make_service = MakeService::new().with_pool(1, Affinity::Request);
+    let server = TestServer::start_with(make_service).unwrap();
+    let client = server.client();
+    let resp = client.execute(&reset()).await.unwrap();
+    assert_eq!(resp.error_type, ErrorType::InvalidRequest as i32);
+
+    let session = JsRequestBuilder::reset()
+        .session("reset_is_scoped", Duration::default())
+        .build()
+        .unwrap();
+    assert_eq!(client.execute(&session).await.unwrap().result, "true");
+}
+
+#[tokio::test]
+async fn unknown_actions_are_rejected() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let client = server.client();
+
+    let request = JsRequest {
+        action: 42,
+        script: "1;".to_string(),
+        ..JsRequest::default()
+    };
+    let resp = client.execute(&request).await.unwrap();
+    assert_eq!(resp.error_type, ErrorType::InvalidRequest as i32);
+    assert_eq!(resp.result, "invalid_request: unknown action 42");
+
+    // The worker is still there
+    assert_eq!(client.eval("1 + 1;").await.unwrap().result, "2");
+}
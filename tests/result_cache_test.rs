@@ -0,0 +1,100 @@
+use std::time::Duration;
+
+use fortuna::ateles::{JsRequest, JsResponse};
+use fortuna::result_cache::{ResultCache, ResultCacheConfig};
+use fortuna::test_support::TestServer;
+use fortuna::{JsRequestBuilder, MakeService};
+mod common;
+
+fn ok(result: &str) -> JsResponse {
+    JsResponse {
+        result: result.to_string(),
+        ..JsResponse::default()
+    }
+}
+
+fn call(doc: &str) -> JsRequest {
+    JsRequestBuilder::call("validate").arg(doc).build().unwrap()
+}
+
+#[test]
+fn parses_ttls() {
+    let config = ResultCacheConfig::parse("call=500, filter=250", 10).unwrap();
+    assert_eq!(config.ttls["call"], Duration::from_millis(500));
+    assert_eq!(config.ttls["filter"], Duration::from_millis(250));
+    assert_eq!(config.max_entries, 10);
+
+    assert!(ResultCacheConfig::parse("eval=500", 10).is_err());
+    assert!(ResultCacheConfig::parse("call", 10).is_err());
+    assert!(ResultCacheConfig::parse("call=soon", 10).is_err());
+}
+
+#[test]
+fn results_are_kept_per_worker_and_generation() {
+    let cache = ResultCache::new(ResultCacheConfig::parse("call=60000", 10).unwrap());
+    assert!(cache.key(1, 1, "eval", &call("{}")).is_none());
+
+    let key = cache.key(1, 1, "call", &call("{\"a\": 1}")).unwrap();
+    assert_eq!(cache.get(&key), None);
+    cache.put(key.clone(), "call", &ok("true"));
+    assert_eq!(cache.get(&key), Some(ok("true")));
+
+    // Not for other args, workers or once the worker's state has changed
+    let other_args = cache.key(1, 1, "call", &call("{\"a\": 2}")).unwrap();
+    assert_eq!(cache.get(&other_args), None);
+    let other_worker = cache.key(2, 1, "call", &call("{\"a\": 1}")).unwrap();
+    assert_eq!(cache.get(&other_worker), None);
+    let changed = cache.key(1, 2, "call", &call("{\"a\": 1}")).unwrap();
+    assert_eq!(cache.get(&changed), None);
+
+    // Failures aren't kept
+    let error = JsResponse {
+        status: 1,
+        ..ok("runtime_error")
+    };
+    cache.put(other_args.clone(), "call", &error);
+    assert_eq!(cache.get(&other_args), None);
+}
+
+#[test]
+fn results_expire_and_are_evicted() {
+    let cache = ResultCache::new(ResultCacheConfig::parse("call=50", 2).unwrap());
+    let key = |doc: &str| cache.key(1, 1, "call", &call(doc)).unwrap();
+
+    cache.put(key("1"), "call", &ok("1"));
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(cache.get(&key("1")), None);
+
+    cache.put(key("1"), "call", &ok("1"));
+    cache.put(key("2"), "call", &ok("2"));
+    cache.put(key("3"), "call", &ok("3"));
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.get(&key("1")), None);
+    assert_eq!(cache.get(&key("3")), Some(ok("3")));
+}
+
+#[tokio::test]
+async fn calls_are_answered_from_the_cache() {
+    common::setup();
+    let config = ResultCacheConfig::parse("call=60000", 100).unwrap();
+    let server = TestServer::start_with(MakeService::new().with_result_cache(config)).unwrap();
+    let client = server.client();
+    let session = |builder: JsRequestBuilder| {
+        builder
+            .session("calls_are_answered_from_the_cache", Duration::default())
+            .build()
+            .unwrap()
+    };
+
+    let define = JsRequestBuilder::eval("var n = 0; function count() { return ++n; }");
+    assert_eq!(client.execute(&session(define)).await.unwrap().status, 0);
+
+    let count = || JsRequestBuilder::call("count");
+    assert_eq!(client.execute(&session(count())).await.unwrap().result, "1");
+    assert_eq!(client.execute(&session(count())).await.unwrap().result, "1");
+
+    // An eval could have changed anything
+    let eval = JsRequestBuilder::eval("true;");
+    assert_eq!(client.execute(&session(eval)).await.unwrap().status, 0);
+    assert_eq!(client.execute(&session(count())).await.unwrap().result, "2");
+}
@@ -0,0 +1,40 @@
+use fortuna::config::{self, Config};
+use fortuna::metrics;
+use fortuna::test_support::{self, TestServer};
+use fortuna::JsRequestBuilder;
+mod common;
+
+#[tokio::test]
+async fn retry_crashed_setting() {
+    common::setup();
+    assert!(!config::retry_crashed());
+    let config = Config::parse("retry_crashed = true").unwrap();
+    config.apply();
+    assert!(config::retry_crashed());
+    let (reloadable, restart) = config.changes(&Config::default());
+    assert_eq!(reloadable, vec!["retry_crashed"]);
+    assert!(restart.is_empty());
+
+    // Nothing crashed so nothing was sent twice
+    let server = TestServer::start().unwrap();
+    let rewrite = JsRequestBuilder::rewrite("rewriteFunInt")
+        .arg("function(doc) { emit(doc._id, null); }")
+        .build()
+        .unwrap();
+    let resp = server.client().execute(&rewrite).await.unwrap();
+    assert_eq!(resp.status, 0);
+    assert!(!resp.stats.unwrap().retried);
+    assert!(metrics::render().contains("fortuna_retried_commands_total 0"));
+
+    // The first try panics the worker, the isolate replacing it answers
+    test_support::crash_on("rewriteFunInt", 1);
+    let rewrite = JsRequestBuilder::rewrite("rewriteFunInt")
+        .arg("function(doc) { emit(doc.type, 1); }")
+        .build()
+        .unwrap();
+    let resp = server.client().execute(&rewrite).await.unwrap();
+    assert_eq!(resp.status, 0);
+    assert!(resp.result.contains("emit(doc.type, 1)"), "{}", resp.result);
+    assert!(resp.stats.unwrap().retried);
+    assert!(metrics::render().contains("fortuna_retried_commands_total 1"));
+}
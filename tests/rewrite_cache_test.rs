@@ -0,0 +1,71 @@
+use fortuna::js_server::{create_js_env, Command, Ops};
+use fortuna::rewrite_cache::RewriteCache;
+use fortuna::*;
+use futures::executor::block_on;
+mod common;
+
+fn rewrite(fun: &str, source: &str) -> Command {
+    Command {
+        operation: Ops::REWRITE,
+        payload: fun.to_string(),
+        args: vec![source.to_string()],
+        docs: Vec::new(),
+    }
+}
+
+#[test]
+fn only_runtime_rewrites_are_cached() {
+    let cache = RewriteCache::new(10);
+    assert!(cache
+        .key(&rewrite("rewriteFun", "function(doc) {}"))
+        .is_some());
+    assert!(cache
+        .key(&rewrite("myRewrite", "function(doc) {}"))
+        .is_none());
+    let call = Command {
+        operation: Ops::CALL,
+        ..rewrite("rewriteFun", "function(doc) {}")
+    };
+    assert!(cache.key(&call).is_none());
+
+    cache.set_max_entries(0);
+    assert!(cache
+        .key(&rewrite("rewriteFun", "function(doc) {}"))
+        .is_none());
+}
+
+#[test]
+fn least_recently_used_results_are_evicted() {
+    let cache = RewriteCache::new(2);
+    let key = |source: &str| cache.key(&rewrite("rewriteFun", source)).unwrap();
+
+    cache.put(key("a"), "A".to_string());
+    cache.put(key("b"), "B".to_string());
+    assert_eq!(cache.get(&key("a")), Some("A".to_string()));
+    cache.put(key("c"), "C".to_string());
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.get(&key("b")), None);
+    assert_eq!(cache.get(&key("a")), Some("A".to_string()));
+    assert_eq!(cache.get(&key("c")), Some("C".to_string()));
+
+    cache.set_max_entries(1);
+    assert_eq!(cache.len(), 1);
+    assert_eq!(cache.get(&key("c")), Some("C".to_string()));
+}
+
+#[test]
+fn workers_share_rewrites() {
+    common::setup();
+    let js_env = JSEnv::new();
+    let first = create_js_env(&js_env, &Capabilities::none());
+    let second = create_js_env(&js_env, &Capabilities::none());
+
+    let source = "function(doc) { emit(doc._id, null); }";
+    let rewritten = block_on(first.run(rewrite("rewriteFun", source))).unwrap();
+    assert_eq!(js_env.rewrite_cache.len(), 1);
+
+    // Answered without running anything on the second worker
+    let reply = block_on(second.execute(rewrite("rewriteFun", source)));
+    assert_eq!(reply.result.unwrap(), rewritten);
+    assert_eq!(reply.stats.execution, Default::default());
+}
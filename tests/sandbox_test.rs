@@ -0,0 +1,70 @@
+use fortuna::*;
+mod common;
+
+#[test]
+fn intrinsics_are_frozen() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let mut instance = js_env.create_isolate();
+
+    let script = "Array.prototype.push = function() { return 42; }; [].push(1);";
+    let result = instance.eval(script, &[]).unwrap();
+    assert_eq!(result, "1");
+
+    let result = instance
+        .eval("[typeof SharedArrayBuffer, typeof Atomics];", &[])
+        .unwrap();
+    assert_eq!(result, "[\"undefined\",\"undefined\"]");
+}
+
+#[test]
+fn harness_functions_can_be_locked() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let mut instance = js_env.create_isolate();
+
+    let script = "function emit(k, v) { return 'real'; };";
+    instance.eval(script, &[]).unwrap();
+    instance
+        .call("lockGlobals", &["[\"emit\"]".to_string()])
+        .unwrap();
+
+    let script = "emit = function() { return 'fake'; }; emit();";
+    let result = instance.eval(script, &[]).unwrap();
+    assert_eq!(result, "\"real\"");
+
+    let result = instance
+        .eval("rewriteFun = null; typeof rewriteFun;", &[])
+        .unwrap();
+    assert_eq!(result, "\"function\"");
+}
+
+#[test]
+fn set_globals_installs_frozen_values() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let mut instance = js_env.create_isolate();
+
+    let globals = r#"{"TABLE": {"a": [1, 2], "b": null}, "LIMIT": 10}"#;
+    assert_eq!(instance.set_globals(globals).unwrap(), "true");
+
+    let result = instance.eval("[TABLE.a[1], TABLE.b, LIMIT];", &[]).unwrap();
+    assert_eq!(result, "[2,null,10]");
+
+    let script = "TABLE.a.push(3); TABLE.c = 1; LIMIT = 0; TABLE = {}; [TABLE, LIMIT];";
+    let result = instance.eval(script, &[]);
+    assert!(result.is_err(), "frozen array push should throw");
+
+    let script = "TABLE.c = 1; LIMIT = 0; TABLE = {}; [Object.isFrozen(TABLE.a), TABLE, LIMIT];";
+    let result = instance.eval(script, &[]).unwrap();
+    assert_eq!(result, "[true,{\"a\":[1,2],\"b\":null},10]");
+
+    assert!(instance.set_globals("[1, 2]").is_err());
+    match instance.set_globals("{\"oops\": ") {
+        Err(JSError::CompileError(info)) => assert_eq!(info.name, "SyntaxError"),
+        other => panic!("expected a compile error, got {:?}", other),
+    }
+}
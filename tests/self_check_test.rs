@@ -0,0 +1,38 @@
+use std::time::Duration;
+
+use fortuna::self_check::{self, Check};
+use fortuna::*;
+mod common;
+
+#[test]
+fn the_bundle_passes() {
+    common::setup();
+
+    let checks = self_check::run(&JSEnv::new());
+    let report = self_check::report(&checks);
+    assert!(self_check::passed(&checks), "{}", report);
+    assert!(report.starts_with("ok   create_isolate"));
+    assert!(report.contains("ok   map_doc"));
+    assert!(!report.contains("FAIL"));
+}
+
+#[test]
+fn failures_are_reported() {
+    let checks = vec![
+        Check {
+            name: "eval",
+            took: Duration::from_millis(1),
+            outcome: Ok(()),
+        },
+        Check {
+            name: "map_doc",
+            took: Duration::from_millis(2),
+            outcome: Err("mapping a doc failed: timeout".to_string()),
+        },
+    ];
+    assert!(!self_check::passed(&checks));
+    assert_eq!(
+        self_check::report(&checks),
+        "ok   eval (1ms)\nFAIL map_doc (2ms): mapping a doc failed: timeout\n"
+    );
+}
@@ -0,0 +1,50 @@
+use std::net::TcpListener;
+use std::time::Duration;
+
+use fortuna::client::{AtelesClient, ClientConfig};
+use fortuna::config;
+use fortuna::ServerBuilder;
+mod common;
+
+#[tokio::test]
+async fn builds_a_server_with_its_settings() {
+    common::setup();
+    // A port that was free a moment ago
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let server = ServerBuilder::new()
+        .bind(format!("{}", addr).parse().unwrap())
+        .pool_size(2)
+        .max_body(1024)
+        .timeout(Duration::from_secs(5))
+        .build();
+    assert_eq!(config::default_timeout(), Some(Duration::from_secs(5)));
+    tokio::spawn(server);
+
+    // Retried until the server is listening
+    let config = ClientConfig {
+        retries: 20,
+        backoff: Duration::from_millis(50),
+        max_backoff: Duration::from_millis(200),
+        ..ClientConfig::default()
+    };
+    let client = AtelesClient::with_config(&format!("http://{}", addr), config).unwrap();
+    assert_eq!(client.eval("1 + 1;").await.unwrap().result, "2");
+
+    let limits = client.server_info().await.unwrap().limits.unwrap();
+    assert_eq!(limits.max_body_bytes, 1024);
+    assert_eq!(limits.default_timeout, 5000);
+
+    let script = format!("'{}';", "x".repeat(2048));
+    let resp = client.eval(&script).await.unwrap();
+    assert_eq!(resp.result, "invalid_request: body is over 1024 bytes");
+}
+
+#[tokio::test]
+async fn needs_somewhere_to_listen() {
+    common::setup();
+    let err = ServerBuilder::new().build().await.unwrap_err();
+    assert_eq!(err.to_string(), "nothing to listen on");
+}
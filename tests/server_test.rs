@@ -0,0 +1,191 @@
+use std::time::Duration;
+
+use fortuna::ateles::js_response::ErrorType;
+use fortuna::test_support::TestServer;
+use fortuna::JsRequestBuilder;
+mod common;
+
+#[tokio::test]
+async fn eval_over_http() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let client = server.client();
+
+    let resp = client.eval("let x = 20; x * 2 + 2;").await.unwrap();
+    assert_eq!(resp.status, 0);
+    assert_eq!(resp.result, "42");
+    assert!(resp.stats.is_some());
+}
+
+#[tokio::test]
+async fn call_after_eval_in_a_session() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let client = server.client();
+
+    let define = JsRequestBuilder::eval("function double(x) { return x * 2; }")
+        .session("call_after_eval", Duration::default())
+        .build()
+        .unwrap();
+    assert_eq!(client.execute(&define).await.unwrap().status, 0);
+
+    let call = JsRequestBuilder::call("double")
+        .json_arg("21")
+        .session("call_after_eval", Duration::default())
+        .build()
+        .unwrap();
+    let resp = client.execute(&call).await.unwrap();
+    assert_eq!(resp.result, "42");
+    assert!(!resp.session_created);
+}
+
+#[tokio::test]
+async fn map_a_doc() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let client = server.client();
+
+    let define = JsRequestBuilder::eval("function map(doc) { emit(doc._id, doc.value); }")
+        .session("map_a_doc", Duration::default())
+        .build()
+        .unwrap();
+    assert_eq!(client.execute(&define).await.unwrap().status, 0);
+
+    let map = JsRequestBuilder::call("map")
+        .json_arg("{\"_id\": \"foo\", \"value\": 1}")
+        .session("map_a_doc", Duration::default())
+        .build()
+        .unwrap();
+    let resp = client.execute(&map).await.unwrap();
+    assert_eq!(resp.status, 0);
+    assert_eq!(resp.emitted.len(), 1);
+    assert_eq!(resp.emitted[0].rows, vec!["[\"foo\",1]"]);
+}
+
+#[tokio::test]
+async fn map_with_a_design_doc() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let client = server.client();
+    let session = |builder: JsRequestBuilder| {
+        builder
+            .session("map_with_a_design_doc", Duration::default())
+            .build()
+            .unwrap()
+    };
+
+    let map = JsRequestBuilder::map_doc("ddoc-1", "{\"_id\": \"foo\"}");
+    let resp = client.execute(&session(map)).await.unwrap();
+    assert_eq!(resp.error_type, ErrorType::UnknownDesignDoc as i32);
+
+    let functions = "{\"map\": [\"function(doc) { emit(doc._id, 1); }\"]}";
+    let register = JsRequestBuilder::register_ddoc("ddoc-1", functions);
+    assert_eq!(client.execute(&session(register)).await.unwrap().status, 0);
+
+    let map = JsRequestBuilder::map_doc("ddoc-1", "{\"_id\": \"foo\"}");
+    let resp = client.execute(&session(map)).await.unwrap();
+    assert_eq!(resp.status, 0);
+    assert_eq!(resp.emitted.len(), 1);
+    assert_eq!(resp.emitted[0].rows, vec!["[\"foo\",1]"]);
+}
+
+#[tokio::test]
+async fn large_requests_over_http() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let client = server.client();
+
+    // Big enough to arrive in several chunks
+    let doc = format!("{{\"body\": \"{}\"}}", "x".repeat(4 * 1024 * 1024));
+    let define = JsRequestBuilder::eval("function size(doc) { return doc.body.length; }")
+        .session("large_requests", Duration::default())
+        .build()
+        .unwrap();
+    assert_eq!(client.execute(&define).await.unwrap().status, 0);
+
+    let call = JsRequestBuilder::call("size")
+        .json_arg(&doc)
+        .session("large_requests", Duration::default())
+        .build()
+        .unwrap();
+    let resp = client.execute(&call).await.unwrap();
+    assert_eq!(resp.status, 0);
+    assert_eq!(resp.result, (4 * 1024 * 1024).to_string());
+}
+
+#[tokio::test]
+async fn errors_over_http() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let client = server.client();
+
+    let resp = client.eval("var x = ;").await.unwrap();
+    assert_eq!(resp.status, 1);
+    assert_eq!(resp.error_type, ErrorType::CompileError as i32);
+    assert_eq!(resp.error.unwrap().name, "SyntaxError");
+
+    let resp = client
+        .eval("throw new TypeError('bad doc');")
+        .await
+        .unwrap();
+    assert_eq!(resp.error_type, ErrorType::RuntimeError as i32);
+    assert_eq!(resp.error.unwrap().message, "bad doc");
+
+    let resp = client.call("not_defined", &[]).await.unwrap();
+    assert_eq!(resp.error_type, ErrorType::RuntimeError as i32);
+    // The worker that ran it, to find its thread by
+    assert!(resp.worker > 0);
+
+    // Not a JSRequest at all
+    let resp = client.execute_raw(vec![0xff; 16]).await.unwrap();
+    assert_eq!(resp.error_type, ErrorType::InvalidRequest as i32);
+    assert_eq!(resp.worker, 0);
+}
+
+#[tokio::test]
+async fn timeouts_over_http() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let client = server.client();
+
+    let spin = JsRequestBuilder::eval("while (true) {}")
+        .timeout(Duration::from_millis(100))
+        .build()
+        .unwrap();
+    let resp = client.execute(&spin).await.unwrap();
+    assert_eq!(resp.error_type, ErrorType::Timeout as i32);
+
+    // The worker is usable again afterwards
+    let resp = client.eval("1 + 1;").await.unwrap();
+    assert_eq!(resp.result, "2");
+}
+
+#[tokio::test]
+async fn results_over_max_result_bytes() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let client = server.client();
+
+    let big = JsRequestBuilder::eval("'x'.repeat(1000);")
+        .max_result_bytes(100)
+        .build()
+        .unwrap();
+    let resp = client.execute(&big).await.unwrap();
+    assert_eq!(resp.error_type, ErrorType::ResultTooLarge as i32);
+    assert!(resp.result.contains("100 bytes"), "{}", resp.result);
+
+    let emits = JsRequestBuilder::eval("for (let i = 0; i < 100; i++) { emit(i, 'row'); } 1;")
+        .max_result_bytes(100)
+        .build()
+        .unwrap();
+    let resp = client.execute(&emits).await.unwrap();
+    assert_eq!(resp.error_type, ErrorType::ResultTooLarge as i32);
+    assert!(resp.emitted.is_empty());
+
+    let small = JsRequestBuilder::eval("'x'.repeat(10);")
+        .max_result_bytes(100)
+        .build()
+        .unwrap();
+    let resp = client.execute(&small).await.unwrap();
+    assert_eq!(resp.status, 0);
+}
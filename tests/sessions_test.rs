@@ -0,0 +1,46 @@
+use futures::executor::block_on;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use fortuna::js_server::{Command, Ops};
+use fortuna::sessions::Sessions;
+use fortuna::*;
+mod common;
+
+fn eval(script: &str) -> Command {
+    Command {
+        operation: Ops::EVAL,
+        payload: script.to_string(),
+        args: vec![],
+        docs: Vec::new(),
+    }
+}
+
+#[test]
+fn sessions_outlive_their_callers_until_idle() {
+    common::setup();
+
+    let js_env = Arc::new(JSEnv::new());
+    let sessions = Sessions::start(js_env, Capabilities::none(), Duration::from_secs(60));
+
+    let (js_client, created) = sessions.client("index-1", Duration::default());
+    assert!(created);
+    block_on(js_client.run(eval("var seen = 42;"))).unwrap();
+    drop(js_client);
+
+    let (js_client, created) = sessions.client("index-1", Duration::default());
+    assert!(!created);
+    assert_eq!(block_on(js_client.run(eval("seen;"))).unwrap(), "42");
+
+    let (_, created) = sessions.client("index-2", Duration::from_millis(50));
+    assert!(created);
+    assert_eq!(sessions.len(), 2);
+
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(sessions.expire(), 1);
+    assert_eq!(sessions.len(), 1);
+
+    let (_, created) = sessions.client("index-2", Duration::default());
+    assert!(created);
+}
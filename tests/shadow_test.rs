@@ -0,0 +1,41 @@
+use fortuna::shadow::{compare, is_sampled};
+use fortuna::{JSEnv, JSError, JsResponseBuilder};
+use std::path::Path;
+
+mod common;
+
+#[test]
+fn samples_evenly() {
+    let picked = |sample| (0..100).filter(|n| is_sampled(*n, sample)).count();
+    assert_eq!(picked(0.0), 0);
+    assert_eq!(picked(0.25), 25);
+    assert_eq!(picked(1.0), 100);
+}
+
+#[test]
+fn compares_results() {
+    let ok = |result: &str| JsResponseBuilder::ok(result).build();
+    assert_eq!(compare(&ok("1"), &ok("1")), None);
+    assert!(compare(&ok("1"), &ok("2")).is_some());
+
+    // Logs are expected to differ
+    let logged = JsResponseBuilder::ok("1")
+        .log(log::Level::Info, "shadow")
+        .build();
+    assert_eq!(compare(&ok("1"), &logged), None);
+
+    let failed = JsResponseBuilder::error(JSError::Timeout).build();
+    assert!(compare(&ok("1"), &failed).is_some());
+}
+
+#[test]
+fn env_from_dir() {
+    common::setup();
+    let js = Path::new(env!("CARGO_MANIFEST_DIR")).join("js");
+    let js_env = JSEnv::from_dir(&js).unwrap();
+    let mut isolate = js_env.create_isolate();
+    assert_eq!(isolate.eval("1 + 1;", &[]).unwrap(), "2");
+
+    let err = JSEnv::from_dir(&js.join("sandbox")).err().unwrap();
+    assert!(err.contains("rewrite_anon_fun.js"), "{}", err);
+}
@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+use fortuna::ateles::js_request::Priority;
+use fortuna::ateles::js_response::ErrorType;
+use fortuna::config::{self, Config};
+use fortuna::js_server::{worker_stats, Command, Ops};
+use fortuna::test_support::TestServer;
+use fortuna::*;
+mod common;
+
+fn spin(timeout: Duration) -> JsRequestBuilder {
+    JsRequestBuilder::eval("while (true) {}").timeout(timeout)
+}
+
+#[tokio::test]
+async fn tracks_queue_age() {
+    common::setup();
+    let js_env = JSEnv::new();
+    let js_client = create_js_env(&js_env, &Capabilities::none());
+    let eval = |script: &str| Command {
+        operation: Ops::EVAL,
+        payload: script.to_string(),
+        args: vec![],
+        docs: Vec::new(),
+    };
+    assert_eq!(js_client.queue_age(), Duration::default());
+
+    let busy = js_client.execute_timeout(eval("while (true) {}"), Duration::from_millis(400));
+    let queued = js_client.execute(eval("1;"));
+    let check = async {
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        let age = js_client.queue_age();
+        let stats = worker_stats(Duration::from_millis(10)).await;
+        let worker = stats.iter().find(|w| w.id == js_client.id()).unwrap();
+        (age, worker.queue_age)
+    };
+    let (_, queued, (age, stats_age)) = futures::join!(busy, queued, check);
+    assert_eq!(queued.result.unwrap(), "1");
+    assert!(age >= Duration::from_millis(150), "{:?}", age);
+    assert!(stats_age >= Duration::from_millis(150), "{:?}", stats_age);
+    assert_eq!(js_client.queue_age(), Duration::default());
+}
+
+#[tokio::test]
+async fn sheds_background_requests() {
+    common::setup();
+    Config::parse("shed_queue_age_ms = 100").unwrap().apply();
+    assert_eq!(config::shed_queue_age(), Some(Duration::from_millis(100)));
+
+    let server =
+        TestServer::start_with(MakeService::new().with_pool(1, Affinity::Request)).unwrap();
+    let client = server.client();
+    let background = JsRequestBuilder::eval("2;")
+        .priority(Priority::Background)
+        .build()
+        .unwrap();
+
+    let busy = client.execute(&spin(Duration::from_millis(800)).build().unwrap());
+    let queued = client.eval("1;");
+    let shed = async {
+        tokio::time::delay_for(Duration::from_millis(300)).await;
+        client.execute(&background).await.unwrap()
+    };
+    let (_, queued, shed) = futures::join!(busy, queued, shed);
+    assert_eq!(queued.unwrap().result, "1");
+    assert_eq!(shed.error_type, ErrorType::Overloaded as i32);
+
+    // Once the queue has caught up they run again
+    let resp = client.execute(&background).await.unwrap();
+    assert_eq!(resp.result, "2");
+}
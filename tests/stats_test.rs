@@ -0,0 +1,84 @@
+use futures::executor::block_on;
+
+use fortuna::js_server::{Command, Ops};
+use fortuna::*;
+mod common;
+
+#[test]
+fn replies_report_what_the_command_cost() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let js_client = create_js_env(&js_env, &Capabilities::none());
+
+    let cmd = Command {
+        operation: Ops::EVAL,
+        payload: "let n = 0; for (let i = 0; i < 1000000; i++) { n += i; } n;".to_string(),
+        args: vec![],
+        docs: Vec::new(),
+    };
+    let reply = block_on(js_client.execute(cmd));
+    assert_eq!(reply.result.unwrap(), "499999500000");
+    assert!(reply.stats.execution > std::time::Duration::default());
+    assert!(reply.stats.cpu > std::time::Duration::default());
+    assert!(reply.stats.heap_used > 0);
+
+    let metrics = metrics::render();
+    assert!(metrics.contains("fortuna_command_execution_seconds_count{kind=\"eval\"}"));
+}
+
+#[test]
+fn workers_report_their_isolates() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let js_client = create_js_env(&js_env, &Capabilities::none());
+
+    let cmd = Command {
+        operation: Ops::EVAL,
+        payload: "throw new Error('stats test');".to_string(),
+        args: vec![],
+        docs: Vec::new(),
+    };
+    assert!(block_on(js_client.run(cmd)).is_err());
+
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let stats = rt.block_on(js_server::worker_stats(std::time::Duration::from_secs(5)));
+    let worker = stats
+        .iter()
+        .find(|worker| matches!(&worker.last_error, Some(err) if err.contains("stats test")))
+        .unwrap();
+    assert_eq!(worker.requests, 1);
+    assert_eq!(worker.errors, 1);
+    assert_eq!(worker.thread, format!("fortuna-js-{}", js_client.id()));
+    assert!(worker.os_thread_id.is_some());
+    assert_eq!(worker.recycles, 0);
+    assert_eq!(worker.queue_depth, 0);
+    let heap = worker.heap.as_ref().unwrap();
+    assert!(heap.used > 0 && heap.used <= heap.total);
+    assert!(heap.contexts >= 1);
+}
+
+#[test]
+fn startup_cost_is_measured() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let startup = js_env.measure_startup();
+    assert_eq!(startup.snapshot_bytes, js_env.startup_data.len());
+    assert!(startup.create > std::time::Duration::default());
+
+    let js_client = create_js_env(&js_env, &Capabilities::none());
+    let cmd = Command {
+        operation: Ops::EVAL,
+        payload: "1;".to_string(),
+        args: vec![],
+        docs: Vec::new(),
+    };
+    assert_eq!(block_on(js_client.run(cmd)).unwrap(), "1");
+
+    assert!(metrics::SNAPSHOT_BYTES.get() > 0.0);
+    let metrics = metrics::render();
+    assert!(metrics.contains("fortuna_isolate_create_seconds_count{kind=\"snapshot\"}"));
+    assert!(metrics.contains("fortuna_first_command_seconds_count{kind=\"eval\"}"));
+}
@@ -0,0 +1,81 @@
+use futures::executor::block_on;
+use std::sync::mpsc;
+
+use fortuna::js_server::{self, Command, Ops};
+use fortuna::test_support::TestServer;
+use fortuna::threads;
+use fortuna::*;
+mod common;
+
+#[test]
+fn threads_are_named_and_tracked() {
+    let (started, running) = mpsc::channel();
+    let (stop, stopped) = mpsc::channel::<()>();
+    let thread = threads::spawn("fortuna-test".to_string(), move || {
+        started
+            .send((
+                std::thread::current().name().map(str::to_string),
+                threads::os_thread_id(),
+            ))
+            .unwrap();
+        stopped.recv().unwrap();
+    })
+    .unwrap();
+
+    let (name, os_id) = running.recv().unwrap();
+    assert_eq!(name.as_deref(), Some("fortuna-test"));
+    assert_ne!(os_id, threads::os_thread_id());
+    let info = threads::list()
+        .into_iter()
+        .find(|thread| thread.os_id == os_id)
+        .unwrap();
+    assert_eq!(info.name, "fortuna-test");
+
+    stop.send(()).unwrap();
+    thread.join().unwrap();
+    assert!(!threads::list().iter().any(|thread| thread.os_id == os_id));
+}
+
+#[test]
+fn workers_report_their_thread() {
+    common::setup();
+
+    let js_env = JSEnv::new();
+    let js_client = create_js_env(&js_env, &Capabilities::none());
+    let id = js_client.id();
+    block_on(js_client.run(Command {
+        operation: Ops::EVAL,
+        payload: "1;".to_string(),
+        args: vec![],
+        docs: Vec::new(),
+    }))
+    .unwrap();
+
+    let worker = js_server::workers()
+        .into_iter()
+        .find(|worker| worker.id == id)
+        .unwrap();
+    let os_id = worker.os_thread_id.unwrap();
+    let thread = threads::list()
+        .into_iter()
+        .find(|thread| thread.os_id == os_id)
+        .unwrap();
+    assert_eq!(thread.name, format!("fortuna-js-{}", id));
+}
+
+#[tokio::test]
+async fn threads_are_listed() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+
+    let resp = reqwest::get(&format!("{}/admin/threads", server.url()))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    let threads = body["threads"].as_array().unwrap();
+    assert!(threads
+        .iter()
+        .any(|thread| thread["name"].as_str().unwrap().starts_with("fortuna-js-")));
+    assert!(threads.iter().all(|thread| thread["os_thread_id"].is_i64()));
+}
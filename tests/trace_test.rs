@@ -0,0 +1,130 @@
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use prost::Message;
+use serde_json::Value;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+use fortuna::test_support::TestServer;
+use fortuna::trace::{to_otlp, RequestTrace, TraceContext, Tracer};
+use fortuna::{JsRequestBuilder, MakeService};
+mod common;
+
+const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+#[test]
+fn parses_traceparent() {
+    let context = TraceContext::parse(TRACEPARENT).unwrap();
+    assert_eq!(context.trace_id, 0x4bf92f3577b34da6a3ce929d0e0e4736);
+    assert_eq!(context.parent_id, 0x00f067aa0ba902b7);
+    assert!(context.sampled);
+    assert_eq!(context.header(), TRACEPARENT);
+
+    let unsampled = TraceContext::parse(&TRACEPARENT.replace("-01", "-00")).unwrap();
+    assert!(!unsampled.sampled);
+
+    assert_eq!(TraceContext::parse(""), None);
+    assert_eq!(
+        TraceContext::parse(&TRACEPARENT.replace("00-", "01-")),
+        None
+    );
+    assert_eq!(
+        TraceContext::parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01"),
+        None
+    );
+    assert_eq!(TraceContext::parse("00-4bf92f35-00f067aa0ba902b7-01"), None);
+}
+
+#[test]
+fn requests_get_three_spans() {
+    let context = TraceContext::parse(TRACEPARENT).unwrap();
+    let trace = RequestTrace::start(Some(context));
+    let sent = UNIX_EPOCH + Duration::from_secs(1);
+    let spans = trace.finish(
+        "eval",
+        sent,
+        Duration::from_millis(2),
+        Duration::from_millis(5),
+        None,
+    );
+
+    let names: Vec<&str> = spans.iter().map(|span| span.name.as_str()).collect();
+    assert_eq!(names, vec!["fortuna.execute", "queue_wait", "js_execution"]);
+    assert!(spans.iter().all(|span| span.trace_id == context.trace_id));
+    assert_eq!(spans[0].parent_id, context.parent_id);
+    assert_eq!(spans[1].parent_id, spans[0].span_id);
+    assert_eq!(spans[1].end, sent + Duration::from_millis(2));
+    assert_eq!(spans[2].start, spans[1].end);
+    assert_eq!(spans[2].end, sent + Duration::from_millis(7));
+
+    let otlp = to_otlp("fortuna", &spans);
+    let exported = &otlp["resourceSpans"][0]["scopeSpans"][0]["spans"];
+    assert_eq!(exported[0]["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_eq!(exported[0]["parentSpanId"], "00f067aa0ba902b7");
+    assert_eq!(exported[0]["kind"], 2);
+    assert_eq!(exported[1]["startTimeUnixNano"], "1000000000");
+}
+
+// A collector that passes on every body posted to it
+fn start_collector() -> (SocketAddr, UnboundedReceiver<Value>) {
+    let (sender, receiver) = unbounded_channel();
+    let make_svc = make_service_fn(move |_| {
+        let sender = sender.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let sender = sender.clone();
+                async move {
+                    let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                    let _ = sender.send(serde_json::from_slice(&body).unwrap());
+                    Ok::<_, Infallible>(Response::new(Body::from("{}")))
+                }
+            }))
+        }
+    });
+    let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    (addr, receiver)
+}
+
+#[tokio::test]
+async fn continues_the_callers_trace() {
+    common::setup();
+    let (collector, mut exported) = start_collector();
+    let endpoint = format!("http://{}/v1/traces", collector);
+    let make_service = MakeService::new().with_tracer(Tracer::start(&endpoint, "fortuna", 0.0));
+    let server = TestServer::start_with(make_service).unwrap();
+
+    let mut body = Vec::new();
+    JsRequestBuilder::eval("1 + 1;")
+        .build()
+        .unwrap()
+        .encode(&mut body)
+        .unwrap();
+    let resp = reqwest::Client::new()
+        .post(&format!("{}/Ateles/Execute", server.url()))
+        .header("traceparent", TRACEPARENT)
+        .body(body)
+        .send()
+        .await
+        .unwrap();
+    assert!(resp.status().is_success());
+
+    let otlp = tokio::time::timeout(Duration::from_secs(5), exported.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    let resource = &otlp["resourceSpans"][0];
+    assert_eq!(
+        resource["resource"]["attributes"][0]["value"]["stringValue"],
+        "fortuna"
+    );
+    let spans = resource["scopeSpans"][0]["spans"].as_array().unwrap();
+    assert_eq!(spans.len(), 3);
+    assert_eq!(spans[0]["name"], "fortuna.execute");
+    assert_eq!(spans[0]["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_eq!(spans[0]["parentSpanId"], "00f067aa0ba902b7");
+    assert_eq!(spans[0]["attributes"][0]["value"]["stringValue"], "eval");
+}
@@ -0,0 +1,112 @@
+use prost::Message;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+use fortuna::ateles::js_request::{Action, Priority};
+use fortuna::ateles::{JsRequest, JsResponse};
+use fortuna::test_support::TestServer;
+use fortuna::transcode::{request_from_json, request_to_json, response_to_json};
+use fortuna::JsRequestBuilder;
+mod common;
+
+#[test]
+fn requests_round_trip_through_json() {
+    let request = JsRequestBuilder::call("mapDoc")
+        .json_arg("{\"_id\": \"foo\"}")
+        .timeout(Duration::from_secs(5))
+        .priority(Priority::Background)
+        .view_rows("foo")
+        .session("indexer", Duration::from_secs(60))
+        .build()
+        .unwrap();
+    let value = request_to_json(&request);
+    assert_eq!(value["action"], "CALL");
+    assert_eq!(value["priority"], "BACKGROUND");
+    assert_eq!(value["emit_format"], "VIEW_KV");
+    assert_eq!(value["json_args"], json!(["{\"_id\": \"foo\"}"]));
+    assert_eq!(request_from_json(&value).unwrap(), request);
+
+    let request = JsRequestBuilder::wasm_call(&[0, 0x61, 0x73, 0x6d], "add")
+        .json_arg("1")
+        .build()
+        .unwrap();
+    let value = request_to_json(&request);
+    assert_eq!(value["wasm_module"], "0061736d");
+    assert_eq!(request_from_json(&value).unwrap(), request);
+
+    // Values the proto doesn't know are kept as numbers
+    let request = JsRequest {
+        action: 99,
+        ..JsRequest::default()
+    };
+    assert_eq!(request_to_json(&request)["action"], 99);
+}
+
+#[test]
+fn hand_written_requests() {
+    let request = request_from_json(&json!({"action": 1, "script": "1 + 1;"})).unwrap();
+    assert_eq!(request.action, Action::Eval as i32);
+    assert_eq!(request.script, "1 + 1;");
+    assert_eq!(request.timeout, 0);
+
+    let err = |value| request_from_json(&value).unwrap_err();
+    assert_eq!(err(json!({"scrpit": "1;"})), "unknown field scrpit");
+    assert_eq!(err(json!({"action": "RUN"})), "action: unknown value RUN");
+    assert!(err(json!({"args": "1"})).starts_with("args: "));
+    assert!(err(json!({"timeout": 1e12})).starts_with("timeout: "));
+    assert!(err(json!({"wasm_module": "abc"})).starts_with("wasm_module: "));
+    assert!(err(json!([])).contains("object"));
+}
+
+#[tokio::test]
+async fn transcoding_over_http() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let client = reqwest::Client::new();
+
+    let request = JsRequestBuilder::eval("1 + 1;").build().unwrap();
+    let mut body = Vec::new();
+    request.encode(&mut body).unwrap();
+    let resp = client
+        .post(&format!("{}/admin/decode", server.url()))
+        .body(body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let value: serde_json::Value = serde_json::from_str(&resp.text().await.unwrap()).unwrap();
+    assert_eq!(value["action"], "EVAL");
+
+    let resp = client
+        .post(&format!("{}/admin/encode", server.url()))
+        .body(value.to_string())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let encoded = resp.bytes().await.unwrap();
+    assert_eq!(JsRequest::decode(encoded).unwrap(), request);
+
+    let resp = client
+        .post(&format!("{}/admin/encode", server.url()))
+        .body("{\"action\": \"RUN\"}")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+    let js_resp = JsResponse::decode(resp.bytes().await.unwrap()).unwrap();
+    assert!(js_resp.result.contains("unknown value RUN"));
+}
+
+#[test]
+fn responses_as_json() {
+    let mut response = JsResponse::from(fortuna::JSError::QueueFull);
+    response.request_id = "r-1".to_string();
+    response.result_etf = vec![131, 97, 1];
+    let value = response_to_json(&response);
+    assert_eq!(value["status"], 1);
+    assert_eq!(value["error_type"], "QUEUE_FULL");
+    assert_eq!(value["error"], Value::Null);
+    assert_eq!(value["result_etf"], "836101");
+    assert_eq!(value["request_id"], "r-1");
+}
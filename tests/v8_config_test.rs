@@ -0,0 +1,84 @@
+use fortuna::v8_config::{self, HeapConfig, SecurityMode};
+use fortuna::{snapshot_prebuilt, JSEnv};
+
+mod common;
+
+const GIB: usize = 1024 * 1024 * 1024;
+
+#[test]
+fn heap_config_is_detected() {
+    common::setup();
+    let config = v8_config::current().expect("detected when V8 starts");
+    assert!(!config.version.is_empty());
+    assert!(config.max_heap_limit > 0);
+    if config.pointer_compression {
+        assert!(config.max_heap_limit <= 4 * GIB);
+    }
+    assert_eq!(config, v8_config::detect());
+}
+
+#[test]
+fn unenforceable_heap_limits_warn() {
+    let compressed = HeapConfig {
+        version: "8.4.300".to_string(),
+        pointer_compression: true,
+        sandbox: Some(false),
+        max_heap_limit: 4 * GIB,
+    };
+    assert_eq!(v8_config::check_heap_limit(GIB, &compressed), None);
+    assert_eq!(v8_config::check_heap_limit(4 * GIB, &compressed), None);
+    let warning = v8_config::check_heap_limit(8 * GIB, &compressed).unwrap();
+    assert!(warning.contains("pointer compression"));
+    assert!(warning.contains(&(4 * GIB).to_string()));
+
+    let uncompressed = HeapConfig {
+        pointer_compression: false,
+        max_heap_limit: 16 * GIB,
+        ..compressed
+    };
+    assert_eq!(v8_config::check_heap_limit(8 * GIB, &uncompressed), None);
+    assert!(v8_config::check_heap_limit(32 * GIB, &uncompressed).is_some());
+}
+
+#[test]
+fn snapshot_matches_the_build() {
+    common::setup();
+
+    // The build falls back to making it at startup, e.g. when cross compiling
+    if snapshot_prebuilt() {
+        assert!(cfg!(feature = "prebuilt-snapshot"));
+    }
+    let js_env = JSEnv::try_new().unwrap();
+    let mut isolate = js_env.create_isolate();
+    assert_eq!(
+        isolate.eval("typeof rewriteFun;", &[]).unwrap(),
+        "\"function\""
+    );
+}
+
+#[test]
+fn parses_v8_flags() {
+    assert_eq!(
+        v8_config::parse_flags(" --max_old_space_size=512  --jitless "),
+        Ok(vec![
+            "--max_old_space_size=512".to_string(),
+            "--jitless".to_string()
+        ])
+    );
+    assert_eq!(v8_config::parse_flags(""), Ok(vec![]));
+    assert!(v8_config::parse_flags("--jitless max_old_space_size=512").is_err());
+    assert!(v8_config::parse_flags("--").is_err());
+}
+
+#[test]
+fn security_modes() {
+    assert_eq!("default".parse(), Ok(SecurityMode::Default));
+    assert_eq!("hardened".parse(), Ok(SecurityMode::Hardened));
+    assert!("paranoid".parse::<SecurityMode>().is_err());
+    assert!(SecurityMode::Default.v8_flags().is_empty());
+    assert!(SecurityMode::Hardened.v8_flags().contains(&"--jitless"));
+    assert_eq!(SecurityMode::Hardened.as_str(), "hardened");
+    // The tests start V8 with the default platform config
+    common::setup();
+    assert_eq!(v8_config::security_mode(), SecurityMode::Default);
+}
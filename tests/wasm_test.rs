@@ -0,0 +1,82 @@
+use fortuna::ateles::js_response::ErrorType;
+use fortuna::test_support::TestServer;
+use fortuna::*;
+mod common;
+
+// (func (export "add") (param i32 i32) (result i32) local.get 0 local.get 1 i32.add)
+const ADD_WASM: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x07, 0x01, 0x60, 0x02, 0x7f, 0x7f, 0x01,
+    0x7f, 0x03, 0x02, 0x01, 0x00, 0x07, 0x07, 0x01, 0x03, 0x61, 0x64, 0x64, 0x00, 0x00, 0x0a, 0x09,
+    0x01, 0x07, 0x00, 0x20, 0x00, 0x20, 0x01, 0x6a, 0x0b,
+];
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[test]
+fn call_a_wasm_function() {
+    common::setup();
+    let js_env = JSEnv::new();
+    let mut isolate = js_env.create_isolate();
+
+    // The first arg is what the instance is cached under
+    let args = |a: &str, b: &str| vec!["add.wasm".to_string(), hex(ADD_WASM), a.into(), b.into()];
+    assert_eq!(isolate.wasm_call("add", &args("2", "3")).unwrap(), "5");
+    // Served by the cached instance
+    assert_eq!(isolate.wasm_call("add", &args("40", "2")).unwrap(), "42");
+
+    match isolate.wasm_call("sub", &args("1", "1")) {
+        Err(JSError::RuntimeError(info)) => assert_eq!(info.name, "TypeError"),
+        other => panic!("unexpected {:?}", other),
+    }
+    let bad = vec!["bad".to_string(), "00".to_string()];
+    assert!(isolate.wasm_call("add", &bad).is_err());
+}
+
+#[test]
+fn wasm_needs_its_capability() {
+    common::setup();
+    let js_env = JSEnv::new();
+
+    let mut isolate = js_env.create_isolate_with_capabilities(&Capabilities::all());
+    assert_eq!(
+        isolate.eval("typeof WebAssembly", &[]).unwrap(),
+        "\"object\""
+    );
+
+    let mut isolate = js_env.create_isolate_with_capabilities(&Capabilities::none());
+    assert_eq!(
+        isolate.eval("typeof WebAssembly", &[]).unwrap(),
+        "\"undefined\""
+    );
+    let args = vec![
+        "add.wasm".to_string(),
+        hex(ADD_WASM),
+        "1".into(),
+        "2".into(),
+    ];
+    assert!(isolate.wasm_call("add", &args).is_err());
+}
+
+#[tokio::test]
+async fn wasm_call_over_http() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let client = server.client();
+
+    let call = JsRequestBuilder::wasm_call(ADD_WASM, "add")
+        .json_arg("20")
+        .json_arg("22")
+        .build()
+        .unwrap();
+    let resp = client.execute(&call).await.unwrap();
+    assert_eq!(resp.status, 0);
+    assert_eq!(resp.result, "42");
+
+    let invalid = JsRequestBuilder::wasm_call(b"not wasm", "add")
+        .build()
+        .unwrap();
+    let resp = client.execute(&invalid).await.unwrap();
+    assert_eq!(resp.error_type, ErrorType::RuntimeError as i32);
+}
@@ -0,0 +1,151 @@
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use fortuna::audit::{AuditConfig, AuditDest, AuditLog};
+use fortuna::test_support::TestServer;
+use fortuna::websocket::{self, Decoder, Frame};
+use fortuna::MakeService;
+mod common;
+
+// Clients mask what they send
+fn masked(text: &str) -> Vec<u8> {
+    let mask = [1u8, 2, 3, 4];
+    let mut frame = vec![0x81];
+    if text.len() < 126 {
+        frame.push(0x80 | text.len() as u8);
+    } else {
+        frame.push(0x80 | 126);
+        frame.extend_from_slice(&(text.len() as u16).to_be_bytes());
+    }
+    frame.extend_from_slice(&mask);
+    frame.extend(text.bytes().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    frame
+}
+
+#[test]
+fn decodes_frames_as_they_arrive() {
+    let long = "x".repeat(300);
+    let mut stream = masked("hello");
+    stream.extend(websocket::encode_frame(websocket::PING, b"p"));
+    stream.extend(masked(&long));
+
+    let mut decoder = Decoder::new(1024);
+    let mut frames = Vec::new();
+    for chunk in stream.chunks(3) {
+        decoder.extend(chunk);
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            frames.push(frame);
+        }
+    }
+    assert_eq!(
+        frames[0],
+        Frame {
+            fin: true,
+            opcode: websocket::TEXT,
+            payload: b"hello".to_vec(),
+        }
+    );
+    assert_eq!(frames[1].opcode, websocket::PING);
+    assert_eq!(frames[1].payload, b"p");
+    assert_eq!(frames[2].payload, long.as_bytes());
+
+    let mut decoder = Decoder::new(100);
+    decoder.extend(&masked(&long));
+    assert!(decoder.next_frame().is_err());
+}
+
+async fn receive(ws: &mut TcpStream, decoder: &mut Decoder) -> Value {
+    let mut chunk = [0u8; 4096];
+    loop {
+        if let Some(frame) = decoder.next_frame().unwrap() {
+            assert_eq!(frame.opcode, websocket::TEXT);
+            return serde_json::from_slice(&frame.payload).unwrap();
+        }
+        let read = ws.read(&mut chunk).await.unwrap();
+        assert!(read > 0, "websocket closed");
+        decoder.extend(&chunk[..read]);
+    }
+}
+
+async fn connect(server: &TestServer) -> TcpStream {
+    let mut ws = TcpStream::connect(server.addr).await.unwrap();
+    let handshake = format!(
+        "GET /ws HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        server.addr
+    );
+    ws.write_all(handshake.as_bytes()).await.unwrap();
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        ws.read_exact(&mut byte).await.unwrap();
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap().to_lowercase();
+    assert!(head.starts_with("http/1.1 101"), "{}", head);
+    assert!(head.contains("sec-websocket-accept: s3pplmbitxaq9kyguzvi5+0oo0q="));
+    ws
+}
+
+#[tokio::test]
+async fn executes_json_requests() {
+    common::setup();
+    let server = TestServer::start().unwrap();
+    let mut ws = connect(&server).await;
+
+    let mut decoder = Decoder::new(1024 * 1024);
+    let request =
+        r#"{"action": "EVAL", "script": "console.log('hi'); 1 + 1;", "request_id": "ws-1"}"#;
+    ws.write_all(&masked(request)).await.unwrap();
+    let log = receive(&mut ws, &mut decoder).await;
+    assert_eq!(log["type"], "log");
+    assert_eq!(log["request_id"], "ws-1");
+    assert_eq!(log["message"], "hi");
+    let resp = receive(&mut ws, &mut decoder).await;
+    assert_eq!(resp["type"], "response");
+    assert_eq!(resp["response"]["status"], 0);
+    assert_eq!(resp["response"]["result"], "2");
+    assert_eq!(resp["response"]["error_type"], "NONE");
+    assert_eq!(resp["response"]["request_id"], "ws-1");
+
+    ws.write_all(&masked(r#"{"scrpit": "1;"}"#)).await.unwrap();
+    let resp = receive(&mut ws, &mut decoder).await;
+    assert_eq!(resp["response"]["error_type"], "INVALID_REQUEST");
+    assert_eq!(
+        resp["response"]["result"],
+        "invalid_request: unknown field scrpit"
+    );
+
+    let resp = reqwest::get(&format!("{}/ws", server.url())).await.unwrap();
+    assert_eq!(resp.status(), 400);
+}
+
+#[tokio::test]
+async fn audits_what_runs() {
+    common::setup();
+    let path = std::env::temp_dir().join(format!("fortuna-ws-audit-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let audit_log = AuditLog::open(&AuditConfig::new(AuditDest::File(path.clone()))).unwrap();
+    let server = TestServer::start_with(MakeService::new().with_audit_log(audit_log)).unwrap();
+    let mut ws = connect(&server).await;
+
+    let mut decoder = Decoder::new(1024 * 1024);
+    ws.write_all(&masked(r#"{"action": "EVAL", "script": "1 + 1;"}"#))
+        .await
+        .unwrap();
+    let resp = receive(&mut ws, &mut decoder).await;
+    assert_eq!(resp["response"]["result"], "2");
+
+    // Written in the background
+    let mut audited = String::new();
+    for _ in 0..100 {
+        audited = std::fs::read_to_string(&path).unwrap_or_default();
+        if !audited.is_empty() {
+            break;
+        }
+        tokio::time::delay_for(std::time::Duration::from_millis(20)).await;
+    }
+    let entry: Value = serde_json::from_str(audited.lines().next().unwrap()).unwrap();
+    assert_eq!(entry["op"], "eval");
+    assert_eq!(entry["outcome"], "ok");
+}